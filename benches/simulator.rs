@@ -0,0 +1,58 @@
+// Runs the crate's register IO and move sequencing against the simulator on
+// a ManualClock, so what is measured is the crate's own overhead rather than
+// time spent waiting on a drive.  Modbus traffic per move is printed once up
+// front, from the device's IO counters.
+use applied_device::{AppliedDevice, Clock, ManualClock, SimulatedDrive};
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::sync::Arc;
+
+fn simulated_device() -> AppliedDevice {
+    let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+    let drive = SimulatedDrive::with_clock(clock.clone());
+    let mut device = AppliedDevice::with_transport(String::from("bench"), String::new(), drive);
+    device.set_clock(clock);
+    device
+        .enable_motor()
+        .expect("Unable to enable the simulated motor");
+    device
+}
+
+fn moves(c: &mut Criterion) {
+    let mut device = simulated_device();
+    let before = device.io_counters();
+    device.move_servo(10, 10, 100, 50000).expect("Move failed");
+    let after = device.io_counters();
+    println!(
+        "One move: {} round trips, {} bytes, {} retries",
+        after.round_trips() - before.round_trips(),
+        (after.bytes_sent + after.bytes_received) - (before.bytes_sent + before.bytes_received),
+        after.retries - before.retries
+    );
+
+    let mut target = 0;
+    c.bench_function("move", |b| {
+        b.iter(|| {
+            // Back and forth, so every move travels
+            target = if target == 0 { 50000 } else { 0 };
+            device.move_servo(10, 10, 100, target).expect("Move failed")
+        })
+    });
+}
+
+fn reads(c: &mut Criterion) {
+    let mut device = simulated_device();
+    c.bench_function("get_encoder_count", |b| {
+        b.iter(|| device.get_encoder_count().expect("Read failed"))
+    });
+    c.bench_function("get_servo_status", |b| {
+        b.iter(|| {
+            device
+                .get_servo_status()
+                .map(|s| s.len())
+                .expect("Read failed")
+        })
+    });
+}
+
+criterion_group!(benches, moves, reads);
+criterion_main!(benches);
@@ -0,0 +1,127 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use std::convert::TryFrom;
+use tracing::info;
+use yaml_rust::Yaml;
+
+impl AppliedDevice {
+    // For drives fitted with a multi-turn absolute encoder, which keeps its
+    // position through a power cycle.  With skip_homing set, home_servo does
+    // nothing since the position is already known.
+    pub fn set_absolute_encoder(&mut self, enabled: bool, skip_homing: bool) {
+        self.absolute_encoder = enabled;
+        self.skip_homing = enabled && skip_homing;
+    }
+
+    pub fn has_absolute_encoder(&self) -> bool {
+        self.absolute_encoder
+    }
+
+    // The full 48 bit position of an absolute encoder, in counts from its
+    // zero.  The three words are read in one transaction so the position
+    // can't change part way through.  Everything else, from moves to the
+    // snapshot, works in 32 bit encoder counts, so a position past those is
+    // an error rather than being wrapped into them.
+    pub fn get_absolute_position(&mut self) -> Result<i64, DeviceError> {
+        if !self.absolute_encoder {
+            return Err(DeviceError::Config(format!(
+                "{} has no absolute encoder configured",
+                self.servo_name
+            )));
+        }
+        let words = self.read_registers(self.mapped(MappedRegister::AbsolutePosition)?, 3)?;
+        let position = absolute_position(&words);
+        if i32::try_from(position).is_err() {
+            return Err(DeviceError::PositionOutOfRange(format!(
+                "{} is at absolute position {}, past a 32 bit encoder count",
+                self.servo_name, position
+            )));
+        }
+
+        let encoder_count = position as u32 as u64;
+        self.update_snapshot(|s| s.encoder_count = encoder_count);
+        self.persistent.last_position = Some(encoder_count);
+        self.check_position_watches(encoder_count);
+        Ok(position)
+    }
+
+    // Reads where an absolute axis is after a restart
+    pub(crate) fn read_startup_position(&mut self) -> Result<(), DeviceError> {
+        let position = self.get_absolute_position()?;
+        info!(
+            "{} started at absolute position {}",
+            self.servo_name, position
+        );
+        Ok(())
+    }
+}
+
+// Joins the three position words, high first, sign extending from 48 bits
+fn absolute_position(words: &[u16]) -> i64 {
+    let raw = words
+        .iter()
+        .fold(0u64, |position, word| (position << 16) | *word as u64);
+    ((raw << 16) as i64) >> 16
+}
+
+// Reads the optional `absolute_encoder:` section of a device config,
+// returning whether homing should be skipped
+//
+//   absolute_encoder:
+//     skip_homing: true
+pub(crate) fn absolute_encoder_from_yaml(device_conf: &Yaml) -> Result<Option<bool>, DeviceError> {
+    let section = &device_conf["absolute_encoder"];
+    match section {
+        Yaml::BadValue | Yaml::Null => return Ok(None),
+        Yaml::Hash(_) => {}
+        other => {
+            return Err(DeviceError::Config(format!(
+                "absolute_encoder must be a map, got {:?}",
+                other
+            )))
+        }
+    }
+    match &section["skip_homing"] {
+        Yaml::BadValue | Yaml::Null => Ok(Some(false)),
+        Yaml::Boolean(b) => Ok(Some(*b)),
+        other => Err(DeviceError::Config(format!(
+            "absolute_encoder.skip_homing must be true or false, got {:?}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    #[test]
+    fn positions_are_decoded_from_all_48_bits() {
+        assert_eq!(absolute_position(&[0x0002, 0x0000, 0x0001]), (1 << 33) + 1);
+        assert_eq!(absolute_position(&[0xffff, 0xffff, 0xfffb]), -5);
+        assert_eq!(absolute_position(&[0x8000, 0x0000, 0x0000]), -(1 << 47));
+    }
+
+    #[test]
+    fn positions_past_32_bits_are_refused() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let mut device =
+            AppliedDevice::with_transport(String::from("absolute"), String::new(), drive.clone());
+        device.set_clock(clock);
+        device.set_register_map(&SimulatedDrive::register_map());
+        device.set_absolute_encoder(true, true);
+
+        drive.set_position(-5);
+        assert_eq!(device.get_absolute_position().unwrap(), -5);
+        assert_eq!(device.last_snapshot().encoder_count, (-5i32) as u32 as u64);
+
+        drive.set_position((1 << 32) + 7);
+        match device.get_absolute_position() {
+            Err(DeviceError::PositionOutOfRange(msg)) => assert!(msg.contains("4294967303")),
+            other => panic!("Expected PositionOutOfRange, got {:?}", other),
+        }
+        assert_eq!(device.last_snapshot().encoder_count, (-5i32) as u32 as u64);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{AppliedDevice, DeviceError};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One command issued to a drive: the opcode written to the execute
+// register, along with every other register written since the previous
+// command (the command's parameters).  Each of those writes also gets a
+// record of its own, without an opcode, as it is made, so a write that no
+// command follows is still traced.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    pub servo_name: String,
+    pub servo_address: String,
+    pub opcode: Option<u16>,         // None for a register write
+    pub parameters: Vec<(u16, u16)>, // (register, value) in the order written
+    pub reason: Option<String>,      // Set by the caller through set_command_reason
+}
+
+impl AuditRecord {
+    // A single line form of the record, as written by FileAuditLog
+    pub fn to_line(&self) -> String {
+        let millis = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let parameters: Vec<String> = self
+            .parameters
+            .iter()
+            .map(|(r, v)| format!("{}={}", r, v))
+            .collect();
+        let opcode = match self.opcode {
+            Some(opcode) => opcode.to_string(),
+            None => String::from("-"),
+        };
+
+        format!(
+            "{} servo={} address={} opcode={} parameters=[{}] reason={:?}",
+            millis,
+            self.servo_name,
+            self.servo_address,
+            opcode,
+            parameters.join(","),
+            self.reason.as_deref().unwrap_or("")
+        )
+    }
+}
+
+// Receives every command and register write a device issues.  Records are
+// handed over before the write is sent, and a failing sink stops the write
+// from being sent, so nothing reaches the drive without being recorded.
+pub trait AuditSink: Send {
+    fn record(&mut self, record: &AuditRecord) -> Result<(), String>;
+}
+
+impl<F: FnMut(&AuditRecord) -> Result<(), String> + Send> AuditSink for F {
+    fn record(&mut self, record: &AuditRecord) -> Result<(), String> {
+        self(record)
+    }
+}
+
+// Appends one line per record to a file, syncing each line to disk
+pub struct FileAuditLog {
+    file: File,
+}
+
+impl FileAuditLog {
+    pub fn open(path: &str) -> Result<FileAuditLog, DeviceError> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(FileAuditLog { file }),
+            Err(e) => Err(DeviceError::Audit(format!(
+                "Unable to open audit log {}: {}",
+                path, e
+            ))),
+        }
+    }
+}
+
+impl AuditSink for FileAuditLog {
+    fn record(&mut self, record: &AuditRecord) -> Result<(), String> {
+        writeln!(self.file, "{}", record.to_line())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|e| format!("Unable to write audit log: {}", e))
+    }
+}
+
+// The audit state kept by a device
+pub(crate) struct AuditLog {
+    sink: Box<dyn AuditSink>,
+    pending: Vec<(u16, u16)>,
+}
+
+impl AppliedDevice {
+    pub fn set_audit_sink(&mut self, sink: Box<dyn AuditSink>) {
+        self.audit = Some(AuditLog {
+            sink,
+            pending: Vec::new(),
+        });
+    }
+
+    pub fn clear_audit_sink(&mut self) {
+        self.audit = None;
+    }
+
+    // Attaches a reason (work order, operator action, ...) to every command
+    // issued until it is changed or cleared with None
+    pub fn set_command_reason(&mut self, reason: Option<String>) {
+        self.command_reason = reason;
+    }
+
+    // Called for every register write before it is sent
+    pub(crate) fn audit_write(&mut self, register: u16, value: u16) -> Result<(), DeviceError> {
+        let audit = match &mut self.audit {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+
+        let (opcode, parameters) = match register == crate::EXECUTE_COMMAND {
+            true => (Some(value), std::mem::take(&mut audit.pending)),
+            false => (None, vec![(register, value)]),
+        };
+        let record = AuditRecord {
+            timestamp: self.clock.wall_time(),
+            servo_name: self.servo_name.clone(),
+            servo_address: self.servo_address.clone(),
+            opcode,
+            parameters,
+            reason: self.command_reason.clone(),
+        };
+        audit.sink.record(&record).map_err(DeviceError::Audit)?;
+        // Only once recorded, as a write the sink refused is never sent
+        if opcode.is_none() {
+            audit.pending.push((register, value));
+        }
+        Ok(())
+    }
+}
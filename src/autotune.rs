@@ -0,0 +1,88 @@
+use crate::history::outcome_of;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, Operation, ServoGains, ALARM, TUNING,
+};
+use std::time::Duration;
+use tracing::{info, warn};
+
+static TUNING_POLL_INTERVAL: u64 = 500; // In ms
+
+// What an autotune run came up with
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutotuneReport {
+    pub duration: Duration,
+    pub gains: ServoGains,
+}
+
+impl AppliedDevice {
+    pub fn start_autotune(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<AutotuneReport>, DeviceError> {
+        self.start_autotune_with_cancel(timeout, &CancelToken::new())
+    }
+
+    // Runs the drive's auto-tuning, which moves the motor back and forth, and
+    // waits for it to finish.  Returns the resulting gains, or None if tuning
+    // was cancelled or took longer than the timeout, in which case the drive
+    // is told to stop.  An alarm during tuning is an error.
+    pub fn start_autotune_with_cancel(
+        &mut self,
+        timeout: Duration,
+        cancel: &CancelToken,
+    ) -> Result<Option<AutotuneReport>, DeviceError> {
+        let started = self.clock.now();
+        let result = self.autotune(timeout, cancel);
+        let finished = result.as_ref().map(|report| report.is_some());
+        self.record_operation(Operation::Autotune, started, outcome_of(&finished));
+        result
+    }
+
+    fn autotune(
+        &mut self,
+        timeout: Duration,
+        cancel: &CancelToken,
+    ) -> Result<Option<AutotuneReport>, DeviceError> {
+        self.reset_alarm_or_fault()?;
+        info!("Starting auto-tuning of {}", self.servo_name);
+        let started = self.clock.now();
+        self.execute(DriveCommand::Autotune)?;
+        self.clock.sleep(Duration::from_millis(100));
+
+        while self.has_status(TUNING)? {
+            if self.has_status(ALARM)? {
+                let alarms = self.get_servo_alarms()?.join(", ");
+                // The alarm is what gets reported, even if the stop fails too
+                if let Err(e) = self.stop_motion() {
+                    warn!("Unable to stop {}: {}", self.servo_name, e);
+                }
+                return Err(DeviceError::DriveAlarm(format!(
+                    "Auto-tuning of {} stopped with alarms: {}",
+                    self.servo_name, alarms
+                )));
+            }
+            if self.clock.elapsed(started) > timeout {
+                warn!(
+                    "Auto-tuning of {} took longer than {:?}",
+                    self.servo_name, timeout
+                );
+                self.stop_motion()?;
+                return Ok(None);
+            }
+            let poll = Duration::from_millis(TUNING_POLL_INTERVAL);
+            if !self.sleep_and_sample(poll, cancel)? {
+                warn!("Auto-tuning of {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(None);
+            }
+        }
+
+        let gains = self.read_gains()?;
+        let duration = self.clock.elapsed(started);
+        info!(
+            "Finished auto-tuning of {} in {:?}, gains {:?}",
+            self.servo_name, duration, gains
+        );
+        Ok(Some(AutotuneReport { duration, gains }))
+    }
+}
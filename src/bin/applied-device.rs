@@ -0,0 +1,43 @@
+use applied_device::{validate_config, DeviceConfig};
+use std::env;
+use std::path::Path;
+use std::process;
+
+static USAGE: &str = "usage: applied-device init <config.yaml>
+       applied-device validate <config.yaml>";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, path) = match args.as_slice() {
+        [command, path] => (command.as_str(), path.as_str()),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    match command {
+        // Starts a new device config from the commented template
+        "init" => match DeviceConfig::write_template(Path::new(path)) {
+            Ok(()) => println!("Wrote config template to {}", path),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        "validate" => {
+            let report = validate_config(path);
+            for problem in &report.problems {
+                println!("{}", problem);
+            }
+            if !report.is_valid() {
+                process::exit(1);
+            }
+            println!("{} is valid", path);
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use crate::absolute::absolute_encoder_from_yaml;
+use crate::config::{
+    environment_from_var, min_request_gap, resource_location, servo_addresses, verify_writes,
+};
+use crate::device_lock::{lock_dir_from_yaml, DeviceLock};
+use crate::input_filter::input_filters_from_yaml;
+use crate::resolution::{encoder_counts_per_rev_from_yaml, steps_per_rev_from_yaml};
+use crate::rollover::rollover_from_yaml;
+use crate::{
+    AppliedDevice, CancelToken, Clock, CommsWatchdog, ConnectionPool, DeviceConfig, DeviceError,
+    FilterSettings, FirmwareRange, IdleCurrent, InitialState, LimitSwitchConfig, MaintenanceTask,
+    MappedRegister, ModbusTimeouts, MotionDefaults, OpcodeTable, RegisterMap, RetryPolicy,
+    SystemClock, Transport,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+// Builds an AppliedDevice, reading anything not given explicitly from the
+// device configuration file.  If a transport is supplied no connection is
+// opened and the configuration file is not read at all, so the settings
+// that only apply to a connection or a config can't be combined with it.
+pub struct AppliedDeviceBuilder {
+    device_name: String,
+    servo_name: String,
+    transport: Option<Box<dyn Transport>>,
+    pool: Option<(ConnectionPool, u8)>,
+    clock: Option<Arc<dyn Clock>>,
+    retry_policy: Option<RetryPolicy>,
+    timeouts: Option<ModbusTimeouts>,
+    min_request_gap: Option<Duration>,
+    verify_writes: Option<bool>,
+    state_file: Option<PathBuf>,
+    maintenance_tasks: Vec<MaintenanceTask>,
+    limit_switches: Option<LimitSwitchConfig>,
+    input_filters: Vec<(u16, Duration)>,
+    idle_current: Option<IdleCurrent>,
+    steps_per_rev: Option<u32>,
+    encoder_counts_per_rev: Option<u32>,
+    absolute_encoder: Option<bool>, // Whether to skip homing, if fitted
+    rollover: Option<u64>,
+    filters: Option<FilterSettings>,
+    firmware: Option<FirmwareRange>,
+    motion_defaults: Option<MotionDefaults>,
+    environment: Option<String>,
+    config: Option<DeviceConfig>,
+    config_dir: Option<PathBuf>,
+    lock_dir: Option<PathBuf>,
+    watchdog: Option<CommsWatchdog>,
+    home_on_connect: Option<bool>,
+    initial_state: Option<InitialState>,
+    opcodes: Option<OpcodeTable>,
+    register_map: Option<RegisterMap>,
+}
+
+impl AppliedDeviceBuilder {
+    pub fn new(device_name: String, servo_name: String) -> AppliedDeviceBuilder {
+        AppliedDeviceBuilder {
+            device_name,
+            servo_name,
+            transport: None,
+            pool: None,
+            clock: None,
+            retry_policy: None,
+            timeouts: None,
+            min_request_gap: None,
+            verify_writes: None,
+            state_file: None,
+            maintenance_tasks: Vec::new(),
+            limit_switches: None,
+            input_filters: Vec::new(),
+            idle_current: None,
+            steps_per_rev: None,
+            encoder_counts_per_rev: None,
+            absolute_encoder: None,
+            rollover: None,
+            filters: None,
+            firmware: None,
+            motion_defaults: None,
+            environment: None,
+            config: None,
+            config_dir: None,
+            lock_dir: None,
+            watchdog: None,
+            home_on_connect: None,
+            initial_state: None,
+            opcodes: None,
+            register_map: None,
+        }
+    }
+
+    pub fn transport<T: Transport + 'static>(mut self, transport: T) -> AppliedDeviceBuilder {
+        self.transport = Some(Box::new(transport));
+        self
+    }
+
+    // Reach the drive with the given unit ID through a pool of connections
+    // to its gateway, rather than opening a connection of its own.  The
+    // servo then needs no address in the device config.
+    pub fn connection_pool(mut self, pool: &ConnectionPool, unit_id: u8) -> AppliedDeviceBuilder {
+        self.pool = Some((pool.clone(), unit_id));
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> AppliedDeviceBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> AppliedDeviceBuilder {
+        self.retry_policy = Some(policy);
+        self
+    }
+
+    // Overrides the timeouts section of the device config
+    pub fn timeouts(mut self, timeouts: ModbusTimeouts) -> AppliedDeviceBuilder {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    // Never issue more than the given number of transactions per second on
+    // the connection this builder opens.  Overrides the rate_limit section of
+    // the device config.
+    pub fn max_requests_per_second(mut self, max_per_second: u32) -> AppliedDeviceBuilder {
+        self.min_request_gap = Some(Duration::from_secs(1) / max_per_second.max(1));
+        self
+    }
+
+    // Read back every register written, see
+    // AppliedDevice::set_write_verification.  Overrides the verify_writes
+    // setting of the device config.
+    pub fn verify_writes(mut self, verify: bool) -> AppliedDeviceBuilder {
+        self.verify_writes = Some(verify);
+        self
+    }
+
+    // Keep cycle count, travel and runtime in the given file across restarts.
+    // Overrides the state_dir setting of the device config.
+    pub fn state_file(mut self, path: PathBuf) -> AppliedDeviceBuilder {
+        self.state_file = Some(path);
+        self
+    }
+
+    // Adds a maintenance task on top of those in the device config.  A task
+    // with the same name as a configured one replaces it.
+    pub fn maintenance_task(mut self, task: MaintenanceTask) -> AppliedDeviceBuilder {
+        self.maintenance_tasks.push(task);
+        self
+    }
+
+    // Written to the drive once connected.  Overrides the limit_switches
+    // section of the device config.
+    pub fn limit_switches(mut self, config: LimitSwitchConfig) -> AppliedDeviceBuilder {
+        self.limit_switches = Some(config);
+        self
+    }
+
+    // Filters an input once connected, on top of the input_filters section
+    // of the device config
+    pub fn input_filter(mut self, input: u16, filter: Duration) -> AppliedDeviceBuilder {
+        self.input_filters.push((input, filter));
+        self
+    }
+
+    // Written to the drive once connected.  Overrides the idle_current
+    // section of the device config.
+    pub fn idle_current(mut self, idle: IdleCurrent) -> AppliedDeviceBuilder {
+        self.idle_current = Some(idle);
+        self
+    }
+
+    // The microstep resolution the application's units assume.  If the
+    // register map gives it, the drive's own setting is read once connected
+    // and a mismatch logged.  Overrides the steps_per_rev setting of the
+    // device config.
+    pub fn steps_per_rev(mut self, steps: u32) -> AppliedDeviceBuilder {
+        self.steps_per_rev = Some(steps);
+        self
+    }
+
+    // The encoder resolution the application's units assume.  If the
+    // register map gives it, building fails if the drive reports anything
+    // else.  Overrides the encoder_counts_per_rev setting of the device
+    // config.
+    pub fn encoder_counts_per_rev(mut self, counts: u32) -> AppliedDeviceBuilder {
+        self.encoder_counts_per_rev = Some(counts);
+        self
+    }
+
+    // The drive has a multi-turn absolute encoder, whose position is read
+    // once connected.  Overrides the absolute_encoder section of the device
+    // config.
+    pub fn absolute_encoder(mut self, skip_homing: bool) -> AppliedDeviceBuilder {
+        self.absolute_encoder = Some(skip_homing);
+        self
+    }
+
+    // Counts per cycle of a continuous rotation axis, see
+    // AppliedDevice::set_rollover.  Overrides the rollover setting of the
+    // device config.
+    pub fn rollover(mut self, counts_per_cycle: u64) -> AppliedDeviceBuilder {
+        self.rollover = Some(counts_per_cycle);
+        self
+    }
+
+    // Written to the drive once connected.  Overrides the filters section
+    // of the device config.
+    pub fn filters(mut self, filters: FilterSettings) -> AppliedDeviceBuilder {
+        self.filters = Some(filters);
+        self
+    }
+
+    // Checks the drive's firmware once connected.  Overrides the firmware
+    // section of the device config.
+    pub fn firmware_range(mut self, range: FirmwareRange) -> AppliedDeviceBuilder {
+        self.firmware = Some(range);
+        self
+    }
+
+    // Defaults for moves and homing, and any registers to write once
+    // connected.  Overrides the motion, homing and registers sections of
+    // the device config.
+    pub fn motion_defaults(mut self, defaults: MotionDefaults) -> AppliedDeviceBuilder {
+        self.motion_defaults = Some(defaults);
+        self
+    }
+
+    // Selects the config overlay for an environment, such as "dev" using
+    // axis1.dev.yaml over axis1.yaml.  Defaults to APPLIED_DEVICE_ENV.
+    pub fn environment(mut self, environment: &str) -> AppliedDeviceBuilder {
+        self.environment = Some(environment.to_string());
+        self
+    }
+
+    // Uses the given config rather than reading the device's config file
+    pub fn config(mut self, config: DeviceConfig) -> AppliedDeviceBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    // The directory to read <device name>.yaml from.  Overrides
+    // APPLIED_DEVICE_CONFIG_DIR and the default ./thingy/resources.
+    pub fn config_dir(mut self, dir: &Path) -> AppliedDeviceBuilder {
+        self.config_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    // Takes a lock file in the given directory before connecting, so no
+    // other process on this host can open a session to the same drive while
+    // this device exists.  Overrides the exclusive_lock setting of the
+    // device config.
+    pub fn exclusive_lock(mut self, dir: &Path) -> AppliedDeviceBuilder {
+        self.lock_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    // Overrides the watchdog section of the device config
+    pub fn comms_watchdog(mut self, watchdog: CommsWatchdog) -> AppliedDeviceBuilder {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    // Applied once connected, see AppliedDevice::apply_initial_state.
+    // Overrides the initial_state section of the device config.
+    pub fn initial_state(mut self, state: InitialState) -> AppliedDeviceBuilder {
+        self.initial_state = Some(state);
+        self
+    }
+
+    // Opcodes to send in place of the usual ones, see
+    // AppliedDevice::set_opcode_table.  Overrides the opcodes section of the
+    // device config.
+    pub fn opcodes(mut self, table: OpcodeTable) -> AppliedDeviceBuilder {
+        self.opcodes = Some(table);
+        self
+    }
+
+    // Where the drive keeps the registers outside the documented map, see
+    // AppliedDevice::set_register_map.  Overrides the register_map section
+    // of the device config.
+    pub fn register_map(mut self, map: RegisterMap) -> AppliedDeviceBuilder {
+        self.register_map = Some(map);
+        self
+    }
+
+    // Clears any faults, enables the motor and homes once everything else is
+    // set up, failing the build if homing fails.  Overrides homing.on_connect
+    // in the device config.
+    pub fn home_on_connect(mut self, home: bool) -> AppliedDeviceBuilder {
+        self.home_on_connect = Some(home);
+        self
+    }
+
+    pub fn build(self) -> Result<AppliedDevice, DeviceError> {
+        info!("Creating applied device: {}", self.servo_name);
+        let mut state_file = self.state_file.clone();
+        let mut maintenance_tasks = Vec::new();
+        let mut limit_switches = self.limit_switches;
+        let mut input_filters = Vec::new();
+        let mut idle_current = self.idle_current;
+        let mut steps_per_rev = self.steps_per_rev;
+        let mut encoder_counts_per_rev = self.encoder_counts_per_rev;
+        let mut absolute_encoder = self.absolute_encoder;
+        let mut rollover = self.rollover;
+        let mut filters = self.filters;
+        let mut firmware = self.firmware;
+        let mut motion_defaults = self.motion_defaults;
+        let mut watchdog = self.watchdog;
+        let mut initial_state = self.initial_state;
+        let mut verify = self.verify_writes;
+        let mut opcodes = self.opcodes;
+        let mut register_map = self.register_map;
+        let mut read_encoder_resolution = encoder_counts_per_rev.is_some();
+
+        let mut device = match self.transport {
+            Some(transport) => {
+                let unused: Vec<&str> = [
+                    ("config", self.config.is_some()),
+                    ("config_dir", self.config_dir.is_some()),
+                    ("environment", self.environment.is_some()),
+                    ("exclusive_lock", self.lock_dir.is_some()),
+                    ("max_requests_per_second", self.min_request_gap.is_some()),
+                    ("connection_pool", self.pool.is_some()),
+                ]
+                .iter()
+                .filter(|(_, set)| *set)
+                .map(|(name, _)| *name)
+                .collect();
+                if !unused.is_empty() {
+                    return Err(DeviceError::Config(format!(
+                        "{} can't be used along with a transport for {}",
+                        unused.join(", "),
+                        self.servo_name
+                    )));
+                }
+                let mut device =
+                    AppliedDevice::with_transport(self.servo_name, String::new(), transport);
+                if let Some(timeouts) = self.timeouts {
+                    device.timeouts = timeouts;
+                }
+                device
+            }
+            None => {
+                let config = match self.config {
+                    Some(config) => config,
+                    None => {
+                        let environment = self.environment.clone().or_else(environment_from_var);
+                        DeviceConfig::load_file(
+                            &resource_location(&self.device_name, self.config_dir.as_deref()),
+                            environment.as_deref(),
+                        )?
+                    }
+                };
+                info!("Using device configuration at: {}", config.get_source());
+                let device_conf = config.yaml();
+
+                // The addresses of the drive's coupler, tried in order when
+                // connecting.  A pooled drive is reached through the pool instead.
+                let addresses =
+                    match servo_addresses(&device_conf["device"][self.servo_name.as_str()]) {
+                        Some(addresses) if !addresses.is_empty() => addresses,
+                        _ if self.pool.is_some() => Vec::new(),
+                        _ => {
+                            return Err(DeviceError::Config(format!(
+                                "{} has no address for servo {} under device:",
+                                config.get_source(),
+                                self.servo_name
+                            )))
+                        }
+                    };
+
+                let timeouts = match self.timeouts {
+                    Some(t) => t,
+                    None => ModbusTimeouts::from_yaml(device_conf)?,
+                };
+
+                let min_request_gap = match self.min_request_gap {
+                    Some(gap) => Some(gap),
+                    None => min_request_gap(device_conf)?,
+                };
+
+                // `state_dir: <dir>` keeps each servo's counters in <dir>/<servo>.json
+                if let (None, Some(dir)) = (&self.state_file, device_conf["state_dir"].as_str()) {
+                    state_file = Some(PathBuf::from(dir).join(format!("{}.json", self.servo_name)));
+                }
+
+                maintenance_tasks = MaintenanceTask::from_yaml(device_conf)?;
+                input_filters = input_filters_from_yaml(device_conf)?;
+                if encoder_counts_per_rev.is_none() {
+                    encoder_counts_per_rev = encoder_counts_per_rev_from_yaml(device_conf)?;
+                }
+                if firmware.is_none() {
+                    firmware = FirmwareRange::from_yaml(device_conf)?;
+                }
+                if motion_defaults.is_none() {
+                    motion_defaults = Some(MotionDefaults::from_yaml(device_conf)?);
+                }
+                if filters.is_none() {
+                    filters = FilterSettings::from_yaml(device_conf)?;
+                }
+                if rollover.is_none() {
+                    rollover = rollover_from_yaml(device_conf)?;
+                }
+                if absolute_encoder.is_none() {
+                    absolute_encoder = absolute_encoder_from_yaml(device_conf)?;
+                }
+                // Connected to a real drive, so always learn its resolution
+                read_encoder_resolution = true;
+                if steps_per_rev.is_none() {
+                    steps_per_rev = steps_per_rev_from_yaml(device_conf)?;
+                }
+                if idle_current.is_none() {
+                    idle_current = IdleCurrent::from_yaml(device_conf)?;
+                }
+                if limit_switches.is_none() {
+                    limit_switches = LimitSwitchConfig::from_yaml(device_conf)?;
+                }
+                if watchdog.is_none() {
+                    watchdog = CommsWatchdog::from_yaml(device_conf)?;
+                }
+                if initial_state.is_none() {
+                    initial_state = InitialState::from_yaml(device_conf)?;
+                }
+                if verify.is_none() {
+                    verify = verify_writes(device_conf)?;
+                }
+                if opcodes.is_none() {
+                    opcodes = OpcodeTable::from_yaml(device_conf)?;
+                }
+                if register_map.is_none() {
+                    register_map = RegisterMap::from_yaml(device_conf)?;
+                }
+
+                // Before connecting, which would drop another process's session
+                let lock_dir = match self.lock_dir {
+                    Some(dir) => Some(dir),
+                    None => lock_dir_from_yaml(device_conf)?,
+                };
+                // The other drives sharing a pool's gateway aren't locked out
+                let lock = match (lock_dir, &self.pool) {
+                    (Some(dir), None) => {
+                        Some(DeviceLock::acquire(&dir, &addresses[0], &self.servo_name)?)
+                    }
+                    _ => None,
+                };
+
+                let mut device = match self.pool {
+                    Some((pool, unit_id)) => AppliedDevice::pooled(self.servo_name, &pool, unit_id),
+                    None => {
+                        let clock = self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+                        let (coupler, client) = AppliedDevice::open_connection(
+                            &addresses,
+                            &timeouts,
+                            min_request_gap,
+                            clock,
+                        )?;
+                        let mut device =
+                            AppliedDevice::with_transport(self.servo_name, coupler, client);
+                        device.servo_addresses = addresses;
+                        device.min_request_gap = min_request_gap;
+                        device
+                    }
+                };
+                device.lock = lock;
+                device.resource_location = config.get_source().to_string();
+                device.timeouts = timeouts;
+                device
+            }
+        };
+
+        if let Some(clock) = self.clock {
+            device.set_clock(clock);
+        }
+        if let Some(policy) = self.retry_policy {
+            device.retry_policy = policy;
+        }
+        // Before anything is written to the drive
+        if let Some(verify) = verify {
+            device.set_write_verification(verify);
+        }
+        if let Some(path) = state_file {
+            device.set_state_file(&path)?;
+        }
+        for task in maintenance_tasks.into_iter().chain(self.maintenance_tasks) {
+            device.add_maintenance_task(task);
+        }
+        device.check_maintenance();
+        if let Some(map) = register_map {
+            device.set_register_map(&map);
+        }
+        // Before anything else is written to the drive
+        if let Some(range) = firmware {
+            device.check_firmware(&range)?;
+        }
+        if let Some(table) = opcodes {
+            device.set_opcode_table(&table)?;
+        }
+        if let Some(defaults) = motion_defaults {
+            device.set_motion_defaults(defaults);
+            device.apply_register_overrides()?;
+        }
+        if let Some(config) = limit_switches {
+            device.set_limit_switch_config(&config)?;
+        }
+        device.set_rollover(rollover)?;
+        if let Some(skip_homing) = absolute_encoder {
+            device.set_absolute_encoder(true, skip_homing);
+            device.read_startup_position()?;
+        }
+        // Checked against the drive where its register map says where to
+        // look, otherwise taken as configured
+        if read_encoder_resolution {
+            device.encoder_counts_per_rev = encoder_counts_per_rev;
+            if device
+                .register_map
+                .get(MappedRegister::EncoderResolution)
+                .is_some()
+            {
+                device.get_encoder_counts_per_rev()?;
+            }
+        }
+        if let Some(steps) = steps_per_rev {
+            device.steps_per_rev = Some(steps);
+            if device
+                .register_map
+                .get(MappedRegister::StepsPerRev)
+                .is_some()
+            {
+                device.get_steps_per_rev()?;
+            }
+        }
+        if let Some(filters) = filters {
+            device.set_filters(&filters)?;
+        }
+        if let Some(idle) = idle_current {
+            device.set_idle_current(idle)?;
+        }
+        for (input, filter) in input_filters.into_iter().chain(self.input_filters) {
+            device.set_input_filter(input, filter)?;
+        }
+        device.set_comms_watchdog(watchdog);
+        // Failed steps are in the report rather than failing the build
+        if let Some(state) = initial_state {
+            device.initial_state_report = Some(device.apply_initial_state(&state));
+        }
+
+        if self
+            .home_on_connect
+            .unwrap_or(device.motion_defaults.home_on_connect)
+        {
+            info!("Homing {} on connect", device.servo_name);
+            device.reset_alarm_or_fault()?;
+            device.enable_motor()?;
+            // A device that didn't home is not handed back as if it had
+            device.home_servo_with_cancel(&CancelToken::new())?;
+        }
+
+        Ok(device)
+    }
+}
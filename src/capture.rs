@@ -0,0 +1,70 @@
+use crate::{AppliedDevice, CancelToken, DeviceError, InputCondition, MappedRegister, WordOrder};
+use std::time::Duration;
+use tracing::info;
+
+static CAPTURE_POLL_INTERVAL: u64 = 10; // In ms
+
+impl AppliedDevice {
+    // Arms the drive's high speed position capture.  The drive latches the
+    // encoder position itself the moment the input sees the edge, so the
+    // result is exact however slowly it is read back.  Any earlier capture
+    // is discarded.
+    pub fn arm_position_capture(
+        &mut self,
+        input: u16,
+        edge: InputCondition,
+    ) -> Result<(), DeviceError> {
+        if edge != InputCondition::Rising && edge != InputCondition::Falling {
+            return Err(DeviceError::Config(format!(
+                "Position capture needs a rising or falling edge, not {:?}",
+                edge
+            )));
+        }
+
+        info!(
+            "Arming position capture of {} on input {} ({:?})",
+            self.servo_name, input, edge
+        );
+        self.write_register(self.mapped(MappedRegister::CaptureFlag)?, 0)?;
+        self.write_register(self.mapped(MappedRegister::CaptureEdge)?, edge.code())?;
+        self.write_register(self.mapped(MappedRegister::CaptureInput)?, input as u64)
+    }
+
+    pub fn disarm_position_capture(&mut self) -> Result<(), DeviceError> {
+        self.write_register(self.mapped(MappedRegister::CaptureInput)?, 0)
+    }
+
+    // The latched position, or None if the edge hasn't happened yet
+    pub fn get_captured_position(&mut self) -> Result<Option<u64>, DeviceError> {
+        if self.get_register_value(self.mapped(MappedRegister::CaptureFlag)?)? == 0 {
+            return Ok(None);
+        }
+        let position = self.read_u32_ordered(
+            self.mapped(MappedRegister::CapturePosition)?,
+            WordOrder::HighFirst,
+        )?;
+        Ok(Some(position as u64))
+    }
+
+    // Polls for a capture until one arrives, the timeout passes or the
+    // token is cancelled
+    pub fn wait_for_capture(
+        &mut self,
+        timeout: Duration,
+        cancel: &CancelToken,
+    ) -> Result<Option<u64>, DeviceError> {
+        let started = self.clock.now();
+        loop {
+            if let Some(position) = self.get_captured_position()? {
+                return Ok(Some(position));
+            }
+            if self.clock.elapsed(started) >= timeout {
+                return Ok(None);
+            }
+            let poll = Duration::from_millis(CAPTURE_POLL_INTERVAL);
+            if !self.sleep_and_sample(poll, cancel)? {
+                return Ok(None);
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// The source of time for a device (and the simulator).  Everything that
+// waits on or measures the drive goes through this, so tests can swap in a
+// ManualClock and skip through long timeouts instantly.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    // The time of day, for anything scheduled by the calendar
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Real time, as used by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+// A clock that only moves when told to.  Sleeping advances it by the slept
+// duration immediately, so a 60 second homing timeout passes in no time.
+// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Instant,
+    wall_start: SystemTime,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock::starting_at(SystemTime::now())
+    }
+
+    // A clock whose wall_time starts from the given time of day
+    pub fn starting_at(wall_start: SystemTime) -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            wall_start,
+            offset: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    fn offset(&self) -> Duration {
+        match self.offset.lock() {
+            Ok(o) => *o,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut offset = match self.offset.lock() {
+            Ok(o) => o,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *offset += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.offset()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.wall_start + self.offset()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{AppliedDevice, DeviceError, EXECUTE_COMMAND};
+use std::collections::BTreeMap;
+use tracing::info;
+use yaml_rust::Yaml;
+
+// Every named command, for looking them up by name
+static COMMANDS: &[DriveCommand] = &[
+    DriveCommand::FeedToLength,
+    DriveCommand::StartMove,
+    DriveCommand::FeedToSensorMasked,
+    DriveCommand::FeedToSensorSafe,
+    DriveCommand::StartHoming,
+    DriveCommand::ChangeJogSpeed,
+    DriveCommand::SetOutput,
+    DriveCommand::CommenceJog,
+    DriveCommand::DisableMotor,
+    DriveCommand::EnableMotor,
+    DriveCommand::ResetAlarm,
+    DriveCommand::SetInputFilter,
+    DriveCommand::FollowEncoder,
+    DriveCommand::StopJog,
+    DriveCommand::Stop,
+    DriveCommand::Autotune,
+    DriveCommand::Identify,
+    DriveCommand::Disconnect,
+];
+
+// The commands this crate writes to the execute command register.  Any
+// other opcode the drive understands can still be sent with Raw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DriveCommand {
+    FeedToLength,       // "FL" - relative move
+    StartMove,          // "FP" - feed to the position in the distance registers
+    FeedToSensorMasked, // "FM" - feed to sensor with mask distance
+    FeedToSensorSafe,   // "FY" - feed to sensor with safety distance
+    StartHoming,        // "QX" - run the Q segment in parameter 1, segment 1 homes
+    ChangeJogSpeed,     // "CS" - change the speed of a jog under way
+    SetOutput,          // "SO" - set an output low or high
+    CommenceJog,        // "CJ" - jog at the jog velocity until stopped
+    DisableMotor,       // "MD"
+    EnableMotor,        // "ME"
+    ResetAlarm,         // "AR"
+    SetInputFilter,     // "FI" - filter an input, parameters are input and time
+    FollowEncoder,      // "FE" - follow the master encoder input
+    StopJog,            // "SJ" - decelerate a jog to a stop
+    Stop,               // "ST" - decelerate to a stop
+    Autotune,           // Start the drive's own tuning routine
+    Identify,           // Flash the status LED for a few seconds
+    Disconnect,         // Release the drive, parameter 1 set while it is in use
+    Raw(u16),
+}
+
+impl DriveCommand {
+    // The opcode current drives use.  A device's OpcodeTable may send
+    // another.
+    pub fn opcode(self) -> u16 {
+        match self {
+            DriveCommand::FeedToLength => 102,
+            DriveCommand::StartMove => 103,
+            DriveCommand::FeedToSensorMasked => 106,
+            DriveCommand::FeedToSensorSafe => 108,
+            DriveCommand::StartHoming => 120,
+            DriveCommand::ChangeJogSpeed => 128,
+            DriveCommand::SetOutput => 139,
+            DriveCommand::CommenceJog => 150,
+            DriveCommand::DisableMotor => 158,
+            DriveCommand::EnableMotor => 159,
+            DriveCommand::ResetAlarm => 186,
+            DriveCommand::SetInputFilter => 192,
+            DriveCommand::FollowEncoder => 204,
+            DriveCommand::StopJog => 216,
+            DriveCommand::Stop => 225,
+            DriveCommand::Autotune => 230,
+            DriveCommand::Identify => 238,
+            DriveCommand::Disconnect => 254,
+            DriveCommand::Raw(opcode) => opcode,
+        }
+    }
+
+    // The named command for an opcode, Raw for one without a name
+    pub fn from_opcode(opcode: u16) -> DriveCommand {
+        match opcode {
+            102 => DriveCommand::FeedToLength,
+            103 => DriveCommand::StartMove,
+            106 => DriveCommand::FeedToSensorMasked,
+            108 => DriveCommand::FeedToSensorSafe,
+            120 => DriveCommand::StartHoming,
+            128 => DriveCommand::ChangeJogSpeed,
+            139 => DriveCommand::SetOutput,
+            150 => DriveCommand::CommenceJog,
+            158 => DriveCommand::DisableMotor,
+            159 => DriveCommand::EnableMotor,
+            186 => DriveCommand::ResetAlarm,
+            192 => DriveCommand::SetInputFilter,
+            204 => DriveCommand::FollowEncoder,
+            216 => DriveCommand::StopJog,
+            225 => DriveCommand::Stop,
+            230 => DriveCommand::Autotune,
+            238 => DriveCommand::Identify,
+            254 => DriveCommand::Disconnect,
+            opcode => DriveCommand::Raw(opcode),
+        }
+    }
+
+    // The name used for the command in a device config's opcodes section
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            DriveCommand::FeedToLength => Some("feed_to_length"),
+            DriveCommand::StartMove => Some("start_move"),
+            DriveCommand::FeedToSensorMasked => Some("feed_to_sensor_masked"),
+            DriveCommand::FeedToSensorSafe => Some("feed_to_sensor_safe"),
+            DriveCommand::StartHoming => Some("start_homing"),
+            DriveCommand::ChangeJogSpeed => Some("change_jog_speed"),
+            DriveCommand::SetOutput => Some("set_output"),
+            DriveCommand::CommenceJog => Some("commence_jog"),
+            DriveCommand::DisableMotor => Some("disable_motor"),
+            DriveCommand::EnableMotor => Some("enable_motor"),
+            DriveCommand::ResetAlarm => Some("reset_alarm"),
+            DriveCommand::SetInputFilter => Some("set_input_filter"),
+            DriveCommand::FollowEncoder => Some("follow_encoder"),
+            DriveCommand::StopJog => Some("stop_jog"),
+            DriveCommand::Stop => Some("stop"),
+            DriveCommand::Autotune => Some("autotune"),
+            DriveCommand::Identify => Some("identify"),
+            DriveCommand::Disconnect => Some("disconnect"),
+            DriveCommand::Raw(_) => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DriveCommand> {
+        COMMANDS.iter().copied().find(|c| c.name() == Some(name))
+    }
+}
+
+// Opcodes that differ from the ones DriveCommand knows, for drive models or
+// firmware that number their commands differently, along with commands of
+// the device's own.  Anything not in the table is sent as usual.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpcodeTable {
+    opcodes: BTreeMap<String, u16>,               // By command name
+    models: BTreeMap<u16, BTreeMap<String, u16>>, // Further opcodes by model code
+}
+
+impl OpcodeTable {
+    pub fn new() -> OpcodeTable {
+        OpcodeTable::default()
+    }
+
+    // Sends the named command as the given opcode.  The name is either a
+    // DriveCommand's, to change its opcode, or a new one for
+    // AppliedDevice::execute_named.
+    pub fn set(&mut self, name: &str, opcode: u16) {
+        self.opcodes.insert(name.to_string(), opcode);
+    }
+
+    // As set, but only for drives reporting the given model code, taking
+    // precedence over the opcodes for every model
+    pub fn set_for_model(&mut self, model: u16, name: &str, opcode: u16) {
+        self.models
+            .entry(model)
+            .or_default()
+            .insert(name.to_string(), opcode);
+    }
+
+    pub fn opcode(&self, command: DriveCommand) -> u16 {
+        command
+            .name()
+            .and_then(|name| self.opcodes.get(name))
+            .copied()
+            .unwrap_or_else(|| command.opcode())
+    }
+
+    // The opcode of a command by name, whether one of DriveCommand's or the
+    // table's own
+    pub fn named(&self, name: &str) -> Option<u16> {
+        match self.opcodes.get(name) {
+            Some(opcode) => Some(*opcode),
+            None => DriveCommand::from_name(name).map(|c| c.opcode()),
+        }
+    }
+
+    // The command an opcode sent with this table stands for, the way a drive
+    // numbering its commands like this would take it
+    pub(crate) fn command(&self, opcode: u16) -> DriveCommand {
+        let renamed = self
+            .opcodes
+            .iter()
+            .filter(|(_, o)| **o == opcode)
+            .find_map(|(name, _)| DriveCommand::from_name(name));
+        if let Some(command) = renamed {
+            return command;
+        }
+        // A command moved to another opcode no longer answers to its own
+        let command = DriveCommand::from_opcode(opcode);
+        match self.opcode(command) == opcode {
+            true => command,
+            false => DriveCommand::Raw(opcode),
+        }
+    }
+
+    pub(crate) fn has_models(&self) -> bool {
+        !self.models.is_empty()
+    }
+
+    // The table for one model, its own opcodes laid over the others
+    pub(crate) fn for_model(&self, model: u16) -> OpcodeTable {
+        let mut opcodes = self.opcodes.clone();
+        if let Some(overrides) = self.models.get(&model) {
+            opcodes.extend(overrides.iter().map(|(n, o)| (n.clone(), *o)));
+        }
+        OpcodeTable {
+            opcodes,
+            models: BTreeMap::new(),
+        }
+    }
+
+    // Reads the optional `opcodes:` section of a device config
+    //
+    //   opcodes:
+    //     start_homing: 121      # a DriveCommand, sent with another opcode
+    //     release_brake: 140     # a command of this device's own
+    //     models:
+    //       7:                   # model code, see AppliedDevice::get_model
+    //         start_move: 104
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<OpcodeTable>, DeviceError> {
+        let section = match &device_conf["opcodes"] {
+            Yaml::Hash(section) => section,
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "opcodes must map command names to opcodes, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut table = OpcodeTable::new();
+        for (name, value) in section {
+            match name.as_str() {
+                Some("models") => {
+                    let models = match value {
+                        Yaml::Hash(models) => models,
+                        other => {
+                            return Err(DeviceError::Config(format!(
+                                "opcodes.models must map model codes to opcodes, got {:?}",
+                                other
+                            )))
+                        }
+                    };
+                    for (model, opcodes) in models {
+                        let model = match model.as_i64() {
+                            Some(m) if (0..=u16::MAX as i64).contains(&m) => m as u16,
+                            _ => {
+                                return Err(DeviceError::Config(format!(
+                                    "opcodes.models keys must be model codes, got {:?}",
+                                    model
+                                )))
+                            }
+                        };
+                        let opcodes = match opcodes {
+                            Yaml::Hash(opcodes) => opcodes,
+                            other => {
+                                return Err(DeviceError::Config(format!(
+                                    "opcodes.models.{} must map command names to opcodes, got {:?}",
+                                    model, other
+                                )))
+                            }
+                        };
+                        let field = format!("opcodes.models.{}", model);
+                        for (name, opcode) in opcodes {
+                            let (name, opcode) = named_opcode(name, opcode, &field)?;
+                            table.set_for_model(model, &name, opcode);
+                        }
+                    }
+                }
+                _ => {
+                    let (name, opcode) = named_opcode(name, value, "opcodes")?;
+                    table.set(&name, opcode);
+                }
+            }
+        }
+        Ok(Some(table))
+    }
+}
+
+fn named_opcode(name: &Yaml, opcode: &Yaml, field: &str) -> Result<(String, u16), DeviceError> {
+    let name = match name.as_str() {
+        Some(name) => name.to_string(),
+        None => {
+            return Err(DeviceError::Config(format!(
+                "{} keys must be command names, got {:?}",
+                field, name
+            )))
+        }
+    };
+    match opcode.as_i64() {
+        Some(o) if (0..=u16::MAX as i64).contains(&o) => Ok((name, o as u16)),
+        _ => Err(DeviceError::Config(format!(
+            "{}.{} must be an opcode from 0 to {}, got {:?}",
+            field,
+            name,
+            u16::MAX,
+            opcode
+        ))),
+    }
+}
+
+impl AppliedDevice {
+    // Writes a command to the execute command register.  Any parameters it
+    // takes must already be in the parameter registers.
+    pub fn execute(&mut self, command: DriveCommand) -> Result<(), DeviceError> {
+        let opcode = self.opcodes.opcode(command);
+        self.write_register(EXECUTE_COMMAND, opcode as u64)
+    }
+
+    // Writes a command by its name in the device's opcode table, for
+    // commands DriveCommand has no name for
+    pub fn execute_named(&mut self, name: &str) -> Result<(), DeviceError> {
+        match self.opcodes.named(name) {
+            Some(opcode) => self.write_register(EXECUTE_COMMAND, opcode as u64),
+            None => Err(DeviceError::Config(format!(
+                "{} has no command named {}",
+                self.servo_name, name
+            ))),
+        }
+    }
+
+    // Sends commands with the table's opcodes from now on.  A table with
+    // opcodes for particular models reads the drive's model code first.
+    pub fn set_opcode_table(&mut self, table: &OpcodeTable) -> Result<(), DeviceError> {
+        self.opcodes = match table.has_models() {
+            true => {
+                let model = self.get_model()?;
+                info!(
+                    "Using the opcodes for model {} on {}",
+                    model, self.servo_name
+                );
+                table.for_model(model)
+            }
+            false => table.clone(),
+        };
+        Ok(())
+    }
+
+    pub fn get_opcode_table(&self) -> &OpcodeTable {
+        &self.opcodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_read_back_through_the_table() {
+        let mut table = OpcodeTable::new();
+        table.set("start_homing", 121);
+        table.set("release_brake", 140);
+        assert_eq!(table.command(121), DriveCommand::StartHoming);
+        // Homing's own opcode means nothing to a drive numbered like this
+        assert_eq!(table.command(120), DriveCommand::Raw(120));
+        assert_eq!(table.command(103), DriveCommand::StartMove);
+        assert_eq!(table.command(140), DriveCommand::Raw(140));
+    }
+}
@@ -0,0 +1,434 @@
+use crate::validate::check_device_yaml;
+use crate::{ConfigReport, DeviceError, ModbusTimeouts};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::info;
+use yaml_rust::{Yaml, YamlLoader};
+
+static DEFAULT_CONFIG_DIR: &str = "./thingy/resources";
+static CONFIG_TEMPLATE: &str = include_str!("config_template.yaml");
+
+// Names the directory device configs are read from when none is given
+pub static CONFIG_DIR_VAR: &str = "APPLIED_DEVICE_CONFIG_DIR";
+
+// Where the config of a device is read from, first of:
+//   1. the device name itself, if it is an absolute path
+//   2. <device name>.yaml in the config dir given to the builder
+//   3. <device name>.yaml in the dir named by APPLIED_DEVICE_CONFIG_DIR
+//   4. ./thingy/resources/<device name>.yaml
+pub(crate) fn resource_location(device_name: &str, config_dir: Option<&Path>) -> String {
+    if Path::new(device_name).is_absolute() {
+        return device_name.to_string();
+    }
+    let dir = match config_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => match std::env::var(CONFIG_DIR_VAR) {
+            Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(DEFAULT_CONFIG_DIR),
+        },
+    };
+    dir.join(format!("{}.yaml", device_name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Names the environment whose config overlays are used when none is given
+pub static ENVIRONMENT_VAR: &str = "APPLIED_DEVICE_ENV";
+
+// The environment named by APPLIED_DEVICE_ENV, if it is set
+pub(crate) fn environment_from_var() -> Option<String> {
+    std::env::var(ENVIRONMENT_VAR)
+        .ok()
+        .filter(|env| !env.is_empty())
+}
+
+// Reads and parses a device configuration file, returning its first
+// document with any base configs it names merged in underneath it.  With an
+// environment given, an overlay next to the file named for it (axis1.dev.yaml
+// for axis1.yaml in "dev") is merged over the top, if there is one.
+pub(crate) fn load_device_yaml(
+    resource_location: &str,
+    environment: Option<&str>,
+) -> Result<Yaml, DeviceError> {
+    let path = Path::new(resource_location);
+    let conf = load_with_bases(path, &mut Vec::new())?;
+    let environment = match environment {
+        Some(env) => env,
+        None => return Ok(conf),
+    };
+
+    let overlay = overlay_location(path, environment);
+    if !overlay.exists() {
+        info!(
+            "No {} overlay for {}, using it as is",
+            environment, resource_location
+        );
+        return Ok(conf);
+    }
+    info!("Applying {} overlay {}", environment, overlay.display());
+    let overlay_conf = load_with_bases(&overlay, &mut Vec::new())?;
+    Ok(merge_yaml(conf, overlay_conf))
+}
+
+// axis1.yaml becomes axis1.<environment>.yaml
+fn overlay_location(path: &Path, environment: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, environment, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, environment),
+    };
+    path.with_file_name(name)
+}
+
+// A config can start from one or more templates, given relative to its own
+// directory, and override only what differs.  Later bases override earlier
+// ones and the config itself overrides them all.  Sections are merged key
+// by key, anything else is replaced outright.
+//
+//   base: [axis_template.yaml, site.yaml]
+//   device:
+//     axis7: 10.0.0.17
+fn load_with_bases(path: &Path, chain: &mut Vec<PathBuf>) -> Result<Yaml, DeviceError> {
+    if chain.iter().any(|p| p == path) {
+        return Err(DeviceError::Config(format!(
+            "Device config {} includes itself through its bases",
+            path.display()
+        )));
+    }
+    let conf = read_yaml_file(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    chain.push(path.to_path_buf());
+    let conf = merge_bases(conf, dir, path, chain);
+    chain.pop();
+    conf
+}
+
+fn merge_bases(
+    mut conf: Yaml,
+    dir: &Path,
+    path: &Path,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Yaml, DeviceError> {
+    let bases = match &conf["base"] {
+        Yaml::BadValue | Yaml::Null => Vec::new(),
+        Yaml::String(base) => vec![base.clone()],
+        Yaml::Array(bases) => bases
+            .iter()
+            .map(|base| {
+                base.as_str().map(String::from).ok_or_else(|| {
+                    DeviceError::Config(format!(
+                        "base entries of {} must be file names, got {:?}",
+                        path.display(),
+                        base
+                    ))
+                })
+            })
+            .collect::<Result<Vec<String>, DeviceError>>()?,
+        other => {
+            return Err(DeviceError::Config(format!(
+                "base of {} must be a file name or a list of them, got {:?}",
+                path.display(),
+                other
+            )))
+        }
+    };
+    if bases.is_empty() {
+        return Ok(conf);
+    }
+    if let Yaml::Hash(hash) = &mut conf {
+        hash.remove(&Yaml::String(String::from("base")));
+    }
+
+    let mut merged = Yaml::Hash(Default::default());
+    for base in bases {
+        let base_conf = load_with_bases(&dir.join(base), chain)?;
+        merged = merge_yaml(merged, base_conf);
+    }
+    Ok(merge_yaml(merged, conf))
+}
+
+// Lays one config over another, key by key within sections
+pub(crate) fn merge_yaml(base: Yaml, over: Yaml) -> Yaml {
+    match (base, over) {
+        (Yaml::Hash(mut base), Yaml::Hash(over)) => {
+            for (key, value) in over {
+                match base.get_mut(&key) {
+                    Some(existing) => {
+                        let old = std::mem::replace(existing, Yaml::Null);
+                        *existing = merge_yaml(old, value);
+                    }
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+            Yaml::Hash(base)
+        }
+        (_, over) => over,
+    }
+}
+
+fn read_yaml_file(path: &Path) -> Result<Yaml, DeviceError> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(DeviceError::Config(format!(
+                "Unable to read device config {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    let mut buf_reader = BufReader::new(file);
+    let mut contents = String::new();
+    if let Err(e) = buf_reader.read_to_string(&mut contents) {
+        return Err(DeviceError::Config(format!(
+            "Unable to read device config: {}",
+            e
+        )));
+    }
+    parse_device_yaml(&contents)
+}
+
+fn parse_device_yaml(contents: &str) -> Result<Yaml, DeviceError> {
+    let mut device_yaml = match YamlLoader::load_from_str(contents) {
+        Ok(y) => y,
+        Err(e) => {
+            return Err(DeviceError::Config(format!(
+                "Unable to parse config file: {}",
+                e
+            )))
+        }
+    };
+
+    if device_yaml.is_empty() {
+        return Err(DeviceError::Config(String::from("Device config is empty")));
+    }
+    Ok(device_yaml.swap_remove(0))
+}
+
+// A servo listed under `device:` in a device config
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServoEntry {
+    pub name: String,
+    pub address: String,
+    pub fallbacks: Vec<String>, // Tried in order if the address can't be reached
+}
+
+// The addresses of a servo's `device:` entry, either one address or a list
+// to try in order, for drives reachable over more than one network
+//
+//   device:
+//     axis1: 10.0.0.11
+//     axis2: [10.0.0.12, 192.168.5.12, axis2.cell4.local]
+pub(crate) fn servo_addresses(entry: &Yaml) -> Option<Vec<String>> {
+    match entry {
+        Yaml::String(address) => Some(vec![address.clone()]),
+        Yaml::Array(addresses) => addresses
+            .iter()
+            .map(|a| a.as_str().map(String::from))
+            .collect(),
+        _ => None,
+    }
+}
+
+// Every servo the config of a device lists, in the order given, so
+// supervisory code can construct each axis without knowing their names
+pub fn list_servos(device_name: &str) -> Result<Vec<ServoEntry>, DeviceError> {
+    Ok(DeviceConfig::load(device_name)?.get_servos())
+}
+
+// A device configuration, read from a file or given in memory, for building
+// devices without the standard config file
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    yaml: Yaml,
+    source: String, // Where it came from, for messages
+}
+
+impl DeviceConfig {
+    // Parses a config held in memory, e.g. baked into the binary or fetched
+    // from a database.  Any bases it names are read relative to the working
+    // directory.
+    pub fn from_yaml_str(contents: &str) -> Result<DeviceConfig, DeviceError> {
+        let yaml = parse_device_yaml(contents)?;
+        let yaml = merge_bases(yaml, Path::new("."), Path::new("<memory>"), &mut Vec::new())?;
+        DeviceConfig::checked(yaml, String::from("<memory>"), contents)
+    }
+
+    // Reads the config file of a device from where resource_location
+    // finds it, with the overlay of the environment named by
+    // APPLIED_DEVICE_ENV
+    pub fn load(device_name: &str) -> Result<DeviceConfig, DeviceError> {
+        let environment = environment_from_var();
+        DeviceConfig::load_file(
+            &resource_location(device_name, None),
+            environment.as_deref(),
+        )
+    }
+
+    pub fn load_file(path: &str, environment: Option<&str>) -> Result<DeviceConfig, DeviceError> {
+        let yaml = load_device_yaml(path, environment)?;
+        let text = std::fs::read_to_string(path).unwrap_or_default();
+        DeviceConfig::checked(yaml, path.to_string(), &text)
+    }
+
+    // Validates the config, logging any warnings and failing on errors
+    fn checked(yaml: Yaml, source: String, text: &str) -> Result<DeviceConfig, DeviceError> {
+        let mut report = ConfigReport {
+            source: source.clone(),
+            problems: Vec::new(),
+        };
+        check_device_yaml(&yaml, text, &mut report);
+        report.into_result()?;
+        Ok(DeviceConfig { yaml, source })
+    }
+
+    // The servos listed under `device:`
+    pub fn get_servo_names(&self) -> Vec<String> {
+        self.get_servos().into_iter().map(|s| s.name).collect()
+    }
+
+    pub fn get_servos(&self) -> Vec<ServoEntry> {
+        match &self.yaml["device"] {
+            // Loading validated every entry
+            Yaml::Hash(h) => h
+                .iter()
+                .filter_map(|(name, entry)| {
+                    let mut addresses = servo_addresses(entry)?.into_iter();
+                    Some(ServoEntry {
+                        name: name.as_str()?.to_string(),
+                        address: addresses.next()?,
+                        fallbacks: addresses.collect(),
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn get_source(&self) -> &str {
+        &self.source
+    }
+
+    // Writes an example config listing every supported field, with
+    // comments, as a starting point for a new device.  Refuses to replace an
+    // existing file.
+    pub fn write_template(path: &Path) -> Result<(), DeviceError> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| {
+                DeviceError::Config(format!(
+                    "Unable to create config template {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        file.write_all(CONFIG_TEMPLATE.as_bytes()).map_err(|e| {
+            DeviceError::Config(format!(
+                "Unable to write config template {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    // The example config write_template writes
+    pub fn template() -> &'static str {
+        CONFIG_TEMPLATE
+    }
+
+    pub(crate) fn yaml(&self) -> &Yaml {
+        &self.yaml
+    }
+}
+
+// Reads an optional millisecond value, e.g. `read_ms: 500`
+pub(crate) fn millis(section: &Yaml, key: &str) -> Result<Option<Duration>, DeviceError> {
+    match &section[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(ms) if *ms >= 0 => Ok(Some(Duration::from_millis(*ms as u64))),
+        other => Err(DeviceError::Config(format!(
+            "{} must be a non-negative number of milliseconds, got {:?}",
+            key, other
+        ))),
+    }
+}
+
+// Reads the optional `rate_limit:` section of a device config, returning the
+// minimum gap to leave between transactions on the connection.
+//
+//   rate_limit:
+//     max_per_second: 50
+pub(crate) fn min_request_gap(device_conf: &Yaml) -> Result<Option<Duration>, DeviceError> {
+    match &device_conf["rate_limit"]["max_per_second"] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        // Worked out in floating point so a large limit isn't truncated
+        Yaml::Integer(n) if *n > 0 => Ok(Some(Duration::from_secs_f64(1.0 / *n as f64))),
+        other => Err(DeviceError::Config(format!(
+            "rate_limit.max_per_second must be a positive number, got {:?}",
+            other
+        ))),
+    }
+}
+
+// `verify_writes: true` reads back every register written
+pub(crate) fn verify_writes(device_conf: &Yaml) -> Result<Option<bool>, DeviceError> {
+    match &device_conf["verify_writes"] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Boolean(verify) => Ok(Some(*verify)),
+        other => Err(DeviceError::Config(format!(
+            "verify_writes must be true or false, got {:?}",
+            other
+        ))),
+    }
+}
+
+// Reads an optional positive interval of a maintenance task
+pub(crate) fn maintenance_number(
+    intervals: &Yaml,
+    task: &str,
+    key: &str,
+) -> Result<Option<f64>, DeviceError> {
+    match &intervals[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(n) if *n > 0 => Ok(Some(*n as f64)),
+        Yaml::Real(_) if intervals[key].as_f64().is_some_and(|n| n > 0.0) => {
+            Ok(intervals[key].as_f64())
+        }
+        other => Err(DeviceError::Config(format!(
+            "maintenance.{}.{} must be a positive number, got {:?}",
+            task, key, other
+        ))),
+    }
+}
+
+impl ModbusTimeouts {
+    // Reads the optional `timeouts:` section of a device config.  Anything
+    // not given keeps its default.
+    //
+    //   timeouts:
+    //     connect_ms: 1000
+    //     read_ms: 1000
+    //     write_ms: 1000
+    //     status_poll_ms: 250
+    //     command_ms: 1000
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<ModbusTimeouts, DeviceError> {
+        let section = &device_conf["timeouts"];
+        let defaults = ModbusTimeouts::default();
+
+        Ok(ModbusTimeouts {
+            connect: millis(section, "connect_ms")?.or(defaults.connect),
+            read: millis(section, "read_ms")?.or(defaults.read),
+            write: millis(section, "write_ms")?.or(defaults.write),
+            status_poll: millis(section, "status_poll_ms")?.or(defaults.status_poll),
+            command: millis(section, "command_ms")?.or(defaults.command),
+        })
+    }
+}
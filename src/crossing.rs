@@ -0,0 +1,159 @@
+use crate::feed::signed_count;
+use crate::{AppliedDevice, DeviceError};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Instant;
+use tracing::info;
+
+// Which way the position must pass through a threshold
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrossingDirection {
+    Rising,  // Counts going up through the threshold
+    Falling, // Counts going down through it
+    Either,
+}
+
+// Sent each time a watched threshold is crossed
+#[derive(Clone, Debug)]
+pub struct PositionCrossing {
+    pub servo_name: String,
+    pub threshold: i64,
+    pub direction: CrossingDirection, // Rising or Falling, as it happened
+    pub position: i64,                // The first read past the threshold
+    pub at: Instant,
+}
+
+pub(crate) struct PositionWatch {
+    sender: Sender<PositionCrossing>,
+    threshold: i64,
+    direction: CrossingDirection,
+    last: Option<i64>,
+}
+
+impl PositionWatch {
+    // The way the position went through the threshold between the last read
+    // and this one, if it did and that way is watched.  Landing exactly on the
+    // threshold counts as crossing it.
+    fn crossed(&self, position: i64) -> Option<CrossingDirection> {
+        let last = self.last?;
+        let crossed = if last < self.threshold && position >= self.threshold {
+            CrossingDirection::Rising
+        } else if last > self.threshold && position <= self.threshold {
+            CrossingDirection::Falling
+        } else {
+            return None;
+        };
+        match self.direction {
+            CrossingDirection::Either => Some(crossed),
+            wanted if wanted == crossed => Some(crossed),
+            _ => None,
+        }
+    }
+}
+
+impl AppliedDevice {
+    // Sends an event whenever the encoder position crosses the threshold (in
+    // counts) in the given direction, so other equipment can be triggered at
+    // a position rather than a time.  Crossings are found from the position
+    // reads the device already makes: while homing and moving, and from the
+    // idle poll once spawned, so how late an event is depends on those rates.
+    // The watch ends when the receiver is dropped.  Telemetry sinks are told
+    // of each crossing too, through on_position_crossing.
+    pub fn watch_position(
+        &mut self,
+        threshold: i64,
+        direction: CrossingDirection,
+    ) -> Receiver<PositionCrossing> {
+        info!(
+            "Watching {} for {:?} crossings of {}",
+            self.servo_name, direction, threshold
+        );
+        let (sender, receiver) = mpsc::channel();
+        self.position_watches.push(PositionWatch {
+            sender,
+            threshold,
+            direction,
+            last: None,
+        });
+        receiver
+    }
+
+    // Same as watch_position, with the threshold in the application's units
+    pub fn watch_position_units(
+        &mut self,
+        threshold: f64,
+        direction: CrossingDirection,
+    ) -> Result<Receiver<PositionCrossing>, DeviceError> {
+        let counts = self.units_to_counts(threshold).ok_or_else(|| {
+            DeviceError::Config(format!(
+                "No counts_per_unit configured for {} to watch {} at",
+                self.servo_name, threshold
+            ))
+        })?;
+        Ok(self.watch_position(signed_count(counts), direction))
+    }
+
+    pub(crate) fn check_position_watches(&mut self, encoder_count: u64) {
+        if self.position_watches.is_empty() {
+            return;
+        }
+        let position = signed_count(encoder_count);
+        let at = self.clock.now();
+        let servo_name = &self.servo_name;
+        let mut crossings = Vec::new();
+        self.position_watches.retain_mut(|watch| {
+            let crossed = watch.crossed(position);
+            watch.last = Some(position);
+            match crossed {
+                Some(direction) => {
+                    let crossing = PositionCrossing {
+                        servo_name: servo_name.clone(),
+                        threshold: watch.threshold,
+                        direction,
+                        position,
+                        at,
+                    };
+                    crossings.push(crossing.clone());
+                    watch.sender.send(crossing).is_ok()
+                }
+                None => true,
+            }
+        });
+        for crossing in &crossings {
+            self.emit(|t| t.on_position_crossing(crossing));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch(direction: CrossingDirection, last: i64) -> PositionWatch {
+        PositionWatch {
+            sender: mpsc::channel().0,
+            threshold: 100,
+            direction,
+            last: Some(last),
+        }
+    }
+
+    #[test]
+    fn landing_on_the_threshold_crosses_it() {
+        assert_eq!(
+            watch(CrossingDirection::Rising, 50).crossed(100),
+            Some(CrossingDirection::Rising)
+        );
+        assert_eq!(
+            watch(CrossingDirection::Either, 150).crossed(100),
+            Some(CrossingDirection::Falling)
+        );
+    }
+
+    #[test]
+    fn only_the_watched_direction_counts() {
+        assert_eq!(watch(CrossingDirection::Rising, 150).crossed(50), None);
+        assert_eq!(watch(CrossingDirection::Falling, 50).crossed(150), None);
+        // Leaving the threshold isn't crossing it again
+        assert_eq!(watch(CrossingDirection::Either, 100).crossed(150), None);
+    }
+}
@@ -0,0 +1,307 @@
+#[cfg(feature = "arrow")]
+use crate::export::ParquetLog;
+use crate::{AppliedDevice, DeviceError, TraceSample};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataLogFormat {
+    Csv,
+    // The same columns in a Parquet file, which can only be read once the
+    // log is stopped or rotated
+    #[cfg(feature = "arrow")]
+    Parquet,
+}
+
+// What a data log records and where
+#[derive(Clone, Debug)]
+pub struct DataLogConfig {
+    pub path: PathBuf,
+    pub period: Duration,
+    pub registers: Vec<u16>, // Extra registers to read into each row
+    pub rotate_after_rows: Option<usize>, // Start a new numbered file after this many rows
+    pub format: DataLogFormat,
+}
+
+impl DataLogConfig {
+    pub fn new(path: &Path, period: Duration) -> DataLogConfig {
+        DataLogConfig {
+            path: path.to_path_buf(),
+            period,
+            registers: Vec::new(),
+            rotate_after_rows: None,
+            format: DataLogFormat::Csv,
+        }
+    }
+}
+
+// One row of a data log, whatever it is written as
+pub(crate) struct LogRow {
+    pub unix_ms: u64,
+    pub elapsed_ms: u64,
+    pub encoder_count: u64,
+    pub velocity: f64, // Counts/s, from the change in position since the previous row
+    pub status: String,
+    pub registers: Vec<u16>,
+}
+
+enum LogWriter {
+    Csv(BufWriter<File>),
+    #[cfg(feature = "arrow")]
+    Parquet(Box<ParquetLog>),
+}
+
+impl LogWriter {
+    fn write(&mut self, row: LogRow) -> std::io::Result<()> {
+        match self {
+            LogWriter::Csv(writer) => {
+                let mut line = format!(
+                    "{},{},{},{:.1},{}",
+                    row.unix_ms, row.elapsed_ms, row.encoder_count, row.velocity, row.status
+                );
+                for value in &row.registers {
+                    line.push_str(&format!(",{}", value));
+                }
+                writeln!(writer, "{}", line)
+            }
+            #[cfg(feature = "arrow")]
+            LogWriter::Parquet(log) => log.push(row).map_err(std::io::Error::other),
+        }
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            LogWriter::Csv(writer) => writer.flush(),
+            #[cfg(feature = "arrow")]
+            LogWriter::Parquet(log) => log.finish().map_err(std::io::Error::other),
+        }
+    }
+}
+
+// Writes one row per sample.  Columns are the wall clock time, time since
+// logging started, encoder count, velocity (counts/s, from the change in
+// position since the previous row), the status names joined by '|' and then
+// one column per selected register.
+pub(crate) struct DataLogger {
+    config: DataLogConfig,
+    writer: LogWriter,
+    file_index: usize,
+    rows: usize,
+    started: Instant,
+    last: Option<(Instant, u64)>, // Time and position of the previous row
+}
+
+impl DataLogger {
+    fn open(config: DataLogConfig, started: Instant) -> Result<DataLogger, DeviceError> {
+        let file_index = if config.rotate_after_rows.is_some() {
+            1
+        } else {
+            0
+        };
+        let writer = DataLogger::create(&config, file_index)?;
+        Ok(DataLogger {
+            config,
+            writer,
+            file_index,
+            rows: 0,
+            started,
+            last: None,
+        })
+    }
+
+    // Rotated files are numbered, so run.csv becomes run.1.csv, run.2.csv, ...
+    fn file_path(config: &DataLogConfig, index: usize) -> PathBuf {
+        if index == 0 {
+            return config.path.clone();
+        }
+        let stem = config
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match config.path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, index),
+        };
+        config.path.with_file_name(name)
+    }
+
+    fn create(config: &DataLogConfig, index: usize) -> Result<LogWriter, DeviceError> {
+        let path = DataLogger::file_path(config, index);
+        let file = File::create(&path).map_err(|e| {
+            DeviceError::Config(format!("Unable to create {}: {}", path.display(), e))
+        })?;
+        #[cfg(feature = "arrow")]
+        if config.format == DataLogFormat::Parquet {
+            let log = ParquetLog::create(file, &config.registers).map_err(|e| {
+                DeviceError::Config(format!("Unable to write {}: {}", path.display(), e))
+            })?;
+            info!("Logging data to {}", path.display());
+            return Ok(LogWriter::Parquet(Box::new(log)));
+        }
+        let mut writer = BufWriter::new(file);
+
+        let mut header = String::from("unix_ms,elapsed_ms,encoder_count,velocity,status");
+        for register in &config.registers {
+            header.push_str(&format!(",reg_{}", register));
+        }
+        writeln!(writer, "{}", header).map_err(|e| {
+            DeviceError::Config(format!("Unable to write {}: {}", path.display(), e))
+        })?;
+        info!("Logging data to {}", path.display());
+        Ok(LogWriter::Csv(writer))
+    }
+
+    fn due(&self, at: Instant) -> bool {
+        AppliedDevice::sample_due(self.last.map(|(t, _)| t), self.config.period, at)
+    }
+
+    fn write_row(
+        &mut self,
+        sample: &TraceSample,
+        registers: &[u16],
+        wall_time: SystemTime,
+    ) -> std::io::Result<()> {
+        if let Some(limit) = self.config.rotate_after_rows {
+            if self.rows >= limit {
+                self.writer.finish()?;
+                self.writer = DataLogger::create(&self.config, self.file_index + 1)
+                    .map_err(|e| std::io::Error::other(e.to_string()))?;
+                self.file_index += 1;
+                self.rows = 0;
+            }
+        }
+
+        let velocity = match self.last {
+            Some((at, position)) => {
+                let dt = sample.at.saturating_duration_since(at).as_secs_f64();
+                if dt > 0.0 {
+                    (sample.encoder_count as f64 - position as f64) / dt
+                } else {
+                    0.0
+                }
+            }
+            None => 0.0,
+        };
+        let unix_ms = wall_time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let elapsed_ms = sample
+            .at
+            .saturating_duration_since(self.started)
+            .as_millis() as u64;
+
+        self.writer.write(LogRow {
+            unix_ms,
+            elapsed_ms,
+            encoder_count: sample.encoder_count,
+            velocity,
+            status: sample.status.join("|"),
+            registers: registers.to_vec(),
+        })?;
+        self.rows += 1;
+        self.last = Some((sample.at, sample.encoder_count));
+        Ok(())
+    }
+}
+
+impl AppliedDevice {
+    // Starts logging samples to CSV, or another format if configured.  Rows
+    // are taken while waiting on homing and moves, and by a spawned device's
+    // worker while it is idle.
+    pub fn start_data_log(&mut self, config: DataLogConfig) -> Result<(), DeviceError> {
+        self.stop_data_log();
+        self.data_log = Some(DataLogger::open(config, self.clock.now())?);
+        Ok(())
+    }
+
+    pub fn stop_data_log(&mut self) {
+        if let Some(mut logger) = self.data_log.take() {
+            if let Err(e) = logger.writer.finish() {
+                warn!("Unable to flush data log of {}: {}", self.servo_name, e);
+            }
+        }
+    }
+
+    pub fn is_data_logging(&self) -> bool {
+        self.data_log.is_some()
+    }
+
+    pub(crate) fn data_log_period(&self) -> Option<Duration> {
+        self.data_log.as_ref().map(|l| l.config.period)
+    }
+
+    pub(crate) fn data_log_due(&self, at: Instant) -> bool {
+        match &self.data_log {
+            Some(logger) => logger.due(at),
+            None => false,
+        }
+    }
+
+    // Writes a row for the sample if the logger's period has passed.  A
+    // failure to read or write stops the log rather than the motion it is
+    // recording.
+    pub(crate) fn record_data_log(&mut self, sample: &TraceSample) {
+        let registers = match &self.data_log {
+            Some(logger) if logger.due(sample.at) => logger.config.registers.clone(),
+            _ => return,
+        };
+        let mut values = Vec::with_capacity(registers.len());
+        for register in registers {
+            match self.get_register_value(register) {
+                Ok(value) => values.push(value as u16),
+                Err(e) => {
+                    warn!("Unable to read data log of {}: {}", self.servo_name, e);
+                    self.data_log = None;
+                    return;
+                }
+            }
+        }
+
+        let wall_time = self.clock.wall_time();
+        if let Some(logger) = &mut self.data_log {
+            if let Err(e) = logger.write_row(sample, &values, wall_time) {
+                warn!("Unable to write data log of {}: {}", self.servo_name, e);
+                self.data_log = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    #[test]
+    fn rows_are_stamped_with_the_device_clocks_time_of_day() {
+        let clock = ManualClock::starting_at(UNIX_EPOCH + Duration::from_secs(1000));
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let drive = SimulatedDrive::with_clock(shared.clone());
+        let mut device = AppliedDevice::with_transport(String::from("log"), String::new(), drive);
+        device.set_clock(shared);
+        let path = std::env::temp_dir().join(format!("data_log_{}.csv", std::process::id()));
+        let config = DataLogConfig::new(&path, Duration::from_millis(10));
+        let written = DataLogger::file_path(&config, 0);
+        device.start_data_log(config).expect("Unable to start log");
+
+        clock.advance(Duration::from_millis(250));
+        device.record_data_log(&TraceSample {
+            at: clock.now(),
+            encoder_count: 0,
+            velocity: None,
+            status: Vec::new(),
+        });
+        device.stop_data_log();
+
+        let contents = std::fs::read_to_string(&written).expect("Unable to read log");
+        let row = contents.lines().nth(1).expect("No row logged");
+        assert!(row.starts_with("1000250,250,"), "{}", row);
+        let _ = std::fs::remove_file(&written);
+    }
+}
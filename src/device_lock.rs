@@ -0,0 +1,158 @@
+use crate::DeviceError;
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use yaml_rust::Yaml;
+
+// Who holds the lock on a drive, as written in its lock file
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub process: String,
+    pub servo_name: String,
+    pub since: u64, // Seconds since the Unix epoch
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (pid {}) as {} since {}",
+            self.process, self.pid, self.servo_name, self.since
+        )
+    }
+}
+
+impl LockOwner {
+    fn current(servo_name: &str) -> LockOwner {
+        let process = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("unknown"));
+        LockOwner {
+            pid: std::process::id(),
+            process,
+            servo_name: servo_name.to_string(),
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    fn to_file(&self) -> String {
+        format!(
+            "pid={}\nprocess={}\nservo={}\nsince={}\n",
+            self.pid, self.process, self.servo_name, self.since
+        )
+    }
+
+    fn from_file(contents: &str) -> LockOwner {
+        let mut owner = LockOwner {
+            pid: 0,
+            process: String::from("unknown"),
+            servo_name: String::from("unknown"),
+            since: 0,
+        };
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("pid", pid)) => owner.pid = pid.parse().unwrap_or(0),
+                Some(("process", process)) => owner.process = process.to_string(),
+                Some(("servo", servo)) => owner.servo_name = servo.to_string(),
+                Some(("since", since)) => owner.since = since.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        owner
+    }
+}
+
+// An advisory lock on one drive, so two processes on the same host don't
+// both open control sessions to it.  Held until dropped.
+//
+// The lock is the operating system's lock on the file rather than the
+// file existing, so one left behind by a process that died is simply
+// taken over, and two processes can never both take it.  The file itself
+// stays, holding the owner for others to report.
+pub(crate) struct DeviceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl DeviceLock {
+    pub(crate) fn acquire(
+        dir: &Path,
+        address: &str,
+        servo_name: &str,
+    ) -> Result<DeviceLock, DeviceError> {
+        let path = dir.join(lock_file_name(address));
+        let failed = |e: std::io::Error| {
+            DeviceError::Config(format!("Unable to lock {}: {}", path.display(), e))
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(failed)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+                return Err(DeviceError::DeviceBusy(LockOwner::from_file(&contents)));
+            }
+            Err(TryLockError::Error(e)) => return Err(failed(e)),
+        }
+
+        // Whatever a previous owner left in the file is replaced
+        let owner = LockOwner::current(servo_name);
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(owner.to_file().as_bytes()))
+            .map_err(failed)?;
+        info!("Locked {} with {}", address, path.display());
+        Ok(DeviceLock { path, file })
+    }
+}
+
+impl Drop for DeviceLock {
+    // The lock itself goes with the file handle, this only clears the owner
+    fn drop(&mut self) {
+        if let Err(e) = self.file.set_len(0) {
+            warn!("Unable to clear lock file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// One lock file per drive address, whatever servo name it is used under
+fn lock_file_name(address: &str) -> String {
+    let key: String = address
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    format!("applied_device-{}.lock", key)
+}
+
+// Reads the optional `exclusive_lock:` setting of a device config: true to
+// lock drives in the system temp directory, or the directory to use
+//
+//   exclusive_lock: /run/lock
+pub(crate) fn lock_dir_from_yaml(device_conf: &Yaml) -> Result<Option<PathBuf>, DeviceError> {
+    match &device_conf["exclusive_lock"] {
+        Yaml::BadValue | Yaml::Null | Yaml::Boolean(false) => Ok(None),
+        Yaml::Boolean(true) => Ok(Some(std::env::temp_dir())),
+        Yaml::String(dir) => Ok(Some(PathBuf::from(dir))),
+        other => Err(DeviceError::Config(format!(
+            "exclusive_lock must be true, false or a directory, got {:?}",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, InputCondition, INPUTS_REG, PARAMETER_1,
+    PARAMETER_2,
+};
+use std::time::Duration;
+use tracing::{info, warn};
+
+static INPUT_POLL_INTERVAL: u64 = 10; // How often a wait for an input reads it, in ms
+
+impl AppliedDevice {
+    // The drive's inputs, bit 0 being X1
+    pub fn read_inputs(&mut self) -> Result<u16, DeviceError> {
+        Ok(self.get_register_value(INPUTS_REG)? as u16)
+    }
+
+    // Whether an input (1 for X1) is high
+    pub fn get_input(&mut self, input: u16) -> Result<bool, DeviceError> {
+        check_io_number("input", input)?;
+        Ok(self.read_inputs()? & (1 << (input - 1)) != 0)
+    }
+
+    // Drives an output (1 for Y1) high or low
+    pub fn set_output(&mut self, output: u16, high: bool) -> Result<(), DeviceError> {
+        check_io_number("output", output)?;
+        info!(
+            "Setting output {} of {} {}",
+            output,
+            self.servo_name,
+            if high { "high" } else { "low" }
+        );
+        let condition = if high {
+            InputCondition::High
+        } else {
+            InputCondition::Low
+        };
+        self.write_register(PARAMETER_1, output as u64)?;
+        self.write_register(PARAMETER_2, condition.code())?;
+        self.execute(DriveCommand::SetOutput)
+    }
+
+    // Waits for an input to meet a condition.  Edges are only seen once the
+    // input has been read at the level before them.  Returns false if the
+    // timeout ran out or the token was cancelled first.
+    pub fn wait_for_input(
+        &mut self,
+        input: u16,
+        condition: InputCondition,
+        timeout: Option<Duration>,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let started = self.clock.now();
+        let mut previous: Option<bool> = None;
+        loop {
+            let level = self.get_input(input)?;
+            let met = match condition {
+                InputCondition::High => level,
+                InputCondition::Low => !level,
+                InputCondition::Rising => previous == Some(false) && level,
+                InputCondition::Falling => previous == Some(true) && !level,
+            };
+            if met {
+                return Ok(true);
+            }
+            previous = Some(level);
+            if timeout.is_some_and(|timeout| self.clock.elapsed(started) >= timeout) {
+                warn!(
+                    "Input {} of {} wasn't {:?} within {:?}",
+                    input, self.servo_name, condition, timeout
+                );
+                return Ok(false);
+            }
+            if !self.sleep_and_sample(Duration::from_millis(INPUT_POLL_INTERVAL), cancel)? {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+fn check_io_number(what: &str, number: u16) -> Result<(), DeviceError> {
+    if (1..=16).contains(&number) {
+        Ok(())
+    } else {
+        Err(DeviceError::Config(format!(
+            "{} numbers run from 1 to 16, got {}",
+            what, number
+        )))
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{LockOwner, ModbusException, ModbusFunction};
+use std::fmt;
+
+// Everything that can go wrong talking to a device
+#[derive(Debug)]
+pub enum DeviceError {
+    Config(String),             // The device configuration could not be read or used
+    Connection(String),         // The connection to the drive could not be established
+    Modbus(modbus::Error),      // A register read or write failed, after any retries
+    WorkerStopped(String),      // The worker behind a handle is no longer running
+    Audit(String),              // A write could not be recorded, so it was not sent
+    Firmware(String),           // The drive's firmware is outside the configured range
+    GantrySkew(String),         // The two sides of a gantry drifted too far apart
+    ConnectionSeized(String),   // Another client took the drive's only Modbus session
+    DeviceBusy(LockOwner),      // Another process on this host holds the drive's lock
+    TimedOut(String),           // An operation ran past the time allowed for it
+    HomingFailed(String),       // Homing ended without the axis being homed
+    MoveIncomplete(String),     // A move timed out, was cancelled or stopped short of its target
+    DriveAlarm(String),         // The drive raised an alarm part way through an operation
+    ReplayDiverged(String),     // A ReplayTransport's recording doesn't hold the request made
+    MotorNotEnabled(String),    // A step that needs the motor enabled found it disabled
+    EmergencyStopped(String),   // A motion call was still queued when the servo was e-stopped
+    PositionOutOfRange(String), // A position past what a 32 bit encoder count can hold
+    // An interlock refused to let the servo be enabled, homed or moved
+    InterlockDenied {
+        interlock: String,
+        reason: String,
+    },
+    // A register read back after writing it held something else
+    WriteMismatch {
+        register: u16,
+        written: u16,
+        read: u16,
+    },
+    // The drive answered a register read or write with an exception
+    Exception {
+        exception: ModbusException,
+        function: ModbusFunction,
+        register: u16,
+    },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::Config(msg) => write!(f, "Configuration error: {}", msg),
+            DeviceError::Connection(msg) => write!(f, "Connection error: {}", msg),
+            DeviceError::Modbus(e) => write!(f, "Modbus error: {}", e),
+            DeviceError::WorkerStopped(msg) => write!(f, "Device worker stopped: {}", msg),
+            DeviceError::Audit(msg) => write!(f, "Audit error: {}", msg),
+            DeviceError::Firmware(msg) => write!(f, "Incompatible firmware: {}", msg),
+            DeviceError::GantrySkew(msg) => write!(f, "Gantry skew: {}", msg),
+            DeviceError::ConnectionSeized(msg) => {
+                write!(f, "Connection taken over by another client: {}", msg)
+            }
+            DeviceError::DeviceBusy(owner) => write!(f, "Device in use by {}", owner),
+            DeviceError::TimedOut(msg) => write!(f, "Timed out: {}", msg),
+            DeviceError::HomingFailed(msg) => write!(f, "Homing failed: {}", msg),
+            DeviceError::MoveIncomplete(msg) => write!(f, "Move incomplete: {}", msg),
+            DeviceError::DriveAlarm(msg) => write!(f, "Drive alarm: {}", msg),
+            DeviceError::ReplayDiverged(msg) => write!(f, "Replay diverged: {}", msg),
+            DeviceError::MotorNotEnabled(msg) => write!(f, "Motor not enabled: {}", msg),
+            DeviceError::EmergencyStopped(msg) => write!(f, "Emergency stopped: {}", msg),
+            DeviceError::PositionOutOfRange(msg) => write!(f, "Position out of range: {}", msg),
+            DeviceError::InterlockDenied { interlock, reason } => {
+                write!(
+                    f,
+                    "Interlock {} denied the operation: {}",
+                    interlock, reason
+                )
+            }
+            DeviceError::WriteMismatch {
+                register,
+                written,
+                read,
+            } => write!(
+                f,
+                "Register {} reads back {} after writing {}",
+                register, read, written
+            ),
+            DeviceError::Exception {
+                exception,
+                function,
+                register,
+            } => write!(
+                f,
+                "Drive refused function {:#04x} on register {}: {}",
+                function.code(),
+                register,
+                exception
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceError::Modbus(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<modbus::Error> for DeviceError {
+    fn from(e: modbus::Error) -> DeviceError {
+        DeviceError::Modbus(e)
+    }
+}
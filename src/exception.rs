@@ -0,0 +1,135 @@
+use crate::{DeviceError, ModbusFunction};
+use modbus::ExceptionCode;
+use std::fmt;
+
+// An exception response from the drive: it received the request but
+// refused it, as opposed to the request never getting there
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModbusException {
+    IllegalFunction,     // 0x01, the drive doesn't support the function
+    IllegalDataAddress,  // 0x02, no such register
+    IllegalDataValue,    // 0x03, the value is out of range for the register
+    ServerFailure,       // 0x04
+    Acknowledge,         // 0x05, accepted but still being processed
+    ServerBusy,          // 0x06
+    NegativeAcknowledge, // 0x07
+    MemoryParity,        // 0x08
+    GatewayPath,         // 0x0a
+    GatewayTarget,       // 0x0b, no response from behind a gateway
+    Other(u8),
+}
+
+impl ModbusException {
+    pub fn code(self) -> u8 {
+        match self {
+            ModbusException::IllegalFunction => 0x01,
+            ModbusException::IllegalDataAddress => 0x02,
+            ModbusException::IllegalDataValue => 0x03,
+            ModbusException::ServerFailure => 0x04,
+            ModbusException::Acknowledge => 0x05,
+            ModbusException::ServerBusy => 0x06,
+            ModbusException::NegativeAcknowledge => 0x07,
+            ModbusException::MemoryParity => 0x08,
+            ModbusException::GatewayPath => 0x0a,
+            ModbusException::GatewayTarget => 0x0b,
+            ModbusException::Other(code) => code,
+        }
+    }
+
+    // Whether the same request may succeed if sent again later
+    pub fn is_transient(self) -> bool {
+        matches!(
+            self,
+            ModbusException::Acknowledge
+                | ModbusException::ServerBusy
+                | ModbusException::GatewayTarget
+        )
+    }
+
+    pub(crate) fn from_code(code: &ExceptionCode) -> ModbusException {
+        match code {
+            ExceptionCode::IllegalFunction => ModbusException::IllegalFunction,
+            ExceptionCode::IllegalDataAddress => ModbusException::IllegalDataAddress,
+            ExceptionCode::IllegalDataValue => ModbusException::IllegalDataValue,
+            ExceptionCode::SlaveOrServerFailure => ModbusException::ServerFailure,
+            ExceptionCode::Acknowledge => ModbusException::Acknowledge,
+            ExceptionCode::SlaveOrServerBusy => ModbusException::ServerBusy,
+            ExceptionCode::NegativeAcknowledge => ModbusException::NegativeAcknowledge,
+            ExceptionCode::MemoryParity => ModbusException::MemoryParity,
+            ExceptionCode::NotDefined => ModbusException::Other(0x09),
+            ExceptionCode::GatewayPath => ModbusException::GatewayPath,
+            ExceptionCode::GatewayTarget => ModbusException::GatewayTarget,
+        }
+    }
+}
+
+impl fmt::Display for ModbusException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ModbusException::IllegalFunction => "illegal function",
+            ModbusException::IllegalDataAddress => "illegal data address",
+            ModbusException::IllegalDataValue => "illegal data value",
+            ModbusException::ServerFailure => "device failure",
+            ModbusException::Acknowledge => "acknowledged, still processing",
+            ModbusException::ServerBusy => "device busy",
+            ModbusException::NegativeAcknowledge => "negative acknowledge",
+            ModbusException::MemoryParity => "memory parity error",
+            ModbusException::GatewayPath => "gateway path unavailable",
+            ModbusException::GatewayTarget => "gateway target failed to respond",
+            ModbusException::Other(_) => "unknown exception",
+        };
+        write!(f, "{} ({:#04x})", description, self.code())
+    }
+}
+
+// The code sent in an exception response.  The modbus crate's
+// ExceptionCode isn't Copy, so it can't simply be cast to a u8.
+pub(crate) fn exception_code_value(code: &ExceptionCode) -> u8 {
+    match code {
+        ExceptionCode::IllegalFunction => 0x01,
+        ExceptionCode::IllegalDataAddress => 0x02,
+        ExceptionCode::IllegalDataValue => 0x03,
+        ExceptionCode::SlaveOrServerFailure => 0x04,
+        ExceptionCode::Acknowledge => 0x05,
+        ExceptionCode::SlaveOrServerBusy => 0x06,
+        ExceptionCode::NegativeAcknowledge => 0x07,
+        ExceptionCode::MemoryParity => 0x08,
+        ExceptionCode::NotDefined => 0x09,
+        ExceptionCode::GatewayPath => 0x0a,
+        ExceptionCode::GatewayTarget => 0x0b,
+    }
+}
+
+// The exception for a code, NotDefined for one the modbus crate doesn't know
+pub(crate) fn exception_code(value: u8) -> ExceptionCode {
+    match value {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::SlaveOrServerFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::SlaveOrServerBusy,
+        0x07 => ExceptionCode::NegativeAcknowledge,
+        0x08 => ExceptionCode::MemoryParity,
+        0x0a => ExceptionCode::GatewayPath,
+        0x0b => ExceptionCode::GatewayTarget,
+        _ => ExceptionCode::NotDefined,
+    }
+}
+
+// The error to give up a transaction with, decoding exception responses so
+// callers can tell a bad register from a network failure
+pub(crate) fn transaction_error(
+    error: modbus::Error,
+    function: ModbusFunction,
+    register: u16,
+) -> DeviceError {
+    match error {
+        modbus::Error::Exception(code) => DeviceError::Exception {
+            exception: ModbusException::from_code(&code),
+            function,
+            register,
+        },
+        other => DeviceError::Modbus(other),
+    }
+}
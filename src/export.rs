@@ -0,0 +1,311 @@
+use crate::datalog::LogRow;
+use crate::feed::signed_count;
+use crate::{AppliedDevice, DeviceError, ScopeTrace, TraceSample};
+use arrow::array::{ArrayRef, Float64Array, Int64Array, StringArray, UInt16Array, UInt64Array};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+const PARQUET_BATCH_ROWS: usize = 1000; // Data log rows buffered before they are written
+
+// Columns: time since the first sample in µs, encoder count (signed, as the
+// drive holds it), velocity in counts/s (null where it wasn't read) and the
+// status names joined by '|'
+pub fn trace_record_batch(samples: &[TraceSample]) -> Result<RecordBatch, DeviceError> {
+    let first = samples.first().map(|s| s.at);
+    let elapsed_us: Int64Array = samples
+        .iter()
+        .map(|s| first.map(|first| s.at.saturating_duration_since(first).as_micros() as i64))
+        .collect();
+    let encoder_count: Int64Array = samples
+        .iter()
+        .map(|s| signed_count(s.encoder_count))
+        .collect();
+    let velocity: Float64Array = samples.iter().map(|s| s.velocity).collect();
+    let status: StringArray = samples.iter().map(|s| Some(s.status.join("|"))).collect();
+    let schema = Schema::new(vec![
+        Field::new("elapsed_us", DataType::Int64, false),
+        Field::new("encoder_count", DataType::Int64, false),
+        Field::new("velocity", DataType::Float64, true),
+        Field::new("status", DataType::Utf8, false),
+    ]);
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(elapsed_us),
+        Arc::new(encoder_count),
+        Arc::new(velocity),
+        Arc::new(status),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns).map_err(arrow_error)
+}
+
+// Columns: time since the trigger in µs, encoder count, velocity in counts/s
+// and current in amps
+pub fn scope_record_batch(trace: &ScopeTrace) -> Result<RecordBatch, DeviceError> {
+    let samples = &trace.samples;
+    let offset_us: Int64Array = samples
+        .iter()
+        .map(|s| s.offset.as_micros() as i64)
+        .collect();
+    let position: Int64Array = samples.iter().map(|s| s.position).collect();
+    let velocity: Float64Array = samples.iter().map(|s| s.velocity).collect();
+    let current: Float64Array = samples.iter().map(|s| s.current).collect();
+    let schema = Schema::new(vec![
+        Field::new("offset_us", DataType::Int64, false),
+        Field::new("position", DataType::Int64, false),
+        Field::new("velocity", DataType::Float64, false),
+        Field::new("current", DataType::Float64, false),
+    ]);
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(offset_us),
+        Arc::new(position),
+        Arc::new(velocity),
+        Arc::new(current),
+    ];
+    RecordBatch::try_new(Arc::new(schema), columns).map_err(arrow_error)
+}
+
+// Writes a batch to a new Parquet file, replacing any already there
+pub fn write_parquet(batch: &RecordBatch, path: &Path) -> Result<(), DeviceError> {
+    let unable =
+        |e: ParquetError| DeviceError::Config(format!("Unable to write {}: {}", path.display(), e));
+    let file = File::create(path)
+        .map_err(|e| DeviceError::Config(format!("Unable to create {}: {}", path.display(), e)))?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None).map_err(unable)?;
+    writer.write(batch).map_err(unable)?;
+    writer.close().map_err(unable)?;
+    info!("Wrote {} rows to {}", batch.num_rows(), path.display());
+    Ok(())
+}
+
+fn arrow_error(e: ArrowError) -> DeviceError {
+    DeviceError::Config(format!("Unable to build record batch: {}", e))
+}
+
+impl ScopeTrace {
+    pub fn to_record_batch(&self) -> Result<RecordBatch, DeviceError> {
+        scope_record_batch(self)
+    }
+
+    pub fn write_parquet(&self, path: &Path) -> Result<(), DeviceError> {
+        write_parquet(&self.to_record_batch()?, path)
+    }
+}
+
+impl AppliedDevice {
+    // Writes the motion trace recorded so far, see trace_record_batch
+    pub fn write_motion_trace_parquet(&self, path: &Path) -> Result<(), DeviceError> {
+        write_parquet(&trace_record_batch(&self.motion_trace())?, path)
+    }
+}
+
+// A data log written as Parquet, with the same columns as the CSV log.
+// Rows are buffered and written a batch at a time; the file is only
+// readable once finished, which dropping it does.
+pub(crate) struct ParquetLog {
+    schema: SchemaRef,
+    writer: Option<ArrowWriter<File>>,
+    rows: Vec<LogRow>,
+}
+
+impl ParquetLog {
+    pub(crate) fn create(file: File, registers: &[u16]) -> Result<ParquetLog, ParquetError> {
+        let mut fields = vec![
+            Field::new("unix_ms", DataType::UInt64, false),
+            Field::new("elapsed_ms", DataType::UInt64, false),
+            Field::new("encoder_count", DataType::UInt64, false),
+            Field::new("velocity", DataType::Float64, false),
+            Field::new("status", DataType::Utf8, false),
+        ];
+        for register in registers {
+            fields.push(Field::new(
+                format!("reg_{}", register),
+                DataType::UInt16,
+                false,
+            ));
+        }
+        let schema = Arc::new(Schema::new(fields));
+        let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+        Ok(ParquetLog {
+            schema,
+            writer: Some(writer),
+            rows: Vec::with_capacity(PARQUET_BATCH_ROWS),
+        })
+    }
+
+    pub(crate) fn push(&mut self, row: LogRow) -> Result<(), ParquetError> {
+        self.rows.push(row);
+        if self.rows.len() >= PARQUET_BATCH_ROWS {
+            self.write_rows()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<(), ParquetError> {
+        self.write_rows()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+
+    fn write_rows(&mut self) -> Result<(), ParquetError> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(rows.iter().map(|r| r.unix_ms).collect::<UInt64Array>()),
+            Arc::new(rows.iter().map(|r| r.elapsed_ms).collect::<UInt64Array>()),
+            Arc::new(
+                rows.iter()
+                    .map(|r| r.encoder_count)
+                    .collect::<UInt64Array>(),
+            ),
+            Arc::new(rows.iter().map(|r| r.velocity).collect::<Float64Array>()),
+            Arc::new(
+                rows.iter()
+                    .map(|r| Some(r.status.as_str()))
+                    .collect::<StringArray>(),
+            ),
+        ];
+        let registers = self.schema.fields().len() - columns.len();
+        for index in 0..registers {
+            let values: UInt16Array = rows.iter().map(|r| r.registers[index]).collect();
+            columns.push(Arc::new(values));
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        match &mut self.writer {
+            Some(writer) => writer.write(&batch),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ParquetLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Unable to finish Parquet data log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScopeSample;
+    use arrow::array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::time::{Duration, Instant};
+
+    fn read_parquet(path: &Path) -> Vec<RecordBatch> {
+        let file = File::open(path).expect("Unable to open file");
+        ParquetRecordBatchReaderBuilder::try_new(file)
+            .expect("Not a Parquet file")
+            .build()
+            .expect("Unable to read file")
+            .collect::<Result<Vec<RecordBatch>, ArrowError>>()
+            .expect("Unable to read batch")
+    }
+
+    fn column<T: Clone + 'static>(batch: &RecordBatch, name: &str) -> T {
+        batch
+            .column_by_name(name)
+            .expect("No such column")
+            .as_any()
+            .downcast_ref::<T>()
+            .expect("Wrong column type")
+            .clone()
+    }
+
+    #[test]
+    fn trace_rows_are_timed_from_the_first_sample() {
+        let start = Instant::now();
+        let samples = vec![
+            TraceSample {
+                at: start,
+                encoder_count: 10,
+                velocity: None,
+                status: vec![String::from("Motor Enabled")],
+            },
+            TraceSample {
+                at: start + Duration::from_millis(5),
+                encoder_count: u32::MAX as u64, // -1 as the drive holds it
+                velocity: Some(-200.0),
+                status: vec![String::from("Motor Enabled"), String::from("Moving")],
+            },
+        ];
+        let batch = trace_record_batch(&samples).expect("Unable to build batch");
+
+        assert_eq!(batch.num_rows(), 2);
+        let elapsed: Int64Array = column(&batch, "elapsed_us");
+        assert_eq!(elapsed.values(), &[0, 5000]);
+        let counts: Int64Array = column(&batch, "encoder_count");
+        assert_eq!(counts.values(), &[10, -1]);
+        let velocity: Float64Array = column(&batch, "velocity");
+        assert!(velocity.is_null(0));
+        assert_eq!(velocity.value(1), -200.0);
+        let status: StringArray = column(&batch, "status");
+        assert_eq!(status.value(1), "Motor Enabled|Moving");
+    }
+
+    #[test]
+    fn scope_traces_are_read_back_from_parquet() {
+        let trace = ScopeTrace {
+            servo_name: String::from("x"),
+            triggered: Instant::now(),
+            period: Duration::from_millis(1),
+            samples: (0..50)
+                .map(|i| ScopeSample {
+                    offset: Duration::from_millis(i),
+                    position: i as i64 * 10,
+                    velocity: 10_000.0,
+                    current: 0.8,
+                })
+                .collect(),
+            complete: true,
+        };
+        let path = std::env::temp_dir().join(format!("scope_{}.parquet", std::process::id()));
+        trace.write_parquet(&path).expect("Unable to write trace");
+
+        let batches = read_parquet(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0], trace.to_record_batch().unwrap());
+    }
+
+    #[test]
+    fn data_logs_are_written_a_batch_at_a_time() {
+        let path = std::env::temp_dir().join(format!("data_log_{}.parquet", std::process::id()));
+        let file = File::create(&path).expect("Unable to create file");
+        let mut log = ParquetLog::create(file, &[29, 30]).expect("Unable to create log");
+        let rows = PARQUET_BATCH_ROWS * 2 + 1;
+        for row in 0..rows as u64 {
+            log.push(LogRow {
+                unix_ms: 1000 + row,
+                elapsed_ms: row,
+                encoder_count: row * 2,
+                velocity: 0.0,
+                status: String::new(),
+                registers: vec![row as u16, 7],
+            })
+            .expect("Unable to log row");
+        }
+        drop(log);
+
+        let batches = read_parquet(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), rows);
+        let last = batches.last().unwrap();
+        assert_eq!(last.num_columns(), 7);
+        let register: UInt16Array = column(last, "reg_30");
+        assert!(register.values().iter().all(|&value| value == 7));
+        let elapsed: UInt64Array = column(last, "elapsed_ms");
+        assert_eq!(elapsed.value(elapsed.len() - 1), rows as u64 - 1);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::history::outcome_of;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, InterlockAction, MoveWait, Operation,
+    WordOrder, ACCELERATION, DECELERATION, DISTANCE_CHANGE_1, PARAMETER_1, PARAMETER_2, VELOCITY,
+};
+use std::convert::TryFrom;
+use std::time;
+use tracing::{info, info_span, warn};
+
+// Input levels and edges the drive's feed to sensor moves can wait on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputCondition {
+    Low,
+    High,
+    Rising,
+    Falling,
+}
+
+impl InputCondition {
+    // The drive takes the condition as the letter used in its SCL form
+    pub(crate) fn code(&self) -> u64 {
+        let letter = match self {
+            InputCondition::Low => b'L',
+            InputCondition::High => b'H',
+            InputCondition::Rising => b'R',
+            InputCondition::Falling => b'F',
+        };
+        letter as u64
+    }
+}
+
+// A move of `distance` counts from wherever the axis is.  The decel rate
+// sets how far before the end the axis starts slowing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LengthFeed {
+    pub accel: u64,
+    pub decel: u64,
+    pub velocity: u64,
+    pub distance: i64,
+}
+
+// A move that runs until an input meets a condition and then carries on
+// for a fixed offset, as used for registration marks and labels.  The sign
+// of the offset sets the direction of travel.  If the input hasn't
+// triggered by max_distance the axis stops there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorFeed {
+    pub input: u16, // Drive input number, 1 for X1
+    pub condition: InputCondition,
+    pub accel: u64,
+    pub decel: u64,
+    pub velocity: u64,
+    pub offset: i64,
+    pub max_distance: u64,
+}
+
+// Like a SensorFeed, except the input is ignored for the first
+// mask_distance counts (to step off the mark the axis is sitting on) and
+// there is no limit on how far the axis travels looking for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaskedSensorFeed {
+    pub input: u16,
+    pub condition: InputCondition,
+    pub accel: u64,
+    pub decel: u64,
+    pub velocity: u64,
+    pub offset: i64,
+    pub mask_distance: u64,
+}
+
+// The registers behind one of the drive's feed commands
+struct FeedCommand {
+    command: DriveCommand,
+    accel: u64,
+    decel: u64,
+    velocity: u64,
+    distance: i64,
+    change_distance: Option<u64>, // Safety or mask distance
+    max_travel: Option<u64>,      // Furthest the axis can go, None if unlimited
+    input: Option<(u16, InputCondition)>,
+}
+
+// Encoder counts are signed 32 bit values on the drive
+pub(crate) fn signed_count(count: u64) -> i64 {
+    count as u32 as i32 as i64
+}
+
+impl AppliedDevice {
+    pub fn feed_to_length(&mut self, feed: &LengthFeed) -> Result<bool, DeviceError> {
+        self.feed_to_length_with_cancel(feed, &CancelToken::new())
+    }
+
+    // Returns true if the axis ended up the requested distance from where
+    // it started
+    pub fn feed_to_length_with_cancel(
+        &mut self,
+        feed: &LengthFeed,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        info!("Feeding {} by {}", self.servo_name, feed.distance);
+        let started = self.clock.now();
+        let command = FeedCommand {
+            command: DriveCommand::FeedToLength,
+            accel: feed.accel,
+            decel: feed.decel,
+            velocity: feed.velocity,
+            distance: feed.distance,
+            change_distance: None,
+            max_travel: Some(feed.distance.unsigned_abs()),
+            input: None,
+        };
+        let result = self.run_feed(&command, cancel).map(|ends| match ends {
+            Some((start, end)) => {
+                let error = signed_count(end) - (signed_count(start) + feed.distance);
+                if error.unsigned_abs() > self.motion_defaults.tolerance {
+                    warn!("Feed of {} missed by {} counts", self.servo_name, error);
+                    return false;
+                }
+                self.count_feed(start, end, started);
+                true
+            }
+            None => false,
+        });
+        let operation = Operation::FeedToLength {
+            velocity: feed.velocity,
+            distance: feed.distance,
+        };
+        self.record_operation(operation, started, outcome_of(&result));
+        result
+    }
+
+    // Moves up to max_distance (whose sign sets the direction) until the
+    // input meets the condition, stopping as close to that point as the
+    // deceleration allows.  Returns where the axis stopped, or None if the
+    // input never triggered.
+    pub fn move_until_input(
+        &mut self,
+        input: u16,
+        condition: InputCondition,
+        velocity: u64,
+        accel: u64,
+        max_distance: i64,
+    ) -> Result<Option<u64>, DeviceError> {
+        if max_distance == 0 {
+            return Err(DeviceError::Config(String::from(
+                "A move until an input needs a non-zero max_distance to set its direction",
+            )));
+        }
+        // The drive's feed to sensor takes its direction from the distance
+        // to carry on past the trigger, so the shortest one in the right
+        // direction stands in for stopping straight away
+        self.move_until_input_then_offset(
+            input,
+            condition,
+            velocity,
+            accel,
+            max_distance.signum(),
+            max_distance.unsigned_abs(),
+        )
+    }
+
+    // Like move_until_input, but carries on for offset counts past the point
+    // the input triggered.  The sign of the offset sets the direction.
+    pub fn move_until_input_then_offset(
+        &mut self,
+        input: u16,
+        condition: InputCondition,
+        velocity: u64,
+        accel: u64,
+        offset: i64,
+        max_distance: u64,
+    ) -> Result<Option<u64>, DeviceError> {
+        let feed = SensorFeed {
+            input,
+            condition,
+            accel,
+            decel: accel,
+            velocity,
+            offset,
+            max_distance,
+        };
+        self.feed_to_sensor(&feed)
+    }
+
+    pub fn feed_to_sensor(&mut self, feed: &SensorFeed) -> Result<Option<u64>, DeviceError> {
+        self.feed_to_sensor_with_cancel(feed, &CancelToken::new())
+    }
+
+    pub fn feed_to_sensor_with_cancel(
+        &mut self,
+        feed: &SensorFeed,
+        cancel: &CancelToken,
+    ) -> Result<Option<u64>, DeviceError> {
+        if feed.offset == 0 || feed.max_distance == 0 {
+            return Err(DeviceError::Config(String::from(
+                "A feed to sensor needs a non-zero offset and max_distance",
+            )));
+        }
+
+        info!(
+            "Feeding {} to input {} ({:?}), offset {}, at most {}",
+            self.servo_name, feed.input, feed.condition, feed.offset, feed.max_distance
+        );
+        let started = self.clock.now();
+        let command = FeedCommand {
+            command: DriveCommand::FeedToSensorSafe,
+            accel: feed.accel,
+            decel: feed.decel,
+            velocity: feed.velocity,
+            distance: feed.offset,
+            change_distance: Some(feed.max_distance),
+            // A trigger near the safety distance carries on by the offset
+            max_travel: Some(feed.max_distance.saturating_add(feed.offset.unsigned_abs())),
+            input: Some((feed.input, feed.condition)),
+        };
+        let result = self.run_feed(&command, cancel).map(|ends| match ends {
+            Some((start, end)) => {
+                // Stopping at the safety distance is the only sign the input
+                // never came.  A trigger near the end carries the axis on by
+                // the offset, past max_distance, so only a stop within the
+                // position tolerance of it counts as not found.
+                let travel = (signed_count(end) - signed_count(start)).unsigned_abs();
+                let tolerance = self.motion_defaults.tolerance;
+                if travel.max(feed.max_distance) - travel.min(feed.max_distance) <= tolerance {
+                    warn!(
+                        "Input {} of {} did not trigger within {} counts",
+                        feed.input, self.servo_name, feed.max_distance
+                    );
+                    return None;
+                }
+                self.count_feed(start, end, started);
+                Some(end)
+            }
+            None => None,
+        });
+
+        let operation = Operation::FeedToSensor {
+            input: feed.input,
+            velocity: feed.velocity,
+            offset: feed.offset,
+            max_distance: feed.max_distance,
+        };
+        let found = result.as_ref().map(|stop| stop.is_some());
+        self.record_operation(operation, started, outcome_of(&found));
+        result
+    }
+
+    pub fn feed_to_sensor_masked(
+        &mut self,
+        feed: &MaskedSensorFeed,
+    ) -> Result<Option<u64>, DeviceError> {
+        self.feed_to_sensor_masked_with_cancel(feed, &CancelToken::new())
+    }
+
+    // Returns where the axis stopped, or None if the wait was cancelled or
+    // timed out
+    pub fn feed_to_sensor_masked_with_cancel(
+        &mut self,
+        feed: &MaskedSensorFeed,
+        cancel: &CancelToken,
+    ) -> Result<Option<u64>, DeviceError> {
+        if feed.offset == 0 {
+            return Err(DeviceError::Config(String::from(
+                "A feed to sensor needs a non-zero offset",
+            )));
+        }
+
+        info!(
+            "Feeding {} to input {} ({:?}), offset {}, masked for {}",
+            self.servo_name, feed.input, feed.condition, feed.offset, feed.mask_distance
+        );
+        let started = self.clock.now();
+        let command = FeedCommand {
+            command: DriveCommand::FeedToSensorMasked,
+            accel: feed.accel,
+            decel: feed.decel,
+            velocity: feed.velocity,
+            distance: feed.offset,
+            change_distance: Some(feed.mask_distance),
+            max_travel: None,
+            input: Some((feed.input, feed.condition)),
+        };
+        let result = self.run_feed(&command, cancel).map(|ends| {
+            ends.map(|(start, end)| {
+                self.count_feed(start, end, started);
+                end
+            })
+        });
+
+        let operation = Operation::MaskedFeedToSensor {
+            input: feed.input,
+            velocity: feed.velocity,
+            offset: feed.offset,
+            mask_distance: feed.mask_distance,
+        };
+        let found = result.as_ref().map(|stop| stop.is_some());
+        self.record_operation(operation, started, outcome_of(&found));
+        result
+    }
+
+    // Loads and starts a feed command, then waits for it to finish.  Returns
+    // the encoder positions before and after, or None if the wait was
+    // cancelled or timed out (which stops the axis).
+    fn run_feed(
+        &mut self,
+        command: &FeedCommand,
+        cancel: &CancelToken,
+    ) -> Result<Option<(u64, u64)>, DeviceError> {
+        // Distances go to the drive as 32 bit counts
+        let too_far = |distance: String| {
+            DeviceError::Config(format!(
+                "Feed distance {} is past what the drive can move in one go",
+                distance
+            ))
+        };
+        let distance =
+            i32::try_from(command.distance).map_err(|_| too_far(command.distance.to_string()))?;
+        let change_distance = match command.change_distance {
+            Some(change) => Some(u32::try_from(change).map_err(|_| too_far(change.to_string()))?),
+            None => None,
+        };
+        self.check_interlocks(InterlockAction::Feed {
+            distance: command.distance,
+            max_travel: command.max_travel,
+        })?;
+        let span = info_span!("feed", servo = %self.servo_name, distance = command.distance);
+        let _enter = span.enter();
+        self.reset_alarm_or_fault()?;
+        let start = self.get_encoder_count()?;
+
+        self.apply_profile(None)?;
+        self.write_register(ACCELERATION, command.accel)?;
+        self.write_register(DECELERATION, command.decel)?;
+        self.write_register(VELOCITY, self.override_velocity(command.velocity))?;
+        self.write_distance(distance as u32 as u64)?;
+        if let Some(change) = change_distance {
+            self.write_u32_ordered(DISTANCE_CHANGE_1, change, WordOrder::HighFirst)?;
+        }
+        if let Some((input, condition)) = command.input {
+            self.write_register(PARAMETER_1, input as u64)?;
+            self.write_register(PARAMETER_2, condition.code())?;
+        }
+        self.execute(command.command)?;
+        self.set_feeding(true);
+        self.clock.sleep(time::Duration::from_millis(10));
+
+        let finished = self.wait_for_move(cancel);
+        self.set_feeding(false);
+        if finished? != MoveWait::Finished {
+            return Ok(None);
+        }
+        Ok(Some((start, self.get_encoder_count()?)))
+    }
+
+    // Counts a successful feed towards the cycle count and persistent state
+    fn count_feed(&mut self, start: u64, end: u64, started: time::Instant) {
+        self.servo_cycle_count += 1;
+        let cycle_count = self.servo_cycle_count;
+        self.update_snapshot(|s| s.cycle_count = cycle_count);
+        let travel = (signed_count(end) - signed_count(start)).unsigned_abs();
+        self.accumulate_state(travel, self.clock.elapsed(started));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    fn enabled_device() -> (AppliedDevice, SimulatedDrive) {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let mut device =
+            AppliedDevice::with_transport(String::from("feed"), String::new(), drive.clone());
+        device.set_clock(clock);
+        device.enable_motor().expect("Unable to enable the motor");
+        (device, drive)
+    }
+
+    #[test]
+    fn move_until_input_needs_a_direction() {
+        let (mut device, drive) = enabled_device();
+        let result = device.move_until_input(3, InputCondition::Low, 100, 10, 0);
+        match result {
+            Err(DeviceError::Config(msg)) => assert!(msg.contains("max_distance"), "{}", msg),
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+        assert_eq!(drive.position(), 0);
+    }
+
+    #[test]
+    fn feed_past_a_32_bit_distance_is_refused() {
+        let (mut device, drive) = enabled_device();
+        let feed = LengthFeed {
+            accel: 10,
+            decel: 10,
+            velocity: 100,
+            distance: i32::MAX as i64 + 1,
+        };
+        let result = device.feed_to_length(&feed);
+        assert!(
+            matches!(result, Err(DeviceError::Config(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(drive.position(), 0);
+    }
+
+    fn sensor_feed(max_distance: u64) -> SensorFeed {
+        SensorFeed {
+            input: 3,
+            condition: InputCondition::Rising,
+            accel: 10,
+            decel: 10,
+            velocity: 100,
+            offset: 1000,
+            max_distance,
+        }
+    }
+
+    #[test]
+    fn sensor_feed_carries_on_by_the_offset_from_the_mark() {
+        let (mut device, drive) = enabled_device();
+        drive.trip_input_at(3, 5000);
+        let stop = device.feed_to_sensor(&sensor_feed(20000)).unwrap();
+        // The simulated drive sees the mark within a step of passing it
+        let stop = stop.expect("Mark not found") as i64;
+        assert!((6000..6010).contains(&stop), "{}", stop);
+        assert_eq!(drive.position(), stop);
+    }
+
+    #[test]
+    fn sensor_feed_with_no_mark_stops_at_max_distance() {
+        let (mut device, drive) = enabled_device();
+        assert_eq!(device.feed_to_sensor(&sensor_feed(8000)).unwrap(), None);
+        assert_eq!(drive.position(), 8000);
+    }
+
+    #[test]
+    fn length_feed_is_relative_to_where_the_axis_is() {
+        let (mut device, drive) = enabled_device();
+        drive.set_position(10000);
+        let feed = LengthFeed {
+            accel: 10,
+            decel: 10,
+            velocity: 100,
+            distance: -3000,
+        };
+        assert!(device.feed_to_length(&feed).unwrap());
+        assert_eq!(drive.position(), 7000);
+    }
+
+    #[test]
+    fn masked_feed_ignores_the_mark_it_starts_on() {
+        let (mut device, drive) = enabled_device();
+        drive.set_input(3, true);
+        let feed = MaskedSensorFeed {
+            input: 3,
+            condition: InputCondition::High,
+            accel: 10,
+            decel: 10,
+            velocity: 100,
+            offset: 500,
+            mask_distance: 2000,
+        };
+        let stop = device.feed_to_sensor_masked(&feed).unwrap();
+        let stop = stop.expect("Mark not found") as i64;
+        assert!((2500..2510).contains(&stop), "{}", stop);
+    }
+}
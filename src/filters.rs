@@ -0,0 +1,97 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use tracing::info;
+use yaml_rust::Yaml;
+
+// The drive's anti-resonance and command smoothing filters, which usually
+// need refining for each new mechanical build
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilterSettings {
+    pub anti_resonance: bool,
+    pub anti_resonance_damping: u16,
+    pub command_smoothing: u16, // Filter constant, 0 for none
+}
+
+impl FilterSettings {
+    // Reads the optional `filters:` section of a device config.  Anything
+    // not given is off.
+    //
+    //   filters:
+    //     anti_resonance: true
+    //     anti_resonance_damping: 50
+    //     command_smoothing: 10
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<FilterSettings>, DeviceError> {
+        let section = &device_conf["filters"];
+        match section {
+            Yaml::Hash(_) => {}
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "filters must be a map, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        let anti_resonance = match &section["anti_resonance"] {
+            Yaml::BadValue | Yaml::Null => false,
+            Yaml::Boolean(b) => *b,
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "filters.anti_resonance must be true or false, got {:?}",
+                    other
+                )))
+            }
+        };
+        Ok(Some(FilterSettings {
+            anti_resonance,
+            anti_resonance_damping: filter_value(section, "anti_resonance_damping")?,
+            command_smoothing: filter_value(section, "command_smoothing")?,
+        }))
+    }
+}
+
+fn filter_value(section: &Yaml, key: &str) -> Result<u16, DeviceError> {
+    match &section[key] {
+        Yaml::BadValue | Yaml::Null => Ok(0),
+        Yaml::Integer(n) if (0..=u16::MAX as i64).contains(n) => Ok(*n as u16),
+        other => Err(DeviceError::Config(format!(
+            "filters.{} must be from 0 to {}, got {:?}",
+            key,
+            u16::MAX,
+            other
+        ))),
+    }
+}
+
+impl AppliedDevice {
+    pub fn set_filters(&mut self, filters: &FilterSettings) -> Result<(), DeviceError> {
+        info!("Setting filters of {} to {:?}", self.servo_name, filters);
+        self.write_register(
+            self.mapped(MappedRegister::AntiResonanceDamping)?,
+            filters.anti_resonance_damping as u64,
+        )?;
+        self.write_register(
+            self.mapped(MappedRegister::AntiResonance)?,
+            filters.anti_resonance as u64,
+        )?;
+        self.write_register(
+            self.mapped(MappedRegister::CommandSmoothing)?,
+            filters.command_smoothing as u64,
+        )?;
+        self.volatile.filters = Some(*filters);
+        Ok(())
+    }
+
+    pub fn get_filters(&mut self) -> Result<FilterSettings, DeviceError> {
+        Ok(FilterSettings {
+            anti_resonance: self.get_register_value(self.mapped(MappedRegister::AntiResonance)?)?
+                != 0,
+            anti_resonance_damping: self
+                .get_register_value(self.mapped(MappedRegister::AntiResonanceDamping)?)?
+                as u16,
+            command_smoothing: self
+                .get_register_value(self.mapped(MappedRegister::CommandSmoothing)?)?
+                as u16,
+        })
+    }
+}
@@ -0,0 +1,52 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister, WordOrder, FOLLOWING_ERROR_1};
+use tracing::{info, warn};
+
+impl AppliedDevice {
+    // The difference between the commanded and actual position right now, in
+    // encoder counts.  A value creeping towards the fault limit usually means
+    // the axis is binding.
+    pub fn get_following_error(&mut self) -> Result<i64, DeviceError> {
+        let error = self.read_u32_ordered(FOLLOWING_ERROR_1, WordOrder::HighFirst)? as i32 as i64;
+        if let Some(warning) = self.following_error_warning {
+            if error.unsigned_abs() > warning as u64 {
+                warn!(
+                    "Following error of {} is {} counts, over the warning level of {}",
+                    self.servo_name, error, warning
+                );
+            }
+        }
+        self.update_snapshot(|s| s.following_error = error);
+        Ok(error)
+    }
+
+    // The following error at which the drive faults with a Position Limit
+    // Error.  Zero turns the fault off.
+    pub fn set_following_error_limit(&mut self, counts: u16) -> Result<(), DeviceError> {
+        info!(
+            "Setting following error limit of {} to {} counts",
+            self.servo_name, counts
+        );
+        self.write_register(
+            self.mapped(MappedRegister::FollowingErrorLimit)?,
+            counts as u64,
+        )?;
+        self.volatile.following_error_limit = Some(counts);
+        Ok(())
+    }
+
+    pub fn get_following_error_limit(&mut self) -> Result<u16, DeviceError> {
+        Ok(self.get_register_value(self.mapped(MappedRegister::FollowingErrorLimit)?)? as u16)
+    }
+
+    // Logs a warning whenever a following error read is larger than the
+    // given number of counts, well before the drive's own limit is reached
+    pub fn set_following_error_warning(&mut self, counts: Option<u16>) {
+        self.following_error_warning = counts;
+    }
+
+    // Also read the following error whenever the status snapshot is
+    // refreshed, so monitors can show it.  Off by default to save a read.
+    pub fn monitor_following_error(&mut self, enabled: bool) {
+        self.monitor_following_error = enabled;
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use tracing::info;
+
+static GAIN_REGISTERS: [MappedRegister; 5] = [
+    MappedRegister::PositionGain,
+    MappedRegister::IntegralGain,
+    MappedRegister::DerivativeGain,
+    MappedRegister::VelocityFeedforward,
+    MappedRegister::AccelerationFeedforward,
+];
+
+// The drive's servo tuning gains, in its own units.  Read a set from a well
+// tuned axis and write it to others of the same build.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServoGains {
+    pub proportional: u16,             // KP
+    pub integral: u16,                 // KI
+    pub derivative: u16,               // KD
+    pub velocity_feedforward: u16,     // KV
+    pub acceleration_feedforward: u16, // KK
+}
+
+impl ServoGains {
+    // From the gain registers, in the order of GAIN_REGISTERS
+    pub(crate) fn from_registers(values: &[u16]) -> ServoGains {
+        let value = |i: usize| values.get(i).copied().unwrap_or_default();
+        ServoGains {
+            proportional: value(0),
+            integral: value(1),
+            derivative: value(2),
+            velocity_feedforward: value(3),
+            acceleration_feedforward: value(4),
+        }
+    }
+
+    fn to_registers(self) -> [u16; 5] {
+        [
+            self.proportional,
+            self.integral,
+            self.derivative,
+            self.velocity_feedforward,
+            self.acceleration_feedforward,
+        ]
+    }
+}
+
+impl AppliedDevice {
+    pub fn read_gains(&mut self) -> Result<ServoGains, DeviceError> {
+        let mut values = Vec::with_capacity(GAIN_REGISTERS.len());
+        for register in GAIN_REGISTERS {
+            let register = self.mapped(register)?;
+            values.push(self.get_register_value(register)? as u16);
+        }
+        Ok(ServoGains::from_registers(&values))
+    }
+
+    // Takes effect straight away, including on a move in progress
+    pub fn write_gains(&mut self, gains: &ServoGains) -> Result<(), DeviceError> {
+        info!("Writing gains of {}: {:?}", self.servo_name, gains);
+        // All looked up first, so a missing one doesn't leave the set half written
+        let registers = GAIN_REGISTERS
+            .iter()
+            .map(|register| self.mapped(*register))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (register, value) in registers.into_iter().zip(gains.to_registers()) {
+            self.write_register(register, value as u64)?;
+        }
+        self.volatile.gains = Some(*gains);
+        Ok(())
+    }
+}
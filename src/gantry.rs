@@ -0,0 +1,305 @@
+use crate::feed::signed_count;
+use crate::rollover::travel_between;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, InterlockAction, ACCELERATION,
+    DECELERATION, MOVING, VELOCITY,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+static GANTRY_POLL_INTERVAL: u64 = 20; // How often skew is checked while moving, in ms
+
+// Two drives driving either side of one gantry.  They are homed together,
+// moved in lock-step, and stopped together as soon as their positions
+// differ by more than max_skew counts.
+pub struct Gantry {
+    primary: AppliedDevice,
+    secondary: AppliedDevice,
+    max_skew: u64,
+}
+
+impl Gantry {
+    pub fn new(
+        primary: AppliedDevice,
+        secondary: AppliedDevice,
+        max_skew: u64,
+    ) -> Result<Gantry, DeviceError> {
+        if max_skew == 0 {
+            return Err(DeviceError::Config(String::from(
+                "A gantry needs a non-zero skew limit",
+            )));
+        }
+        Ok(Gantry {
+            primary,
+            secondary,
+            max_skew,
+        })
+    }
+
+    pub fn primary(&mut self) -> &mut AppliedDevice {
+        &mut self.primary
+    }
+
+    pub fn secondary(&mut self) -> &mut AppliedDevice {
+        &mut self.secondary
+    }
+
+    pub fn into_devices(self) -> (AppliedDevice, AppliedDevice) {
+        (self.primary, self.secondary)
+    }
+
+    // Difference between the two sides, primary minus secondary
+    pub fn get_skew(&mut self) -> Result<i64, DeviceError> {
+        let primary = signed_count(self.primary.get_encoder_count()?);
+        let secondary = signed_count(self.secondary.get_encoder_count()?);
+        Ok(primary - secondary)
+    }
+
+    // Stops both sides and returns an error if the skew is over the limit
+    pub fn check_skew(&mut self) -> Result<i64, DeviceError> {
+        let skew = self.get_skew()?;
+        if skew.unsigned_abs() <= self.max_skew {
+            return Ok(skew);
+        }
+
+        error!(
+            "Gantry {}/{} skewed by {} counts, stopping both",
+            self.primary.servo_name, self.secondary.servo_name, skew
+        );
+        let stopped = self.stop_motion();
+        let skewed = DeviceError::GantrySkew(format!(
+            "{} and {} differ by {} counts, limit {}",
+            self.primary.servo_name, self.secondary.servo_name, skew, self.max_skew
+        ));
+        stopped.and(Err(skewed))
+    }
+
+    // Stops both sides, even if stopping one of them fails
+    pub fn stop_motion(&mut self) -> Result<(), DeviceError> {
+        let primary = self.primary.stop_motion();
+        let secondary = self.secondary.stop_motion();
+        primary.and(secondary)
+    }
+
+    // Homes both sides at once, then checks they agree.  If either side
+    // fails to home the other is cancelled, and both are stopped.
+    pub fn home(&mut self) -> Result<(), DeviceError> {
+        info!(
+            "Homing gantry {}/{}",
+            self.primary.servo_name, self.secondary.servo_name
+        );
+        let cancel = CancelToken::new();
+        let (primary, secondary) = (&mut self.primary, &mut self.secondary);
+        let home_side = |device: &mut AppliedDevice| {
+            let homed = device.home_servo_with_cancel(&cancel);
+            if homed.is_err() {
+                cancel.cancel();
+            }
+            homed
+        };
+        let (first, second) = thread::scope(|scope| {
+            let other = scope.spawn(|| home_side(secondary));
+            let first = home_side(primary);
+            let second = other.join().unwrap_or_else(|_| {
+                cancel.cancel();
+                Err(DeviceError::WorkerStopped(String::from(
+                    "Homing of the secondary side panicked",
+                )))
+            });
+            (first, second)
+        });
+        if let Err(e) = first.and(second) {
+            error!(
+                "Gantry {}/{} did not home, stopping both",
+                self.primary.servo_name, self.secondary.servo_name
+            );
+            if let Err(stop) = self.stop_motion() {
+                warn!("Unable to stop gantry: {}", stop);
+            }
+            return Err(e);
+        }
+        self.check_skew().map(|_| ())
+    }
+
+    pub fn move_to(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+    ) -> Result<bool, DeviceError> {
+        self.move_to_with_cancel(
+            accel,
+            decel,
+            velocity,
+            encoder_position,
+            &CancelToken::new(),
+        )
+    }
+
+    // Moves both sides to the same position, started as close together as
+    // the connection allows, checking skew throughout.  Returns true if both
+    // reached the position, false if the move was cancelled or timed out.
+    pub fn move_to_with_cancel(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        info!(
+            "Moving gantry {}/{} to {}",
+            self.primary.servo_name, self.secondary.servo_name, encoder_position
+        );
+        let action = InterlockAction::Move {
+            target: encoder_position,
+        };
+        self.primary.check_interlocks(action)?;
+        self.secondary.check_interlocks(action)?;
+        let started = self.primary.clock.now();
+        let start_positions = (
+            self.primary.get_encoder_count()?,
+            self.secondary.get_encoder_count()?,
+        );
+        // One velocity for both sides, the lower of their overrides, so a
+        // difference in override settings doesn't skew them
+        let velocity = self
+            .primary
+            .override_velocity(velocity)
+            .min(self.secondary.override_velocity(velocity));
+        for side in [&mut self.primary, &mut self.secondary] {
+            side.reset_alarm_or_fault()?;
+            side.apply_profile(None)?;
+            side.write_register(ACCELERATION, accel)?;
+            side.write_register(DECELERATION, decel)?;
+            side.write_register(VELOCITY, velocity)?;
+            side.write_distance(encoder_position)?;
+        }
+        // Back to back, so the sides start within a transaction of each other
+        self.primary.execute(DriveCommand::StartMove)?;
+        if let Err(e) = self.secondary.execute(DriveCommand::StartMove) {
+            self.primary.stop_motion()?;
+            return Err(e);
+        }
+
+        // Nothing watches the skew once supervision ends, so both sides are
+        // stopped whatever it ends with
+        match self.supervise_move(started, cancel) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.stop_motion()?;
+                return Ok(false);
+            }
+            Err(e) => {
+                if let Err(stop) = self.stop_motion() {
+                    warn!("Unable to stop gantry: {}", stop);
+                }
+                return Err(e);
+            }
+        }
+
+        let duration = self.primary.clock.elapsed(started);
+        let end_positions = (
+            self.primary.get_encoder_count()?,
+            self.secondary.get_encoder_count()?,
+        );
+        self.primary
+            .accumulate_state(travel_between(start_positions.0, end_positions.0), duration);
+        self.secondary
+            .accumulate_state(travel_between(start_positions.1, end_positions.1), duration);
+        Ok(
+            self.primary.in_range(encoder_position)?
+                && self.secondary.in_range(encoder_position)?,
+        )
+    }
+
+    // Checks skew until both sides stop moving.  Returns false if the move
+    // was cancelled or took too long, leaving the sides to be stopped.
+    fn supervise_move(
+        &mut self,
+        started: Instant,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let poll = Duration::from_millis(GANTRY_POLL_INTERVAL);
+        loop {
+            self.primary.clock.sleep(poll);
+            self.check_skew()?;
+            if cancel.is_cancelled() {
+                warn!(
+                    "Move of gantry {}/{} was cancelled",
+                    self.primary.servo_name, self.secondary.servo_name
+                );
+                return Ok(false);
+            }
+            if !self.primary.has_status(MOVING)? && !self.secondary.has_status(MOVING)? {
+                return Ok(true);
+            }
+            if self.primary.clock.elapsed(started) > self.primary.motion_defaults.move_timeout {
+                warn!(
+                    "Move of gantry {}/{} took too long, stopping",
+                    self.primary.servo_name, self.secondary.servo_name
+                );
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    // Both sides on one clock, enabled
+    fn gantry() -> (Gantry, SimulatedDrive, SimulatedDrive) {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let side = |name: &str| {
+            let drive = SimulatedDrive::with_clock(clock.clone());
+            let mut device =
+                AppliedDevice::with_transport(String::from(name), String::new(), drive.clone());
+            device.set_clock(clock.clone());
+            device.enable_motor().expect("Unable to enable the motor");
+            (device, drive)
+        };
+        let (primary, primary_drive) = side("left");
+        let (secondary, secondary_drive) = side("right");
+        let gantry = Gantry::new(primary, secondary, 100).unwrap();
+        (gantry, primary_drive, secondary_drive)
+    }
+
+    #[test]
+    fn both_sides_move_together() {
+        let (mut gantry, primary, secondary) = gantry();
+        assert!(gantry.move_to(10, 10, 100, 5000).unwrap());
+        assert_eq!(primary.position(), 5000);
+        assert_eq!(secondary.position(), 5000);
+        assert_eq!(gantry.get_skew().unwrap(), 0);
+    }
+
+    #[test]
+    fn skew_past_the_limit_stops_both_sides() {
+        let (mut gantry, primary, secondary) = gantry();
+        // The same drive velocity turns into half the counts per second
+        secondary.set_counts_per_rev(10000);
+        let result = gantry.move_to(10, 10, 100, 50000);
+        assert!(matches!(result, Err(DeviceError::GantrySkew(_))));
+        // Given time to decelerate
+        gantry.primary().clock.sleep(Duration::from_secs(5));
+        assert!(!gantry.primary().has_status(MOVING).unwrap());
+        assert!(!gantry.secondary().has_status(MOVING).unwrap());
+        assert!(primary.position() < 50000);
+    }
+
+    #[test]
+    fn a_skew_limit_is_needed() {
+        let (gantry, _, _) = gantry();
+        let (primary, secondary) = gantry.into_devices();
+        assert!(matches!(
+            Gantry::new(primary, secondary, 0),
+            Err(DeviceError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::virtual_master::VELOCITY_UNITS;
+use crate::{AppliedDevice, CancelToken, DeviceError, MoveOptions, Recipe, RecipeStep};
+use std::time::Duration;
+use tracing::info;
+
+// One command of a single axis G-code program.  Positions and feeds are in
+// the device's units (motion.counts_per_unit), or in counts if it has none.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GcodeCommand {
+    Rapid { position: f64 },             // G0, at the default velocity
+    Linear { position: f64, feed: f64 }, // G1, feed in units per minute
+    Dwell(Duration),                     // G4 P<ms> or S<seconds>
+    Home,                                // G28
+    Absolute,                            // G90, the default
+    Incremental,                         // G91
+}
+
+// Parses the supported subset of G-code: G0, G1 with F, G4, G28, G90 and
+// G91, on the one axis letter given (usually 'X').  G0 and G1 carry over to
+// later lines that only give a position, as F does.  Line numbers, comments
+// in ( ) or after ;, and G20/G21 are ignored; M2 or M30 ends the program.
+pub fn parse_gcode(program: &str, axis: char) -> Result<Vec<GcodeCommand>, DeviceError> {
+    let axis = axis.to_ascii_uppercase();
+    let mut commands = Vec::new();
+    let mut feed: Option<f64> = None;
+    let mut motion: Option<u32> = None; // The G0 or G1 in effect
+
+    for (number, line) in program.lines().enumerate() {
+        let error =
+            |msg: String| DeviceError::Config(format!("G-code line {}: {}", number + 1, msg));
+        let words = words_of(line).map_err(error)?;
+        let mut codes: Vec<(char, u32)> = Vec::new();
+        let mut position: Option<f64> = None;
+        let mut p: Option<f64> = None;
+        let mut s: Option<f64> = None;
+
+        for (letter, value) in words {
+            match letter {
+                'N' => {}
+                'G' | 'M' => codes.push((letter, value as u32)),
+                'F' if value > 0.0 => feed = Some(value),
+                'P' => p = Some(value),
+                'S' => s = Some(value),
+                l if l == axis => position = Some(value),
+                other => {
+                    return Err(error(format!(
+                        "{}{} is not supported, only the {} axis can be moved",
+                        other, value, axis
+                    )))
+                }
+            }
+        }
+
+        let mut dwell = false;
+        for code in codes {
+            match code {
+                ('G', 0) | ('G', 1) => motion = Some(code.1),
+                ('G', 4) => dwell = true,
+                ('G', 28) => commands.push(GcodeCommand::Home),
+                ('G', 90) => commands.push(GcodeCommand::Absolute),
+                ('G', 91) => commands.push(GcodeCommand::Incremental),
+                ('G', 20) | ('G', 21) => {}
+                ('M', 2) | ('M', 30) => return Ok(commands),
+                (letter, value) => {
+                    return Err(error(format!("{}{} is not supported", letter, value)))
+                }
+            }
+        }
+
+        if dwell {
+            let dwell = match (p, s) {
+                (Some(ms), _) if ms >= 0.0 => Duration::from_millis(ms as u64),
+                (None, Some(secs)) if secs >= 0.0 => Duration::from_secs_f64(secs),
+                _ => return Err(error(String::from("G4 needs a P or S dwell time"))),
+            };
+            commands.push(GcodeCommand::Dwell(dwell));
+        }
+        if let Some(position) = position {
+            let command = match (motion, feed) {
+                (Some(0), _) => GcodeCommand::Rapid { position },
+                (Some(_), Some(feed)) => GcodeCommand::Linear { position, feed },
+                (Some(_), None) => return Err(error(String::from("G1 needs an F feed"))),
+                (None, _) => return Err(error(String::from("a move needs G0 or G1"))),
+            };
+            commands.push(command);
+        }
+    }
+    Ok(commands)
+}
+
+// Splits a line into its letter and number words, dropping comments
+fn words_of(line: &str) -> Result<Vec<(char, f64)>, String> {
+    let line = line.split(';').next().unwrap_or("");
+    let mut text = String::new();
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            _ if !in_comment => text.push(c),
+            _ => {}
+        }
+    }
+
+    let mut words = Vec::new();
+    let mut chars = text.chars().filter(|c| !c.is_whitespace()).peekable();
+    while let Some(letter) = chars.next() {
+        if !letter.is_ascii_alphabetic() {
+            return Err(format!("expected a letter, got {:?}", letter));
+        }
+        let mut number = String::new();
+        while let Some(c) = chars.peek() {
+            if c.is_ascii_digit() || *c == '.' || *c == '-' || *c == '+' {
+                number.push(*c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let value = number
+            .parse::<f64>()
+            .map_err(|_| format!("{} needs a number, got {:?}", letter, number))?;
+        words.push((letter.to_ascii_uppercase(), value));
+    }
+    Ok(words)
+}
+
+impl AppliedDevice {
+    pub fn run_gcode(&mut self, program: &str) -> Result<usize, DeviceError> {
+        self.run_gcode_with_cancel(program, &CancelToken::new())
+    }
+
+    // Runs a single axis G-code program on the X axis, as a recipe.  Returns
+    // how many moves, dwells and homings completed, as run_recipe does.
+    pub fn run_gcode_with_cancel(
+        &mut self,
+        program: &str,
+        cancel: &CancelToken,
+    ) -> Result<usize, DeviceError> {
+        let commands = parse_gcode(program, 'X')?;
+        let recipe = self.gcode_recipe(&commands)?;
+        info!(
+            "Running {} G-code commands on {}",
+            recipe.steps.len(),
+            self.servo_name
+        );
+        self.run_recipe_with_cancel(&recipe, cancel)
+    }
+
+    fn gcode_recipe(&mut self, commands: &[GcodeCommand]) -> Result<Recipe, DeviceError> {
+        let scale = self.motion_defaults.counts_per_unit.unwrap_or(1.0);
+        let mut incremental = false;
+        let mut position: Option<f64> = None; // Where the program has sent the axis, in units
+        let mut steps = Vec::new();
+
+        for command in commands {
+            let (target, feed) = match command {
+                GcodeCommand::Rapid { position } => (*position, None),
+                GcodeCommand::Linear { position, feed } => (*position, Some(*feed)),
+                GcodeCommand::Dwell(dwell) => {
+                    steps.push(RecipeStep::Dwell(*dwell));
+                    continue;
+                }
+                GcodeCommand::Home => {
+                    steps.push(RecipeStep::Home);
+                    position = Some(0.0);
+                    continue;
+                }
+                GcodeCommand::Absolute | GcodeCommand::Incremental => {
+                    incremental = *command == GcodeCommand::Incremental;
+                    continue;
+                }
+            };
+
+            let target = if incremental {
+                let from = match position {
+                    Some(p) => p,
+                    None => crate::feed::signed_count(self.get_encoder_count()?) as f64 / scale,
+                };
+                from + target
+            } else {
+                target
+            };
+            position = Some(target);
+
+            let counts = (target * scale).round() as i64 as u32 as u64;
+            let mut options = MoveOptions::to(counts);
+            if let Some(feed) = feed {
+                let counts_per_rev = match self.known_encoder_counts_per_rev() {
+                    Some(counts) => counts,
+                    None => self.get_encoder_counts_per_rev()?,
+                };
+                let counts_per_second = feed * scale / 60.0;
+                let velocity = counts_per_second / counts_per_rev as f64 * VELOCITY_UNITS;
+                options = options.velocity((velocity.round() as u64).max(1));
+            }
+            steps.push(RecipeStep::Move(options));
+        }
+        Ok(Recipe {
+            name: String::from("gcode"),
+            steps,
+        })
+    }
+}
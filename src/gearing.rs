@@ -0,0 +1,79 @@
+use crate::history::outcome_of;
+use crate::{AppliedDevice, DeviceError, DriveCommand, InterlockAction, MappedRegister, Operation};
+use tracing::info;
+
+// How many counts the axis moves for each count of the master encoder.  A
+// negative numerator follows in the opposite direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GearRatio {
+    pub numerator: i16,
+    pub denominator: u16,
+}
+
+impl GearRatio {
+    pub fn new(numerator: i16, denominator: u16) -> GearRatio {
+        GearRatio {
+            numerator,
+            denominator,
+        }
+    }
+}
+
+impl AppliedDevice {
+    // Starts tracking the external encoder (or another axis wired to the
+    // master encoder input) at the given ratio.  The axis keeps following
+    // until stop_following or stop_motion is called.
+    pub fn start_following(&mut self, ratio: GearRatio) -> Result<(), DeviceError> {
+        if ratio.denominator == 0 || ratio.numerator == 0 {
+            return Err(DeviceError::Config(String::from(
+                "A gear ratio needs a non-zero numerator and denominator",
+            )));
+        }
+        self.check_interlocks(InterlockAction::Follow { ratio })?;
+
+        info!(
+            "Servo {} following at {}:{}",
+            self.servo_name, ratio.numerator, ratio.denominator
+        );
+        let started = self.clock.now();
+        let result = self.run_start_following(ratio);
+        let operation = Operation::StartFollowing {
+            numerator: ratio.numerator,
+            denominator: ratio.denominator,
+        };
+        self.record_operation(
+            operation,
+            started,
+            outcome_of(&result.as_ref().map(|_| true)),
+        );
+        result
+    }
+
+    fn run_start_following(&mut self, ratio: GearRatio) -> Result<(), DeviceError> {
+        self.reset_alarm_or_fault()?;
+        self.write_register(
+            self.mapped(MappedRegister::GearNumerator)?,
+            ratio.numerator as u16 as u64,
+        )?;
+        self.write_register(
+            self.mapped(MappedRegister::GearDenominator)?,
+            ratio.denominator as u64,
+        )?;
+        self.execute(DriveCommand::FollowEncoder)?;
+        self.following = Some(ratio);
+        Ok(())
+    }
+
+    pub fn stop_following(&mut self) -> Result<(), DeviceError> {
+        if self.following.is_none() {
+            return Ok(());
+        }
+        info!("Servo {} no longer following", self.servo_name);
+        self.stop_motion()
+    }
+
+    // The ratio the axis is following at, if it is following
+    pub fn get_following(&self) -> Option<GearRatio> {
+        self.following
+    }
+}
@@ -0,0 +1,623 @@
+use crate::exception::transaction_error;
+use crate::state_store::json_string;
+use crate::{
+    AppliedDevice, CancelToken, Clock, DeviceConfig, DeviceError, FirmwareVersion, ModbusFunction,
+    StatusSnapshot, Transport,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use yaml_rust::Yaml;
+
+// What happened on each servo of a group operation, in group order
+#[derive(Debug)]
+pub struct GroupOutcome {
+    pub results: Vec<(String, Result<(), DeviceError>)>,
+}
+
+impl GroupOutcome {
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+
+    pub fn failures(&self) -> Vec<(&str, &DeviceError)> {
+        self.results
+            .iter()
+            .filter_map(|(name, result)| result.as_ref().err().map(|e| (name.as_str(), e)))
+            .collect()
+    }
+}
+
+// The order servos are homed in.  Each stage is homed at the same time, and
+// a stage only starts once the one before it has homed without error, e.g.
+// a Z axis before the X and Y axes it would otherwise drag something across.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomingOrder {
+    stages: Vec<Vec<String>>,
+}
+
+impl HomingOrder {
+    // One servo at a time, in group order
+    pub fn sequential() -> HomingOrder {
+        HomingOrder { stages: Vec::new() }
+    }
+
+    // Every servo at once
+    pub fn parallel() -> HomingOrder {
+        HomingOrder {
+            stages: vec![Vec::new()],
+        }
+    }
+
+    // Servos not named in any stage are homed together after the last one
+    pub fn stages(stages: Vec<Vec<String>>) -> HomingOrder {
+        HomingOrder { stages }
+    }
+}
+
+static TIMEOUT_POLL_INTERVAL: u64 = 10; // How often running operations are checked against their timeout, in ms
+static BROADCAST_UNIT_ID: u8 = 0; // Addresses every drive on the bus
+
+// The clock an operation is timed by, when it started and how to cancel it
+type RunningOperation = (Arc<dyn Clock>, Instant, CancelToken);
+
+// Every servo of one device configuration, for working on them as a whole.
+// Operations on the whole group run on several servos at once.
+pub struct AppliedDeviceGroup {
+    devices: Vec<AppliedDevice>,
+    max_parallel: usize, // Servos worked on at once, 0 for all of them
+    timeout: Option<Duration>,
+    broadcast: Option<Box<dyn Transport>>, // Used by broadcast_write when set
+}
+
+impl AppliedDeviceGroup {
+    pub fn new(devices: Vec<AppliedDevice>) -> AppliedDeviceGroup {
+        AppliedDeviceGroup {
+            devices,
+            max_parallel: 0,
+            timeout: None,
+            broadcast: None,
+        }
+    }
+
+    // Limits how many servos a group operation works on at once, e.g. to
+    // spare a slow network.  0, the default, works on all of them at once.
+    pub fn set_max_parallel(&mut self, max_parallel: usize) {
+        self.max_parallel = max_parallel;
+    }
+
+    // How long a group operation may take on any one servo.  Homing and
+    // other long operations are cancelled once it runs out, and the servo
+    // reported as timed out, without holding up the rest of the group.
+    pub fn set_operation_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // A connection to a gateway that acknowledges broadcast writes (unit ID
+    // 0) itself, for broadcast_write to use.  A drive never answers a
+    // broadcast, so one sent straight to the drives would only time out.
+    pub fn set_broadcast_transport<T: Transport + 'static>(&mut self, transport: Option<T>) {
+        self.broadcast = transport.map(|t| Box::new(t) as Box<dyn Transport>);
+    }
+
+    // Connects to every servo listed under `device:` in the device config,
+    // using the overlays of the environment named by APPLIED_DEVICE_ENV
+    pub fn from_config(device_name: &str) -> Result<AppliedDeviceGroup, DeviceError> {
+        AppliedDeviceGroup::from_device_config(DeviceConfig::load(device_name)?)
+    }
+
+    // Connects to every servo listed under `device:` in the given config
+    pub fn from_device_config(config: DeviceConfig) -> Result<AppliedDeviceGroup, DeviceError> {
+        let servos = match &config.yaml()["device"] {
+            Yaml::Hash(h) => h,
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "device must be a map of servo names to addresses, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut devices = Vec::new();
+        for servo_name in servos.keys() {
+            let servo_name = match servo_name.as_str() {
+                Some(n) => n,
+                None => {
+                    return Err(DeviceError::Config(format!(
+                        "servo names must be strings, got {:?}",
+                        servo_name
+                    )))
+                }
+            };
+            devices.push(AppliedDevice::from_config(
+                config.clone(),
+                servo_name.to_string(),
+            )?);
+        }
+        info!(
+            "Created group of {} servos for {}",
+            devices.len(),
+            config.get_source()
+        );
+        Ok(AppliedDeviceGroup::new(devices))
+    }
+
+    pub fn add(&mut self, device: AppliedDevice) {
+        self.devices.push(device);
+    }
+
+    pub fn get(&mut self, servo_name: &str) -> Option<&mut AppliedDevice> {
+        self.devices.iter_mut().find(|d| d.servo_name == servo_name)
+    }
+
+    pub fn get_names(&self) -> Vec<&str> {
+        self.devices.iter().map(|d| d.servo_name.as_str()).collect()
+    }
+
+    pub fn devices(&mut self) -> &mut [AppliedDevice] {
+        &mut self.devices
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn into_devices(self) -> Vec<AppliedDevice> {
+        self.devices
+    }
+
+    pub fn enable_all(&mut self) -> GroupOutcome {
+        self.run_all(|device, _| device.enable_motor())
+    }
+
+    pub fn disable_all(&mut self) -> GroupOutcome {
+        self.run_all(|device, _| device.disable_motor())
+    }
+
+    pub fn reset_all_faults(&mut self) -> GroupOutcome {
+        self.run_all(|device, _| device.reset_alarm_or_fault())
+    }
+
+    // Writes the same value to a register of every drive at close to the
+    // same moment, e.g. a velocity override for all axes.  Sent as one
+    // Modbus broadcast through the broadcast transport if there is one.
+    // Otherwise, or if the broadcast fails, each drive is written from its
+    // own thread, all released together.  A broadcast is not read back, even
+    // with write verification on.
+    pub fn broadcast_write(&mut self, register: u16, value: u16) -> GroupOutcome {
+        // Every drive's audit log records the write once, before it is sent
+        let audited: Vec<(String, Result<(), DeviceError>)> = self
+            .devices
+            .iter_mut()
+            .map(|d| (d.servo_name.clone(), d.audit_write(register, value)))
+            .collect();
+        if audited.iter().any(|(_, result)| result.is_err()) {
+            return GroupOutcome { results: audited };
+        }
+        if self.broadcast.is_some() {
+            match self.send_broadcast(register, value) {
+                Ok(()) => {
+                    info!("Broadcast {} to register {}", value, register);
+                    return GroupOutcome { results: audited };
+                }
+                Err(e) => warn!(
+                    "Broadcast to register {} failed, writing each drive: {}",
+                    register, e
+                ),
+            }
+        }
+
+        let barrier = Barrier::new(self.devices.len());
+        let barrier = &barrier;
+        let results = thread::scope(|scope| {
+            let writes: Vec<_> = self
+                .devices
+                .iter_mut()
+                .map(|device| {
+                    scope.spawn(move || {
+                        let span = device.log_span.clone();
+                        let _enter = span.enter();
+                        barrier.wait();
+                        (
+                            device.servo_name.clone(),
+                            device.send_write(register, value as u64),
+                        )
+                    })
+                })
+                .collect();
+            writes
+                .into_iter()
+                .map(|write| {
+                    write.join().unwrap_or_else(|_| {
+                        (
+                            String::new(),
+                            Err(DeviceError::WorkerStopped(String::from(
+                                "Broadcast write panicked",
+                            ))),
+                        )
+                    })
+                })
+                .collect()
+        });
+        GroupOutcome { results }
+    }
+
+    fn send_broadcast(&mut self, register: u16, value: u16) -> Result<(), DeviceError> {
+        let transport = match &mut self.broadcast {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        transport.set_unit_id(BROADCAST_UNIT_ID);
+        transport
+            .write_single_register(register, value)
+            .map_err(|e| transaction_error(e, ModbusFunction::WriteSingleRegister, register))
+    }
+
+    // Homes every servo in the given order.  Servos in stages after a failed
+    // one are left alone and reported as not homed.
+    pub fn home_all(&mut self, order: &HomingOrder) -> Result<GroupOutcome, DeviceError> {
+        let names: Vec<String> = self.devices.iter().map(|d| d.servo_name.clone()).collect();
+        let mut stages: Vec<Vec<String>> = Vec::new();
+        for stage in &order.stages {
+            for name in stage {
+                if !names.contains(name) {
+                    return Err(DeviceError::Config(format!(
+                        "Homing order names {}, which is not in the group",
+                        name
+                    )));
+                }
+            }
+            stages.push(stage.clone());
+        }
+        let unstaged: Vec<String> = names
+            .iter()
+            .filter(|name| !stages.iter().any(|stage| stage.contains(name)))
+            .cloned()
+            .collect();
+        if order.stages.is_empty() {
+            stages = unstaged.into_iter().map(|name| vec![name]).collect();
+        } else if !unstaged.is_empty() {
+            stages.push(unstaged);
+        }
+
+        let mut results = Vec::new();
+        let mut failed = false;
+        for stage in stages.iter().filter(|stage| !stage.is_empty()) {
+            if failed {
+                for name in stage {
+                    let skipped = DeviceError::HomingFailed(format!(
+                        "{} is not homed: an earlier stage failed",
+                        name
+                    ));
+                    results.push((name.clone(), Err(skipped)));
+                }
+                continue;
+            }
+            info!("Homing {}", stage.join(", "));
+            let devices = self
+                .devices
+                .iter_mut()
+                .filter(|d| stage.contains(&d.servo_name))
+                .collect();
+            let homed = run_parallel(
+                devices,
+                self.max_parallel,
+                self.timeout,
+                |device, cancel| device.home_servo_with_cancel(cancel),
+            );
+            failed = homed.iter().any(|(_, result)| result.is_err());
+            results.extend(homed);
+        }
+
+        // Report in group order, whatever order they were homed in
+        results.sort_by_key(|(name, _)| names.iter().position(|n| n == name));
+        Ok(GroupOutcome { results })
+    }
+
+    fn run_all<F>(&mut self, f: F) -> GroupOutcome
+    where
+        F: Fn(&mut AppliedDevice, &CancelToken) -> Result<(), DeviceError> + Sync,
+    {
+        GroupOutcome {
+            results: self.run_each(f),
+        }
+    }
+
+    // Runs f against every servo, several at once, with results in group
+    // order.  f should give up when the token is cancelled, which happens
+    // once the operation timeout runs out.
+    pub fn run_each<R, F>(&mut self, f: F) -> Vec<(String, Result<R, DeviceError>)>
+    where
+        R: Send,
+        F: Fn(&mut AppliedDevice, &CancelToken) -> Result<R, DeviceError> + Sync,
+    {
+        run_parallel(
+            self.devices.iter_mut().collect(),
+            self.max_parallel,
+            self.timeout,
+            f,
+        )
+    }
+
+    // Reads status, alarms and position of every servo
+    pub fn poll_all(&mut self) -> Vec<(String, Result<StatusSnapshot, DeviceError>)> {
+        self.run_each(|device, _| {
+            device.refresh_snapshot()?;
+            Ok(device.last_snapshot())
+        })
+    }
+
+    // Reads the identity and usage of every drive, for asset records.  A
+    // drive that can't be read still gets an entry, with the error.
+    pub fn inventory(&mut self) -> InventoryReport {
+        let read = self.run_each(|device, _| Ok(inventory_entry(device)));
+        let entries = read
+            .into_iter()
+            .zip(self.devices.iter())
+            .map(|((_, entry), device)| {
+                entry.unwrap_or_else(|e| InventoryEntry {
+                    servo_name: device.servo_name.clone(),
+                    address: device.servo_address.clone(),
+                    model: None,
+                    serial_number: None,
+                    firmware: None,
+                    cycle_count: device.get_servo_cycle_count(),
+                    runtime_hours: device.persistent_state().runtime_hours,
+                    error: Some(e.to_string()),
+                })
+            })
+            .collect();
+        InventoryReport { entries }
+    }
+}
+
+// Runs f against each device on a pool of at most max_parallel threads (0
+// for one per device), cancelling any that run past the timeout by the
+// device's own clock and stopping the axis once f returns.  Results are in
+// the order the devices were given.
+fn run_parallel<R, F>(
+    devices: Vec<&mut AppliedDevice>,
+    max_parallel: usize,
+    timeout: Option<Duration>,
+    f: F,
+) -> Vec<(String, Result<R, DeviceError>)>
+where
+    R: Send,
+    F: Fn(&mut AppliedDevice, &CancelToken) -> Result<R, DeviceError> + Sync,
+{
+    let count = devices.len();
+    let workers = match max_parallel {
+        0 => count,
+        n => n.min(count),
+    };
+    let names: Vec<String> = devices.iter().map(|d| d.servo_name.clone()).collect();
+    // Taken from the back, so reversed to start in the order given
+    let queue = Mutex::new(devices.into_iter().enumerate().rev().collect::<Vec<_>>());
+    // When each running operation started, for the timeout
+    let running: Mutex<Vec<Option<RunningOperation>>> = Mutex::new(vec![None; count]);
+    let results: Mutex<Vec<Option<Result<R, DeviceError>>>> =
+        Mutex::new((0..count).map(|_| None).collect());
+    let remaining = AtomicUsize::new(count);
+    let f = &f;
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = match queue.lock() {
+                    Ok(mut q) => q.pop(),
+                    Err(_) => None,
+                };
+                let (index, device) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let span = device.log_span.clone();
+                let _enter = span.enter();
+                let cancel = CancelToken::new();
+                let clock = device.clock.clone();
+                if let Ok(mut r) = running.lock() {
+                    r[index] = Some((clock.clone(), clock.now(), cancel.clone()));
+                }
+                let outcome =
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(device, &cancel)));
+                let result = match outcome {
+                    Ok(_) if cancel.is_cancelled() => {
+                        // Whatever f left running is stopped
+                        if let Err(e) = device.stop_motion() {
+                            warn!("Unable to stop {}: {}", names[index], e);
+                        }
+                        Err(DeviceError::TimedOut(format!(
+                            "Operation on {} took longer than {:?}",
+                            names[index],
+                            timeout.unwrap_or_default()
+                        )))
+                    }
+                    Ok(result) => result,
+                    Err(_) => Err(DeviceError::WorkerStopped(format!(
+                        "Operation on {} panicked",
+                        names[index]
+                    ))),
+                };
+                if let Ok(mut r) = running.lock() {
+                    r[index] = None;
+                }
+                if let Ok(mut r) = results.lock() {
+                    r[index] = Some(result);
+                }
+                remaining.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        if let Some(timeout) = timeout {
+            while remaining.load(Ordering::SeqCst) > 0 {
+                if let Ok(r) = running.lock() {
+                    for (index, (clock, started, cancel)) in r
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(i, r)| r.as_ref().map(|r| (i, r)))
+                    {
+                        if clock.elapsed(*started) > timeout && !cancel.is_cancelled() {
+                            warn!("{} timed out after {:?}, cancelling", names[index], timeout);
+                            cancel.cancel();
+                        }
+                    }
+                }
+                thread::sleep(Duration::from_millis(TIMEOUT_POLL_INTERVAL));
+            }
+        }
+    });
+
+    let results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    names
+        .into_iter()
+        .zip(results)
+        .map(|(name, result)| {
+            let result = result.unwrap_or_else(|| {
+                Err(DeviceError::WorkerStopped(format!(
+                    "Operation on {} never ran",
+                    name
+                )))
+            });
+            (name, result)
+        })
+        .collect()
+}
+
+fn inventory_entry(device: &mut AppliedDevice) -> InventoryEntry {
+    let mut entry = InventoryEntry {
+        servo_name: device.servo_name.clone(),
+        address: device.servo_address.clone(),
+        model: None,
+        serial_number: None,
+        firmware: None,
+        cycle_count: device.get_servo_cycle_count(),
+        runtime_hours: device.persistent_state().runtime_hours,
+        error: None,
+    };
+    let identity = device.get_model().and_then(|model| {
+        Ok((
+            model,
+            device.get_serial_number()?,
+            device.get_firmware_version()?,
+        ))
+    });
+    match identity {
+        Ok((model, serial_number, firmware)) => {
+            entry.model = Some(model);
+            entry.serial_number = Some(serial_number);
+            entry.firmware = Some(firmware);
+        }
+        Err(e) => {
+            warn!("Unable to read identity of {}: {}", entry.servo_name, e);
+            entry.error = Some(e.to_string());
+        }
+    }
+    entry
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InventoryEntry {
+    pub servo_name: String,
+    pub address: String,
+    pub model: Option<u16>, // None if the drive couldn't be read
+    pub serial_number: Option<u32>,
+    pub firmware: Option<FirmwareVersion>,
+    pub cycle_count: i64,
+    pub runtime_hours: f64,
+    pub error: Option<String>, // Why the drive couldn't be read
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InventoryReport {
+    pub entries: Vec<InventoryEntry>,
+}
+
+impl InventoryReport {
+    // A JSON array with one object per drive
+    pub fn to_json(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| String::from("null"));
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "  {{\"servo_name\": {}, \"address\": {}, \"model\": {}, \"serial_number\": {}, \"firmware\": {}, \"cycle_count\": {}, \"runtime_hours\": {:.6}, \"error\": {}}}",
+                    json_string(&e.servo_name),
+                    json_string(&e.address),
+                    optional(e.model.map(|m| m.to_string())),
+                    optional(e.serial_number.map(|s| s.to_string())),
+                    optional(e.firmware.map(|f| json_string(&f.to_string()))),
+                    e.cycle_count,
+                    e.runtime_hours,
+                    optional(e.error.as_deref().map(json_string)),
+                )
+            })
+            .collect();
+        format!("[\n{}\n]\n", entries.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SimulatedDrive, MOTOR_ENABLED};
+
+    fn group(names: &[&str]) -> (AppliedDeviceGroup, Vec<SimulatedDrive>) {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let mut drives = Vec::new();
+        let mut devices = Vec::new();
+        for name in names {
+            let drive = SimulatedDrive::with_clock(clock.clone());
+            let mut device =
+                AppliedDevice::with_transport(name.to_string(), String::new(), drive.clone());
+            device.set_clock(clock.clone());
+            drives.push(drive);
+            devices.push(device);
+        }
+        (AppliedDeviceGroup::new(devices), drives)
+    }
+
+    #[test]
+    fn every_servo_is_worked_on_and_reported_in_group_order() {
+        let (mut group, _) = group(&["x", "y", "z"]);
+        group.set_max_parallel(2);
+        let outcome = group.enable_all();
+        assert!(outcome.is_ok(), "{:?}", outcome);
+        let names: Vec<&str> = outcome.results.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["x", "y", "z"]);
+        for device in group.devices() {
+            assert!(device.has_status(MOTOR_ENABLED).unwrap());
+        }
+    }
+
+    #[test]
+    fn stages_after_a_failed_one_are_not_homed() {
+        let (mut group, drives) = group(&["x", "y", "z"]);
+        group.enable_all();
+        drives[2].set_homing_time(Duration::from_secs(600));
+        drives[0].set_position(1234);
+        let order = HomingOrder::stages(vec![vec![String::from("z")]]);
+        let outcome = group.home_all(&order).unwrap();
+
+        assert!(outcome.results[2].1.is_err());
+        let failures: Vec<&str> = outcome.failures().iter().map(|(n, _)| *n).collect();
+        assert_eq!(failures, vec!["x", "y", "z"]);
+        assert_eq!(drives[0].position(), 1234);
+    }
+
+    #[test]
+    fn homing_orders_only_name_servos_in_the_group() {
+        let (mut group, _) = group(&["x"]);
+        let order = HomingOrder::stages(vec![vec![String::from("w")]]);
+        assert!(matches!(
+            group.home_all(&order),
+            Err(DeviceError::Config(_))
+        ));
+    }
+}
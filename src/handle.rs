@@ -0,0 +1,779 @@
+use crate::{
+    AppliedDevice, AutotuneReport, AxisVelocity, CancelToken, Clock, CrossingDirection,
+    DataLogConfig, DeviceError, DriveCommand, GearRatio, HomingReport, IdleCurrent,
+    InPositionWindow, InitialState, InitialStateReport, InputCondition, InterlockContext,
+    InterlockDecision, IoCounters, JogDirection, LengthFeed, LimitSearch, LimitState,
+    MaintenanceDue, MaskedSensorFeed, MonitorHandle, MotionControl, MoveMetrics, MoveOptions,
+    MoveRequest, MoveVelocitySample, OpcodeTable, PositionCrossing, Recipe, RecoveryPolicy,
+    RecoveryReport, RegisterValue, ScopeSettings, ScopeTrace, SelfTestReport, SensorFeed,
+    ServoGains, StallMode, TraceSample, VelocityMonitor, Waypoint,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+static IDLE_POLL_INTERVAL: u64 = 250; // How often an idle worker refreshes the status snapshot, in ms
+
+pub(crate) type Job = Box<dyn FnOnce(&mut AppliedDevice, &CancelToken) + Send>;
+
+// Calls sent to the worker but not yet finished, and when the last one
+// finished, so schedulers can tell whether anyone is using the servo
+#[derive(Debug)]
+struct Activity {
+    pending: usize,
+    last_finished: Instant,
+}
+
+// A cheap, cloneable handle to an AppliedDevice that is owned by a worker
+// thread.  Every call is sent to the worker over a channel and executed in
+// order, so an HMI thread and a sequence thread can share one servo without
+// holding a lock across blocking moves.  Urgent calls, such as an emergency
+// stop, go ahead of everything queued and are also run from inside the
+// waits of a call in progress.  Motion calls still queued when the servo is
+// e-stopped are dropped rather than started afterwards.
+#[derive(Clone)]
+pub struct AppliedDeviceHandle {
+    servo_name: String,
+    sender: Sender<Job>,
+    urgent: Sender<Job>,
+    abort: CancelToken,
+    estops: Arc<AtomicU64>, // How many emergency stops there have been
+    monitor: MonitorHandle,
+    motion: MotionControl,
+    activity: Arc<Mutex<Activity>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AppliedDevice {
+    // Moves this device onto its own worker thread and returns a handle to it.
+    // The worker exits once every handle has been dropped.
+    pub fn spawn(mut self) -> Result<AppliedDeviceHandle, DeviceError> {
+        let servo_name = self.servo_name.clone();
+        let (sender, receiver) = mpsc::channel();
+        let (urgent, urgent_receiver) = mpsc::channel();
+        self.urgent_jobs = Some(urgent_receiver);
+        let abort = CancelToken::new();
+        let worker_abort = abort.clone();
+        let monitor = self.monitor();
+        let motion = self.motion_control();
+        let clock = self.clock.clone();
+
+        thread::Builder::new()
+            .name(format!("applied-{}", servo_name))
+            .spawn(move || run_worker(self, receiver, worker_abort))
+            .map_err(|e| {
+                DeviceError::WorkerStopped(format!(
+                    "Unable to start the worker for {}: {}",
+                    servo_name, e
+                ))
+            })?;
+
+        Ok(AppliedDeviceHandle {
+            servo_name,
+            sender,
+            urgent,
+            abort,
+            estops: Arc::new(AtomicU64::new(0)),
+            monitor,
+            motion,
+            activity: Arc::new(Mutex::new(Activity {
+                pending: 0,
+                last_finished: clock.now(),
+            })),
+            clock,
+        })
+    }
+}
+
+// Marks a call finished when dropped, so one that panics (taking the worker
+// with it) doesn't leave the handle busy for good
+struct CallFinished {
+    activity: Arc<Mutex<Activity>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Drop for CallFinished {
+    fn drop(&mut self) {
+        let mut activity = lock_activity(&self.activity);
+        activity.pending -= 1;
+        activity.last_finished = self.clock.now();
+    }
+}
+
+impl AppliedDevice {
+    // Runs whatever urgent calls have been sent through a handle.  Called by
+    // the worker between calls and from the waits inside them.
+    pub(crate) fn run_urgent_jobs(&mut self, cancel: &CancelToken) {
+        // Taken out while they run, so an urgent call's own waits don't recurse
+        let jobs = match self.urgent_jobs.take() {
+            Some(jobs) => jobs,
+            None => return,
+        };
+        while let Ok(job) = jobs.try_recv() {
+            job(self, cancel);
+        }
+        self.urgent_jobs = Some(jobs);
+    }
+}
+
+fn lock_activity(activity: &Mutex<Activity>) -> MutexGuard<'_, Activity> {
+    match activity.lock() {
+        Ok(a) => a,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn run_worker(mut device: AppliedDevice, jobs: Receiver<Job>, abort: CancelToken) {
+    let idle_poll = Duration::from_millis(IDLE_POLL_INTERVAL);
+    loop {
+        // Taken again each time round, as a reconnect can change the address
+        let span = device.log_span.clone();
+        let _enter = span.enter();
+        // Wake often enough to keep data logs and position streams at their rates
+        let timeout = match device.sample_period() {
+            Some(period) => period.min(idle_poll),
+            None => idle_poll,
+        };
+        match jobs.recv_timeout(timeout) {
+            Ok(job) => {
+                device.run_urgent_jobs(&abort);
+                // An abort requested while this job was queued applies to it,
+                // so the token is only cleared once the job has seen it
+                job(&mut device, &abort);
+                abort.reset();
+                device.check_comms_watchdog();
+            }
+            // Keep the snapshot fresh for monitors while nobody is asking for anything
+            Err(RecvTimeoutError::Timeout) => {
+                device.run_urgent_jobs(&abort);
+                // Nothing was running or queued for an abort to stop
+                abort.reset();
+                if let Err(e) = device.refresh_snapshot() {
+                    warn!("Unable to refresh status of {}: {}", device.servo_name, e);
+                }
+                device.check_comms_watchdog();
+                if let Err(e) = device.sample_if_due() {
+                    warn!("Unable to sample {}: {}", device.servo_name, e);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    info!(
+        "All handles dropped, stopping worker for {}",
+        device.servo_name
+    );
+}
+
+impl AppliedDeviceHandle {
+    pub fn get_name(&self) -> &str {
+        &self.servo_name
+    }
+
+    // A read-only view of the cached state that never waits on the worker
+    pub fn monitor(&self) -> MonitorHandle {
+        self.monitor.clone()
+    }
+
+    // Runs the given closure against the device on the worker thread and
+    // waits for its result.  The closure is also handed the worker's abort
+    // token so long operations can be interrupted through abort().
+    pub fn call<R, F>(&self, f: F) -> Result<R, DeviceError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppliedDevice, &CancelToken) -> R + Send + 'static,
+    {
+        self.send_call(f, false)
+    }
+
+    // Like call, but runs ahead of any calls waiting on the worker and, if a
+    // call is in progress, from its next wait (within a few tens of ms of a
+    // homing or move wait).  The closure must not wait on the worker itself.
+    // Only stopping, holding and resuming are let through this way.
+    pub(crate) fn call_urgent<R, F>(&self, f: F) -> Result<R, DeviceError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppliedDevice, &CancelToken) -> R + Send + 'static,
+    {
+        self.send_call(f, true)
+    }
+
+    fn send_call<R, F>(&self, f: F, urgent: bool) -> Result<R, DeviceError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppliedDevice, &CancelToken) -> R + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let finished = CallFinished {
+            activity: self.activity.clone(),
+            clock: self.clock.clone(),
+        };
+        let job: Job = Box::new(move |device, abort| {
+            let result = f(device, abort);
+            drop(finished);
+            // The caller may have gone away; nothing to do about it here
+            let _ = reply_tx.send(result);
+        });
+
+        // A job that never runs is dropped, marking it finished all the same
+        self.activity().pending += 1;
+        if self.post(job, urgent).is_err() {
+            return Err(DeviceError::WorkerStopped(format!(
+                "Worker for {} is no longer running",
+                self.servo_name
+            )));
+        }
+
+        reply_rx.recv().map_err(|_| {
+            DeviceError::WorkerStopped(format!(
+                "Worker for {} stopped before replying",
+                self.servo_name
+            ))
+        })
+    }
+
+    // Urgent jobs come with an empty job on the ordinary channel, to wake an
+    // idle worker
+    fn post(&self, job: Job, urgent: bool) -> Result<(), mpsc::SendError<Job>> {
+        if !urgent {
+            return self.sender.send(job);
+        }
+        self.urgent.send(job)?;
+        self.sender.send(Box::new(|_, _| {}))
+    }
+
+    // Whether a call is running on the worker or waiting to
+    pub fn is_busy(&self) -> bool {
+        self.activity().pending > 0
+    }
+
+    // How long since the last call finished, or zero while one is running
+    pub fn idle_time(&self) -> Duration {
+        let activity = self.activity();
+        if activity.pending > 0 {
+            Duration::ZERO
+        } else {
+            self.clock.elapsed(activity.last_finished)
+        }
+    }
+
+    // The clock of the device behind the handle
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn activity(&self) -> MutexGuard<'_, Activity> {
+        lock_activity(&self.activity)
+    }
+
+    // Like call, for closures that can fail themselves
+    fn call_device<R, F>(&self, f: F) -> Result<R, DeviceError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppliedDevice, &CancelToken) -> Result<R, DeviceError> + Send + 'static,
+    {
+        self.call(f)?
+    }
+
+    // Like call_device, for calls that set the axis moving or undo an
+    // emergency stop, such as enabling the motor or running a raw command.
+    // One still waiting when emergency_stop is called fails instead of
+    // running.
+    fn call_motion<R, F>(&self, f: F) -> Result<R, DeviceError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut AppliedDevice, &CancelToken) -> Result<R, DeviceError> + Send + 'static,
+    {
+        let estops = self.estops.clone();
+        let queued_at = estops.load(Ordering::SeqCst);
+        self.call_device(move |device, abort| {
+            if estops.load(Ordering::SeqCst) != queued_at {
+                warn!(
+                    "Dropping a motion call queued before the emergency stop of {}",
+                    device.servo_name
+                );
+                return Err(DeviceError::EmergencyStopped(format!(
+                    "{} was stopped while the call was queued",
+                    device.servo_name
+                )));
+            }
+            f(device, abort)
+        })
+    }
+
+    // Cancels whatever long running operation (homing or a move) the worker is
+    // currently executing.  The axis is stopped by the operation itself.  An
+    // abort with nothing running or queued does nothing.
+    pub fn abort(&self) {
+        info!("Aborting current operation on {}", self.servo_name);
+        self.cancel_if_busy();
+    }
+
+    // Checked under the activity lock: the worker only clears the token after
+    // a call has marked itself finished, so a cancel made while the call was
+    // still pending is always cleared, and never left for the next call
+    fn cancel_if_busy(&self) {
+        let activity = self.activity();
+        if activity.pending > 0 {
+            self.abort.cancel();
+        }
+    }
+
+    // Sends the move in progress to a new position without waiting on the
+    // worker.  Returns false if no move is in progress.
+    pub fn retarget(&self, new_position: u64) -> bool {
+        self.motion.retarget(new_position)
+    }
+
+    // Takes effect on the move in progress, if any, and on later moves
+    pub fn set_velocity_override(&self, percent: u32) {
+        self.motion.set_velocity_override(percent)
+    }
+
+    // Pauses the move in progress, see MotionControl::feed_hold.  The hold
+    // is applied as an urgent call rather than at the move's next poll.
+    pub fn feed_hold(&self) -> bool {
+        if !self.motion.feed_hold() {
+            return false;
+        }
+        self.apply_urgently("hold");
+        true
+    }
+
+    // Stops the axis ahead of anything queued, aborting the operation in
+    // progress.  Motion calls already waiting fail with EmergencyStopped;
+    // anything else waiting, and every call made afterwards, runs as usual.
+    pub fn emergency_stop(&self) -> Result<(), DeviceError> {
+        warn!("Emergency stop of {}", self.servo_name);
+        self.estops.fetch_add(1, Ordering::SeqCst);
+        self.cancel_if_busy();
+        self.call_urgent(|device, _| device.stop_motion())?
+    }
+
+    // Continues a held move, applied urgently as feed_hold is
+    pub fn resume(&self) -> bool {
+        if !self.motion.resume() {
+            return false;
+        }
+        self.apply_urgently("resume");
+        true
+    }
+
+    fn apply_urgently(&self, what: &'static str) {
+        let apply: Job = Box::new(move |device, _| {
+            if let Err(e) = device.apply_motion_requests() {
+                warn!("Unable to {} {}: {}", what, device.servo_name, e);
+            }
+        });
+        let _ = self.post(apply, true);
+    }
+
+    pub fn home_servo(&self) -> Result<(), DeviceError> {
+        self.call_motion(|device, abort| device.home_servo_with_cancel(abort))
+    }
+
+    pub fn home_servo_with_report(&self) -> Result<HomingReport, DeviceError> {
+        self.call_motion(|device, abort| device.home_servo_with_report(abort))
+    }
+
+    pub fn move_servo(
+        &self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+    ) -> Result<(), DeviceError> {
+        self.call_motion(move |device, abort| {
+            device.move_servo_with_cancel(accel, decel, velocity, encoder_position, abort)
+        })
+    }
+
+    pub fn move_until_input(
+        &self,
+        input: u16,
+        condition: InputCondition,
+        velocity: u64,
+        accel: u64,
+        max_distance: i64,
+    ) -> Result<Option<u64>, DeviceError> {
+        self.move_until_input_then_offset(
+            input,
+            condition,
+            velocity,
+            accel,
+            max_distance.signum(),
+            max_distance.unsigned_abs(),
+        )
+    }
+
+    pub fn move_until_input_then_offset(
+        &self,
+        input: u16,
+        condition: InputCondition,
+        velocity: u64,
+        accel: u64,
+        offset: i64,
+        max_distance: u64,
+    ) -> Result<Option<u64>, DeviceError> {
+        let feed = SensorFeed {
+            input,
+            condition,
+            accel,
+            decel: accel,
+            velocity,
+            offset,
+            max_distance,
+        };
+        self.feed_to_sensor(feed)
+    }
+
+    pub fn feed_to_length(&self, feed: LengthFeed) -> Result<bool, DeviceError> {
+        self.call_motion(move |device, abort| device.feed_to_length_with_cancel(&feed, abort))
+    }
+
+    pub fn feed_to_sensor(&self, feed: SensorFeed) -> Result<Option<u64>, DeviceError> {
+        self.call_motion(move |device, abort| device.feed_to_sensor_with_cancel(&feed, abort))
+    }
+
+    pub fn feed_to_sensor_masked(
+        &self,
+        feed: MaskedSensorFeed,
+    ) -> Result<Option<u64>, DeviceError> {
+        self.call_motion(move |device, abort| {
+            device.feed_to_sensor_masked_with_cancel(&feed, abort)
+        })
+    }
+
+    pub fn start_following(&self, ratio: GearRatio) -> Result<(), DeviceError> {
+        self.call_motion(move |device, _| device.start_following(ratio))
+    }
+
+    pub fn stop_following(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.stop_following())
+    }
+
+    pub fn start_jog(&self, accel: u64, decel: u64, velocity: i16) -> Result<(), DeviceError> {
+        self.call_motion(move |device, _| device.start_jog(accel, decel, velocity))
+    }
+
+    pub fn change_jog_velocity(&self, velocity: i16) -> Result<bool, DeviceError> {
+        self.call_motion(move |device, _| device.change_jog_velocity(velocity))
+    }
+
+    pub fn stop_jog(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.stop_jog())
+    }
+
+    pub fn jog_to_limit(
+        &self,
+        direction: JogDirection,
+        velocity: u16,
+    ) -> Result<LimitSearch, DeviceError> {
+        self.call_motion(move |device, abort| {
+            device.jog_to_limit_with_cancel(direction, velocity, abort)
+        })
+    }
+
+    pub fn execute_move(&self, request: MoveRequest) -> Result<(), DeviceError> {
+        self.call_motion(move |device, abort| device.execute_move_with_cancel(&request, abort))
+    }
+
+    pub fn move_with(&self, options: MoveOptions) -> Result<(), DeviceError> {
+        self.call_motion(move |device, abort| device.move_with_cancel(&options, abort))
+    }
+
+    // Runs the whole trajectory on the worker.  abort stops it part way.
+    pub fn execute_trajectory(&self, waypoints: Vec<Waypoint>) -> Result<usize, DeviceError> {
+        self.call_motion(move |device, abort| {
+            device.execute_trajectory_with_cancel(waypoints, abort)
+        })
+    }
+
+    pub fn run_recipe(&self, recipe: Recipe) -> Result<usize, DeviceError> {
+        self.call_motion(move |device, abort| device.run_recipe_with_cancel(&recipe, abort))
+    }
+
+    pub fn set_output(&self, output: u16, high: bool) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.set_output(output, high))
+    }
+
+    pub fn get_input(&self, input: u16) -> Result<bool, DeviceError> {
+        self.call_device(move |device, _| device.get_input(input))
+    }
+
+    pub fn recover(
+        &self,
+        policy: RecoveryPolicy,
+        timeout: Duration,
+    ) -> Result<RecoveryReport, DeviceError> {
+        self.call_motion(move |device, _| device.recover(policy, timeout))
+    }
+
+    pub fn metrics(&self) -> Result<MoveMetrics, DeviceError> {
+        self.call(|device, _| device.metrics())
+    }
+
+    pub fn io_counters(&self) -> Result<IoCounters, DeviceError> {
+        self.call(|device, _| device.io_counters())
+    }
+
+    pub fn apply_initial_state(
+        &self,
+        state: InitialState,
+    ) -> Result<InitialStateReport, DeviceError> {
+        self.call_motion(move |device, _| Ok(device.apply_initial_state(&state)))
+    }
+
+    pub fn add_interlock<F>(&self, name: &str, check: F) -> Result<(), DeviceError>
+    where
+        F: Fn(&InterlockContext) -> InterlockDecision + Send + Sync + 'static,
+    {
+        let name = name.to_string();
+        self.call(move |device, _| device.add_interlock(&name, check))
+    }
+
+    pub fn remove_interlock(&self, name: &str) -> Result<bool, DeviceError> {
+        let name = name.to_string();
+        self.call(move |device, _| device.remove_interlock(&name))
+    }
+
+    pub fn arm_position_capture(
+        &self,
+        input: u16,
+        edge: InputCondition,
+    ) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.arm_position_capture(input, edge))
+    }
+
+    pub fn disarm_position_capture(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.disarm_position_capture())
+    }
+
+    pub fn get_captured_position(&self) -> Result<Option<u64>, DeviceError> {
+        self.call_device(|device, _| device.get_captured_position())
+    }
+
+    pub fn start_autotune(&self, timeout: Duration) -> Result<Option<AutotuneReport>, DeviceError> {
+        self.call_motion(move |device, abort| device.start_autotune_with_cancel(timeout, abort))
+    }
+
+    pub fn read_gains(&self) -> Result<ServoGains, DeviceError> {
+        self.call_device(|device, _| device.read_gains())
+    }
+
+    pub fn write_gains(&self, gains: ServoGains) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.write_gains(&gains))
+    }
+
+    pub fn self_test(&self, distance: u64) -> Result<SelfTestReport, DeviceError> {
+        self.call_motion(move |device, _| Ok(device.self_test(distance)))
+    }
+
+    pub fn dump_registers(&self) -> Result<Vec<(u16, u16)>, DeviceError> {
+        self.call_device(|device, _| device.dump_registers())
+    }
+
+    pub fn dump_register_range(
+        &self,
+        first: u16,
+        last: u16,
+    ) -> Result<Vec<RegisterValue>, DeviceError> {
+        self.call_device(move |device, _| device.dump_register_range(first, last))
+    }
+
+    pub fn read_u32(&self, register: u16) -> Result<u32, DeviceError> {
+        self.call_device(move |device, _| device.read_u32(register))
+    }
+
+    pub fn read_i32(&self, register: u16) -> Result<i32, DeviceError> {
+        self.call_device(move |device, _| device.read_i32(register))
+    }
+
+    pub fn write_u32(&self, register: u16, value: u32) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.write_u32(register, value))
+    }
+
+    pub fn write_i32(&self, register: u16, value: i32) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.write_i32(register, value))
+    }
+
+    pub fn report(&self) -> Result<String, DeviceError> {
+        self.call_device(|device, _| Ok(device.report()))
+    }
+
+    pub fn identify(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.identify())
+    }
+
+    pub fn execute(&self, command: DriveCommand) -> Result<(), DeviceError> {
+        self.call_motion(move |device, _| device.execute(command))
+    }
+
+    pub fn execute_named(&self, name: &str) -> Result<(), DeviceError> {
+        let name = name.to_string();
+        self.call_motion(move |device, _| device.execute_named(&name))
+    }
+
+    pub fn set_opcode_table(&self, table: &OpcodeTable) -> Result<(), DeviceError> {
+        let table = table.clone();
+        self.call_device(move |device, _| device.set_opcode_table(&table))
+    }
+
+    pub fn stop_motion(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.stop_motion())
+    }
+
+    pub fn enable_motor(&self) -> Result<(), DeviceError> {
+        self.call_motion(|device, _| device.enable_motor())
+    }
+
+    pub fn disable_motor(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.disable_motor())
+    }
+
+    pub fn reset_alarm_or_fault(&self) -> Result<(), DeviceError> {
+        self.call_motion(|device, _| device.reset_alarm_or_fault())
+    }
+
+    pub fn get_servo_status(&self) -> Result<Vec<String>, DeviceError> {
+        self.call_device(|device, _| device.get_servo_status().cloned())
+    }
+
+    pub fn get_servo_alarms(&self) -> Result<Vec<String>, DeviceError> {
+        self.call_device(|device, _| device.get_servo_alarms().cloned())
+    }
+
+    pub fn limit_switches(&self) -> Result<LimitState, DeviceError> {
+        self.call_device(|device, _| device.limit_switches())
+    }
+
+    pub fn set_input_filter(&self, input: u16, filter: Duration) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.set_input_filter(input, filter))
+    }
+
+    pub fn set_in_position_window(&self, window: InPositionWindow) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.set_in_position_window(window))
+    }
+
+    pub fn get_following_error(&self) -> Result<i64, DeviceError> {
+        self.call_device(|device, _| device.get_following_error())
+    }
+
+    pub fn current_velocity(&self) -> Result<AxisVelocity, DeviceError> {
+        self.call_device(|device, _| device.current_velocity())
+    }
+
+    pub fn get_motor_current(&self) -> Result<f64, DeviceError> {
+        self.call_device(|device, _| device.get_motor_current())
+    }
+
+    pub fn arm_scope(&self, settings: ScopeSettings) -> Result<(), DeviceError> {
+        self.call(move |device, _| device.arm_scope(settings))
+    }
+
+    pub fn take_scope_trace(&self) -> Result<Option<ScopeTrace>, DeviceError> {
+        self.call(|device, _| device.take_scope_trace())
+    }
+
+    pub fn capture_scope(
+        &self,
+        period: Duration,
+        duration: Duration,
+    ) -> Result<ScopeTrace, DeviceError> {
+        self.call_device(move |device, abort| device.capture_scope(period, duration, abort))
+    }
+
+    pub fn set_stall_detection(
+        &self,
+        mode: StallMode,
+        sensitivity: u16,
+    ) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.set_stall_detection(mode, sensitivity))
+    }
+
+    pub fn set_idle_current(&self, idle: IdleCurrent) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.set_idle_current(idle))
+    }
+
+    pub fn get_encoder_count(&self) -> Result<u64, DeviceError> {
+        self.call_device(|device, _| device.get_encoder_count())
+    }
+
+    pub fn get_servo_cycle_count(&self) -> Result<i64, DeviceError> {
+        self.call(|device, _| device.get_servo_cycle_count())
+    }
+
+    pub fn maintenance_due(&self) -> Result<Vec<MaintenanceDue>, DeviceError> {
+        self.call(|device, _| device.maintenance_due())
+    }
+
+    pub fn mark_serviced(&self, task: &str) -> Result<(), DeviceError> {
+        let task = task.to_string();
+        self.call_device(move |device, _| device.mark_serviced(&task))
+    }
+
+    pub fn motion_trace(&self) -> Result<Vec<TraceSample>, DeviceError> {
+        self.call(|device, _| device.motion_trace())
+    }
+
+    pub fn start_data_log(&self, config: DataLogConfig) -> Result<(), DeviceError> {
+        self.call_device(move |device, _| device.start_data_log(config))
+    }
+
+    pub fn stop_data_log(&self) -> Result<(), DeviceError> {
+        self.call(|device, _| device.stop_data_log())
+    }
+
+    // Positions sampled by the worker at (roughly) the given period, see
+    // AppliedDevice::stream_position
+    pub fn stream_position(
+        &self,
+        period: Duration,
+    ) -> Result<Receiver<(Instant, i64)>, DeviceError> {
+        self.call(move |device, _| device.stream_position(period))
+    }
+
+    pub fn monitor_move_velocity(
+        &self,
+        monitor: Option<VelocityMonitor>,
+    ) -> Result<(), DeviceError> {
+        self.call(move |device, _| device.monitor_move_velocity(monitor))
+    }
+
+    // See AppliedDevice::stream_move_velocity
+    pub fn stream_move_velocity(&self) -> Result<Receiver<MoveVelocitySample>, DeviceError> {
+        self.call(|device, _| device.stream_move_velocity())
+    }
+
+    // Velocities sampled by the worker, see AppliedDevice::stream_velocity
+    pub fn stream_velocity(
+        &self,
+        period: Duration,
+    ) -> Result<Receiver<(Instant, f64)>, DeviceError> {
+        self.call(move |device, _| device.stream_velocity(period))
+    }
+
+    // Crossings found by the worker, see AppliedDevice::watch_position
+    pub fn watch_position(
+        &self,
+        threshold: i64,
+        direction: CrossingDirection,
+    ) -> Result<Receiver<PositionCrossing>, DeviceError> {
+        self.call(move |device, _| device.watch_position(threshold, direction))
+    }
+
+    pub fn watch_position_units(
+        &self,
+        threshold: f64,
+        direction: CrossingDirection,
+    ) -> Result<Receiver<PositionCrossing>, DeviceError> {
+        self.call_device(move |device, _| device.watch_position_units(threshold, direction))
+    }
+
+    pub fn shutdown(&self) -> Result<(), DeviceError> {
+        self.call_device(|device, _| device.shutdown())
+    }
+}
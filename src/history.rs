@@ -0,0 +1,134 @@
+use crate::AppliedDevice;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime};
+
+static DEFAULT_HISTORY_CAPACITY: usize = 100;
+
+// What the axis was asked to do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Home,
+    Move {
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        target: u64,
+    },
+    FeedToLength {
+        velocity: u64,
+        distance: i64,
+    },
+    FeedToSensor {
+        input: u16,
+        velocity: u64,
+        offset: i64,
+        max_distance: u64,
+    },
+    MaskedFeedToSensor {
+        input: u16,
+        velocity: u64,
+        offset: i64,
+        mask_distance: u64,
+    },
+    StartFollowing {
+        numerator: i16,
+        denominator: u16,
+    },
+    StartJog {
+        velocity: i64,
+    },
+    Autotune,
+    ResetAlarm,
+    EnableMotor,
+    DisableMotor,
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OperationOutcome {
+    Completed,
+    Incomplete,     // Timed out, was cancelled or missed its target
+    Failed(String), // Returned an error
+}
+
+#[derive(Clone, Debug)]
+pub struct OperationRecord {
+    pub operation: Operation,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub outcome: OperationOutcome,
+}
+
+// A fixed size ring buffer of the most recent operations
+pub(crate) struct OperationHistory {
+    records: VecDeque<OperationRecord>,
+    capacity: usize,
+}
+
+impl Default for OperationHistory {
+    fn default() -> OperationHistory {
+        OperationHistory {
+            records: VecDeque::with_capacity(DEFAULT_HISTORY_CAPACITY),
+            capacity: DEFAULT_HISTORY_CAPACITY,
+        }
+    }
+}
+
+impl OperationHistory {
+    fn push(&mut self, record: OperationRecord) {
+        while self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+}
+
+impl AppliedDevice {
+    // The last n operations, oldest first
+    pub fn recent_operations(&self, n: usize) -> Vec<OperationRecord> {
+        let records = &self.history.records;
+        records
+            .iter()
+            .skip(records.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    // How many operations are remembered (100 by default, at least 1)
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        let capacity = capacity.max(1);
+        self.history.capacity = capacity;
+        while self.history.records.len() > capacity {
+            self.history.records.pop_front();
+        }
+    }
+
+    // started is from the device clock, and the record's start time is the
+    // same clock's time of day, so a simulated clock gives simulated times
+    pub(crate) fn record_operation(
+        &mut self,
+        operation: Operation,
+        started: Instant,
+        outcome: OperationOutcome,
+    ) {
+        let duration = self.clock.elapsed(started);
+        let now = self.clock.wall_time();
+        // A clock whose time of day can't go back that far gives the end
+        let wall_start = now.checked_sub(duration).unwrap_or(now);
+        self.history.push(OperationRecord {
+            operation,
+            started: wall_start,
+            duration,
+            outcome,
+        });
+    }
+}
+
+// Maps the result of an operation that reports whether it completed
+pub(crate) fn outcome_of<E: std::fmt::Display>(result: &Result<bool, E>) -> OperationOutcome {
+    match result {
+        Ok(true) => OperationOutcome::Completed,
+        Ok(false) => OperationOutcome::Incomplete,
+        Err(e) => OperationOutcome::Failed(e.to_string()),
+    }
+}
@@ -0,0 +1,394 @@
+use crate::config::millis;
+use crate::{AppliedDevice, CancelToken, DeviceError, MoveOptions, ALARM, HOMING};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use yaml_rust::Yaml;
+
+static DEFAULT_HOMING_RETRIES: u32 = 3;
+static HOMING_POLL_INTERVAL: u64 = 300; // In ms
+
+// What to do when the drive alarms part way through homing
+//
+//   homing:
+//     retries: 3             # further attempts after an alarm
+//     retry_delay_ms: 1000   # pause after resetting the alarm
+//     reset_alarms: true     # false gives up at the first alarm
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomingRetryPolicy {
+    pub retries: u32,
+    pub retry_delay: Duration,
+    pub reset_alarms: bool,
+}
+
+impl Default for HomingRetryPolicy {
+    fn default() -> HomingRetryPolicy {
+        HomingRetryPolicy {
+            retries: DEFAULT_HOMING_RETRIES,
+            retry_delay: Duration::ZERO,
+            reset_alarms: true,
+        }
+    }
+}
+
+impl HomingRetryPolicy {
+    pub(crate) fn from_yaml(homing: &Yaml) -> Result<HomingRetryPolicy, DeviceError> {
+        let defaults = HomingRetryPolicy::default();
+        Ok(HomingRetryPolicy {
+            retries: match &homing["retries"] {
+                Yaml::BadValue | Yaml::Null => defaults.retries,
+                Yaml::Integer(n) if (0..=u32::MAX as i64).contains(n) => *n as u32,
+                other => {
+                    return Err(DeviceError::Config(format!(
+                        "homing.retries must be a number of attempts, got {:?}",
+                        other
+                    )))
+                }
+            },
+            retry_delay: millis(homing, "retry_delay_ms")?.unwrap_or(defaults.retry_delay),
+            reset_alarms: match &homing["reset_alarms"] {
+                Yaml::BadValue | Yaml::Null => defaults.reset_alarms,
+                Yaml::Boolean(reset) => *reset,
+                other => {
+                    return Err(DeviceError::Config(format!(
+                        "homing.reset_alarms must be true or false, got {:?}",
+                        other
+                    )))
+                }
+            },
+        })
+    }
+}
+
+// Where the axis goes once homed, e.g. a fixture's standby position
+//
+//   homing:
+//     offset: 5000           # encoder count to move to once homed
+//     zero_at_offset: true   # then make that position 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HomingOffset {
+    pub position: u64,
+    pub zero_at_offset: bool,
+}
+
+impl HomingOffset {
+    pub(crate) fn from_yaml(homing: &Yaml) -> Result<Option<HomingOffset>, DeviceError> {
+        let position = match &homing["offset"] {
+            Yaml::BadValue | Yaml::Null => None,
+            Yaml::Integer(n) if (i32::MIN as i64..=i32::MAX as i64).contains(n) => {
+                Some(*n as i32 as u32 as u64)
+            }
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "homing.offset must be an encoder count, got {:?}",
+                    other
+                )))
+            }
+        };
+        let zero_at_offset = match &homing["zero_at_offset"] {
+            Yaml::BadValue | Yaml::Null => false,
+            Yaml::Boolean(zero) => *zero,
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "homing.zero_at_offset must be true or false, got {:?}",
+                    other
+                )))
+            }
+        };
+        match position {
+            Some(position) => Ok(Some(HomingOffset {
+                position,
+                zero_at_offset,
+            })),
+            None if zero_at_offset => Err(DeviceError::Config(String::from(
+                "homing.zero_at_offset needs a homing.offset",
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HomingAttemptOutcome {
+    Completed,
+    Alarm(Vec<String>), // The drive's alarms when homing was interrupted
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomingAttempt {
+    pub duration: Duration,
+    pub outcome: HomingAttemptOutcome,
+}
+
+// How a homing went, one entry per time the drive was told to home
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HomingReport {
+    pub completed: bool, // Including any move to the homing offset
+    pub attempts: Vec<HomingAttempt>,
+    pub offset_position: Option<u64>, // Where the offset move ended, before any rezeroing
+}
+
+impl HomingReport {
+    // Why the axis isn't homed, None if it is
+    pub fn failure(&self) -> Option<String> {
+        if self.completed {
+            return None;
+        }
+        let reason = match self.attempts.last().map(|a| &a.outcome) {
+            Some(HomingAttemptOutcome::Alarm(alarms)) => format!("alarm {}", alarms.join(", ")),
+            Some(HomingAttemptOutcome::TimedOut) => String::from("timed out"),
+            Some(HomingAttemptOutcome::Cancelled) => String::from("cancelled"),
+            Some(HomingAttemptOutcome::Completed) => {
+                String::from("did not reach its homing offset")
+            }
+            None => String::from("never started"),
+        };
+        Some(reason)
+    }
+}
+
+impl AppliedDevice {
+    // Turns a homing that didn't complete into an error, for callers that
+    // can't carry on with an unhomed axis
+    pub(crate) fn require_homed(&self, report: HomingReport) -> Result<HomingReport, DeviceError> {
+        match report.failure() {
+            Some(reason) => Err(DeviceError::HomingFailed(format!(
+                "{} is not homed: {}",
+                self.servo_name, reason
+            ))),
+            None => Ok(report),
+        }
+    }
+
+    // Waits for homing started with start_homing to finish, restarting it
+    // after an alarm as the motion defaults' homing retry policy allows
+    pub(crate) fn wait_for_homing_attempts(
+        &mut self,
+        cancel: &CancelToken,
+    ) -> Result<HomingReport, DeviceError> {
+        let policy = self.motion_defaults.homing_retry.clone();
+        let began = self.clock.now();
+        let mut report = HomingReport::default();
+        loop {
+            let attempt_started = self.clock.now();
+            let outcome = self.wait_for_homing_attempt(began, cancel)?;
+            report.attempts.push(HomingAttempt {
+                duration: self.clock.elapsed(attempt_started),
+                outcome: outcome.clone(),
+            });
+            match outcome {
+                HomingAttemptOutcome::Completed => {
+                    report.completed = true;
+                    return Ok(report);
+                }
+                HomingAttemptOutcome::Alarm(_)
+                    if policy.reset_alarms && report.attempts.len() <= policy.retries as usize =>
+                {
+                    warn!("Got alarm during homing.  Trying to reset.");
+                    self.reset_alarm_or_fault()?;
+                    let delay_started = self.clock.now();
+                    if !self.sleep_unless_cancelled(policy.retry_delay, cancel) {
+                        warn!("Homing of servo {} was cancelled", self.servo_name);
+                        // Or the report would put the failure down to the alarm
+                        report.attempts.push(HomingAttempt {
+                            duration: self.clock.elapsed(delay_started),
+                            outcome: HomingAttemptOutcome::Cancelled,
+                        });
+                        return Ok(report);
+                    }
+                    warn!(
+                        "Restarting homing procedure, attempt {} of {}",
+                        report.attempts.len() + 1,
+                        policy.retries + 1
+                    );
+                    self.start_homing()?;
+                }
+                HomingAttemptOutcome::Alarm(alarms) => {
+                    warn!(
+                        "!!Homing of servo {} stopped by alarm: {:?}!!",
+                        self.servo_name, alarms
+                    );
+                    return Ok(report);
+                }
+                HomingAttemptOutcome::TimedOut | HomingAttemptOutcome::Cancelled => {
+                    return Ok(report)
+                }
+            }
+        }
+    }
+
+    // Moves to the offset once homed, then makes it zero if asked to
+    pub(crate) fn move_to_homing_offset(
+        &mut self,
+        offset: HomingOffset,
+        cancel: &CancelToken,
+        report: &mut HomingReport,
+    ) -> Result<(), DeviceError> {
+        info!(
+            "Moving {} to its homing offset of {}",
+            self.servo_name, offset.position
+        );
+        let request = self.resolve_move(&MoveOptions::to(offset.position))?;
+        let arrived = self.perform_move(&request, cancel)?;
+        report.offset_position = Some(self.get_encoder_count()?);
+        report.completed = arrived;
+        if !arrived {
+            warn!(
+                "{} did not reach its homing offset of {}",
+                self.servo_name, offset.position
+            );
+            return Ok(());
+        }
+        if offset.zero_at_offset {
+            self.set_encoder_position(0)?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_homing_attempt(
+        &mut self,
+        began: Instant,
+        cancel: &CancelToken,
+    ) -> Result<HomingAttemptOutcome, DeviceError> {
+        while self.has_status(HOMING)? {
+            info!("Servo status: {:?}", self.servo_status);
+            if cancel.is_cancelled() {
+                warn!("Homing of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(HomingAttemptOutcome::Cancelled);
+            }
+            if self.has_status(ALARM)? {
+                let alarms = self.get_servo_alarms()?.clone();
+                return Ok(HomingAttemptOutcome::Alarm(alarms));
+            }
+            // We will wait until max homing allowed time
+            if self.clock.elapsed(began) > self.motion_defaults.homing_timeout {
+                warn!("!!Unable to finish homing procedure!!");
+                self.stop_motion()?;
+                return Ok(HomingAttemptOutcome::TimedOut);
+            }
+            let poll = Duration::from_millis(HOMING_POLL_INTERVAL);
+            if !self.sleep_and_sample(poll, cancel)? {
+                warn!("Homing of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(HomingAttemptOutcome::Cancelled);
+            }
+        }
+        // An alarm can end homing, clearing HOMING before it was ever seen
+        if self.has_status(ALARM)? {
+            let alarms = self.get_servo_alarms()?.clone();
+            return Ok(HomingAttemptOutcome::Alarm(alarms));
+        }
+        Ok(HomingAttemptOutcome::Completed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, DriveCommand, ManualClock, SimulatedDrive, Transport, EXECUTE_COMMAND};
+    use std::sync::Arc;
+    use yaml_rust::YamlLoader;
+
+    static HOMING_ALARM: u16 = 0x0004;
+
+    // Raises an alarm on the first status poll of every homing
+    struct AlarmsEveryHoming {
+        drive: SimulatedDrive,
+        homing: bool,
+    }
+
+    impl Transport for AlarmsEveryHoming {
+        fn read_holding_registers(
+            &mut self,
+            address: u16,
+            quantity: u16,
+        ) -> modbus::Result<Vec<u16>> {
+            if self.homing {
+                self.drive.inject_alarm(HOMING_ALARM);
+                self.homing = false;
+            }
+            self.drive.read_holding_registers(address, quantity)
+        }
+
+        fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+            if address == EXECUTE_COMMAND && value == DriveCommand::StartHoming.opcode() {
+                self.homing = true;
+            }
+            self.drive.write_single_register(address, value)
+        }
+    }
+
+    fn alarming_device(policy: HomingRetryPolicy) -> (AppliedDevice, ManualClock) {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let transport = AlarmsEveryHoming {
+            drive: SimulatedDrive::with_clock(shared.clone()),
+            homing: false,
+        };
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), transport);
+        device.set_clock(shared);
+        device.motion_defaults.homing_retry = policy;
+        device.enable_motor().unwrap();
+        (device, clock)
+    }
+
+    #[test]
+    fn homing_is_given_up_once_the_retries_run_out() {
+        let (mut device, _) = alarming_device(HomingRetryPolicy {
+            retries: 2,
+            ..HomingRetryPolicy::default()
+        });
+        let report = device.home_servo_with_report(&CancelToken::new()).unwrap();
+        assert!(!report.completed);
+        assert_eq!(report.attempts.len(), 3);
+        assert!(report
+            .attempts
+            .iter()
+            .all(|a| matches!(a.outcome, HomingAttemptOutcome::Alarm(_))));
+        assert!(matches!(
+            device.home_servo(),
+            Err(DeviceError::HomingFailed(_))
+        ));
+    }
+
+    #[test]
+    fn alarms_are_not_reset_unless_the_policy_says_so() {
+        let (mut device, _) = alarming_device(HomingRetryPolicy {
+            reset_alarms: false,
+            ..HomingRetryPolicy::default()
+        });
+        let report = device.home_servo_with_report(&CancelToken::new()).unwrap();
+        assert_eq!(report.attempts.len(), 1);
+    }
+
+    #[test]
+    fn retries_wait_for_the_retry_delay() {
+        let (mut device, clock) = alarming_device(HomingRetryPolicy {
+            retries: 1,
+            retry_delay: Duration::from_secs(5),
+            reset_alarms: true,
+        });
+        let started = clock.now();
+        let report = device.home_servo_with_report(&CancelToken::new()).unwrap();
+        assert_eq!(report.attempts.len(), 2);
+        assert!(clock.elapsed(started) >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retry_policy_settings_not_given_keep_their_defaults() {
+        let homing = &YamlLoader::load_from_str("retries: 0\nretry_delay_ms: 250\n").unwrap()[0];
+        let policy = HomingRetryPolicy::from_yaml(homing).unwrap();
+        assert_eq!(
+            policy,
+            HomingRetryPolicy {
+                retries: 0,
+                retry_delay: Duration::from_millis(250),
+                reset_alarms: true,
+            }
+        );
+        let homing = &YamlLoader::load_from_str("retries: -1\n").unwrap()[0];
+        assert!(HomingRetryPolicy::from_yaml(homing).is_err());
+    }
+}
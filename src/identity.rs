@@ -0,0 +1,182 @@
+use crate::{AppliedDevice, DeviceError, DriveCommand, MappedRegister};
+use std::fmt;
+use tracing::{error, info, warn};
+use yaml_rust::Yaml;
+
+impl AppliedDevice {
+    // Makes the drive flash its status LED so it can be found among others
+    // in a cabinet.  Has no effect on motion.
+    pub fn identify(&mut self) -> Result<(), DeviceError> {
+        info!(
+            "Flashing the status LED of {} at {}",
+            self.servo_name, self.servo_address
+        );
+        self.execute(DriveCommand::Identify)
+    }
+}
+
+// A drive firmware revision, e.g. 1.07
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl FirmwareVersion {
+    // The drive reports the major revision in the high byte
+    pub(crate) fn from_register(value: u16) -> FirmwareVersion {
+        FirmwareVersion {
+            major: (value >> 8) as u8,
+            minor: (value & 0xff) as u8,
+        }
+    }
+
+    // Parses "1.07" or "1.7" style versions, as used in device configs
+    pub fn parse(version: &str) -> Option<FirmwareVersion> {
+        let (major, minor) = version.trim().split_once('.')?;
+        Some(FirmwareVersion {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}", self.major, self.minor)
+    }
+}
+
+// The firmware revisions a device is known to work with.  A drive outside
+// the range is either refused or reported through
+// TelemetrySink::on_firmware_mismatch, depending on `refuse`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FirmwareRange {
+    pub min: Option<FirmwareVersion>,
+    pub max: Option<FirmwareVersion>,
+    pub refuse: bool,
+}
+
+impl FirmwareRange {
+    pub fn contains(&self, version: FirmwareVersion) -> bool {
+        self.min.is_none_or(|min| version >= min) && self.max.is_none_or(|max| version <= max)
+    }
+
+    // Reads the optional `firmware:` section of a device config
+    //
+    //   firmware:
+    //     min: "1.05"
+    //     max: "1.20"
+    //     on_mismatch: error    # or warn, the default
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<FirmwareRange>, DeviceError> {
+        let section = &device_conf["firmware"];
+        match section {
+            Yaml::Hash(_) => {}
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "firmware must be a map, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        let refuse = match section["on_mismatch"].as_str() {
+            None | Some("warn") => false,
+            Some("error") => true,
+            Some(other) => {
+                return Err(DeviceError::Config(format!(
+                    "firmware.on_mismatch must be warn or error, got {}",
+                    other
+                )))
+            }
+        };
+        Ok(Some(FirmwareRange {
+            min: firmware_version(section, "min")?,
+            max: firmware_version(section, "max")?,
+            refuse,
+        }))
+    }
+}
+
+fn firmware_version(section: &Yaml, key: &str) -> Result<Option<FirmwareVersion>, DeviceError> {
+    let version = match &section[key] {
+        Yaml::BadValue | Yaml::Null => return Ok(None),
+        Yaml::String(s) => FirmwareVersion::parse(s),
+        Yaml::Real(s) => FirmwareVersion::parse(s),
+        _ => None,
+    };
+    match version {
+        Some(v) => Ok(Some(v)),
+        None => Err(DeviceError::Config(format!(
+            "firmware.{} must be a version such as \"1.07\", got {:?}",
+            key, section[key]
+        ))),
+    }
+}
+
+// Reported when a drive's firmware is outside the configured range
+#[derive(Clone, Debug, PartialEq)]
+pub struct FirmwareMismatch {
+    pub servo_name: String,
+    pub found: FirmwareVersion,
+    pub range: FirmwareRange,
+}
+
+impl AppliedDevice {
+    // The drive's model as its numeric code
+    pub fn get_model(&mut self) -> Result<u16, DeviceError> {
+        Ok(self.get_register_value(self.mapped(MappedRegister::ModelCode)?)? as u16)
+    }
+
+    pub fn get_serial_number(&mut self) -> Result<u32, DeviceError> {
+        let words = self.read_registers(self.mapped(MappedRegister::SerialNumber)?, 2)?;
+        Ok(words
+            .iter()
+            .fold(0, |serial, word| (serial << 16) | *word as u32))
+    }
+
+    pub fn get_firmware_version(&mut self) -> Result<FirmwareVersion, DeviceError> {
+        let value = self.get_register_value(self.mapped(MappedRegister::FirmwareRevision)?)? as u16;
+        Ok(FirmwareVersion::from_register(value))
+    }
+
+    // Reads the firmware revision and compares it with the given range
+    pub fn check_firmware(
+        &mut self,
+        range: &FirmwareRange,
+    ) -> Result<FirmwareVersion, DeviceError> {
+        let found = self.get_firmware_version()?;
+        if range.contains(found) {
+            info!("{} has firmware {}", self.servo_name, found);
+            return Ok(found);
+        }
+
+        let allowed = format!(
+            "{} to {}",
+            range.min.map_or(String::from("any"), |v| v.to_string()),
+            range.max.map_or(String::from("any"), |v| v.to_string())
+        );
+        if range.refuse {
+            error!(
+                "{} has firmware {}, outside the allowed {}",
+                self.servo_name, found, allowed
+            );
+            return Err(DeviceError::Firmware(format!(
+                "{} has firmware {}, allowed {}",
+                self.servo_name, found, allowed
+            )));
+        }
+        warn!(
+            "{} has firmware {}, outside the expected {}",
+            self.servo_name, found, allowed
+        );
+        let mismatch = FirmwareMismatch {
+            servo_name: self.servo_name.clone(),
+            found,
+            range: *range,
+        };
+        self.emit(|t| t.on_firmware_mismatch(&mismatch));
+        Ok(found)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::millis;
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use std::time::Duration;
+use tracing::info;
+use yaml_rust::Yaml;
+
+static MAX_IDLE_CURRENT_DELAY: u64 = 10000; // In ms
+
+// Current reduction once the motor has been still for `delay`, to keep
+// steppers from overheating while holding position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdleCurrent {
+    pub percent: u8, // Of the running current
+    pub delay: Duration,
+}
+
+impl IdleCurrent {
+    // Reads the optional `idle_current:` section of a device config
+    //
+    //   idle_current:
+    //     percent: 50
+    //     delay_ms: 500
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<IdleCurrent>, DeviceError> {
+        let section = &device_conf["idle_current"];
+        if section.is_badvalue() || section.is_null() {
+            return Ok(None);
+        }
+        let percent = match &section["percent"] {
+            Yaml::Integer(p) if (0..=100).contains(p) => *p as u8,
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "idle_current.percent must be from 0 to 100, got {:?}",
+                    other
+                )))
+            }
+        };
+        let delay = millis(section, "delay_ms")?.unwrap_or_default();
+        Ok(Some(IdleCurrent { percent, delay }))
+    }
+}
+
+impl AppliedDevice {
+    pub fn set_idle_current(&mut self, idle: IdleCurrent) -> Result<(), DeviceError> {
+        let ms = idle.delay.as_millis() as u64;
+        if idle.percent > 100 || ms > MAX_IDLE_CURRENT_DELAY {
+            return Err(DeviceError::Config(format!(
+                "Idle current must be at most 100% after at most {}ms, got {}% after {}ms",
+                MAX_IDLE_CURRENT_DELAY, idle.percent, ms
+            )));
+        }
+
+        info!(
+            "Setting idle current of {} to {}% after {}ms",
+            self.servo_name, idle.percent, ms
+        );
+        self.write_register(
+            self.mapped(MappedRegister::IdleCurrentPercent)?,
+            idle.percent as u64,
+        )?;
+        self.write_register(self.mapped(MappedRegister::IdleCurrentDelay)?, ms)?;
+        self.volatile.idle_current = Some(idle);
+        Ok(())
+    }
+
+    pub fn get_idle_current(&mut self) -> Result<IdleCurrent, DeviceError> {
+        Ok(IdleCurrent {
+            percent: self.get_register_value(self.mapped(MappedRegister::IdleCurrentPercent)?)?
+                as u8,
+            delay: Duration::from_millis(
+                self.get_register_value(self.mapped(MappedRegister::IdleCurrentDelay)?)?,
+            ),
+        })
+    }
+}
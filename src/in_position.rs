@@ -0,0 +1,46 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use std::time::Duration;
+use tracing::info;
+
+static MAX_IN_POSITION_TIME: u64 = 65535; // In ms
+
+// When the drive sets In Position after a move: once the position error
+// has stayed within `counts` of the target for `time`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InPositionWindow {
+    pub counts: u16,
+    pub time: Duration,
+}
+
+impl AppliedDevice {
+    pub fn set_in_position_window(&mut self, window: InPositionWindow) -> Result<(), DeviceError> {
+        let ms = window.time.as_millis() as u64;
+        if ms > MAX_IN_POSITION_TIME {
+            return Err(DeviceError::Config(format!(
+                "In position time of {}ms is longer than the drive allows ({}ms)",
+                ms, MAX_IN_POSITION_TIME
+            )));
+        }
+
+        info!(
+            "Setting in position window of {} to {} counts for {}ms",
+            self.servo_name, window.counts, ms
+        );
+        self.write_register(
+            self.mapped(MappedRegister::InPositionCounts)?,
+            window.counts as u64,
+        )?;
+        self.write_register(self.mapped(MappedRegister::InPositionTime)?, ms)?;
+        self.volatile.in_position = Some(window);
+        Ok(())
+    }
+
+    pub fn get_in_position_window(&mut self) -> Result<InPositionWindow, DeviceError> {
+        Ok(InPositionWindow {
+            counts: self.get_register_value(self.mapped(MappedRegister::InPositionCounts)?)? as u16,
+            time: Duration::from_millis(
+                self.get_register_value(self.mapped(MappedRegister::InPositionTime)?)?,
+            ),
+        })
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{AppliedDevice, DeviceError, MOTOR_ENABLED};
+use tracing::{info, warn};
+use yaml_rust::Yaml;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrakeState {
+    Engaged,  // Brake output low, so the brake holds with its coil off
+    Released, // Brake output high
+}
+
+// The state the hardware is driven to as soon as the device is connected
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InitialState {
+    pub motor_enabled: Option<bool>,
+    pub brake: Option<(u16, BrakeState)>, // The output wired to the brake, 1 for Y1
+    pub outputs: Vec<(u16, bool)>,        // Output number and whether it is high
+}
+
+// How each step of applying an initial state went
+#[derive(Debug, Default)]
+pub struct InitialStateReport {
+    pub steps: Vec<(String, Result<(), DeviceError>)>, // e.g. ("enable motor", Ok(()))
+}
+
+impl InitialStateReport {
+    pub fn is_ok(&self) -> bool {
+        self.steps.iter().all(|(_, result)| result.is_ok())
+    }
+
+    pub fn failures(&self) -> Vec<&(String, Result<(), DeviceError>)> {
+        self.steps.iter().filter(|(_, r)| r.is_err()).collect()
+    }
+}
+
+impl InitialState {
+    // Reads the optional `initial_state:` section of a device config
+    //
+    //   initial_state:
+    //     motor: enabled          # or disabled
+    //     brake: {output: 2, state: engaged}
+    //     outputs:
+    //       1: low
+    //       3: high
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<InitialState>, DeviceError> {
+        let section = &device_conf["initial_state"];
+        match section {
+            Yaml::Hash(_) => {}
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "initial_state must be a map, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        let motor_enabled = match &section["motor"] {
+            Yaml::BadValue | Yaml::Null => None,
+            Yaml::String(s) if s == "enabled" => Some(true),
+            Yaml::String(s) if s == "disabled" => Some(false),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "initial_state.motor must be enabled or disabled, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let brake = match &section["brake"] {
+            Yaml::BadValue | Yaml::Null => None,
+            brake => {
+                let output = output_number(&brake["output"], "initial_state.brake.output")?;
+                let state = match brake["state"].as_str() {
+                    Some("engaged") => BrakeState::Engaged,
+                    Some("released") => BrakeState::Released,
+                    _ => {
+                        return Err(DeviceError::Config(format!(
+                            "initial_state.brake.state must be engaged or released, got {:?}",
+                            brake["state"]
+                        )))
+                    }
+                };
+                Some((output, state))
+            }
+        };
+
+        let mut outputs = Vec::new();
+        match &section["outputs"] {
+            Yaml::BadValue | Yaml::Null => {}
+            Yaml::Hash(h) => {
+                for (output, level) in h {
+                    let output = output_number(output, "initial_state.outputs")?;
+                    let high = match level.as_str() {
+                        Some("high") => true,
+                        Some("low") => false,
+                        _ => {
+                            return Err(DeviceError::Config(format!(
+                                "initial_state.outputs.{} must be high or low, got {:?}",
+                                output, level
+                            )))
+                        }
+                    };
+                    // Driving it here as well would undo the brake's ordering
+                    if brake.map(|(b, _)| b) == Some(output) {
+                        return Err(DeviceError::Config(format!(
+                            "initial_state.outputs.{} is the brake's output, set it under brake",
+                            output
+                        )));
+                    }
+                    outputs.push((output, high));
+                }
+            }
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "initial_state.outputs must map output numbers to high or low, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        Ok(Some(InitialState {
+            motor_enabled,
+            brake,
+            outputs,
+        }))
+    }
+}
+
+fn output_number(value: &Yaml, field: &str) -> Result<u16, DeviceError> {
+    match value.as_i64() {
+        Some(n) if (1..=16).contains(&n) => Ok(n as u16),
+        _ => Err(DeviceError::Config(format!(
+            "{} must be an output number from 1 to 16, got {:?}",
+            field, value
+        ))),
+    }
+}
+
+impl AppliedDevice {
+    // Drives the hardware to the given state, carrying on past any step that
+    // fails.  The brake is engaged before the motor is disabled, and only
+    // released once the motor has been enabled and reads back as enabled.
+    // Otherwise releasing it is a failed step and the brake is left alone.
+    pub fn apply_initial_state(&mut self, state: &InitialState) -> InitialStateReport {
+        info!("Applying initial state to {}", self.servo_name);
+        let mut report = InitialStateReport::default();
+        let servo_name = self.servo_name.clone();
+        let step = |report: &mut InitialStateReport, name: String, result| {
+            if let Err(e) = &result {
+                warn!("Initial state of {}: {} failed: {}", servo_name, name, e);
+            }
+            report.steps.push((name, result));
+        };
+
+        let brake_name = |output: u16, brake: BrakeState| {
+            format!("{:?} brake on output {}", brake, output).to_lowercase()
+        };
+
+        if let Some((output, BrakeState::Engaged)) = state.brake {
+            let result = self.set_output(output, false);
+            step(&mut report, brake_name(output, BrakeState::Engaged), result);
+        }
+        let enabled = match state.motor_enabled {
+            Some(true) => {
+                let result = self.enable_motor();
+                let enabled = result.is_ok() && matches!(self.has_status(MOTOR_ENABLED), Ok(true));
+                step(&mut report, String::from("enable motor"), result);
+                enabled
+            }
+            Some(false) => {
+                let result = self.disable_motor();
+                step(&mut report, String::from("disable motor"), result);
+                false
+            }
+            None => false,
+        };
+        if let Some((output, BrakeState::Released)) = state.brake {
+            let result = match enabled {
+                true => self.set_output(output, true),
+                false => Err(DeviceError::MotorNotEnabled(String::from(
+                    "the brake was left as it was",
+                ))),
+            };
+            step(
+                &mut report,
+                brake_name(output, BrakeState::Released),
+                result,
+            );
+        }
+        for (output, high) in &state.outputs {
+            let result = self.set_output(*output, *high);
+            let level = if *high { "high" } else { "low" };
+            step(&mut report, format!("output {} {}", output, level), result);
+        }
+        report
+    }
+
+    // The outcome of the initial state applied when the device was built,
+    // from the builder or the device config
+    pub fn get_initial_state_report(&self) -> Option<&InitialStateReport> {
+        self.initial_state_report.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn parse(conf: &str) -> Result<Option<InitialState>, DeviceError> {
+        let docs = YamlLoader::load_from_str(conf).expect("Bad test YAML");
+        InitialState::from_yaml(&docs[0])
+    }
+
+    #[test]
+    fn reads_every_part() {
+        let state = parse(
+            "initial_state:\n  motor: enabled\n  brake: {output: 2, state: released}\n  outputs:\n    1: low\n    3: high\n",
+        )
+        .expect("Unable to parse")
+        .expect("No initial state");
+        assert_eq!(state.motor_enabled, Some(true));
+        assert_eq!(state.brake, Some((2, BrakeState::Released)));
+        assert_eq!(state.outputs, vec![(1, false), (3, true)]);
+    }
+
+    #[test]
+    fn missing_section_is_none() {
+        assert_eq!(parse("address: 10.0.0.1\n").expect("Unable to parse"), None);
+    }
+
+    #[test]
+    fn brake_output_is_rejected_among_outputs() {
+        let result = parse(
+            "initial_state:\n  brake: {output: 2, state: engaged}\n  outputs:\n    2: high\n",
+        );
+        assert!(matches!(result, Err(DeviceError::Config(_))));
+    }
+
+    #[test]
+    fn outputs_past_16_are_rejected() {
+        let result = parse("initial_state:\n  outputs:\n    17: high\n");
+        assert!(matches!(result, Err(DeviceError::Config(_))));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{AppliedDevice, DeviceError, DriveCommand, PARAMETER_1, PARAMETER_2};
+use std::time::Duration;
+use tracing::info;
+use yaml_rust::Yaml;
+
+static MAX_INPUT_FILTER: u64 = 32767; // In ms
+
+impl AppliedDevice {
+    // Ignores changes on an input that don't last for the given time.  Feeds,
+    // capture and limit switches all see the filtered input.  A zero time
+    // turns filtering off.
+    pub fn set_input_filter(&mut self, input: u16, filter: Duration) -> Result<(), DeviceError> {
+        if !(1..=16).contains(&input) {
+            return Err(DeviceError::Config(format!(
+                "Input {} of {} can't be filtered, inputs are 1 to 16",
+                input, self.servo_name
+            )));
+        }
+        let ms = filter.as_millis() as u64;
+        if ms > MAX_INPUT_FILTER {
+            return Err(DeviceError::Config(format!(
+                "Input filter of {}ms is longer than the drive allows ({}ms)",
+                ms, MAX_INPUT_FILTER
+            )));
+        }
+
+        info!(
+            "Filtering input {} of {} for {}ms",
+            input, self.servo_name, ms
+        );
+        self.write_register(PARAMETER_1, input as u64)?;
+        self.write_register(PARAMETER_2, ms)?;
+        self.execute(DriveCommand::SetInputFilter)?;
+        if ms == 0 {
+            self.input_filters.remove(&input);
+        } else {
+            self.input_filters.insert(input, filter);
+        }
+        Ok(())
+    }
+
+    // The filter time last set on an input, if any.  The drive can't report
+    // its filters, so one set with the vendor's tool isn't known here.
+    pub fn get_input_filter(&self, input: u16) -> Option<Duration> {
+        self.input_filters.get(&input).copied()
+    }
+}
+
+// Reads the optional `input_filters:` section of a device config, a map of
+// input number to filter time in ms.
+//
+//   input_filters:
+//     1: 5
+//     3: 20
+pub(crate) fn input_filters_from_yaml(
+    device_conf: &Yaml,
+) -> Result<Vec<(u16, Duration)>, DeviceError> {
+    let section = match &device_conf["input_filters"] {
+        Yaml::Hash(h) => h,
+        Yaml::BadValue | Yaml::Null => return Ok(Vec::new()),
+        other => {
+            return Err(DeviceError::Config(format!(
+                "input_filters must be a map of input to milliseconds, got {:?}",
+                other
+            )))
+        }
+    };
+
+    let mut filters = Vec::new();
+    for (input, ms) in section {
+        match (input, ms) {
+            (Yaml::Integer(i), Yaml::Integer(ms)) if (1..=16).contains(i) && *ms >= 0 => {
+                filters.push((*i as u16, Duration::from_millis(*ms as u64)))
+            }
+            _ => {
+                return Err(DeviceError::Config(format!(
+                    "input_filters entries must be an input from 1 to 16 and milliseconds, got {:?}: {:?}",
+                    input, ms
+                )))
+            }
+        }
+    }
+    Ok(filters)
+}
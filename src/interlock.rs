@@ -0,0 +1,100 @@
+use crate::{AppliedDevice, DeviceError, GearRatio};
+use std::sync::Arc;
+use tracing::warn;
+
+type Check = Arc<dyn Fn(&InterlockContext) -> InterlockDecision + Send + Sync>;
+
+// The operation an interlock is asked about
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterlockAction {
+    Enable,
+    Home,
+    Move {
+        target: u64,
+    },
+    // Relative, for feeds to length or to a sensor.  For a feed to a sensor
+    // the distance is the offset past the input and max_travel how far the
+    // axis may go in all, None for a masked feed that never gives up.
+    Feed {
+        distance: i64,
+        max_travel: Option<u64>,
+    },
+    Jog {
+        velocity: i64,
+    }, // Negative for the negative direction
+    Follow {
+        ratio: GearRatio,
+    }, // Electronic gearing to the master encoder
+}
+
+// What an interlock is given to decide with
+#[derive(Clone, Debug)]
+pub struct InterlockContext {
+    pub servo_name: String,
+    pub action: InterlockAction,
+    pub position: u64, // Encoder count as last read, which may be a little stale
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InterlockDecision {
+    Allow,
+    Deny(String), // Why, e.g. "guard door open"
+}
+
+// A named check run before the servo is enabled, homed or moved
+#[derive(Clone)]
+pub(crate) struct Interlock {
+    name: String,
+    check: Check,
+}
+
+impl AppliedDevice {
+    // Adds a check, such as "door closed" or "air pressure OK", that must
+    // allow every enable, home and move.  Replaces any interlock of the same
+    // name.
+    pub fn add_interlock<F>(&mut self, name: &str, check: F)
+    where
+        F: Fn(&InterlockContext) -> InterlockDecision + Send + Sync + 'static,
+    {
+        self.interlocks.retain(|i| i.name != name);
+        self.interlocks.push(Interlock {
+            name: name.to_string(),
+            check: Arc::new(check),
+        });
+    }
+
+    pub fn remove_interlock(&mut self, name: &str) -> bool {
+        let before = self.interlocks.len();
+        self.interlocks.retain(|i| i.name != name);
+        self.interlocks.len() != before
+    }
+
+    pub fn get_interlock_names(&self) -> Vec<String> {
+        self.interlocks.iter().map(|i| i.name.clone()).collect()
+    }
+
+    // Runs every interlock in the order added, failing with the first denial
+    pub(crate) fn check_interlocks(&self, action: InterlockAction) -> Result<(), DeviceError> {
+        if self.interlocks.is_empty() {
+            return Ok(());
+        }
+        let context = InterlockContext {
+            servo_name: self.servo_name.clone(),
+            action,
+            position: self.last_snapshot().encoder_count,
+        };
+        for interlock in &self.interlocks {
+            if let InterlockDecision::Deny(reason) = (interlock.check)(&context) {
+                warn!(
+                    "Interlock {} denied {:?} on {}: {}",
+                    interlock.name, action, self.servo_name, reason
+                );
+                return Err(DeviceError::InterlockDenied {
+                    interlock: interlock.name.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+}
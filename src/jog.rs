@@ -0,0 +1,364 @@
+use crate::feed::signed_count;
+use crate::history::outcome_of;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, InterlockAction, MappedRegister,
+    Operation, MOVING,
+};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+static LIMIT_POLL_INTERVAL: u64 = 50; // How often jog_to_limit checks the limit switches, in ms
+
+// Which way to jog.  Cw is the direction of increasing encoder counts, the
+// one the cw limit switch stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JogDirection {
+    Cw,
+    Ccw,
+}
+
+// Where a jog_to_limit ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitSearch {
+    pub limit_reached: bool, // False if it ran out of travel or time, or was cancelled
+    pub position: u64,       // Encoder count once stopped
+    pub travel: u64,         // Counts covered from the starting position
+}
+
+impl AppliedDevice {
+    // Starts the axis turning at the given velocity, in the same units as a
+    // move's, until stop_jog or stop_motion.  A negative velocity jogs in
+    // the negative direction.
+    pub fn start_jog(&mut self, accel: u64, decel: u64, velocity: i16) -> Result<(), DeviceError> {
+        info!("Servo {} jogging at {}", self.servo_name, velocity);
+        let started = self.clock.now();
+        let result = self.run_start_jog(accel, decel, velocity);
+        self.record_operation(
+            Operation::StartJog {
+                velocity: velocity as i64,
+            },
+            started,
+            outcome_of(&result.as_ref().map(|_| true)),
+        );
+        result
+    }
+
+    fn run_start_jog(&mut self, accel: u64, decel: u64, velocity: i16) -> Result<(), DeviceError> {
+        self.check_interlocks(InterlockAction::Jog {
+            velocity: velocity as i64,
+        })?;
+        self.reset_alarm_or_fault()?;
+        self.write_register(self.mapped(MappedRegister::JogAccel)?, accel)?;
+        self.write_register(self.mapped(MappedRegister::JogDecel)?, decel)?;
+        let velocity = self.override_jog_velocity(velocity);
+        self.write_register(
+            self.mapped(MappedRegister::JogVelocity)?,
+            velocity as u16 as u64,
+        )?;
+        self.execute(DriveCommand::CommenceJog)?;
+        self.jogging = Some(velocity);
+        Ok(())
+    }
+
+    // Changes the speed, and with a change of sign the direction, of the jog
+    // under way without stopping it first, for an operator's jog wheel or
+    // slider.  The drive ramps to the new velocity at the jog accel and
+    // decel.  Returns false (and does nothing) if the axis isn't jogging.
+    pub fn change_jog_velocity(&mut self, velocity: i16) -> Result<bool, DeviceError> {
+        let current = match self.jogging {
+            Some(current) => current,
+            None => return Ok(false),
+        };
+        let velocity = self.override_jog_velocity(velocity);
+        if velocity == current {
+            return Ok(true);
+        }
+        // Checked for any non-zero speed, not just a change of sign, which
+        // slowing to zero first would hide
+        if velocity != 0 {
+            self.check_interlocks(InterlockAction::Jog {
+                velocity: velocity as i64,
+            })?;
+        }
+        self.write_register(
+            self.mapped(MappedRegister::JogVelocity)?,
+            velocity as u16 as u64,
+        )?;
+        self.execute(DriveCommand::ChangeJogSpeed)?;
+        self.jogging = Some(velocity);
+        Ok(true)
+    }
+
+    // Decelerates a jog to a stop at the jog decel
+    pub fn stop_jog(&mut self) -> Result<(), DeviceError> {
+        if self.jogging.is_none() {
+            return Ok(());
+        }
+        info!("Servo {} no longer jogging", self.servo_name);
+        self.execute(DriveCommand::StopJog)?;
+        self.jogging = None;
+        Ok(())
+    }
+
+    // The velocity the axis is jogging at, after the velocity override, if
+    // it is jogging
+    pub fn get_jog_velocity(&self) -> Option<i16> {
+        self.jogging
+    }
+
+    // Furthest jog_to_limit may travel looking for a limit switch before
+    // giving up, None for no limit other than the move timeout
+    pub fn set_limit_search_travel(&mut self, travel: Option<u64>) {
+        self.limit_search_travel = travel;
+    }
+
+    pub fn get_limit_search_travel(&self) -> Option<u64> {
+        self.limit_search_travel
+    }
+
+    // Jogs until the limit switch in that direction trips, then reports
+    // where the axis stopped, e.g. to measure travel while setting up a
+    // machine.  Uses the accel and decel of the motion defaults.  The limit
+    // alarm is left for the next move to clear.
+    pub fn jog_to_limit(
+        &mut self,
+        direction: JogDirection,
+        velocity: u16,
+    ) -> Result<LimitSearch, DeviceError> {
+        self.jog_to_limit_with_cancel(direction, velocity, &CancelToken::new())
+    }
+
+    pub fn jog_to_limit_with_cancel(
+        &mut self,
+        direction: JogDirection,
+        velocity: u16,
+        cancel: &CancelToken,
+    ) -> Result<LimitSearch, DeviceError> {
+        let missing = |what: &str| {
+            DeviceError::Config(format!(
+                "No {} configured for {} to jog to its limit with",
+                what, self.servo_name
+            ))
+        };
+        let accel = self.motion_defaults.accel.ok_or_else(|| missing("accel"))?;
+        let decel = self.motion_defaults.decel.ok_or_else(|| missing("decel"))?;
+        if velocity == 0 {
+            return Err(DeviceError::Config(format!(
+                "{} can't jog to its limit at a velocity of 0",
+                self.servo_name
+            )));
+        }
+
+        let start = self.get_encoder_count()?;
+        if self.at_limit(direction)? {
+            info!(
+                "{} is already at its {:?} limit",
+                self.servo_name, direction
+            );
+            return Ok(LimitSearch {
+                limit_reached: true,
+                position: start,
+                travel: 0,
+            });
+        }
+
+        let speed = velocity.min(i16::MAX as u16) as i16;
+        let velocity = match direction {
+            JogDirection::Cw => speed,
+            JogDirection::Ccw => -speed,
+        };
+        self.start_jog(accel, decel, velocity)?;
+        let limit_reached = match self.search_for_limit(direction, start, cancel) {
+            Ok(limit_reached) => limit_reached,
+            Err(e) => {
+                // Don't leave the axis jogging towards the limit
+                error!(
+                    "!!{} stopped looking for its {:?} limit: {}!!",
+                    self.servo_name, direction, e
+                );
+                if let Err(stop) = self.stop_motion() {
+                    warn!("Unable to stop {}: {}", self.servo_name, stop);
+                }
+                return Err(e);
+            }
+        };
+
+        // Wait for the axis to come to rest before reading where it ended up
+        let poll = Duration::from_millis(LIMIT_POLL_INTERVAL);
+        let stopping = self.clock.now();
+        while self.has_status(MOVING)?
+            && self.clock.elapsed(stopping) < self.motion_defaults.move_timeout
+        {
+            self.clock.sleep(poll);
+        }
+        let position = self.get_encoder_count()?;
+        let travel = (signed_count(position) - signed_count(start)).unsigned_abs();
+        info!(
+            "{} jogged {} counts to {} looking for its {:?} limit, reached: {}",
+            self.servo_name, travel, position, direction, limit_reached
+        );
+        Ok(LimitSearch {
+            limit_reached,
+            position,
+            travel,
+        })
+    }
+
+    fn at_limit(&mut self, direction: JogDirection) -> Result<bool, DeviceError> {
+        let limits = self.limit_switches()?;
+        Ok(match direction {
+            JogDirection::Cw => limits.cw_active,
+            JogDirection::Ccw => limits.ccw_active,
+        })
+    }
+
+    // Polls the jog under way until it reaches the limit, runs out of
+    // travel or time, or is cancelled.  Returns whether the limit was
+    // reached.
+    fn search_for_limit(
+        &mut self,
+        direction: JogDirection,
+        start: u64,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let started = self.clock.now();
+        let poll = Duration::from_millis(LIMIT_POLL_INTERVAL);
+        loop {
+            if self.at_limit(direction)? {
+                // The drive stops the jog itself
+                self.jogging = None;
+                return Ok(true);
+            }
+            let travel =
+                (signed_count(self.get_encoder_count()?) - signed_count(start)).unsigned_abs();
+            if self
+                .limit_search_travel
+                .is_some_and(|budget| travel >= budget)
+            {
+                warn!(
+                    "{} found no {:?} limit within {} counts",
+                    self.servo_name, direction, travel
+                );
+                self.stop_jog()?;
+                return Ok(false);
+            }
+            if self.clock.elapsed(started) > self.motion_defaults.move_timeout {
+                error!(
+                    "!!{} did not reach its {:?} limit!!",
+                    self.servo_name, direction
+                );
+                self.stop_jog()?;
+                return Ok(false);
+            }
+            if !self.sleep_and_sample(poll, cancel)? {
+                warn!(
+                    "Jog of servo {} to its limit was cancelled",
+                    self.servo_name
+                );
+                self.stop_motion()?;
+                return Ok(false);
+            }
+        }
+    }
+
+    // Scales a jog velocity by the velocity override, keeping its sign
+    fn override_jog_velocity(&self, velocity: i16) -> i16 {
+        if velocity == 0 {
+            return 0;
+        }
+        let speed = self.override_velocity(velocity.unsigned_abs() as u64);
+        speed.min(i16::MAX as u64) as i16 * velocity.signum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, LimitSwitchConfig, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    fn jog_device() -> (AppliedDevice, SimulatedDrive, ManualClock) {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let drive = SimulatedDrive::with_clock(shared.clone());
+        let mut device =
+            AppliedDevice::with_transport(String::from("jog"), String::new(), drive.clone());
+        device.set_clock(shared);
+        device.set_register_map(&SimulatedDrive::register_map());
+        device.enable_motor().expect("Unable to enable the motor");
+        (device, drive, clock)
+    }
+
+    #[test]
+    fn jog_changes_direction_without_stopping_first() {
+        let (mut device, drive, clock) = jog_device();
+        device.start_jog(10, 10, 100).unwrap();
+        clock.advance(Duration::from_secs(2));
+        let turned_at = drive.position();
+        assert!(turned_at > 0);
+
+        assert!(device.change_jog_velocity(-100).unwrap());
+        assert_eq!(device.get_jog_velocity(), Some(-100));
+        clock.advance(Duration::from_secs(10));
+        assert!(drive.position() < turned_at);
+        assert!(device.has_status(MOVING).unwrap());
+    }
+
+    #[test]
+    fn jog_speed_only_changes_while_jogging() {
+        let (mut device, drive, clock) = jog_device();
+        assert!(!device.change_jog_velocity(100).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(drive.position(), 0);
+    }
+
+    // With limit switches on inputs 6 (cw) and 7 (ccw)
+    fn limited_device() -> (AppliedDevice, SimulatedDrive) {
+        let (mut device, drive, _) = jog_device();
+        device
+            .set_limit_switch_config(&LimitSwitchConfig {
+                enabled: true,
+                normally_closed: false,
+                cw_input: 6,
+                ccw_input: 7,
+            })
+            .unwrap();
+        device.motion_defaults.accel = Some(10);
+        device.motion_defaults.decel = Some(10);
+        (device, drive)
+    }
+
+    #[test]
+    fn jog_to_limit_reports_where_the_limit_stopped_it() {
+        let (mut device, drive) = limited_device();
+        drive.set_position(1000);
+        drive.trip_input_at(6, 20000);
+        let search = device.jog_to_limit(JogDirection::Cw, 100).unwrap();
+        assert!(search.limit_reached);
+        assert!(search.position >= 20000, "{:?}", search);
+        assert_eq!(search.travel, search.position - 1000);
+        assert_eq!(drive.position() as u64, search.position);
+        assert_eq!(device.get_jog_velocity(), None);
+    }
+
+    #[test]
+    fn jog_to_limit_gives_up_after_its_travel() {
+        let (mut device, drive) = limited_device();
+        device.set_limit_search_travel(Some(5000));
+        let search = device.jog_to_limit(JogDirection::Ccw, 100).unwrap();
+        assert!(!search.limit_reached);
+        assert!(search.travel >= 5000, "{:?}", search);
+        assert!(drive.position() <= -5000);
+        assert!(!device.has_status(MOVING).unwrap());
+    }
+
+    #[test]
+    fn jog_to_limit_needs_the_default_accel() {
+        let (mut device, drive, _) = jog_device();
+        assert!(matches!(
+            device.jog_to_limit(JogDirection::Cw, 100),
+            Err(DeviceError::Config(_))
+        ));
+        assert_eq!(drive.position(), 0);
+    }
+}
@@ -1,31 +1,187 @@
 extern crate modbus;
 
-use log::{error, info, warn};
-use modbus::tcp;
-use modbus::Client;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
+use history::outcome_of;
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc};
 use std::time::Instant;
 use std::{fmt, time};
-use yaml_rust::YamlLoader;
+use tracing::{error, field, info, info_span, warn};
+use transport::TcpTransport;
 
-static ALARM_REG: u16 = 0;
-static STATUS_REG: u16 = 1;
-static ENCODER_POS_1_REG: u16 = 4;
+mod absolute;
+mod audit;
+mod autotune;
+mod builder;
+mod cancel;
+mod capture;
+mod clock;
+mod command;
+mod config;
+mod crossing;
+mod datalog;
+mod device_lock;
+mod digital_io;
+mod error;
+mod exception;
+#[cfg(feature = "arrow")]
+mod export;
+mod feed;
+mod filters;
+mod following_error;
+mod gains;
+mod gantry;
+#[cfg(feature = "gcode")]
+mod gcode;
+mod gearing;
+mod group;
+mod handle;
+mod history;
+mod homing;
+mod identity;
+mod idle_current;
+mod in_position;
+mod initial_state;
+mod input_filter;
+mod interlock;
+mod jog;
+mod limits;
+mod maintenance;
+mod metrics;
+mod monitor;
+mod motion_control;
+mod motion_defaults;
+#[cfg(feature = "opcua")]
+mod opcua_server;
+mod pool;
+mod profile;
+mod rate_limit;
+mod recipe;
+mod recovery;
+mod registers;
+mod replay;
+mod report;
+mod resolution;
+mod retry;
+mod rollover;
+#[cfg(feature = "ros2")]
+mod ros_bridge;
+mod scheduler;
+mod scope;
+mod seizure;
+mod self_test;
+mod sim;
+mod stall;
+mod state_store;
+mod stats;
+mod stream;
+mod telemetry;
+mod trace;
+mod trajectory;
+mod transport;
+mod validate;
+mod velocity;
+mod virtual_master;
+mod watchdog;
+mod wire_log;
+mod write_read;
+
+pub use audit::{AuditRecord, AuditSink, FileAuditLog};
+pub use autotune::AutotuneReport;
+pub use builder::AppliedDeviceBuilder;
+pub use cancel::CancelToken;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use command::{DriveCommand, OpcodeTable};
+pub use config::{list_servos, DeviceConfig, ServoEntry, CONFIG_DIR_VAR, ENVIRONMENT_VAR};
+pub use crossing::{CrossingDirection, PositionCrossing};
+pub use datalog::{DataLogConfig, DataLogFormat};
+pub use device_lock::LockOwner;
+pub use error::DeviceError;
+pub use exception::ModbusException;
+#[cfg(feature = "arrow")]
+pub use export::{scope_record_batch, trace_record_batch, write_parquet};
+pub use feed::{InputCondition, LengthFeed, MaskedSensorFeed, SensorFeed};
+pub use filters::FilterSettings;
+pub use gains::ServoGains;
+pub use gantry::Gantry;
+#[cfg(feature = "gcode")]
+pub use gcode::{parse_gcode, GcodeCommand};
+pub use gearing::GearRatio;
+pub use group::{AppliedDeviceGroup, GroupOutcome, HomingOrder, InventoryEntry, InventoryReport};
+pub use handle::AppliedDeviceHandle;
+pub use history::{Operation, OperationOutcome, OperationRecord};
+pub use homing::{
+    HomingAttempt, HomingAttemptOutcome, HomingOffset, HomingReport, HomingRetryPolicy,
+};
+pub use identity::{FirmwareMismatch, FirmwareRange, FirmwareVersion};
+pub use idle_current::IdleCurrent;
+pub use in_position::InPositionWindow;
+pub use initial_state::{BrakeState, InitialState, InitialStateReport};
+pub use interlock::{InterlockAction, InterlockContext, InterlockDecision};
+pub use jog::{JogDirection, LimitSearch};
+pub use limits::{LimitState, LimitSwitchConfig};
+pub use maintenance::{MaintenanceCounter, MaintenanceDue, MaintenanceTask, ServiceRecord};
+pub use metrics::{Histogram, IoCounters, MoveMetrics};
+pub use monitor::{MonitorHandle, StatusSnapshot};
+pub use motion_control::{MotionControl, MoveWait};
+pub use motion_defaults::{MotionDefaults, MoveOptions};
+#[cfg(feature = "opcua")]
+pub use opcua_server::OpcUaServer;
+pub use pool::{ConnectionPool, PooledTransport};
+pub use profile::{MotionProfile, MoveRequest};
+pub use rate_limit::RateLimitedTransport;
+pub use recipe::{Recipe, RecipeProgress, RecipeStep};
+pub use recovery::{RecoveryPolicy, RecoveryReport};
+pub use registers::{register_name, MappedRegister, RegisterMap, RegisterValue, WordOrder};
+pub use replay::{RecordingTransport, ReplayTransport};
+pub use retry::RetryPolicy;
+#[cfg(feature = "ros2")]
+pub use ros_bridge::RosBridge;
+pub use scheduler::{ConflictPolicy, MotionScheduler, ScheduledMotion, Trigger};
+pub use scope::{ScopeSample, ScopeSettings, ScopeTrace, ScopeTrigger};
+pub use self_test::{SelfTestReport, SelfTestStep};
+pub use sim::SimulatedDrive;
+pub use stall::{StallMode, STALL_DETECTED};
+pub use state_store::PersistentState;
+pub use stats::{MoveStats, RunningStats};
+pub use telemetry::{MoveEnd, MoveStart, TelemetrySink};
+pub use trace::TraceSample;
+pub use trajectory::{TrajectoryProgress, Waypoint};
+pub use transport::{ModbusTimeouts, Transport};
+pub use validate::{validate_config, ConfigProblem, ConfigReport, Severity};
+pub use velocity::{AxisVelocity, MoveVelocitySample, VelocityMonitor};
+pub use virtual_master::VirtualMaster;
+pub use watchdog::CommsWatchdog;
+pub use wire_log::{ModbusFunction, WIRE_LOG_TARGET};
+
+// Registers are numbered from 0 for 40001 in the drive's Modbus map
+static ALARM_REG: u16 = 0; // 40001, "AL"
+static STATUS_REG: u16 = 1; // 40002, "SC"
+static INPUTS_REG: u16 = 3; // 40004, driver board inputs, bit 0 being X1
+static ENCODER_POS_1_REG: u16 = 4; // 40005, "IE" high word
 static ENCODER_POS_2_REG: u16 = 5;
+static ACTUAL_VELOCITY: u16 = 10; // 40011, "IV0", signed, in the same units as VELOCITY
+static FOLLOWING_ERROR_1: u16 = 14; // 40015, "IX" high word, signed counts
+static FOLLOWING_ERROR_2: u16 = 15;
+static ACTUAL_CURRENT: u16 = 18; // 40019, "IC", signed, in 0.01 A
 static MAX_REGISTER: u16 = 56; // The last register we really care about seeing
-static MAX_32_BIT: u64 = 65536;
 static MAX_HOMING_TIME: u64 = 60; // Max allowed time to home servo, in seconds
 static MAX_MOVE_TIME: u64 = 30; // Max allowed time to execute a move, in seconds
+static MAX_SETTLE_TIME: u64 = 1000; // Max time to wait for In Position once motion ends, in ms
+static SETTLE_POLL_INTERVAL: u64 = 10; // In ms
 static ENCODER_POSITION_RANGE: u64 = 1000; // Allowed +/- range value an encoder position
 
-static ACCELERATION: u16 = 27;
-static DECELERATION: u16 = 28;
-static VELOCITY: u16 = 29;
-static DISTANCE_1: u16 = 30;
+static ACCELERATION: u16 = 27; // 40028, "AC"
+static DECELERATION: u16 = 28; // 40029, "DE"
+static VELOCITY: u16 = 29; // 40030, "VE"
+static DISTANCE_1: u16 = 30; // 40031, "DI" high word
 static DISTANCE_2: u16 = 31;
-static EXECUTE_COMMAND: u16 = 124;
+static DISTANCE_CHANGE_1: u16 = 32; // 40033, "DC", used as the safety distance of sensor feeds
+static DISTANCE_CHANGE_2: u16 = 33;
+static EXECUTE_COMMAND: u16 = 124; // 40125, the opcode of the command to run
+static PARAMETER_1: u16 = 125; // 40126, parameters of the command written to EXECUTE_COMMAND
+static PARAMETER_2: u16 = 126;
+static CANCEL_POLL_INTERVAL: u64 = 25; // How often a sleep checks for cancellation, in ms
+static HOLD_POLL_INTERVAL: u64 = 50; // How often a held move checks for a resume, in ms
 
 // STATUS NAMES
 pub static MOTOR_ENABLED: &str = "Motor Enabled";
@@ -80,13 +236,72 @@ static STATUS_CODE_NAMES: &[&str] = &[
 ];
 
 pub struct AppliedDevice {
-    servo_name: String,    // The provided name of this applied servo
-    servo_address: String, // The IP/Hostname of the device
-    client: tcp::Transport,
+    servo_name: String,                 // The provided name of this applied servo
+    servo_address: String,              // The IP/Hostname of the device
+    servo_addresses: Vec<String>,       // All the configured ones, tried in order
+    pool: Option<(ConnectionPool, u8)>, // With the unit ID, when reached through a pool
+    log_span: tracing::Span,            // Carries the servo and address, see get_log_span
+    client: Box<dyn Transport>,
     resource_location: String, // the location of the configuration file for this device
     servo_status: Vec<String>,
     servo_alarm: Vec<String>,
     servo_cycle_count: i64, // The count of move cycles this servo has made.
+    snapshot: monitor::SharedSnapshot, // Last read state, shared with any monitor handles
+    telemetry: Vec<Arc<dyn TelemetrySink>>,
+    wire_log: Option<wire_log::WireLog>, // Set when wire level logging is enabled
+    clock: Arc<dyn Clock>,
+    retry_policy: RetryPolicy,
+    timeouts: ModbusTimeouts,
+    min_request_gap: Option<time::Duration>, // Rate limit applied to connections this device opens
+    verify_writes: bool,                     // Read back every register written
+    opcodes: OpcodeTable,                    // Opcodes sent in place of DriveCommand's own
+    register_map: RegisterMap, // Addresses of the drive registers outside the documented map
+    combined_move_writes: bool, // Set up moves with FC23, see set_combined_move_writes
+    word_order: WordOrder,     // Of the 32 bit values read and written by read_u32 etc.
+    audit: Option<audit::AuditLog>,
+    command_reason: Option<String>, // Attached to audit records
+    history: history::OperationHistory,
+    persistent: PersistentState, // Counters kept across restarts if a state store is set
+    state_store: Option<state_store::StateStore>,
+    maintenance: maintenance::MaintenanceSchedule,
+    stats: stats::StatsCollector,
+    last_settle_time: Option<time::Duration>, // Of the last move to reach In Position
+    metrics: metrics::MoveMetrics,
+    move_timings: metrics::MoveTimings,
+    io: IoCounters,
+    trace: Option<trace::MotionTrace>,
+    data_log: Option<datalog::DataLogger>,
+    position_streams: Vec<stream::PositionStream>,
+    velocity_streams: Vec<stream::VelocityStream>,
+    velocity_monitor: Option<velocity::MoveVelocityMonitor>,
+    scope: Option<scope::Scope>,
+    position_watches: Vec<crossing::PositionWatch>,
+    motion_control: MotionControl,
+    move_target: Option<u64>, // Of the move in progress, which may have been retargeted
+    move_velocity: u64,       // As commanded for the move in progress, before any override
+    following: Option<GearRatio>,
+    jogging: Option<i16>,             // Velocity of the jog under way
+    limit_search_travel: Option<u64>, // Travel allowed to jog_to_limit
+    jerk_filter: Option<u16>,         // As last written to the drive
+    default_profile: MotionProfile,
+    blending_supported: bool,
+    input_filters: BTreeMap<u16, time::Duration>, // As set through this crate
+    following_error_warning: Option<u16>,
+    monitor_following_error: bool,
+    stall_mode: StallMode,
+    steps_per_rev: Option<u32>, // What unit conversions assume
+    encoder_counts_per_rev: Option<u32>,
+    absolute_encoder: bool,
+    skip_homing: bool,
+    rollover: Option<u64>, // Counts per cycle of a continuous rotation axis
+    motion_defaults: MotionDefaults,
+    reacquire_on_seizure: bool,
+    lock: Option<device_lock::DeviceLock>, // Held for as long as the device exists
+    interlocks: Vec<interlock::Interlock>,
+    urgent_jobs: Option<mpsc::Receiver<handle::Job>>, // Set once spawned
+    watchdog: Option<watchdog::WatchdogState>,
+    volatile: recovery::VolatileSettings, // Written again by recover()
+    initial_state_report: Option<InitialStateReport>,
 }
 
 impl fmt::Display for AppliedDevice {
@@ -99,48 +314,84 @@ impl fmt::Display for AppliedDevice {
     }
 }
 
+// The parent of every log line about one servo
+fn device_span(servo_name: &str, address: &str) -> tracing::Span {
+    info_span!("device", servo = %servo_name, address = %address)
+}
+
 impl AppliedDevice {
-    pub fn get_servo_cycle_count(&mut self) -> i64 {
+    pub fn get_servo_cycle_count(&self) -> i64 {
         self.servo_cycle_count
     }
 
-    pub fn get_encoder_count(&mut self) -> u64 {
-        let x: u16 = *self
-            .client
-            .read_holding_registers(ENCODER_POS_1_REG, 1)
-            .expect("IO Error")
-            .first()
-            .unwrap();
-
-        let y: u16 = *self
-            .client
-            .read_holding_registers(ENCODER_POS_2_REG, 1)
-            .expect("IO Error")
-            .first()
-            .unwrap();
+    pub fn get_encoder_count(&mut self) -> Result<u64, DeviceError> {
+        let encoder_position =
+            self.read_u32_ordered(ENCODER_POS_1_REG, WordOrder::HighFirst)? as u64;
+        self.update_snapshot(|s| s.encoder_count = encoder_position);
+        self.persistent.last_position = Some(encoder_position);
+        self.check_position_watches(encoder_position);
 
-        let encoder_position: u64 = y as u64 + (x as u64 * MAX_32_BIT);
+        Ok(encoder_position)
+    }
 
-        encoder_position
+    // Redefines the axis's current position as the given encoder count,
+    // without moving it
+    pub fn set_encoder_position(&mut self, encoder_position: u64) -> Result<(), DeviceError> {
+        info!(
+            "Redefining the position of {} as {}",
+            self.servo_name, encoder_position
+        );
+        self.write_u32_ordered(
+            ENCODER_POS_1_REG,
+            encoder_position as u32,
+            WordOrder::HighFirst,
+        )?;
+        self.get_encoder_count().map(|_| ())
     }
 
-    pub fn get_servo_alarms(&mut self) -> &Vec<String> {
-        let read: usize = self.get_register_value(ALARM_REG) as usize;
+    pub fn get_servo_alarms(&mut self) -> Result<&Vec<String>, DeviceError> {
+        let read: usize = self.get_register_value(ALARM_REG)? as usize;
         // Reset the current array of servo alarm values
-        self.servo_alarm = Vec::new();
+        let previous = std::mem::take(&mut self.servo_alarm);
 
         for (i, name) in ALARM_CODE_NAMES.iter().enumerate() {
             if read & (1 << i) != 0 {
-                self.servo_status.push(name.to_string());
+                // A stepper drive reports a stall as a position limit
+                let name = match (i, self.stall_mode) {
+                    (0, StallMode::Detect | StallMode::Prevent) => STALL_DETECTED,
+                    _ => name,
+                };
+                self.servo_alarm.push(name.to_string());
                 // println!("{:16b} & {:16b} = {}", read, (1 << i), ALARM_CODE_NAMES[i]);
             }
         }
+        let alarms = self.servo_alarm.clone();
+        if !alarms.is_empty() && alarms != previous {
+            self.emit(|t| t.on_alarm(&self.servo_name, &alarms));
+        }
+        let stalled = |a: &Vec<String>| a.iter().any(|name| name == STALL_DETECTED);
+        if stalled(&alarms) && !stalled(&previous) {
+            let position = self.last_snapshot().encoder_count;
+            warn!("{} stalled near position {}", self.servo_name, position);
+            self.emit(|t| t.on_stall(&self.servo_name, position));
+        }
+        let limits = LimitState::from_alarm_register(read as u64);
+        self.update_snapshot(|s| {
+            s.alarms = alarms;
+            s.limits = limits;
+        });
 
-        &self.servo_alarm
+        Ok(&self.servo_alarm)
     }
 
-    pub fn get_servo_status(&mut self) -> &Vec<String> {
-        let read: usize = self.get_register_value(STATUS_REG) as usize;
+    pub fn get_servo_status(&mut self) -> Result<&Vec<String>, DeviceError> {
+        let read: usize = self.get_register_value(STATUS_REG)? as usize;
+        self.set_servo_status(read);
+        Ok(&self.servo_status)
+    }
+
+    // Decodes a value of the status register into the STATUS NAMES
+    fn set_servo_status(&mut self, read: usize) {
         // Reset the current array of servo status values
         self.servo_status = Vec::new();
 
@@ -150,289 +401,1121 @@ impl AppliedDevice {
                 // println!("{:16b} & {:16b} = {}", read, (1 << i), STATUS_CODE_NAMES[i]);
             }
         }
+        let status = self.servo_status.clone();
+        self.update_snapshot(|s| s.status = status);
+    }
 
-        &self.servo_status
+    // Reads the status register and checks for one of the STATUS NAMES
+    pub fn has_status(&mut self, status: &str) -> Result<bool, DeviceError> {
+        Ok(self.get_servo_status()?.iter().any(|s| s == status))
     }
 
-    pub fn reset_alarm_or_fault(&mut self) {
-        let mut alarm_present: bool = self.get_servo_status().contains(&ALARM.to_string());
-        let mut fault_present: bool = self.get_servo_status().contains(&FAULT.to_string());
+    pub fn reset_alarm_or_fault(&mut self) -> Result<(), DeviceError> {
+        let span = info_span!(
+            "reset_alarm",
+            servo = %self.servo_name,
+            duration_ms = field::Empty
+        );
+        let _enter = span.enter();
+        let started = self.clock.now();
+
+        let result = self.clear_alarm_or_fault();
+        span.record(
+            "duration_ms",
+            self.clock.elapsed(started).as_millis() as u64,
+        );
+        // Only remember resets that actually had something to clear
+        if !matches!(result, Ok(None)) {
+            let outcome = outcome_of(&result.as_ref().map(|cleared| *cleared == Some(true)));
+            self.record_operation(Operation::ResetAlarm, started, outcome);
+        }
+        result.map(|_| ())
+    }
+
+    // Returns None if there was nothing to reset, otherwise whether the
+    // alarm or fault was cleared
+    fn clear_alarm_or_fault(&mut self) -> Result<Option<bool>, DeviceError> {
+        let mut alarm_present: bool = self.has_status(ALARM)?;
+        let mut fault_present: bool = self.has_status(FAULT)?;
         let mut try_count: i8 = 0;
 
         if !alarm_present && !fault_present {
-            self.enable_motor();
-            return;
+            self.enable_motor()?;
+            return Ok(None);
         }
 
         while alarm_present || fault_present {
             warn!(
-                "Found alarm: {} or fault: {}, trying to reset",
-                alarm_present, fault_present
+                "Found alarm: {} or fault: {}, trying to reset (alarms: {:?})",
+                alarm_present,
+                fault_present,
+                self.get_servo_alarms()?
             );
-            self.write_register(EXECUTE_COMMAND, 186);
-            std::thread::sleep(time::Duration::from_millis(1000));
+            self.execute(DriveCommand::ResetAlarm)?;
+            self.clock.sleep(time::Duration::from_millis(1000));
 
             if try_count > 2 {
                 warn!("!!Unable to reset alarm or fault!!");
-                return;
+                return Ok(Some(false));
             }
             try_count += 1;
-            alarm_present = self.get_servo_status().contains(&ALARM.to_string());
-            fault_present = self.get_servo_status().contains(&FAULT.to_string());
+            alarm_present = self.has_status(ALARM)?;
+            fault_present = self.has_status(FAULT)?;
         }
 
-        self.enable_motor();
+        self.enable_motor()?;
+        Ok(Some(true))
     }
 
     // This enables the motor if it is currently not enabled
-    pub fn enable_motor(&mut self) {
-        if self.get_servo_status().contains(&MOTOR_ENABLED.to_string()) {
-            return;
+    pub fn enable_motor(&mut self) -> Result<(), DeviceError> {
+        if self.has_status(MOTOR_ENABLED)? {
+            return Ok(());
         }
+        self.check_interlocks(InterlockAction::Enable)?;
 
-        self.write_register(EXECUTE_COMMAND, 159);
-        std::thread::sleep(time::Duration::from_millis(1000));
+        let span = info_span!("enable", servo = %self.servo_name);
+        let _enter = span.enter();
+        let started = self.clock.now();
+        let result = self.execute(DriveCommand::EnableMotor);
+        if result.is_ok() {
+            self.clock.sleep(time::Duration::from_millis(1000));
+        }
+        self.record_operation(
+            Operation::EnableMotor,
+            started,
+            outcome_of(&result.as_ref().map(|_| true)),
+        );
+        result
     }
 
     // This disables the motor if the motor is currently enabled
-    pub fn disable_motor(&mut self) {
-        if self.get_servo_status().contains(&MOTOR_ENABLED.to_string()) {
-            self.write_register(EXECUTE_COMMAND, 158);
-            std::thread::sleep(time::Duration::from_millis(1000));
+    pub fn disable_motor(&mut self) -> Result<(), DeviceError> {
+        if self.has_status(MOTOR_ENABLED)? {
+            let span = info_span!("disable", servo = %self.servo_name);
+            let _enter = span.enter();
+            let started = self.clock.now();
+            let result = self.execute(DriveCommand::DisableMotor);
+            if result.is_ok() {
+                self.following = None;
+                self.jogging = None;
+                self.clock.sleep(time::Duration::from_millis(1000));
+            }
+            self.record_operation(
+                Operation::DisableMotor,
+                started,
+                outcome_of(&result.as_ref().map(|_| true)),
+            );
+            return result;
         }
+        Ok(())
+    }
+
+    pub fn home_servo(&mut self) -> Result<(), DeviceError> {
+        self.home_servo_with_cancel(&CancelToken::new())
     }
 
-    pub fn home_servo(&mut self) {
-        self.reset_alarm_or_fault();
+    // Same as home_servo, but gives up (and stops the axis) as soon as the
+    // provided token is cancelled.  Homing that times out, alarms or is
+    // cancelled is an error.
+    pub fn home_servo_with_cancel(&mut self, cancel: &CancelToken) -> Result<(), DeviceError> {
+        let report = self.home_servo_with_report(cancel)?;
+        self.require_homed(report).map(|_| ())
+    }
+
+    // Same as home_servo_with_cancel, returning how each attempt went.  An
+    // incomplete homing is only an error through the report's completed.
+    pub fn home_servo_with_report(
+        &mut self,
+        cancel: &CancelToken,
+    ) -> Result<HomingReport, DeviceError> {
+        if self.skip_homing {
+            info!(
+                "Not homing {}, its absolute encoder knows where it is",
+                self.servo_name
+            );
+            return Ok(HomingReport {
+                completed: true,
+                attempts: Vec::new(),
+                offset_position: None,
+            });
+        }
+        self.check_interlocks(InterlockAction::Home)?;
+
+        let span = info_span!(
+            "home",
+            servo = %self.servo_name,
+            completed = field::Empty,
+            duration_ms = field::Empty
+        );
+        let _enter = span.enter();
+        let started = self.clock.now();
+
+        let result = self.home(cancel);
+        let completed = result.as_ref().map(|report| report.completed);
+        self.record_operation(Operation::Home, started, outcome_of(&completed));
+        self.accumulate_state(0, self.clock.elapsed(started));
+        let report = result?;
+        span.record("completed", report.completed);
+        span.record(
+            "duration_ms",
+            self.clock.elapsed(started).as_millis() as u64,
+        );
+        Ok(report)
+    }
+
+    fn home(&mut self, cancel: &CancelToken) -> Result<HomingReport, DeviceError> {
+        self.reset_alarm_or_fault()?;
 
         // This will start the actual homing process
         info!("Starting to home servo: {}", self.servo_name);
-        self.write_register(125, 1);
-        std::thread::sleep(time::Duration::from_millis(1000));
-        self.write_register(EXECUTE_COMMAND, 120);
-        std::thread::sleep(time::Duration::from_millis(1000));
-
-        // Now we wait until homing is complete or a timer expires and bail.
-        let now = Instant::now();
-        while self.get_servo_status().contains(&HOMING.to_string()) {
-            info!("Servo status: {:?}", self.get_servo_status());
-            if self.get_servo_status().contains(&ALARM.to_string()) {
-                warn!("Got alarm during homing.  Trying to reset.");
-                self.reset_alarm_or_fault();
-                warn!("Restarting homing procedure.");
-                self.write_register(125, 1);
-                std::thread::sleep(time::Duration::from_millis(1000));
-                self.write_register(EXECUTE_COMMAND, 120);
-                std::thread::sleep(time::Duration::from_millis(1000));
-            }
-            // We will wait until max homing allowed time
-            if now.elapsed().as_secs() > MAX_HOMING_TIME {
-                warn!("!!Unable to finish homing procedure!!");
-                return;
+        self.start_homing()?;
+
+        let mut report = self.wait_for_homing_attempts(cancel)?;
+        if report.completed {
+            info!("Finished homing servo: {}", self.servo_name);
+            if let Some(offset) = self.motion_defaults.homing_offset {
+                self.move_to_homing_offset(offset, cancel, &mut report)?;
             }
-            std::thread::sleep(time::Duration::from_millis(300));
         }
+        Ok(report)
+    }
 
-        info!("Finished homing servo: {}", self.servo_name);
+    fn start_homing(&mut self) -> Result<(), DeviceError> {
+        self.write_register(PARAMETER_1, self.motion_defaults.homing_mode as u64)?;
+        self.clock.sleep(time::Duration::from_millis(1000));
+        self.execute(DriveCommand::StartHoming)?;
+        self.clock.sleep(time::Duration::from_millis(1000));
+        Ok(())
     }
 
-    pub fn move_servo(&mut self, accel: u64, decel: u64, velocity: u64, encoder_position: u64) {
-        if self.in_range(encoder_position) {
-            return;
-        }
+    // Waits until homing is complete, restarting it if an alarm shows up
+    // and the homing retry policy allows.  Returns false if homing failed,
+    // timed out or was cancelled.
+    pub fn wait_for_homing(&mut self, cancel: &CancelToken) -> Result<bool, DeviceError> {
+        Ok(self.wait_for_homing_attempts(cancel)?.completed)
+    }
 
-        // Setup our two move portions
-        let move1: u64 = encoder_position / MAX_32_BIT;
-        let move2: u64 = encoder_position % MAX_32_BIT;
+    pub fn move_servo(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+    ) -> Result<(), DeviceError> {
+        self.move_servo_with_cancel(
+            accel,
+            decel,
+            velocity,
+            encoder_position,
+            &CancelToken::new(),
+        )
+    }
 
-        info!(
-            "Moving to position: {} (move 1: {}, move 2: {})",
-            encoder_position, move1, move2
+    // Same as move_servo, but the wait for the move to finish is abandoned
+    // (and the axis stopped) as soon as the provided token is cancelled.
+    // Either way a move that doesn't reach its target is a MoveIncomplete
+    // error.
+    pub fn move_servo_with_cancel(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+        cancel: &CancelToken,
+    ) -> Result<(), DeviceError> {
+        let request = MoveRequest::new(accel, decel, velocity, encoder_position);
+        self.execute_move_with_cancel(&request, cancel)
+    }
+
+    pub fn execute_move(&mut self, request: &MoveRequest) -> Result<(), DeviceError> {
+        self.execute_move_with_cancel(request, &CancelToken::new())
+    }
+
+    pub fn execute_move_with_cancel(
+        &mut self,
+        request: &MoveRequest,
+        cancel: &CancelToken,
+    ) -> Result<(), DeviceError> {
+        let arrived = self.perform_move(request, cancel)?;
+        self.require_arrived(arrived, request.target)
+    }
+
+    // Fails a move that timed out, was cancelled or stopped short, once it
+    // has been stopped and recorded
+    pub(crate) fn require_arrived(&self, arrived: bool, target: u64) -> Result<(), DeviceError> {
+        if arrived {
+            return Ok(());
+        }
+        Err(DeviceError::MoveIncomplete(format!(
+            "{} did not reach {}",
+            self.servo_name,
+            feed::signed_count(target)
+        )))
+    }
+
+    // Runs a move with all its bookkeeping, returning true if the servo
+    // ended up at the (possibly retargeted) target
+    pub(crate) fn perform_move(
+        &mut self,
+        request: &MoveRequest,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let (accel, decel, velocity) = (request.accel, request.decel, request.velocity);
+        let encoder_position = request.target;
+        self.check_interlocks(InterlockAction::Move {
+            target: encoder_position,
+        })?;
+        let span = info_span!(
+            "move",
+            servo = %self.servo_name,
+            target = encoder_position,
+            velocity,
+            completed = field::Empty,
+            duration_ms = field::Empty
         );
+        let _enter = span.enter();
+        let started = self.clock.now();
+        let start_position = self.last_snapshot().encoder_count;
+        let io_before = self.io;
+        self.emit(|t| {
+            t.on_move_start(&MoveStart {
+                servo_name: self.servo_name.clone(),
+                accel,
+                decel,
+                velocity,
+                target: encoder_position,
+            })
+        });
+
+        let result = self.run_move(request, cancel);
+        let operation = Operation::Move {
+            accel,
+            decel,
+            velocity,
+            target: encoder_position,
+        };
+        self.record_operation(operation, started, outcome_of(&result));
+        let completed = *result.as_ref().unwrap_or(&false);
+        let end_position = self.last_snapshot().encoder_count;
+        // Only set once the move has actually been started
+        let commanded = self.move_target.is_some();
+        let target = self.move_target.take().unwrap_or(encoder_position);
+        let duration = self.clock.elapsed(started);
+        span.record("completed", completed);
+        span.record("duration_ms", duration.as_millis() as u64);
+
+        let travel = rollover::travel_between(start_position, end_position);
+        self.accumulate_state(travel, duration);
+        // A servo already in range didn't move, so there's nothing to count
+        let ended = result.as_ref().ok().copied();
+        if commanded || ended.is_none() {
+            self.record_move_stats(ended, duration, target, end_position);
+        }
+        self.record_move_io(io_before);
+
+        self.emit(|t| {
+            t.on_move_end(&MoveEnd {
+                servo_name: self.servo_name.clone(),
+                target,
+                actual: end_position,
+                completed,
+                duration,
+            })
+        });
+        result
+    }
+
+    // Performs the move, returning true if the servo ended up at the requested position
+    fn run_move(
+        &mut self,
+        request: &MoveRequest,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let encoder_position = request.target;
+        if self.in_range(encoder_position)? {
+            return Ok(true);
+        }
+
+        info!("Moving to position: {}", encoder_position);
 
         // Reset any possible faults, etc.
-        self.reset_alarm_or_fault();
+        self.reset_alarm_or_fault()?;
 
         // Setup the move parameter registers and let them settle
-        self.write_register(ACCELERATION, accel);
-        self.write_register(DECELERATION, decel);
-        self.write_register(VELOCITY, velocity);
-        self.write_register(DISTANCE_1, move1);
-        self.write_register(DISTANCE_2, move2);
-        std::thread::sleep(time::Duration::from_millis(25));
+        self.apply_profile(request.profile.as_ref())?;
+        self.move_velocity = request.velocity;
+        let velocity = self.override_velocity(request.velocity);
+        if !self.write_move_block(request.accel, request.decel, velocity, encoder_position)? {
+            self.write_register(ACCELERATION, request.accel)?;
+            self.write_register(DECELERATION, request.decel)?;
+            self.write_register(VELOCITY, velocity)?;
+            self.write_distance(encoder_position)?;
+            self.clock.sleep(time::Duration::from_millis(25));
 
-        info!(
-            "D1: {}, D2: {}",
-            self.get_register_value(DISTANCE_1),
-            self.get_register_value(DISTANCE_2)
-        );
+            info!(
+                "D1: {}, D2: {}",
+                self.get_register_value(DISTANCE_1)?,
+                self.get_register_value(DISTANCE_2)?
+            );
+        }
 
         // This will start the actual move
-        self.write_register(EXECUTE_COMMAND, 103);
-        std::thread::sleep(time::Duration::from_millis(10));
-
-        // We can wait until we are in position or freak out if we
-        // have not made it in time.
-        let now = Instant::now();
-        while self.get_servo_status().contains(&MOVING.to_string()) {
-            self.reset_alarm_or_fault();
-            if self.get_servo_status().contains(&IN_POSITION.to_string()) {
-                break;
-            }
-            if now.elapsed().as_secs() > MAX_MOVE_TIME {
-                error!("!!Unable to finish requested move!!");
-                break;
+        self.execute(DriveCommand::StartMove)?;
+        self.note_move_commanded();
+        self.begin_velocity_monitor(request.accel, request.decel);
+        self.trigger_scope();
+        self.move_target = Some(encoder_position);
+        self.set_in_motion(true);
+        self.clock.sleep(time::Duration::from_millis(10));
+
+        let finished = loop {
+            let finished = self.wait_for_move(cancel);
+            // A retarget that arrived as the move was finishing starts it up again
+            if matches!(finished, Ok(MoveWait::Finished)) && self.motion_control.has_requests() {
+                if let Err(e) = self.apply_motion_requests() {
+                    break Err(e);
+                }
+                continue;
             }
-            std::thread::sleep(time::Duration::from_millis(300));
-            //info!("Encoder count (MOVING): {}", self.get_encoder_count());
+            break finished;
+        };
+        self.end_velocity_monitor();
+        self.set_in_motion(false);
+        if finished? != MoveWait::Finished {
+            return Ok(false);
+        }
+        self.note_motion_ended();
+        self.last_settle_time = self.wait_for_settle(cancel)?;
+        if let Some(settle) = self.last_settle_time {
+            self.metrics.settle_time.record(settle);
         }
-        if !self.in_range(encoder_position) {
+
+        // The move may have been retargeted while under way
+        let encoder_position = self.move_target.unwrap_or(encoder_position);
+
+        if !self.in_range(encoder_position)? {
             warn!(
                 "Unable to reach requested encoder position of {} (actual: {})",
                 encoder_position,
-                self.get_encoder_count()
+                self.get_encoder_count()?
             );
+            Ok(false)
         } else {
             self.servo_cycle_count += 1;
-            info!("Encoder count (FINAL): {}", self.get_encoder_count(),);
+            let cycle_count = self.servo_cycle_count;
+            self.update_snapshot(|s| s.cycle_count = cycle_count);
+            info!("Encoder count (FINAL): {}", self.get_encoder_count()?);
+            Ok(true)
+        }
+    }
+
+    // Writes the target position into the two distance registers
+    fn write_distance(&mut self, encoder_position: u64) -> Result<(), DeviceError> {
+        info!("Distance: {}", encoder_position);
+        self.write_u32_ordered(DISTANCE_1, encoder_position as u32, WordOrder::HighFirst)
+    }
+
+    // We can wait until we are in position or freak out if we
+    // have not made it in time.  A move that is cancelled or runs out of
+    // time is stopped.
+    pub fn wait_for_move(&mut self, cancel: &CancelToken) -> Result<MoveWait, DeviceError> {
+        let mut now = self.clock.now();
+        loop {
+            let held = self.motion_control.is_held();
+            if !held {
+                if !self.has_status(MOVING)? {
+                    break;
+                }
+                self.note_motion_seen();
+            }
+            if cancel.is_cancelled() {
+                warn!("Move of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(MoveWait::Cancelled);
+            }
+            // Time spent held doesn't count towards the move's time limit
+            if self.apply_motion_requests()? {
+                now = self.clock.now();
+                self.restart_velocity_monitor();
+            }
+            let poll = if held {
+                time::Duration::from_millis(HOLD_POLL_INTERVAL)
+            } else {
+                self.reset_alarm_or_fault()?;
+                if self.has_status(IN_POSITION)? {
+                    break;
+                }
+                if self.clock.elapsed(now) > self.motion_defaults.move_timeout {
+                    error!("!!Unable to finish requested move!!");
+                    self.stop_motion()?;
+                    return Ok(MoveWait::TimedOut);
+                }
+                time::Duration::from_millis(300)
+            };
+            if !self.sleep_and_sample(poll, cancel)? {
+                warn!("Move of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(MoveWait::Cancelled);
+            }
+        }
+
+        Ok(MoveWait::Finished)
+    }
+
+    // Waits for In Position after motion has ended, returning how long it
+    // took to come up or None if it did not within MAX_SETTLE_TIME
+    fn wait_for_settle(
+        &mut self,
+        cancel: &CancelToken,
+    ) -> Result<Option<time::Duration>, DeviceError> {
+        let now = self.clock.now();
+        let limit = time::Duration::from_millis(MAX_SETTLE_TIME);
+        while !self.has_status(IN_POSITION)? {
+            if self.clock.elapsed(now) >= limit {
+                warn!("Servo {} did not settle in position", self.servo_name);
+                return Ok(None);
+            }
+            let poll = time::Duration::from_millis(SETTLE_POLL_INTERVAL);
+            if !self.sleep_unless_cancelled(poll, cancel) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(self.clock.elapsed(now)))
+    }
+
+    // Decelerates the servo to a stop, abandoning any move in progress
+    pub fn stop_motion(&mut self) -> Result<(), DeviceError> {
+        let span = info_span!("stop", servo = %self.servo_name);
+        let _enter = span.enter();
+        info!("Stopping servo: {}", self.servo_name);
+        let started = self.clock.now();
+        let result = self.execute(DriveCommand::Stop);
+        if result.is_ok() {
+            self.following = None;
+            self.jogging = None;
+        }
+        self.record_operation(
+            Operation::Stop,
+            started,
+            outcome_of(&result.as_ref().map(|_| true)),
+        );
+        result
+    }
+
+    // Sleeps for the given duration in small slices so a cancellation is
+    // noticed quickly, running any urgent calls between slices.  Returns
+    // false if the token was cancelled.
+    fn sleep_unless_cancelled(&mut self, duration: time::Duration, cancel: &CancelToken) -> bool {
+        let slice = time::Duration::from_millis(CANCEL_POLL_INTERVAL);
+        let now = self.clock.now();
+        while self.clock.elapsed(now) < duration {
+            self.run_urgent_jobs(cancel);
+            if cancel.is_cancelled() {
+                return false;
+            }
+            // Urgent jobs, or a coarse clock, may have used up the rest
+            let remaining = duration.saturating_sub(self.clock.elapsed(now));
+            if remaining.is_zero() {
+                break;
+            }
+            self.clock.sleep(slice.min(remaining));
+        }
+
+        !cancel.is_cancelled()
+    }
+
+    // Used by the homing and move waits in place of a plain sleep.  When a
+    // motion trace, data log or position stream is running, the wait is
+    // broken up so each gets samples at its own period.  Returns false if the token was
+    // cancelled.
+    pub(crate) fn sleep_and_sample(
+        &mut self,
+        duration: time::Duration,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let period = match self.sample_period() {
+            Some(p) => p,
+            None => return Ok(self.sleep_unless_cancelled(duration, cancel)),
+        };
+
+        let now = self.clock.now();
+        loop {
+            self.take_samples()?;
+            let elapsed = self.clock.elapsed(now);
+            if elapsed >= duration {
+                return Ok(true);
+            }
+            if !self.sleep_unless_cancelled(period.min(duration.saturating_sub(elapsed)), cancel) {
+                return Ok(false);
+            }
+        }
+    }
+
+    // The shortest period any of the samplers wants, if any are running
+    pub(crate) fn sample_period(&self) -> Option<time::Duration> {
+        [
+            self.trace_period(),
+            self.data_log_period(),
+            self.stream_period(),
+            self.velocity_monitor_period(),
+            self.scope_period(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()
+    }
+
+    // Reads position and status once and hands them to whichever of the
+    // motion trace, data log, streams, velocity monitor and scope want a sample
+    pub(crate) fn take_samples(&mut self) -> Result<(), DeviceError> {
+        let now = self.clock.now();
+        let wants_velocity = !self.velocity_streams.is_empty()
+            || self.velocity_monitor_due(now)
+            || self.scope_due(now);
+        let velocity = match wants_velocity {
+            true => Some(self.read_velocity()?),
+            false => None,
+        };
+        let sample = TraceSample {
+            status: self.get_servo_status()?.clone(),
+            encoder_count: self.get_encoder_count()?,
+            velocity,
+            at: self.clock.now(),
+        };
+        self.record_trace_sample(&sample);
+        self.record_stream_sample(&sample);
+        self.record_move_velocity(&sample);
+        self.record_scope_sample(&sample);
+        self.record_data_log(&sample);
+        Ok(())
+    }
+
+    // Whether a sampler last fed at `last` wants another sample.  A sample up
+    // to a tenth of a period early is accepted, so jitter in the time reads
+    // take does not make a sampler skip a whole period.
+    pub(crate) fn sample_due(last: Option<Instant>, period: time::Duration, at: Instant) -> bool {
+        match last {
+            Some(last) => at.saturating_duration_since(last) + period / 10 >= period,
+            None => true,
+        }
+    }
+
+    // Takes a sample if the data log or a position stream is due one.  Used
+    // by a spawned device's worker between jobs.
+    pub(crate) fn sample_if_due(&mut self) -> Result<(), DeviceError> {
+        let now = self.clock.now();
+        if self.data_log_due(now) || self.stream_due(now) {
+            self.take_samples()?;
         }
+        Ok(())
     }
 
     // Returns:
     //      TRUE if servo encoder position is with +/- range
-    // based on value of ENCODER_POSITION_RANGE
+    // based on the configured tolerance, ENCODER_POSITION_RANGE by default
     //      FALSE if it is not
-    pub fn in_range(&mut self, requested_pos: u64) -> bool {
-        let min_pos = requested_pos - ENCODER_POSITION_RANGE;
-        let max_pos = requested_pos + ENCODER_POSITION_RANGE;
-        let curr_pos: u64 = self.get_encoder_count();
+    // With rollover on, the requested position matches in any cycle
+    pub fn in_range(&mut self, requested_pos: u64) -> Result<bool, DeviceError> {
+        let curr_pos: u64 = self.get_encoder_count()?;
 
-        curr_pos >= min_pos && curr_pos <= max_pos
+        Ok(self.positions_within(curr_pos, requested_pos, self.motion_defaults.tolerance))
     }
 
-    pub fn initialize(&mut self) {
-        // TODO: Make this return bool true for success
-        self.write_register(125, 1);
-        std::thread::sleep(time::Duration::from_millis(1000));
-        self.write_register(EXECUTE_COMMAND, 120);
-        std::thread::sleep(time::Duration::from_millis(1000));
+    pub fn initialize(&mut self) -> Result<(), DeviceError> {
+        self.write_register(PARAMETER_1, 1)?;
+        self.clock.sleep(time::Duration::from_millis(1000));
+        self.execute(DriveCommand::StartHoming)?;
+        self.clock.sleep(time::Duration::from_millis(1000));
+        Ok(())
     }
 
     // Issues the disconnect commands to the device to allow for connection
     // by another client
-    pub fn shutdown(&mut self) {
+    pub fn shutdown(&mut self) -> Result<(), DeviceError> {
         info!("Issuing disconnect commands");
-        self.write_register(125, 1);
-        std::thread::sleep(time::Duration::from_millis(10));
+        self.write_register(PARAMETER_1, 1)?;
+        self.clock.sleep(time::Duration::from_millis(10));
 
-        self.write_register(EXECUTE_COMMAND, 254);
-        std::thread::sleep(time::Duration::from_millis(10));
+        self.execute(DriveCommand::Disconnect)?;
+        self.clock.sleep(time::Duration::from_millis(10));
 
-        self.write_register(125, 0);
-        std::thread::sleep(time::Duration::from_millis(10));
-        self.write_register(EXECUTE_COMMAND, 254);
-        std::thread::sleep(time::Duration::from_millis(10));
+        self.write_register(PARAMETER_1, 0)?;
+        self.clock.sleep(time::Duration::from_millis(10));
+        self.execute(DriveCommand::Disconnect)?;
+        self.clock.sleep(time::Duration::from_millis(10));
         info!("Done disconnecting.");
+        Ok(())
     }
 
-    pub fn write_register(&mut self, register: u16, value: u64) {
-        self.client
-            .write_single_register(register, value as u16)
-            .unwrap();
+    pub fn write_register(&mut self, register: u16, value: u64) -> Result<(), DeviceError> {
+        self.audit_write(register, value as u16)?;
+        self.send_write(register, value)
     }
 
-    pub fn get_register_value(&mut self, register: u16) -> u64 {
-        let ret = *self
-            .client
-            .read_holding_registers(register, 1)
-            .expect("IO Error")
-            .first()
-            .unwrap_or(&0);
+    // Writes a register that has already been through the audit log
+    pub(crate) fn send_write(&mut self, register: u16, value: u64) -> Result<(), DeviceError> {
+        let mut attempt = 1;
+        let first_started = self.clock.now();
+        loop {
+            let started = self.clock.now();
+            let result = self
+                .client
+                .set_read_timeout(self.timeouts.command.or(self.timeouts.read))
+                .and_then(|_| self.client.write_single_register(register, value as u16));
+            self.io.count_write(result.is_ok());
+            let took = self.clock.elapsed(started);
+            if let Some(log) = &mut self.wire_log {
+                log.record(
+                    &self.servo_name,
+                    ModbusFunction::WriteSingleRegister,
+                    register,
+                    &[value as u16],
+                    took,
+                    result.as_ref().err(),
+                );
+            }
+            // The drive has acted on an acknowledged write however long it
+            // took, so a late one is only worth a warning.  Failing it would
+            // see it sent again, running a command like a relative move twice.
+            if result.is_ok() {
+                self.warn_if_late(self.timeouts.command, started, register);
+            }
+            match result {
+                Ok(()) if self.verify_writes && register != EXECUTE_COMMAND => {
+                    return self.verify_write(register, value as u16)
+                }
+                Ok(()) => return Ok(()),
+                // The drive may have run a command whose reply was lost, or
+                // acknowledged one it is still running, so it is only sent
+                // again after the drive says it was too busy to take it
+                Err(e)
+                    if register == EXECUTE_COMMAND
+                        && !matches!(
+                            e,
+                            modbus::Error::Exception(modbus::ExceptionCode::SlaveOrServerBusy)
+                        ) =>
+                {
+                    return Err(exception::transaction_error(
+                        e,
+                        ModbusFunction::WriteSingleRegister,
+                        register,
+                    ))
+                }
+                // No further attempts once the command's time has run out
+                Err(e)
+                    if self
+                        .check_deadline(self.timeouts.command, first_started)
+                        .is_err() =>
+                {
+                    return Err(exception::transaction_error(
+                        e,
+                        ModbusFunction::WriteSingleRegister,
+                        register,
+                    ))
+                }
+                Err(e) => {
+                    self.retry_or_fail(e, ModbusFunction::WriteSingleRegister, register, attempt)?
+                }
+            }
+            attempt += 1;
+        }
+    }
 
-        ret as u64
+    // Reads a register straight back after writing it.  The command register
+    // is left out, since writing it runs the command rather than storing it.
+    fn verify_write(&mut self, register: u16, written: u16) -> Result<(), DeviceError> {
+        let read = *self.read_registers(register, 1)?.first().unwrap_or(&0);
+        if read != written {
+            error!(
+                "Register {} of {} reads back {} after writing {}",
+                register, self.servo_name, read, written
+            );
+            return Err(DeviceError::WriteMismatch {
+                register,
+                written,
+                read,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_register_value(&mut self, register: u16) -> Result<u64, DeviceError> {
+        let ret = *self.read_registers(register, 1)?.first().unwrap_or(&0);
+
+        Ok(ret as u64)
     }
 
-    pub fn dump_registers(&mut self) {
-        info!("Dumping registers up to {}", MAX_REGISTER);
-        for (n, i) in self
-            .client
-            .read_holding_registers(0, MAX_REGISTER)
-            .expect("IO Error")
-            .iter()
-            .enumerate()
-        {
-            info!("Register {}: {}", n, i);
+    // Every register read goes through here
+    fn read_registers(&mut self, register: u16, count: u16) -> Result<Vec<u16>, DeviceError> {
+        let mut attempt = 1;
+        loop {
+            let limit = match register {
+                r if r == STATUS_REG || r == ALARM_REG => self.timeouts.status_poll,
+                r if r == ENCODER_POS_1_REG || r == ENCODER_POS_2_REG => self.timeouts.status_poll,
+                _ => None,
+            };
+            let started = self.clock.now();
+            let result = self
+                .client
+                .set_read_timeout(limit.or(self.timeouts.read))
+                .and_then(|_| self.client.read_holding_registers(register, count));
+            self.io.count_read(result.as_ref().ok().map(|v| v.len()));
+            let took = self.clock.elapsed(started);
+            if let Some(log) = &mut self.wire_log {
+                let values = match &result {
+                    Ok(v) => v.as_slice(),
+                    Err(_) => &[],
+                };
+                log.record(
+                    &self.servo_name,
+                    ModbusFunction::ReadHoldingRegisters,
+                    register,
+                    values,
+                    took,
+                    result.as_ref().err(),
+                );
+            }
+            // The socket's read timeout enforces the limit, so a reply that
+            // made it in time but was slow overall is still worth using
+            if result.is_ok() {
+                self.warn_if_late(limit, started, register);
+            }
+            match result {
+                Ok(values) => return Ok(values),
+                Err(e) => {
+                    self.retry_or_fail(e, ModbusFunction::ReadHoldingRegisters, register, attempt)?
+                }
+            }
+            attempt += 1;
         }
-        info!("Done reading registers.");
     }
 
-    pub fn get_name(&mut self) -> &String {
+    // Fails a transaction that came back later than its per transaction limit
+    fn check_deadline(
+        &self,
+        limit: Option<time::Duration>,
+        started: Instant,
+    ) -> modbus::Result<()> {
+        let took = self.clock.elapsed(started);
+        match limit {
+            Some(limit) if took > limit => Err(modbus::Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Transaction took {:?}, limit is {:?}", took, limit),
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn warn_if_late(
+        &self,
+        limit: Option<time::Duration>,
+        started: Instant,
+        register: u16,
+    ) {
+        if let Err(e) = self.check_deadline(limit, started) {
+            warn!(
+                "Transaction on register {} of {} was answered late: {}",
+                register, self.servo_name, e
+            );
+        }
+    }
+
+    // Decides what to do about a failed transaction.  Returns Ok (after
+    // backing off) if it should be tried again, or the error to give up with.
+    fn retry_or_fail(
+        &mut self,
+        error: modbus::Error,
+        function: ModbusFunction,
+        register: u16,
+        attempt: u32,
+    ) -> Result<(), DeviceError> {
+        if let Some(reason) = replay::replay_failure(&error) {
+            return Err(DeviceError::ReplayDiverged(reason));
+        }
+        // A dropped session is only put down to another client once
+        // retrying over a new connection hasn't helped
+        let dropped = seizure::is_seizure(&error);
+        // Pooled connections are shared by every drive behind the gateway,
+        // so losing one says nothing about this drive being taken over
+        let seized = dropped && self.pool.is_none();
+        if attempt >= self.retry_policy.max_attempts || !self.retry_policy.is_retryable(&error) {
+            if seized {
+                return self.handle_seizure(error, attempt);
+            }
+            return Err(exception::transaction_error(error, function, register));
+        }
+
+        self.io.retries += 1;
+        let backoff = self.retry_policy.backoff(attempt);
+        warn!(
+            "Transaction on register {} of {} failed (attempt {}): {}, retrying in {:?}",
+            register, self.servo_name, attempt, error, backoff
+        );
+        self.clock.sleep(backoff);
+        if dropped {
+            if let Err(e) = self.reconnect() {
+                warn!("Unable to reconnect to {}: {}", self.servo_name, e);
+                if seized {
+                    return self.handle_seizure(error, attempt);
+                }
+                return Err(exception::transaction_error(error, function, register));
+            }
+        }
+        Ok(())
+    }
+
+    // Sets how failed register reads and writes are retried
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    // Reads back every register written and fails with WriteMismatch when
+    // the drive holds something else, for gateways that drop writes
+    pub fn set_write_verification(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
+
+    pub fn get_write_verification(&self) -> bool {
+        self.verify_writes
+    }
+
+    // The span naming this servo and its address.  Everything a spawned
+    // device or a group does runs inside it, and the operations within add
+    // spans of their own (home, move, ...), so each log line says which
+    // servo and operation it came from.  Enter it around direct calls to get
+    // the same:
+    //
+    //   let _enter = device.get_log_span().clone().entered();
+    pub fn get_log_span(&self) -> &tracing::Span {
+        &self.log_span
+    }
+
+    pub fn get_name(&self) -> &String {
         &self.servo_name
     }
 
-    pub fn get_address(&mut self) -> &String {
+    // The address the current connection was made to
+    pub fn get_address(&self) -> &String {
         &self.servo_address
     }
 
-    pub fn get_resource_location(&mut self) -> &String {
+    // Every address configured for the drive, in the order they are tried
+    pub fn get_addresses(&self) -> &Vec<String> {
+        &self.servo_addresses
+    }
+
+    pub fn get_resource_location(&self) -> &String {
         &self.resource_location
     }
 
-    pub fn new(device_name: String, servo_name: String) -> Result<AppliedDevice, String> {
-        // The tcp_config object will let us specify a timeout
-        //let mut tcp_config = tcp::Config::default();
-        //tcp_config.tcp_connect_timeout = Some(time::Duration::from_millis(1000));
-        let tcp_config = modbus::Config {
-            tcp_connect_timeout: Some(time::Duration::from_millis(1000)),
-            ..Default::default()
+    // Drops the current connection and opens a new one, trying each
+    // configured address in order
+    pub fn reconnect(&mut self) -> Result<(), DeviceError> {
+        info!("Reconnecting to device {}", self.servo_name);
+        // The pool replaces broken connections itself
+        if let Some((pool, unit_id)) = &self.pool {
+            self.client = Box::new(pool.transport(*unit_id));
+            self.emit(|t| t.on_reconnect(&self.servo_name, &self.servo_address));
+            return Ok(());
+        }
+        let addresses = if self.servo_addresses.is_empty() {
+            vec![self.servo_address.clone()]
+        } else {
+            self.servo_addresses.clone()
         };
+        // Kept to the same rate limit as the connection it replaces
+        let (address, client) = AppliedDevice::open_connection(
+            &addresses,
+            &self.timeouts,
+            self.min_request_gap,
+            self.clock.clone(),
+        )?;
+        self.client = client;
+        self.servo_address = address;
+        self.log_span = device_span(&self.servo_name, &self.servo_address);
+        self.emit(|t| t.on_reconnect(&self.servo_name, &self.servo_address));
+
+        Ok(())
+    }
 
-        // The resource location is pretty standard
-        let resource_location: String = format!("./thingy/resources/{}.yaml", device_name);
-        info!("Creating applied device: {}", servo_name);
-        info!("Using device configuration at: {}", resource_location);
-        let file = match File::open(resource_location.clone()) {
-            Ok(f) => f,
-            Err(e) => return Err(format!("Unable to read device config: {}", e)),
+    fn connect(address: &str, timeouts: &ModbusTimeouts) -> Result<TcpTransport, DeviceError> {
+        let (host, port) = match validate::parse_address(address) {
+            Some(parsed) => parsed,
+            None => {
+                return Err(DeviceError::Connection(format!(
+                    "{} is not an IP address or host name",
+                    address
+                )))
+            }
         };
+        // A bare IPv6 host is fine here, the port is given separately
+        match TcpTransport::connect(&host, port, timeouts) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(DeviceError::Connection(format!(
+                "Unable to create TCP connection: {}",
+                e
+            ))),
+        }
+    }
 
-        let mut buf_reader = BufReader::new(file);
-        let mut contents = String::new();
-        buf_reader
-            .read_to_string(&mut contents)
-            .expect("Unable to read input file.");
+    // Tries each address in turn, returning the first that connects
+    fn connect_any(
+        addresses: &[String],
+        timeouts: &ModbusTimeouts,
+    ) -> Result<(String, TcpTransport), DeviceError> {
+        let mut failures = Vec::new();
+        for address in addresses {
+            info!("Connecting to device at {}", address);
+            match AppliedDevice::connect(address, timeouts) {
+                Ok(client) => {
+                    if !failures.is_empty() {
+                        warn!(
+                            "Connected to fallback address {} after {} failed",
+                            address,
+                            failures.len()
+                        );
+                    }
+                    return Ok((address.clone(), client));
+                }
+                Err(e) => {
+                    warn!("Unable to connect to {}: {}", address, e);
+                    failures.push(format!("{}: {}", address, e));
+                }
+            }
+        }
+        Err(DeviceError::Connection(format!(
+            "No address could be reached ({})",
+            failures.join("; ")
+        )))
+    }
 
-        let device_yaml = match YamlLoader::load_from_str(&contents) {
-            Ok(y) => y,
-            Err(e) => return Err(format!("Unable to parse config file: {}", e)),
+    // Opens the TCP connection to a drive through the first of its
+    // addresses that answers, rate limited if a minimum gap between
+    // requests is given.  Returns the address used.
+    fn open_connection(
+        addresses: &[String],
+        timeouts: &ModbusTimeouts,
+        min_request_gap: Option<time::Duration>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<(String, Box<dyn Transport>), DeviceError> {
+        let (address, client) = AppliedDevice::connect_any(addresses, timeouts)?;
+        let client: Box<dyn Transport> = match min_request_gap {
+            Some(gap) => Box::new(RateLimitedTransport::new(client, gap).with_clock(clock)),
+            None => Box::new(client),
         };
+        Ok((address, client))
+    }
 
-        let device_conf = &device_yaml[0];
+    // device_name is either the name of a config in the config directory or
+    // the absolute path of one.  See AppliedDeviceBuilder::config_dir.
+    pub fn new(device_name: String, servo_name: String) -> Result<AppliedDevice, DeviceError> {
+        AppliedDevice::builder(device_name, servo_name).build()
+    }
 
-        // Get device coupler information from the device config yaml and connect to the coupler
-        let coupler_raw = &device_conf["device"][servo_name.as_str()];
-        let coupler = match coupler_raw.as_str() {
-            Some(s) => s,
-            _ => {
-                warn!("Using default coupler IP of 127.0.0.1");
-                "127.0.0.1"
-            }
-        };
+    pub fn builder(device_name: String, servo_name: String) -> AppliedDeviceBuilder {
+        AppliedDeviceBuilder::new(device_name, servo_name)
+    }
 
-        info!("Connecting to device at {}", coupler);
-        let client = match tcp::Transport::new_with_cfg(coupler, tcp_config) {
-            Ok(c) => c,
-            Err(e) => return Err(format!("Unable to create TCP connection: {}", e)),
-        };
+    // Connects using a config held in memory instead of a file on disk
+    pub fn from_yaml_str(contents: &str, servo_name: String) -> Result<AppliedDevice, DeviceError> {
+        AppliedDevice::from_config(DeviceConfig::from_yaml_str(contents)?, servo_name)
+    }
 
-        Ok(AppliedDevice {
-            servo_name: servo_name.to_string(),
-            servo_address: coupler.to_string(),
-            client,
-            resource_location,
+    pub fn from_config(
+        config: DeviceConfig,
+        servo_name: String,
+    ) -> Result<AppliedDevice, DeviceError> {
+        AppliedDeviceBuilder::new(String::new(), servo_name)
+            .config(config)
+            .build()
+    }
+
+    // Creates a device that talks through the given transport instead of
+    // opening its own TCP connection (e.g. a recording or replay transport).
+    pub fn with_transport<T: Transport + 'static>(
+        servo_name: String,
+        servo_address: String,
+        transport: T,
+    ) -> AppliedDevice {
+        let log_span = device_span(&servo_name, &servo_address);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        AppliedDevice {
+            servo_name,
+            servo_addresses: Vec::new(),
+            pool: None,
+            servo_address,
+            log_span,
+            client: Box::new(transport),
+            resource_location: String::new(),
             servo_status: Vec::new(),
             servo_alarm: Vec::new(),
             servo_cycle_count: 0i64,
-        })
+            snapshot: Default::default(),
+            telemetry: Vec::new(),
+            wire_log: None,
+            stats: stats::StatsCollector::new(clock.now()),
+            clock,
+            retry_policy: RetryPolicy::default(),
+            timeouts: ModbusTimeouts::default(),
+            min_request_gap: None,
+            verify_writes: false,
+            opcodes: OpcodeTable::new(),
+            register_map: RegisterMap::new(),
+            combined_move_writes: false,
+            word_order: WordOrder::default(),
+            audit: None,
+            command_reason: None,
+            history: Default::default(),
+            persistent: Default::default(),
+            state_store: None,
+            maintenance: Default::default(),
+            last_settle_time: None,
+            metrics: Default::default(),
+            move_timings: Default::default(),
+            io: Default::default(),
+            trace: None,
+            data_log: None,
+            position_streams: Vec::new(),
+            velocity_streams: Vec::new(),
+            velocity_monitor: None,
+            scope: None,
+            position_watches: Vec::new(),
+            motion_control: Default::default(),
+            move_target: None,
+            move_velocity: 0,
+            following: None,
+            jogging: None,
+            limit_search_travel: None,
+            jerk_filter: None,
+            default_profile: Default::default(),
+            blending_supported: false,
+            input_filters: BTreeMap::new(),
+            following_error_warning: None,
+            monitor_following_error: false,
+            stall_mode: StallMode::Off,
+            steps_per_rev: None,
+            encoder_counts_per_rev: None,
+            absolute_encoder: false,
+            skip_homing: false,
+            rollover: None,
+            motion_defaults: MotionDefaults::default(),
+            reacquire_on_seizure: false,
+            lock: None,
+            interlocks: Vec::new(),
+            urgent_jobs: None,
+            watchdog: None,
+            volatile: recovery::VolatileSettings::default(),
+            initial_state_report: None,
+        }
+    }
+
+    // Replaces the clock used for every wait and timeout, e.g. with a
+    // ManualClock shared with a SimulatedDrive in tests.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        self.reset_stats();
     }
 }
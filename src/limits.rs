@@ -0,0 +1,157 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister, ALARM_REG};
+use tracing::info;
+use yaml_rust::Yaml;
+
+// Alarm register bits the drive raises while an end of travel switch is tripped
+static CCW_LIMIT_BIT: u64 = 1 << 1;
+static CW_LIMIT_BIT: u64 = 1 << 2;
+
+// Values of the limit mode register, as for the drive's "DL" command
+static LIMITS_ACTIVE_CLOSED: u64 = 1;
+static LIMITS_ACTIVE_OPEN: u64 = 2;
+static LIMITS_DISABLED: u64 = 3; // The inputs are general purpose
+
+// How the drive treats its end of travel inputs.  A normally closed switch
+// trips the limit when its input opens, so a broken wire stops the axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimitSwitchConfig {
+    pub enabled: bool,
+    pub normally_closed: bool,
+    pub cw_input: u16, // Drive input number, 1 for X1
+    pub ccw_input: u16,
+}
+
+// Which end of travel switches are tripped
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LimitState {
+    pub cw_active: bool,
+    pub ccw_active: bool,
+}
+
+impl LimitState {
+    pub(crate) fn from_alarm_register(alarms: u64) -> LimitState {
+        LimitState {
+            cw_active: alarms & CW_LIMIT_BIT != 0,
+            ccw_active: alarms & CCW_LIMIT_BIT != 0,
+        }
+    }
+
+    pub fn any_active(&self) -> bool {
+        self.cw_active || self.ccw_active
+    }
+}
+
+impl LimitSwitchConfig {
+    // Reads the optional `limit_switches:` section of a device config.
+    // Inputs must be given when the limits are enabled.
+    //
+    //   limit_switches:
+    //     enabled: true
+    //     normally_closed: true
+    //     cw_input: 6
+    //     ccw_input: 7
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<LimitSwitchConfig>, DeviceError> {
+        let section = &device_conf["limit_switches"];
+        match section {
+            Yaml::Hash(_) => {}
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "limit_switches must be a map, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        let enabled = limit_flag(section, "enabled", true)?;
+        let config = LimitSwitchConfig {
+            enabled,
+            normally_closed: limit_flag(section, "normally_closed", false)?,
+            cw_input: limit_input(section, "cw_input", enabled)?,
+            ccw_input: limit_input(section, "ccw_input", enabled)?,
+        };
+        Ok(Some(config))
+    }
+
+    fn mode(&self) -> u64 {
+        match (self.enabled, self.normally_closed) {
+            (false, _) => LIMITS_DISABLED,
+            (true, true) => LIMITS_ACTIVE_OPEN,
+            (true, false) => LIMITS_ACTIVE_CLOSED,
+        }
+    }
+}
+
+fn limit_flag(section: &Yaml, key: &str, default: bool) -> Result<bool, DeviceError> {
+    match &section[key] {
+        Yaml::BadValue | Yaml::Null => Ok(default),
+        Yaml::Boolean(b) => Ok(*b),
+        other => Err(DeviceError::Config(format!(
+            "limit_switches.{} must be true or false, got {:?}",
+            key, other
+        ))),
+    }
+}
+
+fn limit_input(section: &Yaml, key: &str, required: bool) -> Result<u16, DeviceError> {
+    match &section[key] {
+        Yaml::BadValue | Yaml::Null if !required => Ok(0),
+        Yaml::Integer(n) if (1..=16).contains(n) => Ok(*n as u16),
+        other => Err(DeviceError::Config(format!(
+            "limit_switches.{} must be an input number from 1 to 16, got {:?}",
+            key, other
+        ))),
+    }
+}
+
+impl AppliedDevice {
+    pub fn limit_switches(&mut self) -> Result<LimitState, DeviceError> {
+        let limits = LimitState::from_alarm_register(self.get_register_value(ALARM_REG)?);
+        self.update_snapshot(|s| s.limits = limits);
+        Ok(limits)
+    }
+
+    // Sets up the drive's end of travel inputs
+    pub fn set_limit_switch_config(
+        &mut self,
+        config: &LimitSwitchConfig,
+    ) -> Result<(), DeviceError> {
+        if config.enabled
+            && (!(1..=16).contains(&config.cw_input) || !(1..=16).contains(&config.ccw_input))
+        {
+            return Err(DeviceError::Config(format!(
+                "Limit switch inputs of {} must be from 1 to 16",
+                self.servo_name
+            )));
+        }
+
+        info!(
+            "Setting limit switches of {} to {:?}",
+            self.servo_name, config
+        );
+        self.write_register(self.mapped(MappedRegister::LimitMode)?, config.mode())?;
+        if config.enabled {
+            self.write_register(
+                self.mapped(MappedRegister::LimitCwInput)?,
+                config.cw_input as u64,
+            )?;
+            self.write_register(
+                self.mapped(MappedRegister::LimitCcwInput)?,
+                config.ccw_input as u64,
+            )?;
+        }
+        self.volatile.limit_switches = Some(*config);
+        Ok(())
+    }
+
+    // Reads the limit switch setup back from the drive
+    pub fn get_limit_switch_config(&mut self) -> Result<LimitSwitchConfig, DeviceError> {
+        let mode = self.get_register_value(self.mapped(MappedRegister::LimitMode)?)?;
+        Ok(LimitSwitchConfig {
+            enabled: mode == LIMITS_ACTIVE_CLOSED || mode == LIMITS_ACTIVE_OPEN,
+            normally_closed: mode == LIMITS_ACTIVE_OPEN,
+            cw_input: self.get_register_value(self.mapped(MappedRegister::LimitCwInput)?)? as u16,
+            ccw_input: self.get_register_value(self.mapped(MappedRegister::LimitCcwInput)?)? as u16,
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config::maintenance_number;
+use crate::{AppliedDevice, DeviceError};
+use std::fmt;
+use tracing::{info, warn};
+use yaml_rust::Yaml;
+
+// The persistent counters a maintenance interval can be measured in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaintenanceCounter {
+    Cycles,
+    Travel, // Encoder counts
+    RuntimeHours,
+}
+
+impl fmt::Display for MaintenanceCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MaintenanceCounter::Cycles => write!(f, "cycles"),
+            MaintenanceCounter::Travel => write!(f, "travel"),
+            MaintenanceCounter::RuntimeHours => write!(f, "runtime hours"),
+        }
+    }
+}
+
+// A recurring maintenance job, due once any of its intervals has passed
+// since it was last marked as serviced
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaintenanceTask {
+    pub name: String,
+    pub every_cycles: Option<i64>,
+    pub every_travel: Option<u64>,
+    pub every_runtime_hours: Option<f64>,
+}
+
+impl MaintenanceTask {
+    pub fn new(name: &str) -> MaintenanceTask {
+        MaintenanceTask {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Reads the optional `maintenance:` section of a device config.  Each
+    // entry names a task and gives one or more intervals.
+    //
+    //   maintenance:
+    //     regrease:
+    //       cycles: 500000
+    //     inspect_belt:
+    //       travel: 2000000
+    //       runtime_hours: 1000
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Vec<MaintenanceTask>, DeviceError> {
+        let section = match &device_conf["maintenance"] {
+            Yaml::Hash(h) => h,
+            Yaml::BadValue | Yaml::Null => return Ok(Vec::new()),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "maintenance must be a map of tasks, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut tasks = Vec::new();
+        for (name, intervals) in section {
+            let name = match name.as_str() {
+                Some(n) => n,
+                None => {
+                    return Err(DeviceError::Config(format!(
+                        "maintenance task names must be strings, got {:?}",
+                        name
+                    )))
+                }
+            };
+            let task = MaintenanceTask {
+                name: name.to_string(),
+                every_cycles: maintenance_number(intervals, name, "cycles")?.map(|n| n as i64),
+                every_travel: maintenance_number(intervals, name, "travel")?.map(|n| n as u64),
+                every_runtime_hours: maintenance_number(intervals, name, "runtime_hours")?,
+            };
+            if task.intervals().is_empty() {
+                return Err(DeviceError::Config(format!(
+                    "maintenance task {} needs cycles, travel or runtime_hours",
+                    name
+                )));
+            }
+            tasks.push(task);
+        }
+        Ok(tasks)
+    }
+
+    fn intervals(&self) -> Vec<(MaintenanceCounter, f64)> {
+        let mut intervals = Vec::new();
+        if let Some(n) = self.every_cycles {
+            intervals.push((MaintenanceCounter::Cycles, n as f64));
+        }
+        if let Some(n) = self.every_travel {
+            intervals.push((MaintenanceCounter::Travel, n as f64));
+        }
+        if let Some(n) = self.every_runtime_hours {
+            intervals.push((MaintenanceCounter::RuntimeHours, n));
+        }
+        intervals
+    }
+}
+
+// Raised when a maintenance task's interval has passed
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceDue {
+    pub servo_name: String,
+    pub task: String,
+    pub counter: MaintenanceCounter,
+    pub since_service: f64, // How far the counter has moved since the task was last serviced
+    pub interval: f64,
+}
+
+// The counter values at the time a task was last serviced
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServiceRecord {
+    pub task: String,
+    pub cycle_count: i64,
+    pub total_travel: u64,
+    pub runtime_hours: f64,
+}
+
+#[derive(Default)]
+pub(crate) struct MaintenanceSchedule {
+    tasks: Vec<MaintenanceTask>,
+}
+
+impl AppliedDevice {
+    pub fn add_maintenance_task(&mut self, task: MaintenanceTask) {
+        self.maintenance.tasks.retain(|t| t.name != task.name);
+        self.maintenance.tasks.push(task);
+    }
+
+    pub fn get_maintenance_tasks(&self) -> &Vec<MaintenanceTask> {
+        &self.maintenance.tasks
+    }
+
+    // Every task whose interval has passed, one entry per overdue counter
+    pub fn maintenance_due(&self) -> Vec<MaintenanceDue> {
+        let mut due = Vec::new();
+        for task in &self.maintenance.tasks {
+            let serviced = self.service_record(&task.name);
+            for (counter, interval) in task.intervals() {
+                let since_service = match counter {
+                    MaintenanceCounter::Cycles => {
+                        (self.servo_cycle_count - serviced.cycle_count) as f64
+                    }
+                    MaintenanceCounter::Travel => {
+                        self.persistent
+                            .total_travel
+                            .saturating_sub(serviced.total_travel) as f64
+                    }
+                    MaintenanceCounter::RuntimeHours => {
+                        self.persistent.runtime_hours - serviced.runtime_hours
+                    }
+                };
+                if since_service >= interval {
+                    due.push(MaintenanceDue {
+                        servo_name: self.servo_name.clone(),
+                        task: task.name.clone(),
+                        counter,
+                        since_service,
+                        interval,
+                    });
+                }
+            }
+        }
+        due
+    }
+
+    // Restarts the task's intervals from the current counters.  The record
+    // is saved straight away when a state file is in use.
+    pub fn mark_serviced(&mut self, task: &str) -> Result<(), DeviceError> {
+        if !self.maintenance.tasks.iter().any(|t| t.name == task) {
+            return Err(DeviceError::Config(format!(
+                "No maintenance task named {} on {}",
+                task, self.servo_name
+            )));
+        }
+
+        info!("Maintenance task {} serviced on {}", task, self.servo_name);
+        let record = ServiceRecord {
+            task: task.to_string(),
+            cycle_count: self.servo_cycle_count,
+            total_travel: self.persistent.total_travel,
+            runtime_hours: self.persistent.runtime_hours,
+        };
+        self.persistent.serviced.retain(|r| r.task != task);
+        self.persistent.serviced.push(record);
+        self.persistent.raised.retain(|t| t != task);
+        self.flush_state()
+    }
+
+    fn service_record(&self, task: &str) -> ServiceRecord {
+        match self.persistent.serviced.iter().find(|r| r.task == task) {
+            Some(r) => r.clone(),
+            None => ServiceRecord::default(),
+        }
+    }
+
+    // Reports tasks that have newly become due to the telemetry sinks.  The
+    // tasks raised are kept with the counters, so a restart doesn't raise
+    // them all again.
+    pub(crate) fn check_maintenance(&mut self) {
+        for due in self.maintenance_due() {
+            if !self.persistent.raised.contains(&due.task) {
+                self.persistent.raised.push(due.task.clone());
+                warn!(
+                    "Maintenance task {} is due on {}: {} {:.1} since service (every {})",
+                    due.task, due.servo_name, due.counter, due.since_service, due.interval
+                );
+                self.emit(|t| t.on_maintenance_due(&due));
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{AppliedDevice, RunningStats};
+use std::time::{Duration, Instant};
+
+static MBAP_HEADER: u64 = 7; // Bytes in the Modbus TCP header of every frame
+static REQUEST_PDU: u64 = 5; // Function, register and count or value
+
+// Upper bounds of the histogram buckets, in ms.  Anything longer than the
+// last goes in an overflow bucket.
+static BUCKET_BOUNDS: &[u64] = &[
+    1, 2, 5, 10, 20, 50, 100, 200, 300, 500, 1000, 2000, 5000, 10000, 30000,
+];
+
+// Counts of durations in fixed buckets, for seeing how timings spread
+// rather than just their average
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: Duration,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    counts: Vec<u64>, // One per bound in BUCKET_BOUNDS, then the overflow
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram {
+            count: 0,
+            sum: Duration::ZERO,
+            min: None,
+            max: None,
+            counts: vec![0; BUCKET_BOUNDS.len() + 1],
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, sample: Duration) {
+        let ms = sample.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += sample;
+        self.min = Some(self.min.map_or(sample, |min| min.min(sample)));
+        self.max = Some(self.max.map_or(sample, |max| max.max(sample)));
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum / self.count as u32)
+    }
+
+    // Each bucket's upper bound and how many samples fell in it.  The last
+    // bucket, with no bound, holds everything longer than the others.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        let bounds = BUCKET_BOUNDS
+            .iter()
+            .map(|ms| Some(Duration::from_millis(*ms)))
+            .chain(std::iter::once(None));
+        bounds.zip(self.counts.iter().copied()).collect()
+    }
+
+    // An estimate of the given percentile (0 to 100): the upper bound of the
+    // bucket it falls in, or the longest sample if that is sooner
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let max = self.max?;
+        let wanted = ((percentile.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= wanted.max(1) {
+                return Some(bound.map_or(max, |bound| bound.min(max)));
+            }
+        }
+        Some(max)
+    }
+}
+
+// Where the time goes in a move, for seeing what the polling intervals add
+// to cycle time.  Moves too short to ever be read as Moving are left out of
+// the first two.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MoveMetrics {
+    pub motion_start_latency: Histogram, // From the move command until Moving is first read
+    pub motion_duration: Histogram,      // From Moving first being read until it clears
+    pub settle_time: Histogram,          // From Moving clearing until In Position
+    pub round_trips_per_move: RunningStats,
+    pub bytes_per_move: RunningStats, // Sent and received
+}
+
+// Modbus traffic since the device was created, counting every attempt
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoCounters {
+    pub reads: u64,
+    pub writes: u64,
+    pub retries: u64,
+    pub bytes_sent: u64, // Whole Modbus TCP frames, headers included
+    pub bytes_received: u64,
+}
+
+impl IoCounters {
+    pub fn round_trips(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    // `values` is how many registers came back, None if the read failed
+    pub(crate) fn count_read(&mut self, values: Option<usize>) {
+        self.reads += 1;
+        self.bytes_sent += MBAP_HEADER + REQUEST_PDU;
+        if let Some(values) = values {
+            self.bytes_received += MBAP_HEADER + 2 + 2 * values as u64;
+        }
+    }
+
+    // A write of `written` registers and read of however many came back
+    pub(crate) fn count_write_read(&mut self, written: usize, values: Option<usize>) {
+        self.writes += 1;
+        self.bytes_sent += MBAP_HEADER + REQUEST_PDU + 5 + 2 * written as u64;
+        if let Some(values) = values {
+            self.bytes_received += MBAP_HEADER + 2 + 2 * values as u64;
+        }
+    }
+
+    pub(crate) fn count_write(&mut self, answered: bool) {
+        self.writes += 1;
+        self.bytes_sent += MBAP_HEADER + REQUEST_PDU;
+        if answered {
+            // The drive echoes the request
+            self.bytes_received += MBAP_HEADER + REQUEST_PDU;
+        }
+    }
+}
+
+// When the move under way was commanded and first seen moving
+#[derive(Default)]
+pub(crate) struct MoveTimings {
+    commanded: Option<Instant>,
+    motion_started: Option<Instant>,
+}
+
+impl AppliedDevice {
+    pub fn metrics(&self) -> MoveMetrics {
+        self.metrics.clone()
+    }
+
+    pub fn reset_metrics(&mut self) {
+        self.metrics = MoveMetrics::default();
+    }
+
+    pub fn io_counters(&self) -> IoCounters {
+        self.io
+    }
+
+    // Adds the traffic of a finished move, given the counters from its start
+    pub(crate) fn record_move_io(&mut self, before: IoCounters) {
+        let round_trips = self.io.round_trips() - before.round_trips();
+        let bytes = self.io.bytes() - before.bytes();
+        self.metrics.round_trips_per_move.add(round_trips as f64);
+        self.metrics.bytes_per_move.add(bytes as f64);
+    }
+
+    pub(crate) fn note_move_commanded(&mut self) {
+        self.move_timings = MoveTimings {
+            commanded: Some(self.clock.now()),
+            motion_started: None,
+        };
+    }
+
+    // Called each time Moving is read, only the first counts
+    pub(crate) fn note_motion_seen(&mut self) {
+        if self.move_timings.commanded.is_some() && self.move_timings.motion_started.is_none() {
+            self.move_timings.motion_started = Some(self.clock.now());
+        }
+    }
+
+    // Called once Moving has cleared
+    pub(crate) fn note_motion_ended(&mut self) {
+        let timings = std::mem::take(&mut self.move_timings);
+        if let (Some(commanded), Some(started)) = (timings.commanded, timings.motion_started) {
+            let duration = self.clock.elapsed(started);
+            let metrics = &mut self.metrics;
+            metrics
+                .motion_start_latency
+                .record(started.saturating_duration_since(commanded));
+            metrics.motion_duration.record(duration);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{AppliedDevice, Clock, DeviceError, LimitState};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// The most recently read state of a servo.  This is updated every time the
+// device reads its status, alarms or encoder position.
+#[derive(Clone, Debug, Default)]
+pub struct StatusSnapshot {
+    pub status: Vec<String>,
+    pub alarms: Vec<String>,
+    pub encoder_count: u64,
+    pub cycle_count: i64,
+    pub limits: LimitState,       // Decoded from the alarms
+    pub following_error: i64,     // Only kept fresh if monitor_following_error is on
+    pub velocity: f64, // In counts/s, as of the last current_velocity or velocity stream read
+    pub comms_stale: bool, // Set while the communication watchdog finds the above too old
+    pub updated: Option<Instant>, // When any of the above was last refreshed
+}
+
+pub(crate) type SharedSnapshot = Arc<RwLock<StatusSnapshot>>;
+
+// A writer that panicked mid-update can't leave a snapshot half written in a
+// way that matters to a reader, so poisoning is ignored.
+pub(crate) fn read_snapshot(snapshot: &SharedSnapshot) -> StatusSnapshot {
+    match snapshot.read() {
+        Ok(s) => s.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+// A cloneable, read-only view of a servo's cached state.  It never talks to
+// the drive itself, so it can be handed to dashboards and loggers while a
+// separate control handle is busy performing motion.
+#[derive(Clone)]
+pub struct MonitorHandle {
+    servo_name: String,
+    snapshot: SharedSnapshot,
+    clock: Arc<dyn Clock>, // The device's, which stamps the snapshot
+}
+
+impl MonitorHandle {
+    pub(crate) fn new(
+        servo_name: String,
+        snapshot: SharedSnapshot,
+        clock: Arc<dyn Clock>,
+    ) -> MonitorHandle {
+        MonitorHandle {
+            servo_name,
+            snapshot,
+            clock,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.servo_name
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        read_snapshot(&self.snapshot)
+    }
+
+    pub fn get_servo_status(&self) -> Vec<String> {
+        self.snapshot().status
+    }
+
+    pub fn get_servo_alarms(&self) -> Vec<String> {
+        self.snapshot().alarms
+    }
+
+    pub fn get_encoder_count(&self) -> u64 {
+        self.snapshot().encoder_count
+    }
+
+    pub fn get_servo_cycle_count(&self) -> i64 {
+        self.snapshot().cycle_count
+    }
+
+    pub fn limit_switches(&self) -> LimitState {
+        self.snapshot().limits
+    }
+
+    pub fn get_following_error(&self) -> i64 {
+        self.snapshot().following_error
+    }
+
+    // In counts/s
+    pub fn get_velocity(&self) -> f64 {
+        self.snapshot().velocity
+    }
+
+    // False while the communication watchdog finds the state stale
+    pub fn is_healthy(&self) -> bool {
+        !self.snapshot().comms_stale
+    }
+
+    // How old the cached data is, or None if nothing has been read yet
+    pub fn age(&self) -> Option<Duration> {
+        self.snapshot().updated.map(|u| self.clock.elapsed(u))
+    }
+}
+
+impl AppliedDevice {
+    pub fn monitor(&self) -> MonitorHandle {
+        MonitorHandle::new(
+            self.servo_name.clone(),
+            self.snapshot.clone(),
+            self.clock.clone(),
+        )
+    }
+
+    // The state as of the last read, without touching the drive
+    pub fn last_snapshot(&self) -> StatusSnapshot {
+        read_snapshot(&self.snapshot)
+    }
+
+    // Reads status, alarms and encoder position so the cached snapshot is current
+    pub fn refresh_snapshot(&mut self) -> Result<(), DeviceError> {
+        self.get_servo_status()?;
+        self.get_servo_alarms()?;
+        self.get_encoder_count()?;
+        if self.monitor_following_error {
+            self.get_following_error()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn update_snapshot<F: FnOnce(&mut StatusSnapshot)>(&self, f: F) {
+        let now = self.clock.now();
+        self.annotate_snapshot(|s| {
+            f(s);
+            s.updated = Some(now);
+        });
+    }
+
+    // Changes the snapshot without counting as a read from the drive
+    pub(crate) fn annotate_snapshot<F: FnOnce(&mut StatusSnapshot)>(&self, f: F) {
+        let mut snapshot = match self.snapshot.write() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        f(&mut snapshot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AppliedDevice, Clock, ManualClock, SimulatedDrive, ALARM, MOTOR_ENABLED};
+    use std::sync::Arc;
+
+    #[test]
+    fn alarms_are_kept_apart_from_the_status() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let mut device =
+            AppliedDevice::with_transport(String::from("monitor"), String::new(), drive.clone());
+        device.set_clock(clock);
+        device.enable_motor().expect("Unable to enable the motor");
+        drive.inject_alarm(1 << 3);
+
+        let status = device.get_servo_status().expect("Read failed").clone();
+        assert_eq!(status, vec![MOTOR_ENABLED.to_string(), ALARM.to_string()]);
+        let alarms = device.get_servo_alarms().expect("Read failed").clone();
+        assert_eq!(alarms, vec![String::from("Over Temp Error")]);
+        // Reading the alarms must not add them to the status read before
+        assert_eq!(device.servo_status, status);
+
+        let monitor = device.monitor();
+        assert_eq!(monitor.get_servo_status(), status);
+        assert_eq!(monitor.get_servo_alarms(), alarms);
+    }
+}
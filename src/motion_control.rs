@@ -0,0 +1,237 @@
+use crate::{AppliedDevice, DeviceError, DriveCommand, InterlockAction, VELOCITY};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::info;
+
+static MAX_VELOCITY_OVERRIDE: u32 = 200; // In percent
+
+// How a wait for a move to finish ended.  The axis has been stopped for
+// anything but Finished.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MoveWait {
+    Finished,
+    Cancelled,
+    TimedOut,
+}
+
+#[derive(Debug)]
+struct PendingMotion {
+    in_motion: bool,
+    fixed: bool, // A feed or blended segment, which can't be retargeted, held or sped up
+    retarget: Option<u64>,
+    velocity_override: u32, // Percent of the commanded velocity, kept across moves
+    override_changed: bool,
+    hold_requested: bool,
+    resume_requested: bool,
+    held: bool, // Stopped by a feed hold, waiting to resume
+}
+
+impl Default for PendingMotion {
+    fn default() -> PendingMotion {
+        PendingMotion {
+            in_motion: false,
+            fixed: false,
+            retarget: None,
+            velocity_override: 100,
+            override_changed: false,
+            hold_requested: false,
+            resume_requested: false,
+            held: false,
+        }
+    }
+}
+
+// A cheap, cloneable handle for adjusting a move while it is in flight.
+// Moves block the thread driving the device, so changes are left here by
+// other threads and picked up by the move's wait loop on its next poll.
+// Every clone shares the same state.
+#[derive(Clone, Debug, Default)]
+pub struct MotionControl {
+    pending: Arc<Mutex<PendingMotion>>,
+}
+
+impl MotionControl {
+    fn pending(&self) -> MutexGuard<'_, PendingMotion> {
+        match self.pending.lock() {
+            Ok(p) => p,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Sends the move in progress to a new position without stopping.  Returns
+    // false (and does nothing) if no move is in progress.  The drive must
+    // accept a new feed to position while moving for this to take effect
+    // smoothly.
+    pub fn retarget(&self, new_position: u64) -> bool {
+        let mut pending = self.pending();
+        if !pending.in_motion || pending.fixed {
+            return false;
+        }
+        pending.retarget = Some(new_position);
+        true
+    }
+
+    // Scales the velocity of the move in progress and every move after it,
+    // e.g. 50 for an operator's half speed setting.  Limited to 1-200%.
+    pub fn set_velocity_override(&self, percent: u32) {
+        let mut pending = self.pending();
+        pending.velocity_override = percent.clamp(1, MAX_VELOCITY_OVERRIDE);
+        pending.override_changed = pending.in_motion && !pending.fixed;
+    }
+
+    pub fn get_velocity_override(&self) -> u32 {
+        self.pending().velocity_override
+    }
+
+    // Decelerates the move in progress to a stop without abandoning it.  The
+    // move keeps waiting, with its timeout paused, until resume is called or
+    // it is cancelled.  Returns false if no move is in progress.
+    pub fn feed_hold(&self) -> bool {
+        let mut pending = self.pending();
+        if !pending.in_motion || pending.fixed {
+            return false;
+        }
+        pending.hold_requested = true;
+        pending.resume_requested = false;
+        true
+    }
+
+    // Continues a held move to its original target.  Returns false if no
+    // move is held or being held.
+    pub fn resume(&self) -> bool {
+        let mut pending = self.pending();
+        if !pending.held && !pending.hold_requested {
+            return false;
+        }
+        pending.hold_requested = false;
+        pending.resume_requested = pending.held;
+        true
+    }
+
+    pub fn is_held(&self) -> bool {
+        let pending = self.pending();
+        pending.held || pending.hold_requested
+    }
+
+    pub fn is_in_motion(&self) -> bool {
+        self.pending().in_motion
+    }
+
+    pub(crate) fn has_requests(&self) -> bool {
+        let pending = self.pending();
+        pending.retarget.is_some() || pending.override_changed || pending.hold_requested
+    }
+}
+
+impl AppliedDevice {
+    pub fn motion_control(&self) -> MotionControl {
+        self.motion_control.clone()
+    }
+
+    pub fn set_velocity_override(&mut self, percent: u32) {
+        self.motion_control.set_velocity_override(percent);
+    }
+
+    // The velocity register value for a commanded velocity, after the
+    // override, limited to what the 16 bit register holds
+    pub(crate) fn override_velocity(&self, velocity: u64) -> u64 {
+        let percent = self.motion_control.get_velocity_override() as u64;
+        (velocity.saturating_mul(percent) / 100).clamp(1, u16::MAX as u64)
+    }
+
+    // Marks the start or end of a move, dropping any requests left over
+    pub(crate) fn set_in_motion(&mut self, in_motion: bool) {
+        self.mark_motion(in_motion, false);
+    }
+
+    // As set_in_motion for a feed or a blended trajectory segment, which is
+    // in motion but carries on to its own end whatever is asked of it
+    // through the motion control
+    pub(crate) fn set_feeding(&mut self, feeding: bool) {
+        self.mark_motion(feeding, true);
+    }
+
+    fn mark_motion(&mut self, in_motion: bool, fixed: bool) {
+        let mut pending = self.motion_control.pending();
+        pending.in_motion = in_motion;
+        pending.fixed = fixed && in_motion;
+        pending.retarget = None;
+        pending.override_changed = false;
+        pending.hold_requested = false;
+        pending.resume_requested = false;
+        pending.held = false;
+    }
+
+    // Carries out whatever was requested through the motion control since
+    // the last poll.  Returns true if the axis was set moving again, after
+    // a resume for example.
+    pub(crate) fn apply_motion_requests(&mut self) -> Result<bool, DeviceError> {
+        let (retarget, override_changed, hold, resume, held) = {
+            let mut pending = self.motion_control.pending();
+            let requests = (
+                pending.retarget.take(),
+                pending.override_changed,
+                pending.hold_requested,
+                pending.resume_requested,
+                pending.held,
+            );
+            pending.override_changed = false;
+            pending.resume_requested = false;
+            if pending.hold_requested {
+                pending.hold_requested = false;
+                pending.held = true;
+            } else if requests.3 {
+                pending.held = false;
+            }
+            requests
+        };
+
+        if hold {
+            let target = self.move_target.unwrap_or_default();
+            let position = self.last_snapshot().encoder_count;
+            info!(
+                "Feed hold on {} with {} counts to go",
+                self.servo_name,
+                target.max(position) - target.min(position)
+            );
+            self.execute(DriveCommand::Stop)?;
+        }
+
+        // The drive picks up a new velocity when the feed is triggered again
+        if override_changed {
+            let velocity = self.override_velocity(self.move_velocity);
+            info!(
+                "Changing velocity of {} to {} ({}%)",
+                self.servo_name,
+                velocity,
+                self.motion_control.get_velocity_override()
+            );
+            self.write_register(VELOCITY, velocity)?;
+        }
+        if let Some(position) = retarget {
+            // The new target has to pass the interlocks the original one did
+            if let Err(e) = self.check_interlocks(InterlockAction::Move { target: position }) {
+                self.stop_motion()?;
+                return Err(e);
+            }
+            info!(
+                "Retargeting move of {} to position {}",
+                self.servo_name, position
+            );
+            self.write_distance(position)?;
+            self.move_target = Some(position);
+        }
+        if resume {
+            info!("Resuming move of {}", self.servo_name);
+        }
+
+        // A held axis stays stopped until it is resumed, whatever else changed
+        let still_held = (held || hold) && !resume;
+        let restart = resume || (!still_held && (retarget.is_some() || override_changed));
+        if restart {
+            self.execute(DriveCommand::StartMove)?;
+            self.clock.sleep(Duration::from_millis(10));
+        }
+        Ok(restart)
+    }
+}
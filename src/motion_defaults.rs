@@ -0,0 +1,325 @@
+use crate::config::millis;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, HomingOffset, HomingRetryPolicy, MotionProfile,
+    MoveRequest, ENCODER_POSITION_RANGE, MAX_HOMING_TIME, MAX_MOVE_TIME, MAX_REGISTER,
+};
+use std::time::Duration;
+use tracing::info;
+use yaml_rust::Yaml;
+
+// Motion settings a device config can give once rather than on every call.
+// Moves made with MoveOptions fall back to these for anything left out.
+//
+//   motion:
+//     accel: 100
+//     decel: 100
+//     velocity: 240
+//     tolerance: 1000         # counts either side of a target that count as there
+//     move_timeout_ms: 30000
+//     counts_per_unit: 2000.0 # for positions given in the application's units
+//   homing:
+//     mode: 1
+//     timeout_ms: 60000
+//     on_connect: true        # home as soon as the device is built
+//     retries: 3              # see HomingRetryPolicy
+//     offset: 5000            # see HomingOffset
+//   registers:                # written as given on connect
+//     46: 10
+#[derive(Clone, Debug, PartialEq)]
+pub struct MotionDefaults {
+    pub accel: Option<u64>,
+    pub decel: Option<u64>,
+    pub velocity: Option<u64>,
+    pub tolerance: u64,
+    pub move_timeout: Duration,
+    pub counts_per_unit: Option<f64>,
+    pub homing_mode: u16,
+    pub homing_timeout: Duration,
+    pub homing_retry: HomingRetryPolicy,
+    pub homing_offset: Option<HomingOffset>, // Moved to once homed
+    pub home_on_connect: bool,
+    pub register_overrides: Vec<(u16, u16)>,
+}
+
+impl Default for MotionDefaults {
+    fn default() -> MotionDefaults {
+        MotionDefaults {
+            accel: None,
+            decel: None,
+            velocity: None,
+            tolerance: ENCODER_POSITION_RANGE,
+            move_timeout: Duration::from_secs(MAX_MOVE_TIME),
+            counts_per_unit: None,
+            homing_mode: 1,
+            homing_timeout: Duration::from_secs(MAX_HOMING_TIME),
+            homing_retry: HomingRetryPolicy::default(),
+            homing_offset: None,
+            home_on_connect: false,
+            register_overrides: Vec::new(),
+        }
+    }
+}
+
+impl MotionDefaults {
+    // Reads the optional `motion:`, `homing:` and `registers:` sections of a
+    // device config.  Anything not given keeps its default.
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<MotionDefaults, DeviceError> {
+        let motion = &device_conf["motion"];
+        let homing = &device_conf["homing"];
+        let defaults = MotionDefaults::default();
+
+        let counts_per_unit = match &motion["counts_per_unit"] {
+            Yaml::BadValue | Yaml::Null => None,
+            Yaml::Integer(n) if *n > 0 => Some(*n as f64),
+            Yaml::Real(_) if motion["counts_per_unit"].as_f64().is_some_and(|n| n > 0.0) => {
+                motion["counts_per_unit"].as_f64()
+            }
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "motion.counts_per_unit must be a positive number, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut register_overrides = Vec::new();
+        match &device_conf["registers"] {
+            Yaml::BadValue | Yaml::Null => {}
+            Yaml::Hash(registers) => {
+                for (register, value) in registers {
+                    let register = match register.as_i64() {
+                        Some(r) if (0..=MAX_REGISTER as i64).contains(&r) => r as u16,
+                        _ => {
+                            return Err(DeviceError::Config(format!(
+                                "registers keys must be register numbers from 0 to {}, got {:?}",
+                                MAX_REGISTER, register
+                            )))
+                        }
+                    };
+                    let value = match value.as_i64() {
+                        Some(v) if (0..=u16::MAX as i64).contains(&v) => v as u16,
+                        _ => {
+                            return Err(DeviceError::Config(format!(
+                                "registers.{} must be a 16 bit value, got {:?}",
+                                register, value
+                            )))
+                        }
+                    };
+                    register_overrides.push((register, value));
+                }
+            }
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "registers must map register numbers to values, got {:?}",
+                    other
+                )))
+            }
+        }
+
+        let homing_offset = HomingOffset::from_yaml(homing)?;
+        let motion_defaults = MotionDefaults {
+            accel: positive(motion, "motion", "accel")?,
+            decel: positive(motion, "motion", "decel")?,
+            velocity: positive(motion, "motion", "velocity")?,
+            tolerance: match &motion["tolerance"] {
+                Yaml::BadValue | Yaml::Null => defaults.tolerance,
+                Yaml::Integer(n) if *n >= 0 => *n as u64,
+                other => {
+                    return Err(DeviceError::Config(format!(
+                        "motion.tolerance must be a non-negative number of counts, got {:?}",
+                        other
+                    )))
+                }
+            },
+            move_timeout: millis(motion, "move_timeout_ms")?.unwrap_or(defaults.move_timeout),
+            counts_per_unit,
+            homing_mode: match positive(homing, "homing", "mode")? {
+                Some(mode) if mode <= u16::MAX as u64 => mode as u16,
+                Some(mode) => {
+                    return Err(DeviceError::Config(format!(
+                        "homing.mode must be a 16 bit value, got {}",
+                        mode
+                    )))
+                }
+                None => defaults.homing_mode,
+            },
+            homing_timeout: millis(homing, "timeout_ms")?.unwrap_or(defaults.homing_timeout),
+            homing_retry: HomingRetryPolicy::from_yaml(homing)?,
+            homing_offset,
+            home_on_connect: match homing["on_connect"] {
+                Yaml::BadValue | Yaml::Null => defaults.home_on_connect,
+                Yaml::Boolean(home) => home,
+                ref other => {
+                    return Err(DeviceError::Config(format!(
+                        "homing.on_connect must be true or false, got {:?}",
+                        other
+                    )))
+                }
+            },
+            register_overrides,
+        };
+        let MotionDefaults {
+            accel,
+            decel,
+            velocity,
+            ..
+        } = &motion_defaults;
+        if homing_offset.is_some() && (accel.is_none() || decel.is_none() || velocity.is_none()) {
+            return Err(DeviceError::Config(String::from(
+                "homing.offset needs motion.accel, decel and velocity to move with",
+            )));
+        }
+        Ok(motion_defaults)
+    }
+}
+
+pub(crate) fn positive(section: &Yaml, name: &str, key: &str) -> Result<Option<u64>, DeviceError> {
+    match &section[key] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(n) if *n > 0 => Ok(Some(*n as u64)),
+        other => Err(DeviceError::Config(format!(
+            "{}.{} must be a positive number, got {:?}",
+            name, key, other
+        ))),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum MoveTarget {
+    Counts(u64),
+    Units(f64),
+}
+
+// A move where anything left as None comes from the device's motion
+// defaults.  The target is given either in encoder counts or in the
+// application's units, scaled by the configured counts_per_unit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoveOptions {
+    target: MoveTarget,
+    pub accel: Option<u64>,
+    pub decel: Option<u64>,
+    pub velocity: Option<u64>,
+    pub profile: Option<MotionProfile>,
+}
+
+impl MoveOptions {
+    pub fn to(encoder_position: u64) -> MoveOptions {
+        MoveOptions::with_target(MoveTarget::Counts(encoder_position))
+    }
+
+    pub fn to_units(position: f64) -> MoveOptions {
+        MoveOptions::with_target(MoveTarget::Units(position))
+    }
+
+    fn with_target(target: MoveTarget) -> MoveOptions {
+        MoveOptions {
+            target,
+            accel: None,
+            decel: None,
+            velocity: None,
+            profile: None,
+        }
+    }
+
+    pub fn accel(mut self, accel: u64) -> MoveOptions {
+        self.accel = Some(accel);
+        self
+    }
+
+    pub fn decel(mut self, decel: u64) -> MoveOptions {
+        self.decel = Some(decel);
+        self
+    }
+
+    pub fn velocity(mut self, velocity: u64) -> MoveOptions {
+        self.velocity = Some(velocity);
+        self
+    }
+
+    pub fn profile(mut self, profile: MotionProfile) -> MoveOptions {
+        self.profile = Some(profile);
+        self
+    }
+}
+
+impl AppliedDevice {
+    pub fn set_motion_defaults(&mut self, defaults: MotionDefaults) {
+        self.motion_defaults = defaults;
+    }
+
+    pub fn get_motion_defaults(&self) -> &MotionDefaults {
+        &self.motion_defaults
+    }
+
+    // Writes the configured register overrides to the drive
+    pub(crate) fn apply_register_overrides(&mut self) -> Result<(), DeviceError> {
+        for (register, value) in self.motion_defaults.register_overrides.clone() {
+            info!(
+                "Overriding register {} of {} with {}",
+                register, self.servo_name, value
+            );
+            self.write_register(register, value as u64)?;
+        }
+        Ok(())
+    }
+
+    // Converts a position in the application's units to encoder counts, or
+    // None if no counts_per_unit is configured
+    pub fn units_to_counts(&self, position: f64) -> Option<u64> {
+        self.motion_defaults
+            .counts_per_unit
+            .map(|scale| (position * scale).round() as i64 as u32 as u64)
+    }
+
+    pub fn counts_to_units(&self, counts: u64) -> Option<f64> {
+        self.motion_defaults
+            .counts_per_unit
+            .map(|scale| counts as u32 as i32 as f64 / scale)
+    }
+
+    // Fills in whatever the options leave out from the motion defaults
+    pub fn resolve_move(&self, options: &MoveOptions) -> Result<MoveRequest, DeviceError> {
+        let defaults = &self.motion_defaults;
+        let missing = |what: &str| {
+            DeviceError::Config(format!(
+                "No {} given for the move of {} and none configured",
+                what, self.servo_name
+            ))
+        };
+        let target = match options.target {
+            MoveTarget::Counts(counts) => counts,
+            MoveTarget::Units(position) => self
+                .units_to_counts(position)
+                .ok_or_else(|| missing("counts_per_unit"))?,
+        };
+        Ok(MoveRequest {
+            accel: options
+                .accel
+                .or(defaults.accel)
+                .ok_or_else(|| missing("accel"))?,
+            decel: options
+                .decel
+                .or(defaults.decel)
+                .ok_or_else(|| missing("decel"))?,
+            velocity: options
+                .velocity
+                .or(defaults.velocity)
+                .ok_or_else(|| missing("velocity"))?,
+            target,
+            profile: options.profile.clone(),
+        })
+    }
+
+    pub fn move_with(&mut self, options: &MoveOptions) -> Result<(), DeviceError> {
+        self.move_with_cancel(options, &CancelToken::new())
+    }
+
+    pub fn move_with_cancel(
+        &mut self,
+        options: &MoveOptions,
+        cancel: &CancelToken,
+    ) -> Result<(), DeviceError> {
+        let request = self.resolve_move(options)?;
+        self.execute_move_with_cancel(&request, cancel)
+    }
+}
@@ -0,0 +1,297 @@
+use crate::feed::signed_count;
+use crate::{AppliedDeviceHandle, DeviceError, MonitorHandle, MoveOptions};
+use opcua::server::callbacks;
+use opcua::server::prelude::*;
+use opcua::server::session::SessionManager;
+use opcua::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{info, warn};
+
+static NAMESPACE_URI: &str = "urn:applied_device";
+static DEFAULT_UPDATE_PERIOD: u64 = 100; // Between variable refreshes, in ms
+
+// The variables of one servo, refreshed from its monitor
+struct OpcUaDevice {
+    monitor: MonitorHandle,
+    age: NodeId,
+    position: NodeId,
+    status: NodeId,
+    alarms: NodeId,
+    last_error: NodeId,
+    errors: Arc<Mutex<String>>, // Set by commands running in the background
+}
+
+// An embedded OPC UA server exposing servos to SCADA.  Each servo is an
+// object under Objects/Devices named after the servo, with
+//
+//   AgeMs       Int64     since the cached state was last read, -1 if never
+//   Position    Int64     encoder counts
+//   Status      String    status flags, comma separated
+//   Alarms      String    active alarms, comma separated
+//   LastError   String    why the last command failed, empty if it didn't
+//   Home()                starts homing
+//   MoveTo(Int64)         starts a move to a position in counts
+//   Stop()                aborts what is running and stops motion
+//
+// Home and MoveTo return once the command has started; a failure shows up
+// in LastError.  Clients connect anonymously with no security, so the
+// server should only be reachable from the plant network.
+pub struct OpcUaServer {
+    server: Server,
+    namespace: u16,
+    devices_folder: NodeId,
+    devices: Arc<Mutex<Vec<OpcUaDevice>>>,
+    update_period: u64,
+}
+
+impl OpcUaServer {
+    pub fn new(host: &str, port: u16) -> Result<OpcUaServer, DeviceError> {
+        let server = ServerBuilder::new_anonymous("applied_device")
+            .application_uri(NAMESPACE_URI)
+            .product_uri(NAMESPACE_URI)
+            .host_and_port(host, port)
+            .discovery_urls(vec![format!("opc.tcp://{}:{}/", host, port)])
+            .create_sample_keypair(true)
+            .server()
+            .ok_or_else(|| {
+                DeviceError::Config(format!(
+                    "Unable to create an OPC UA server on {}:{}",
+                    host, port
+                ))
+            })?;
+
+        let (namespace, devices_folder) = {
+            let address_space = server.address_space();
+            let mut address_space = address_space.write();
+            let namespace = address_space
+                .register_namespace(NAMESPACE_URI)
+                .map_err(|_| opcua_error("Unable to register the namespace"))?;
+            let devices_folder = NodeId::new(namespace, "Devices");
+            if !address_space.add_folder_with_id(
+                &devices_folder,
+                "Devices",
+                "Devices",
+                &NodeId::objects_folder_id(),
+            ) {
+                return Err(opcua_error("Unable to add the Devices folder"));
+            }
+            (namespace, devices_folder)
+        };
+
+        Ok(OpcUaServer {
+            server,
+            namespace,
+            devices_folder,
+            devices: Arc::new(Mutex::new(Vec::new())),
+            update_period: DEFAULT_UPDATE_PERIOD,
+        })
+    }
+
+    // How often the variables are refreshed from the servos' cached state
+    // (every 100 ms by default).  Takes effect when the server is run.
+    pub fn set_update_period(&mut self, period_ms: u64) {
+        self.update_period = period_ms;
+    }
+
+    pub fn add_device(&mut self, handle: AppliedDeviceHandle) -> Result<(), DeviceError> {
+        let name = handle.get_name().to_string();
+        let ns = self.namespace;
+        let id = |field: &str| NodeId::new(ns, format!("{}.{}", name, field));
+        let errors = Arc::new(Mutex::new(String::new()));
+
+        let address_space = self.server.address_space();
+        let mut address_space = address_space.write();
+        let object = ObjectBuilder::new(&NodeId::new(ns, name.clone()), &name, &name)
+            .organized_by(self.devices_folder.clone())
+            .insert(&mut address_space);
+        if !object {
+            return Err(opcua_error(&format!(
+                "A device named {} already exists",
+                name
+            )));
+        }
+        let object = NodeId::new(ns, name.clone());
+
+        let variables: Vec<(&str, DataTypeId, Variant)> = vec![
+            ("AgeMs", DataTypeId::Int64, (-1i64).into()),
+            ("Position", DataTypeId::Int64, 0i64.into()),
+            ("Status", DataTypeId::String, "".into()),
+            ("Alarms", DataTypeId::String, "".into()),
+            ("LastError", DataTypeId::String, "".into()),
+        ];
+        for (field, data_type, initial) in variables {
+            VariableBuilder::new(&id(field), field, field)
+                .data_type(data_type)
+                .value(initial)
+                .component_of(object.clone())
+                .insert(&mut address_space);
+        }
+
+        let home = handle.clone();
+        let home_errors = errors.clone();
+        MethodBuilder::new(&id("Home"), "Home", "Home")
+            .component_of(object.clone())
+            .callback(Box::new(CommandMethod(move |_: &[Variant]| {
+                start_command(&home, &home_errors, |h| h.home_servo());
+                Ok(())
+            })))
+            .insert(&mut address_space);
+
+        let move_to = handle.clone();
+        let move_errors = errors.clone();
+        MethodBuilder::new(&id("MoveTo"), "MoveTo", "MoveTo")
+            .component_of(object.clone())
+            .input_args(
+                &mut address_space,
+                &[("Position", DataTypeId::Int64).into()],
+            )
+            .callback(Box::new(CommandMethod(move |args: &[Variant]| {
+                // Written to the drive as a signed 32 bit count
+                let counts = match args.first() {
+                    Some(Variant::Int64(position))
+                        if (i32::MIN as i64..=i32::MAX as i64).contains(position) =>
+                    {
+                        *position as i32 as u32 as u64
+                    }
+                    Some(Variant::Int64(_)) => return Err(StatusCode::BadOutOfRange),
+                    _ => return Err(StatusCode::BadInvalidArgument),
+                };
+                start_command(&move_to, &move_errors, move |h| {
+                    h.move_with(MoveOptions::to(counts))
+                });
+                Ok(())
+            })))
+            .insert(&mut address_space);
+
+        let stop = handle.clone();
+        let stop_errors = errors.clone();
+        MethodBuilder::new(&id("Stop"), "Stop", "Stop")
+            .component_of(object)
+            .callback(Box::new(CommandMethod(move |_: &[Variant]| {
+                stop.abort();
+                record(&stop_errors, stop.stop_motion());
+                Ok(())
+            })))
+            .insert(&mut address_space);
+
+        info!("Exposing {} over OPC UA", name);
+        lock_devices(&self.devices).push(OpcUaDevice {
+            monitor: handle.monitor(),
+            age: id("AgeMs"),
+            position: id("Position"),
+            status: id("Status"),
+            alarms: id("Alarms"),
+            last_error: id("LastError"),
+            errors,
+        });
+        Ok(())
+    }
+
+    // Serves clients until the process ends
+    pub fn run(mut self) {
+        let address_space = self.server.address_space();
+        let devices = self.devices.clone();
+        self.server.add_polling_action(self.update_period, move || {
+            let mut address_space = address_space.write();
+            for device in lock_devices(&devices).iter() {
+                update_device(&mut address_space, device);
+            }
+        });
+        info!("OPC UA server running");
+        self.server.run();
+    }
+}
+
+fn update_device(address_space: &mut AddressSpace, device: &OpcUaDevice) {
+    let snapshot = device.monitor.snapshot();
+    let now = DateTime::now();
+    let last_error = match device.errors.lock() {
+        Ok(e) => e.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    let values: Vec<(&NodeId, Variant)> = vec![
+        (
+            &device.age,
+            device
+                .monitor
+                .age()
+                .map(|age| age.as_millis() as i64)
+                .unwrap_or(-1)
+                .into(),
+        ),
+        (
+            &device.position,
+            signed_count(snapshot.encoder_count).into(),
+        ),
+        (&device.status, snapshot.status.join(", ").into()),
+        (&device.alarms, snapshot.alarms.join(", ").into()),
+        (&device.last_error, last_error.into()),
+    ];
+    for (node, value) in values {
+        address_space.set_variable_value(node.clone(), value, &now, &now);
+    }
+}
+
+// Runs a command on its own thread so the method call returns straight away
+fn start_command<F>(handle: &AppliedDeviceHandle, errors: &Arc<Mutex<String>>, f: F)
+where
+    F: FnOnce(&AppliedDeviceHandle) -> Result<(), DeviceError> + Send + 'static,
+{
+    let handle = handle.clone();
+    let errors = errors.clone();
+    record(&errors, Ok(()));
+    thread::spawn(move || {
+        let result = f(&handle);
+        if let Err(e) = &result {
+            warn!("OPC UA command on {} failed: {}", handle.get_name(), e);
+        }
+        record(&errors, result);
+    });
+}
+
+fn record(errors: &Mutex<String>, result: Result<(), DeviceError>) {
+    let message = match result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    match errors.lock() {
+        Ok(mut e) => *e = message,
+        Err(poisoned) => *poisoned.into_inner() = message,
+    }
+}
+
+fn lock_devices(devices: &Mutex<Vec<OpcUaDevice>>) -> std::sync::MutexGuard<'_, Vec<OpcUaDevice>> {
+    match devices.lock() {
+        Ok(d) => d,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Adapts a closure over the input arguments to an OPC UA method callback
+struct CommandMethod<F>(F);
+
+impl<F> callbacks::Method for CommandMethod<F>
+where
+    F: FnMut(&[Variant]) -> Result<(), StatusCode> + Send + Sync,
+{
+    fn call(
+        &mut self,
+        _session_id: &NodeId,
+        _session_manager: Arc<RwLock<SessionManager>>,
+        request: &CallMethodRequest,
+    ) -> Result<CallMethodResult, StatusCode> {
+        let args = request.input_arguments.clone().unwrap_or_default();
+        (self.0)(&args)?;
+        Ok(CallMethodResult {
+            status_code: StatusCode::Good,
+            input_argument_results: None,
+            input_argument_diagnostic_infos: None,
+            output_arguments: None,
+        })
+    }
+}
+
+fn opcua_error(message: &str) -> DeviceError {
+    DeviceError::Config(format!("OPC UA: {}", message))
+}
@@ -0,0 +1,303 @@
+use crate::transport::{ModbusTimeouts, Transport};
+use crate::{AppliedDevice, DeviceError};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{info, warn};
+
+type Connector = Box<dyn Fn() -> Result<Box<dyn Transport>, DeviceError> + Send + Sync>;
+
+// A limited set of connections to one gateway, shared by every drive behind
+// it.  Each transaction borrows a free connection, addressing its drive by
+// unit ID, so dozens of drives need only as many sockets as are allowed.
+// Clones share the same connections.
+#[derive(Clone)]
+pub struct ConnectionPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    gateway: String,
+    max_connections: usize,
+    connect: Connector,
+    state: Mutex<PoolState>,
+    returned: Condvar, // Signalled whenever a connection is given back or dropped
+}
+
+struct PoolState {
+    idle: Vec<Box<dyn Transport>>,
+    open: usize, // Idle and in use
+}
+
+impl ConnectionPool {
+    // Opens at most max_connections Modbus TCP connections to the gateway,
+    // as they are needed
+    pub fn new(gateway: &str, max_connections: usize, timeouts: &ModbusTimeouts) -> ConnectionPool {
+        let address = gateway.to_string();
+        let timeouts = timeouts.clone();
+        ConnectionPool::with_connector(gateway, max_connections, move || {
+            let client = AppliedDevice::connect(&address, &timeouts)?;
+            Ok(Box::new(client) as Box<dyn Transport>)
+        })
+    }
+
+    // A pool whose connections are opened by the given function instead, e.g.
+    // to wrap each in a RateLimitedTransport
+    pub fn with_connector<F>(gateway: &str, max_connections: usize, connect: F) -> ConnectionPool
+    where
+        F: Fn() -> Result<Box<dyn Transport>, DeviceError> + Send + Sync + 'static,
+    {
+        ConnectionPool {
+            shared: Arc::new(Shared {
+                gateway: gateway.to_string(),
+                max_connections: max_connections.max(1),
+                connect: Box::new(connect),
+                state: Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn get_gateway(&self) -> &str {
+        &self.shared.gateway
+    }
+
+    // Connections currently open, whether in use or not
+    pub fn get_open_connections(&self) -> usize {
+        self.shared.lock().open
+    }
+
+    // A transport for the drive with the given unit ID, to build a device with
+    pub fn transport(&self, unit_id: u8) -> PooledTransport {
+        PooledTransport {
+            pool: self.clone(),
+            unit_id,
+            read_timeout: None,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Takes an idle connection, opens another if the pool isn't full, or
+    // waits for one to be given back
+    fn checkout(&self) -> modbus::Result<Box<dyn Transport>> {
+        let mut state = self.lock();
+        loop {
+            if let Some(connection) = state.idle.pop() {
+                return Ok(connection);
+            }
+            if state.open < self.max_connections {
+                state.open += 1;
+                drop(state);
+                info!("Opening pooled connection to {}", self.gateway);
+                return (self.connect)().map_err(|e| {
+                    self.discard();
+                    modbus::Error::Io(io::Error::new(io::ErrorKind::NotConnected, e.to_string()))
+                });
+            }
+            state = match self.returned.wait(state) {
+                Ok(s) => s,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+    }
+
+    fn checkin(&self, connection: Box<dyn Transport>) {
+        self.lock().idle.push(connection);
+        self.returned.notify_one();
+    }
+
+    // Forgets a connection that failed, so the next checkout opens a new one
+    fn discard(&self) {
+        let mut state = self.lock();
+        state.open = state.open.saturating_sub(1);
+        self.returned.notify_one();
+    }
+}
+
+// One drive's view of a ConnectionPool
+pub struct PooledTransport {
+    pool: ConnectionPool,
+    unit_id: u8,
+    read_timeout: Option<Option<Duration>>, // None leaves each connection's own
+}
+
+impl PooledTransport {
+    fn transaction<R>(
+        &mut self,
+        f: impl FnOnce(&mut dyn Transport) -> modbus::Result<R>,
+    ) -> modbus::Result<R> {
+        let shared = &self.pool.shared;
+        let mut connection = shared.checkout()?;
+        connection.set_unit_id(self.unit_id);
+        let result = match self.read_timeout {
+            Some(timeout) => connection.set_read_timeout(timeout),
+            None => Ok(()),
+        };
+        let result = result.and_then(|_| f(connection.as_mut()));
+        match &result {
+            // The socket is no good after an IO error
+            Err(modbus::Error::Io(e)) => {
+                warn!(
+                    "Dropping pooled connection to {} after: {}",
+                    shared.gateway, e
+                );
+                shared.discard();
+            }
+            _ => shared.checkin(connection),
+        }
+        result
+    }
+}
+
+impl Transport for PooledTransport {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        self.transaction(|c| c.read_holding_registers(address, quantity))
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        self.transaction(|c| c.write_single_register(address, value))
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        self.unit_id = unit_id;
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        self.transaction(|c| {
+            c.write_read_multiple_registers(write_address, values, read_address, read_quantity)
+        })
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+        self.read_timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+impl AppliedDevice {
+    // A device for the drive with the given unit ID behind the pool's gateway
+    pub fn pooled(servo_name: String, pool: &ConnectionPool, unit_id: u8) -> AppliedDevice {
+        let address = format!("{} unit {}", pool.get_gateway(), unit_id);
+        let mut device =
+            AppliedDevice::with_transport(servo_name, address, pool.transport(unit_id));
+        device.pool = Some((pool.clone(), unit_id));
+        device
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    // Counts how many connections are opened and how many transactions run
+    // at once, failing reads of register 99 as a dropped socket would
+    #[derive(Clone, Default)]
+    struct Gateway {
+        opened: Arc<AtomicUsize>,
+        active: Arc<AtomicUsize>,
+        most_active: Arc<AtomicUsize>,
+        units: Arc<Mutex<Vec<u8>>>,
+    }
+
+    struct Connection {
+        gateway: Gateway,
+        unit_id: u8,
+    }
+
+    impl Transport for Connection {
+        fn read_holding_registers(
+            &mut self,
+            address: u16,
+            quantity: u16,
+        ) -> modbus::Result<Vec<u16>> {
+            let active = self.gateway.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.gateway.most_active.fetch_max(active, Ordering::SeqCst);
+            self.gateway.units.lock().unwrap().push(self.unit_id);
+            thread::sleep(Duration::from_millis(20));
+            self.gateway.active.fetch_sub(1, Ordering::SeqCst);
+            match address {
+                99 => Err(modbus::Error::Io(io::Error::from(
+                    io::ErrorKind::BrokenPipe,
+                ))),
+                _ => Ok(vec![0; quantity as usize]),
+            }
+        }
+
+        fn write_single_register(&mut self, _address: u16, _value: u16) -> modbus::Result<()> {
+            Ok(())
+        }
+
+        fn set_unit_id(&mut self, unit_id: u8) {
+            self.unit_id = unit_id;
+        }
+    }
+
+    fn pool(max_connections: usize) -> (ConnectionPool, Gateway) {
+        let gateway = Gateway::default();
+        let connecting = gateway.clone();
+        let pool = ConnectionPool::with_connector("gateway", max_connections, move || {
+            connecting.opened.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(Connection {
+                gateway: connecting.clone(),
+                unit_id: 0,
+            }) as Box<dyn Transport>)
+        });
+        (pool, gateway)
+    }
+
+    #[test]
+    fn drives_take_turns_on_an_idle_connection() {
+        let (pool, gateway) = pool(2);
+        pool.transport(3).read_holding_registers(1, 1).unwrap();
+        pool.transport(4).read_holding_registers(1, 1).unwrap();
+        assert_eq!(gateway.opened.load(Ordering::SeqCst), 1);
+        assert_eq!(*gateway.units.lock().unwrap(), vec![3, 4]);
+    }
+
+    #[test]
+    fn a_connection_that_fails_is_replaced() {
+        let (pool, gateway) = pool(1);
+        let mut transport = pool.transport(3);
+        assert!(transport.read_holding_registers(99, 1).is_err());
+        assert_eq!(pool.get_open_connections(), 0);
+        transport.read_holding_registers(1, 1).unwrap();
+        assert_eq!(gateway.opened.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.get_open_connections(), 1);
+    }
+
+    #[test]
+    fn transactions_wait_for_a_connection_once_the_pool_is_full() {
+        let (pool, gateway) = pool(2);
+        let readers: Vec<_> = (1..=6)
+            .map(|unit_id| {
+                let mut transport = pool.transport(unit_id);
+                thread::spawn(move || transport.read_holding_registers(1, 1))
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap().unwrap();
+        }
+        assert!(gateway.most_active.load(Ordering::SeqCst) <= 2);
+        assert!(gateway.opened.load(Ordering::SeqCst) <= 2);
+        assert_eq!(gateway.units.lock().unwrap().len(), 6);
+    }
+}
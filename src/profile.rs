@@ -0,0 +1,107 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use tracing::info;
+
+// The shape of a move's velocity over time.  The crate writes whatever
+// registers the chosen profile needs before starting a move.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MotionProfile {
+    #[default]
+    Trapezoidal,
+    // Jerk limited starts and stops for high inertia loads.  jerk is the
+    // drive's jerk filter setting, lower being smoother.
+    SCurve {
+        jerk: u16,
+    },
+    // Raw register values written before each move, for drive features
+    // this crate has no name for
+    Custom {
+        registers: Vec<(u16, u16)>,
+    },
+}
+
+// Everything that describes a single move
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoveRequest {
+    pub accel: u64,
+    pub decel: u64,
+    pub velocity: u64,
+    pub target: u64,
+    pub profile: Option<MotionProfile>, // None uses the device's default profile
+}
+
+impl MoveRequest {
+    pub fn new(accel: u64, decel: u64, velocity: u64, target: u64) -> MoveRequest {
+        MoveRequest {
+            accel,
+            decel,
+            velocity,
+            target,
+            profile: None,
+        }
+    }
+
+    pub fn profile(mut self, profile: MotionProfile) -> MoveRequest {
+        self.profile = Some(profile);
+        self
+    }
+
+    // Smooths the starts and stops of the move, for high inertia loads
+    pub fn s_curve(self, jerk: u16) -> MoveRequest {
+        self.profile(MotionProfile::SCurve { jerk })
+    }
+}
+
+impl AppliedDevice {
+    // The profile used by moves and feeds that don't ask for one
+    pub fn set_default_profile(&mut self, profile: MotionProfile) {
+        self.default_profile = profile;
+    }
+
+    pub fn get_default_profile(&self) -> &MotionProfile {
+        &self.default_profile
+    }
+
+    // Writes the registers the profile needs, falling back to the default
+    // profile if none is given
+    pub(crate) fn apply_profile(
+        &mut self,
+        profile: Option<&MotionProfile>,
+    ) -> Result<(), DeviceError> {
+        let profile = profile.unwrap_or(&self.default_profile).clone();
+        match profile {
+            MotionProfile::Trapezoidal => self.set_jerk_filter(0),
+            MotionProfile::SCurve { jerk } => self.set_jerk_filter(jerk),
+            MotionProfile::Custom { registers } => {
+                for (register, value) in registers {
+                    self.write_register(register, value as u64)?;
+                    if self.register_map.get(MappedRegister::JerkFilter) == Some(register) {
+                        self.jerk_filter = Some(value);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Sets the drive's jerk filter, 0 turning it off.  Only written when it
+    // changes.  A drive with no jerk filter in its register map only makes
+    // trapezoidal moves, so those need nothing written.
+    fn set_jerk_filter(&mut self, jerk: u16) -> Result<(), DeviceError> {
+        if self.jerk_filter == Some(jerk) {
+            return Ok(());
+        }
+        if jerk == 0 && self.register_map.get(MappedRegister::JerkFilter).is_none() {
+            return Ok(());
+        }
+        let register = self.mapped(MappedRegister::JerkFilter)?;
+        info!("Setting jerk filter of {} to {}", self.servo_name, jerk);
+        self.write_register(register, jerk as u64)?;
+        self.jerk_filter = Some(jerk);
+        Ok(())
+    }
+
+    // The jerk filter setting last written to the drive, if any
+    pub fn get_jerk_filter(&self) -> Option<u16> {
+        self.jerk_filter
+    }
+}
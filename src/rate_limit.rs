@@ -0,0 +1,82 @@
+use crate::clock::{Clock, SystemClock};
+use crate::transport::Transport;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Wraps a transport so transactions are spaced at least a minimum gap
+// apart, keeping a connection under a given number of transactions per
+// second.  Useful when several drives sit behind one Ethernet gateway that
+// falls over under aggressive polling.
+pub struct RateLimitedTransport<T: Transport> {
+    inner: T,
+    min_gap: Duration,
+    last_request: Option<Instant>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<T: Transport> RateLimitedTransport<T> {
+    pub fn new(inner: T, min_gap: Duration) -> RateLimitedTransport<T> {
+        RateLimitedTransport {
+            inner,
+            min_gap,
+            last_request: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    // Limits the connection to at most the given number of transactions per second
+    pub fn per_second(inner: T, max_per_second: u32) -> RateLimitedTransport<T> {
+        RateLimitedTransport::new(inner, Duration::from_secs(1) / max_per_second.max(1))
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RateLimitedTransport<T> {
+        self.clock = clock;
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn wait_for_slot(&mut self) {
+        if let Some(last) = self.last_request {
+            let since = self.clock.elapsed(last);
+            if since < self.min_gap {
+                self.clock.sleep(self.min_gap - since);
+            }
+        }
+        self.last_request = Some(self.clock.now());
+    }
+}
+
+impl<T: Transport> Transport for RateLimitedTransport<T> {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        self.wait_for_slot();
+        self.inner.read_holding_registers(address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        self.wait_for_slot();
+        self.inner.write_single_register(address, value)
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        self.inner.set_unit_id(unit_id)
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        self.wait_for_slot();
+        self.inner
+            .write_read_multiple_registers(write_address, values, read_address, read_quantity)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
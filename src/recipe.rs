@@ -0,0 +1,346 @@
+use crate::config::millis;
+use crate::motion_defaults::positive;
+use crate::{AppliedDevice, CancelToken, DeviceError, InputCondition, MoveOptions};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, info_span, warn};
+use yaml_rust::{Yaml, YamlLoader};
+
+// One step of a recipe
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecipeStep {
+    Home,
+    Move(MoveOptions), // Anything left out comes from the motion defaults
+    WaitForInput {
+        input: u16,
+        condition: InputCondition,
+        timeout: Option<Duration>,
+    },
+    SetOutput {
+        output: u16,
+        high: bool,
+    },
+    Dwell(Duration),
+}
+
+impl fmt::Display for RecipeStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecipeStep::Home => write!(f, "home"),
+            RecipeStep::Move(options) => write!(f, "move {:?}", options),
+            RecipeStep::WaitForInput {
+                input, condition, ..
+            } => write!(f, "wait for input {} {:?}", input, condition),
+            RecipeStep::SetOutput { output, high } => {
+                write!(
+                    f,
+                    "set output {} {}",
+                    output,
+                    if *high { "high" } else { "low" }
+                )
+            }
+            RecipeStep::Dwell(dwell) => write!(f, "dwell {:?}", dwell),
+        }
+    }
+}
+
+// A named sequence of steps for one servo, such as a changeover procedure.
+// Recipes are YAML, or JSON, files:
+//
+//   name: changeover_a
+//   steps:
+//     - home
+//     - move: {position: 20000, velocity: 240}    # accel and decel from the motion defaults
+//     - move: {position_units: 12.5}
+//     - wait_for_input: {input: 3, condition: high, timeout_ms: 5000}
+//     - set_output: {output: 2, high: true}
+//     - dwell_ms: 500
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipe {
+    pub name: String,
+    pub steps: Vec<RecipeStep>,
+}
+
+// Reported to telemetry sinks as each step of a recipe starts
+#[derive(Clone, Debug)]
+pub struct RecipeProgress {
+    pub servo_name: String,
+    pub recipe: String,
+    pub step: usize, // Index of the step starting
+    pub steps: usize,
+    pub description: String,
+}
+
+impl Recipe {
+    pub fn load(path: &Path) -> Result<Recipe, DeviceError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            DeviceError::Config(format!("Unable to read recipe {}: {}", path.display(), e))
+        })?;
+        Recipe::from_yaml_str(&contents)
+    }
+
+    pub fn from_yaml_str(contents: &str) -> Result<Recipe, DeviceError> {
+        let docs = YamlLoader::load_from_str(contents)
+            .map_err(|e| DeviceError::Config(format!("Unable to parse recipe: {}", e)))?;
+        let doc = match docs.into_iter().next() {
+            Some(doc) => doc,
+            None => return Err(DeviceError::Config(String::from("The recipe is empty"))),
+        };
+
+        let name = match doc["name"].as_str() {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(DeviceError::Config(format!(
+                    "A recipe needs a name, got {:?}",
+                    doc["name"]
+                )))
+            }
+        };
+        let steps = match &doc["steps"] {
+            Yaml::Array(steps) => steps
+                .iter()
+                .enumerate()
+                .map(|(i, step)| {
+                    parse_step(step).map_err(|e| match e {
+                        DeviceError::Config(msg) => {
+                            DeviceError::Config(format!("Step {} of {}: {}", i + 1, name, msg))
+                        }
+                        other => other,
+                    })
+                })
+                .collect::<Result<Vec<RecipeStep>, DeviceError>>()?,
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "Recipe steps must be a list, got {:?}",
+                    other
+                )))
+            }
+        };
+        Ok(Recipe { name, steps })
+    }
+}
+
+fn parse_step(step: &Yaml) -> Result<RecipeStep, DeviceError> {
+    if step.as_str() == Some("home") {
+        return Ok(RecipeStep::Home);
+    }
+    let (kind, args) = match step {
+        Yaml::Hash(h) if h.len() == 1 => match h.iter().next() {
+            Some((Yaml::String(kind), args)) => (kind.as_str(), args),
+            _ => return Err(unknown_step(step)),
+        },
+        _ => return Err(unknown_step(step)),
+    };
+
+    match kind {
+        "move" => {
+            let units = match &args["position_units"] {
+                Yaml::Integer(n) => Some(*n as f64),
+                other => other.as_f64(),
+            };
+            let mut options = match (&args["position"], units) {
+                // As an encoder count, so negative positions are written as
+                // the drive's two's complement
+                (Yaml::Integer(p), None) if (i32::MIN as i64..=i32::MAX as i64).contains(p) => {
+                    MoveOptions::to(*p as i32 as u32 as u64)
+                }
+                (Yaml::Integer(p), None) => {
+                    return Err(DeviceError::Config(format!(
+                        "move.position must be an encoder count, got {}",
+                        p
+                    )))
+                }
+                (Yaml::BadValue, Some(units)) => MoveOptions::to_units(units),
+                _ => {
+                    return Err(DeviceError::Config(String::from(
+                        "a move needs a position in counts or position_units",
+                    )))
+                }
+            };
+            options.accel = positive(args, "move", "accel")?;
+            options.decel = positive(args, "move", "decel")?;
+            options.velocity = positive(args, "move", "velocity")?;
+            Ok(RecipeStep::Move(options))
+        }
+        "wait_for_input" => {
+            let condition = match args["condition"].as_str().unwrap_or("high") {
+                "high" => InputCondition::High,
+                "low" => InputCondition::Low,
+                "rising" => InputCondition::Rising,
+                "falling" => InputCondition::Falling,
+                other => {
+                    return Err(DeviceError::Config(format!(
+                        "condition must be high, low, rising or falling, got {}",
+                        other
+                    )))
+                }
+            };
+            Ok(RecipeStep::WaitForInput {
+                input: io_number(args, "input")?,
+                condition,
+                timeout: millis(args, "timeout_ms")?,
+            })
+        }
+        "set_output" => Ok(RecipeStep::SetOutput {
+            output: io_number(args, "output")?,
+            high: match args["high"] {
+                Yaml::Boolean(high) => high,
+                ref other => {
+                    return Err(DeviceError::Config(format!(
+                        "set_output.high must be true or false, got {:?}",
+                        other
+                    )))
+                }
+            },
+        }),
+        "dwell_ms" => match args {
+            Yaml::Integer(ms) if *ms >= 0 => {
+                Ok(RecipeStep::Dwell(Duration::from_millis(*ms as u64)))
+            }
+            other => Err(DeviceError::Config(format!(
+                "dwell_ms must be a non-negative number of milliseconds, got {:?}",
+                other
+            ))),
+        },
+        _ => Err(unknown_step(step)),
+    }
+}
+
+fn io_number(args: &Yaml, key: &str) -> Result<u16, DeviceError> {
+    match args[key].as_i64() {
+        Some(n) if (1..=16).contains(&n) => Ok(n as u16),
+        _ => Err(DeviceError::Config(format!(
+            "{} must be a number from 1 to 16, got {:?}",
+            key, args[key]
+        ))),
+    }
+}
+
+fn unknown_step(step: &Yaml) -> DeviceError {
+    DeviceError::Config(format!(
+        "expected home, move, wait_for_input, set_output or dwell_ms, got {:?}",
+        step
+    ))
+}
+
+impl AppliedDevice {
+    pub fn run_recipe(&mut self, recipe: &Recipe) -> Result<usize, DeviceError> {
+        self.run_recipe_with_cancel(recipe, &CancelToken::new())
+    }
+
+    // Runs each step in turn.  Stops at the first step that doesn't complete
+    // (a move that misses its target, an input that never comes) or when the
+    // token is cancelled.  Returns how many steps completed.
+    pub fn run_recipe_with_cancel(
+        &mut self,
+        recipe: &Recipe,
+        cancel: &CancelToken,
+    ) -> Result<usize, DeviceError> {
+        let span = info_span!("recipe", servo = %self.servo_name, recipe = %recipe.name);
+        let _enter = span.enter();
+        let steps = recipe.steps.len();
+
+        for (index, step) in recipe.steps.iter().enumerate() {
+            info!(
+                "Recipe {} step {} of {}: {}",
+                recipe.name,
+                index + 1,
+                steps,
+                step
+            );
+            self.emit(|t| {
+                t.on_recipe_progress(&RecipeProgress {
+                    servo_name: self.servo_name.clone(),
+                    recipe: recipe.name.clone(),
+                    step: index,
+                    steps,
+                    description: step.to_string(),
+                })
+            });
+
+            let completed = match step {
+                // A cancel stops the recipe here, any other failure is an error
+                RecipeStep::Home => {
+                    let report = self.home_servo_with_report(cancel)?;
+                    if cancel.is_cancelled() {
+                        false
+                    } else {
+                        self.require_homed(report)?;
+                        true
+                    }
+                }
+                RecipeStep::Move(options) => {
+                    let request = self.resolve_move(options)?;
+                    self.perform_move(&request, cancel)?
+                }
+                RecipeStep::WaitForInput {
+                    input,
+                    condition,
+                    timeout,
+                } => self.wait_for_input(*input, *condition, *timeout, cancel)?,
+                RecipeStep::SetOutput { output, high } => {
+                    self.set_output(*output, *high)?;
+                    true
+                }
+                RecipeStep::Dwell(dwell) => self.sleep_and_sample(*dwell, cancel)?,
+            };
+            if !completed || cancel.is_cancelled() {
+                warn!(
+                    "Recipe {} on {} stopped at step {} of {}",
+                    recipe.name,
+                    self.servo_name,
+                    index + 1,
+                    steps
+                );
+                return Ok(index);
+            }
+        }
+        info!("Recipe {} on {} finished", recipe.name, self.servo_name);
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_every_kind_of_step() {
+        let recipe = Recipe::from_yaml_str(
+            "name: changeover\nsteps:\n  - home\n  - move: {position: -200, velocity: 240}\n  - wait_for_input: {input: 3, condition: rising, timeout_ms: 5000}\n  - set_output: {output: 2, high: true}\n  - dwell_ms: 500\n",
+        )
+        .expect("Unable to parse");
+        assert_eq!(recipe.name, "changeover");
+        let mut to = MoveOptions::to(-200i32 as u32 as u64);
+        to.velocity = Some(240);
+        assert_eq!(
+            recipe.steps,
+            vec![
+                RecipeStep::Home,
+                RecipeStep::Move(to),
+                RecipeStep::WaitForInput {
+                    input: 3,
+                    condition: InputCondition::Rising,
+                    timeout: Some(Duration::from_millis(5000)),
+                },
+                RecipeStep::SetOutput {
+                    output: 2,
+                    high: true
+                },
+                RecipeStep::Dwell(Duration::from_millis(500)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_steps_name_their_place() {
+        let result = Recipe::from_yaml_str("name: r\nsteps:\n  - home\n  - jump: 3\n");
+        match result {
+            Err(DeviceError::Config(msg)) => assert!(msg.starts_with("Step 2 of r"), "{}", msg),
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+        assert!(Recipe::from_yaml_str("steps: []\n").is_err());
+        assert!(Recipe::from_yaml_str("name: r\nsteps:\n  - move: {velocity: 5}\n").is_err());
+    }
+}
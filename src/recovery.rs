@@ -0,0 +1,190 @@
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, FilterSettings, IdleCurrent, InPositionWindow,
+    LimitSwitchConfig, ServoGains, StallMode, INITIALIZING,
+};
+use std::time::Duration;
+use tracing::{info, info_span, warn};
+
+static RECOVERY_POLL_INTERVAL: u64 = 500; // Between attempts to reach the drive, in ms
+
+// Drive settings written through this device that the drive loses when its
+// power is cycled, kept so recover() can write them again
+#[derive(Default)]
+pub(crate) struct VolatileSettings {
+    pub(crate) limit_switches: Option<LimitSwitchConfig>,
+    pub(crate) gains: Option<ServoGains>,
+    pub(crate) filters: Option<FilterSettings>,
+    pub(crate) idle_current: Option<IdleCurrent>,
+    pub(crate) in_position: Option<InPositionWindow>,
+    pub(crate) steps_per_rev: Option<u32>,
+    pub(crate) stall_detection: Option<(StallMode, u16)>, // Mode and sensitivity
+    pub(crate) following_error_limit: Option<u16>,
+}
+
+// How recover() deals with the position the drive lost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecoveryPolicy {
+    Rehome,
+    // Compare the drive's position with the last one read before the outage,
+    // within the motion tolerance.  An absolute encoder is read again first.
+    Revalidate,
+    Keep, // Leave the position as the drive reports it
+}
+
+// What recover() did
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    pub waited: Duration,          // Until the drive answered
+    pub reconnected: bool,         // A new connection had to be opened
+    pub was_initializing: bool,    // The drive was still starting up when reached
+    pub restored: Vec<String>,     // Settings written again, e.g. "gains"
+    pub homed: bool,               // The axis was homed
+    pub position_ok: Option<bool>, // From revalidating, None if not revalidated
+}
+
+impl AppliedDevice {
+    // Brings the device back after the drive's power was cycled or browned
+    // out: waits up to `timeout` for the drive to answer and finish starting
+    // up, clears alarms, writes again the settings the drive lost and only
+    // then enables the motor, then re-homes or revalidates the position per
+    // the policy.
+    pub fn recover(
+        &mut self,
+        policy: RecoveryPolicy,
+        timeout: Duration,
+    ) -> Result<RecoveryReport, DeviceError> {
+        let span = info_span!("recover", servo = %self.servo_name);
+        let _enter = span.enter();
+        let mut report = RecoveryReport::default();
+        let position_before = self.last_snapshot().encoder_count;
+        let started = self.clock.now();
+        let poll = Duration::from_millis(RECOVERY_POLL_INTERVAL);
+
+        info!("Recovering {} after a power loss", self.servo_name);
+        loop {
+            match self.get_servo_status() {
+                Ok(status) => {
+                    if !status.iter().any(|s| s == INITIALIZING) {
+                        break;
+                    }
+                    report.was_initializing = true;
+                }
+                Err(e) => {
+                    if self.clock.elapsed(started) >= timeout {
+                        return Err(DeviceError::TimedOut(format!(
+                            "{} did not answer within {}ms of recovery starting: {}",
+                            self.servo_name,
+                            timeout.as_millis(),
+                            e
+                        )));
+                    }
+                    // A failed reconnect is tried again on the next pass
+                    if self.reconnect().is_ok() {
+                        report.reconnected = true;
+                    }
+                }
+            }
+            if self.clock.elapsed(started) >= timeout {
+                return Err(DeviceError::TimedOut(format!(
+                    "{} was still initializing {}ms after recovery started",
+                    self.servo_name,
+                    timeout.as_millis()
+                )));
+            }
+            self.clock.sleep(poll);
+        }
+        report.waited = self.clock.elapsed(started);
+        info!(
+            "{} answered after {}ms",
+            self.servo_name,
+            report.waited.as_millis()
+        );
+
+        // Limits, gains and the like go back before the motor can move
+        self.reset_alarm_or_fault()?;
+        self.restore_volatile_settings(&mut report.restored)?;
+        self.enable_motor()?;
+
+        match policy {
+            RecoveryPolicy::Rehome => {
+                let homing = self.home_servo_with_report(&CancelToken::new())?;
+                report.homed = homing.completed;
+                if let Some(reason) = homing.failure() {
+                    warn!("{} was not re-homed: {}", self.servo_name, reason);
+                }
+            }
+            RecoveryPolicy::Revalidate => {
+                if self.absolute_encoder {
+                    self.read_startup_position()?;
+                }
+                let position = self.get_encoder_count()?;
+                let ok = self.positions_within(
+                    position,
+                    position_before,
+                    self.motion_defaults.tolerance,
+                );
+                if !ok {
+                    warn!(
+                        "{} is at {} after recovery, it was at {}",
+                        self.servo_name, position, position_before
+                    );
+                }
+                report.position_ok = Some(ok);
+            }
+            RecoveryPolicy::Keep => {}
+        }
+        info!("Recovered {}: {:?}", self.servo_name, report);
+        Ok(report)
+    }
+
+    fn restore_volatile_settings(&mut self, restored: &mut Vec<String>) -> Result<(), DeviceError> {
+        // The drive is back to its stored jerk filter, so the next S-curve
+        // move has to write it again
+        self.jerk_filter = None;
+        if let Some(steps) = self.volatile.steps_per_rev {
+            self.set_steps_per_rev(steps)?;
+            restored.push(String::from("steps_per_rev"));
+        }
+        if !self.motion_defaults.register_overrides.is_empty() {
+            self.apply_register_overrides()?;
+            restored.push(String::from("registers"));
+        }
+        if let Some(config) = self.volatile.limit_switches {
+            self.set_limit_switch_config(&config)?;
+            restored.push(String::from("limit_switches"));
+        }
+        if let Some(gains) = self.volatile.gains {
+            self.write_gains(&gains)?;
+            restored.push(String::from("gains"));
+        }
+        if let Some(filters) = self.volatile.filters {
+            self.set_filters(&filters)?;
+            restored.push(String::from("filters"));
+        }
+        if let Some(idle) = self.volatile.idle_current {
+            self.set_idle_current(idle)?;
+            restored.push(String::from("idle_current"));
+        }
+        if let Some(window) = self.volatile.in_position {
+            self.set_in_position_window(window)?;
+            restored.push(String::from("in_position"));
+        }
+        if let Some((mode, sensitivity)) = self.volatile.stall_detection {
+            self.set_stall_detection(mode, sensitivity)?;
+            restored.push(String::from("stall_detection"));
+        }
+        if let Some(limit) = self.volatile.following_error_limit {
+            self.set_following_error_limit(limit)?;
+            restored.push(String::from("following_error_limit"));
+        }
+        let input_filters: Vec<(u16, Duration)> =
+            self.input_filters.iter().map(|(i, f)| (*i, *f)).collect();
+        for (input, filter) in &input_filters {
+            self.set_input_filter(*input, *filter)?;
+        }
+        if !input_filters.is_empty() {
+            restored.push(String::from("input_filters"));
+        }
+        Ok(())
+    }
+}
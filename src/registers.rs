@@ -0,0 +1,408 @@
+use crate::{
+    AppliedDevice, DeviceError, ACCELERATION, ACTUAL_CURRENT, ACTUAL_VELOCITY, ALARM_REG,
+    DECELERATION, DISTANCE_1, DISTANCE_2, DISTANCE_CHANGE_1, DISTANCE_CHANGE_2, ENCODER_POS_1_REG,
+    ENCODER_POS_2_REG, EXECUTE_COMMAND, FOLLOWING_ERROR_1, FOLLOWING_ERROR_2, INPUTS_REG,
+    MAX_REGISTER, PARAMETER_1, PARAMETER_2, STATUS_REG, VELOCITY,
+};
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+use yaml_rust::Yaml;
+
+static MAX_READ_COUNT: u16 = 125; // Most holding registers one Modbus request may read
+
+// Which half of a 32 bit value is in the lower numbered of its two
+// registers.  The drive's own pairs, such as the encoder position, are
+// always high word first.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WordOrder {
+    #[default]
+    HighFirst,
+    LowFirst,
+}
+
+// One register read back from the drive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterValue {
+    pub register: u16,
+    pub value: u16,
+    pub name: Option<&'static str>, // Where the library knows what the register is
+}
+
+// What a register holds, for the registers of the drive's documented
+// Modbus map this library uses
+pub fn register_name(register: u16) -> Option<&'static str> {
+    let name = match register {
+        r if r == ALARM_REG => "alarm code",
+        r if r == STATUS_REG => "status code",
+        r if r == INPUTS_REG => "inputs",
+        r if r == ENCODER_POS_1_REG => "encoder position (high)",
+        r if r == ENCODER_POS_2_REG => "encoder position (low)",
+        r if r == ACTUAL_VELOCITY => "actual velocity",
+        r if r == FOLLOWING_ERROR_1 => "following error (high)",
+        r if r == FOLLOWING_ERROR_2 => "following error (low)",
+        r if r == ACTUAL_CURRENT => "actual current",
+        r if r == ACCELERATION => "acceleration",
+        r if r == DECELERATION => "deceleration",
+        r if r == VELOCITY => "velocity",
+        r if r == DISTANCE_1 => "distance (high)",
+        r if r == DISTANCE_2 => "distance (low)",
+        r if r == DISTANCE_CHANGE_1 => "distance change (high)",
+        r if r == DISTANCE_CHANGE_2 => "distance change (low)",
+        r if r == EXECUTE_COMMAND => "execute command",
+        r if r == PARAMETER_1 => "command parameter 1",
+        r if r == PARAMETER_2 => "command parameter 2",
+        _ => return None,
+    };
+    Some(name)
+}
+
+// Drive settings and readings outside the Modbus map every drive shares.
+// Where each lives depends on the drive model and its Q program, so a
+// device only reads or writes one once its RegisterMap gives the address
+// from the drive's manual.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappedRegister {
+    GearNumerator,   // Electronic gearing ratio used when following
+    GearDenominator, //
+    JerkFilter,      // S-curve smoothing, 0 for trapezoidal moves
+    CaptureInput,    // Input watched by position capture, 0 when disarmed
+    CaptureEdge,
+    CapturePosition, // Latched position, two registers high word first
+    CaptureFlag,     // Set by the drive once a position is latched
+    LimitMode,       // How the end of travel inputs are used, as for "DL"
+    LimitCwInput,
+    LimitCcwInput,
+    InPositionCounts,    // Position error allowed for In Position
+    InPositionTime,      // How long the error must stay in the window, in ms
+    FollowingErrorLimit, // Position error that faults the drive
+    StallMode,           // 0 off, 1 detect, 2 prevent
+    StallSensitivity,
+    IdleCurrentPercent, // Of the running current, once the motor is still
+    IdleCurrentDelay,   // In ms
+    StepsPerRev,        // Microstep resolution
+    EncoderResolution,  // Encoder counts per motor rev
+    AbsolutePosition,   // 48 bit absolute encoder position, three registers high word first
+    PositionGain,
+    IntegralGain,
+    DerivativeGain,
+    VelocityFeedforward,
+    AccelerationFeedforward,
+    AntiResonance, // 1 to enable
+    AntiResonanceDamping,
+    CommandSmoothing,
+    FirmwareRevision, // Major revision in the high byte
+    ModelCode,
+    SerialNumber, // Two registers, high word first
+    JogAccel,     // In the same units as ACCELERATION
+    JogDecel,
+    JogVelocity, // Signed, the sign setting the direction
+}
+
+static MAPPED_REGISTERS: &[MappedRegister] = &[
+    MappedRegister::GearNumerator,
+    MappedRegister::GearDenominator,
+    MappedRegister::JerkFilter,
+    MappedRegister::CaptureInput,
+    MappedRegister::CaptureEdge,
+    MappedRegister::CapturePosition,
+    MappedRegister::CaptureFlag,
+    MappedRegister::LimitMode,
+    MappedRegister::LimitCwInput,
+    MappedRegister::LimitCcwInput,
+    MappedRegister::InPositionCounts,
+    MappedRegister::InPositionTime,
+    MappedRegister::FollowingErrorLimit,
+    MappedRegister::StallMode,
+    MappedRegister::StallSensitivity,
+    MappedRegister::IdleCurrentPercent,
+    MappedRegister::IdleCurrentDelay,
+    MappedRegister::StepsPerRev,
+    MappedRegister::EncoderResolution,
+    MappedRegister::AbsolutePosition,
+    MappedRegister::PositionGain,
+    MappedRegister::IntegralGain,
+    MappedRegister::DerivativeGain,
+    MappedRegister::VelocityFeedforward,
+    MappedRegister::AccelerationFeedforward,
+    MappedRegister::AntiResonance,
+    MappedRegister::AntiResonanceDamping,
+    MappedRegister::CommandSmoothing,
+    MappedRegister::FirmwareRevision,
+    MappedRegister::ModelCode,
+    MappedRegister::SerialNumber,
+    MappedRegister::JogAccel,
+    MappedRegister::JogDecel,
+    MappedRegister::JogVelocity,
+];
+
+impl MappedRegister {
+    // The name used for the register in a device config's register_map section
+    pub fn name(&self) -> &'static str {
+        match self {
+            MappedRegister::GearNumerator => "gear_numerator",
+            MappedRegister::GearDenominator => "gear_denominator",
+            MappedRegister::JerkFilter => "jerk_filter",
+            MappedRegister::CaptureInput => "capture_input",
+            MappedRegister::CaptureEdge => "capture_edge",
+            MappedRegister::CapturePosition => "capture_position",
+            MappedRegister::CaptureFlag => "capture_flag",
+            MappedRegister::LimitMode => "limit_mode",
+            MappedRegister::LimitCwInput => "limit_cw_input",
+            MappedRegister::LimitCcwInput => "limit_ccw_input",
+            MappedRegister::InPositionCounts => "in_position_counts",
+            MappedRegister::InPositionTime => "in_position_time",
+            MappedRegister::FollowingErrorLimit => "following_error_limit",
+            MappedRegister::StallMode => "stall_mode",
+            MappedRegister::StallSensitivity => "stall_sensitivity",
+            MappedRegister::IdleCurrentPercent => "idle_current_percent",
+            MappedRegister::IdleCurrentDelay => "idle_current_delay",
+            MappedRegister::StepsPerRev => "steps_per_rev",
+            MappedRegister::EncoderResolution => "encoder_resolution",
+            MappedRegister::AbsolutePosition => "absolute_position",
+            MappedRegister::PositionGain => "position_gain",
+            MappedRegister::IntegralGain => "integral_gain",
+            MappedRegister::DerivativeGain => "derivative_gain",
+            MappedRegister::VelocityFeedforward => "velocity_feedforward",
+            MappedRegister::AccelerationFeedforward => "acceleration_feedforward",
+            MappedRegister::AntiResonance => "anti_resonance",
+            MappedRegister::AntiResonanceDamping => "anti_resonance_damping",
+            MappedRegister::CommandSmoothing => "command_smoothing",
+            MappedRegister::FirmwareRevision => "firmware_revision",
+            MappedRegister::ModelCode => "model_code",
+            MappedRegister::SerialNumber => "serial_number",
+            MappedRegister::JogAccel => "jog_accel",
+            MappedRegister::JogDecel => "jog_decel",
+            MappedRegister::JogVelocity => "jog_velocity",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MappedRegister> {
+        MAPPED_REGISTERS.iter().copied().find(|r| r.name() == name)
+    }
+
+    // How many registers, from the mapped one on, the value takes up
+    pub fn width(&self) -> u16 {
+        match self {
+            MappedRegister::CapturePosition | MappedRegister::SerialNumber => 2,
+            MappedRegister::AbsolutePosition => 3,
+            _ => 1,
+        }
+    }
+}
+
+// Where a device's drive keeps each MappedRegister.  Empty to begin with,
+// so nothing outside the documented map is touched until configured.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegisterMap {
+    registers: BTreeMap<MappedRegister, u16>,
+}
+
+impl RegisterMap {
+    pub fn new() -> RegisterMap {
+        RegisterMap::default()
+    }
+
+    pub fn set(&mut self, register: MappedRegister, address: u16) {
+        self.registers.insert(register, address);
+    }
+
+    pub fn get(&self, register: MappedRegister) -> Option<u16> {
+        self.registers.get(&register).copied()
+    }
+
+    // The mapped register an address belongs to, if any
+    pub fn register_at(&self, address: u16) -> Option<MappedRegister> {
+        self.registers
+            .iter()
+            .find(|(register, first)| (**first..*first + register.width()).contains(&address))
+            .map(|(register, _)| *register)
+    }
+
+    // Reads the optional `register_map:` section of a device config, giving
+    // the addresses of registers outside the documented map by name
+    //
+    //   register_map:
+    //     steps_per_rev: 45
+    //     jog_velocity: 42
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<RegisterMap>, DeviceError> {
+        let section = match &device_conf["register_map"] {
+            Yaml::Hash(section) => section,
+            Yaml::BadValue | Yaml::Null => return Ok(None),
+            other => {
+                return Err(DeviceError::Config(format!(
+                    "register_map must map register names to addresses, got {:?}",
+                    other
+                )))
+            }
+        };
+
+        let mut map = RegisterMap::new();
+        for (name, address) in section {
+            let register = match name.as_str().and_then(MappedRegister::from_name) {
+                Some(register) => register,
+                None => {
+                    return Err(DeviceError::Config(format!(
+                        "register_map has no register named {:?}",
+                        name
+                    )))
+                }
+            };
+            match address.as_i64() {
+                Some(a) if (0..=(u16::MAX - register.width()) as i64).contains(&a) => {
+                    map.set(register, a as u16)
+                }
+                _ => {
+                    return Err(DeviceError::Config(format!(
+                        "register_map.{} must be a register number, got {:?}",
+                        register.name(),
+                        address
+                    )))
+                }
+            }
+        }
+        Ok(Some(map))
+    }
+}
+
+// The two words of a value, in register order
+pub(crate) fn split_u32(value: u32, order: WordOrder) -> [u16; 2] {
+    let (high, low) = ((value >> 16) as u16, value as u16);
+    match order {
+        WordOrder::HighFirst => [high, low],
+        WordOrder::LowFirst => [low, high],
+    }
+}
+
+pub(crate) fn join_u32(words: [u16; 2], order: WordOrder) -> u32 {
+    let (high, low) = match order {
+        WordOrder::HighFirst => (words[0], words[1]),
+        WordOrder::LowFirst => (words[1], words[0]),
+    };
+    ((high as u32) << 16) | low as u32
+}
+
+impl AppliedDevice {
+    // Gives the addresses of the drive's registers outside the documented
+    // map.  Features that need a register it doesn't give fail with a
+    // config error rather than guess where it is.
+    pub fn set_register_map(&mut self, map: &RegisterMap) {
+        self.register_map = map.clone();
+    }
+
+    pub fn get_register_map(&self) -> &RegisterMap {
+        &self.register_map
+    }
+
+    // The address of a mapped register, failing if the map doesn't give it
+    pub(crate) fn mapped(&self, register: MappedRegister) -> Result<u16, DeviceError> {
+        self.register_map.get(register).ok_or_else(|| {
+            DeviceError::Config(format!(
+                "No {} register in the register map of {}",
+                register.name(),
+                self.servo_name
+            ))
+        })
+    }
+
+    // The word order read_u32, write_u32 and their signed versions use
+    pub fn set_word_order(&mut self, order: WordOrder) {
+        self.word_order = order;
+    }
+
+    pub fn get_word_order(&self) -> WordOrder {
+        self.word_order
+    }
+
+    // Reads a 32 bit value from a register and the one after it, in a single
+    // transaction so the two halves can't come from different moments
+    pub fn read_u32(&mut self, register: u16) -> Result<u32, DeviceError> {
+        self.read_u32_ordered(register, self.word_order)
+    }
+
+    pub fn read_i32(&mut self, register: u16) -> Result<i32, DeviceError> {
+        Ok(self.read_u32(register)? as i32)
+    }
+
+    // Writes a 32 bit value to a register and the one after it
+    pub fn write_u32(&mut self, register: u16, value: u32) -> Result<(), DeviceError> {
+        self.write_u32_ordered(register, value, self.word_order)
+    }
+
+    pub fn write_i32(&mut self, register: u16, value: i32) -> Result<(), DeviceError> {
+        self.write_u32(register, value as u32)
+    }
+
+    pub(crate) fn read_u32_ordered(
+        &mut self,
+        register: u16,
+        order: WordOrder,
+    ) -> Result<u32, DeviceError> {
+        let words = self.read_registers(register, 2)?;
+        match words.as_slice() {
+            [first, second, ..] => Ok(join_u32([*first, *second], order)),
+            _ => Err(DeviceError::Modbus(modbus::Error::InvalidResponse)),
+        }
+    }
+
+    pub(crate) fn write_u32_ordered(
+        &mut self,
+        register: u16,
+        value: u32,
+        order: WordOrder,
+    ) -> Result<(), DeviceError> {
+        let [first, second] = split_u32(value, order);
+        self.write_register(register, first as u64)?;
+        self.write_register(register + 1, second as u64)
+    }
+
+    // Reads every register up to the last one the library uses, as
+    // (register, value) pairs
+    pub fn dump_registers(&mut self) -> Result<Vec<(u16, u16)>, DeviceError> {
+        Ok(self
+            .dump_register_range(0, MAX_REGISTER - 1)?
+            .into_iter()
+            .map(|r| (r.register, r.value))
+            .collect())
+    }
+
+    // Reads registers first to last inclusive, naming those the library
+    // knows.  Ranges longer than one Modbus request are read in pieces.  A
+    // reversed range is empty, so nothing is read.
+    pub fn dump_register_range(
+        &mut self,
+        first: u16,
+        last: u16,
+    ) -> Result<Vec<RegisterValue>, DeviceError> {
+        if last < first {
+            return Ok(Vec::new());
+        }
+        info!(
+            "Dumping registers {} to {} of {}",
+            first, last, self.servo_name
+        );
+        let mut dump = Vec::with_capacity((last - first) as usize + 1);
+        let mut start = first;
+        loop {
+            let count = (last - start).min(MAX_READ_COUNT - 1) + 1;
+            for (offset, value) in self.read_registers(start, count)?.into_iter().enumerate() {
+                let register = start + offset as u16;
+                debug!("Register {}: {}", register, value);
+                let name = register_name(register).or_else(|| {
+                    self.register_map
+                        .register_at(register)
+                        .map(|mapped| mapped.name())
+                });
+                dump.push(RegisterValue {
+                    register,
+                    value,
+                    name,
+                });
+            }
+            if last - start < count {
+                break;
+            }
+            start += count;
+        }
+        Ok(dump)
+    }
+}
@@ -0,0 +1,461 @@
+use crate::exception::{exception_code, exception_code_value};
+use crate::transport::Transport;
+use crate::DeviceError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::time::Duration;
+use tracing::warn;
+
+// Recordings are plain text, one transaction per line:
+//
+//   R <register> <quantity> <value,value,...>   a successful read
+//   W <register> <value>                        a successful write
+//   M <register> <quantity> <value,value,...> <written,...> <read register>
+//                                               a successful write and read (FC23)
+//   X <R|W|M> <register> <arg> <exception code> a Modbus exception
+//   F <R|W|M> <register> <arg>                  any other failure (IO, bad response)
+//
+// where <arg> is the quantity for reads, the value for writes and the
+// quantity read for FC23, whose <register> is the first one written.
+// Lines starting with # are comments, so an incident recording can be
+// annotated.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Read,
+    Write,
+    WriteRead,
+}
+
+impl Kind {
+    fn letter(&self) -> &'static str {
+        match self {
+            Kind::Read => "R",
+            Kind::Write => "W",
+            Kind::WriteRead => "M",
+        }
+    }
+
+    fn from_letter(letter: &str) -> Option<Kind> {
+        match letter {
+            "R" => Some(Kind::Read),
+            "W" => Some(Kind::Write),
+            "M" => Some(Kind::WriteRead),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Outcome {
+    Read(Vec<u16>),
+    Written,
+    // The values read, with what was written and where the read started
+    WrittenAndRead(Vec<u16>, Vec<u16>, u16),
+    Exception(u8),
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Transaction {
+    kind: Kind,
+    register: u16,
+    arg: u16,
+    outcome: Outcome,
+}
+
+fn join_values(values: &[u16]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(",")
+}
+
+fn parse_values(values: Option<&&str>) -> Option<Vec<u16>> {
+    match values {
+        Some(v) => v
+            .split(',')
+            .map(|x| x.parse::<u16>().ok())
+            .collect::<Option<Vec<u16>>>(),
+        None => Some(Vec::new()),
+    }
+}
+
+impl Transaction {
+    fn to_line(&self) -> String {
+        let kind = self.kind.letter();
+        match &self.outcome {
+            Outcome::Read(values) => {
+                format!("R {} {} {}", self.register, self.arg, join_values(values))
+            }
+            Outcome::Written => format!("W {} {}", self.register, self.arg),
+            Outcome::WrittenAndRead(values, written, read_register) => format!(
+                "M {} {} {} {} {}",
+                self.register,
+                self.arg,
+                join_values(values),
+                join_values(written),
+                read_register
+            ),
+            Outcome::Exception(code) => {
+                format!("X {} {} {} {}", kind, self.register, self.arg, code)
+            }
+            Outcome::Failed => format!("F {} {} {}", kind, self.register, self.arg),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Transaction> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let num = |i: usize| parts.get(i).and_then(|p| p.parse::<u16>().ok());
+
+        match parts.first() {
+            Some(&"R") => Some(Transaction {
+                kind: Kind::Read,
+                register: num(1)?,
+                arg: num(2)?,
+                outcome: Outcome::Read(parse_values(parts.get(3))?),
+            }),
+            Some(&"W") => Some(Transaction {
+                kind: Kind::Write,
+                register: num(1)?,
+                arg: num(2)?,
+                outcome: Outcome::Written,
+            }),
+            Some(&"M") => Some(Transaction {
+                kind: Kind::WriteRead,
+                register: num(1)?,
+                arg: num(2)?,
+                outcome: Outcome::WrittenAndRead(
+                    parse_values(parts.get(3))?,
+                    parse_values(Some(parts.get(4)?))?,
+                    num(5)?,
+                ),
+            }),
+            Some(&"X") | Some(&"F") => {
+                let outcome = if parts[0] == "X" {
+                    Outcome::Exception(parts.get(4)?.parse::<u8>().ok()?)
+                } else {
+                    Outcome::Failed
+                };
+                Some(Transaction {
+                    kind: Kind::from_letter(parts.get(1)?)?,
+                    register: num(2)?,
+                    arg: num(3)?,
+                    outcome,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Wraps another transport and appends every transaction it carries to a
+// recording file, so real traffic can later be served by a ReplayTransport.
+pub struct RecordingTransport<T: Transport> {
+    inner: T,
+    file: File,
+}
+
+impl<T: Transport> RecordingTransport<T> {
+    pub fn new(inner: T, path: &str) -> Result<RecordingTransport<T>, DeviceError> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(RecordingTransport { inner, file }),
+            Err(e) => Err(DeviceError::Config(format!(
+                "Unable to open recording {}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, transaction: Transaction) {
+        // Flushing every line keeps the recording useful if the process dies mid incident
+        let result =
+            writeln!(self.file, "{}", transaction.to_line()).and_then(|_| self.file.flush());
+        if let Err(e) = result {
+            warn!("Unable to write to transport recording: {}", e);
+        }
+    }
+}
+
+fn outcome_of_error(error: &modbus::Error) -> Outcome {
+    match error {
+        modbus::Error::Exception(code) => Outcome::Exception(exception_code_value(code)),
+        _ => Outcome::Failed,
+    }
+}
+
+impl<T: Transport> Transport for RecordingTransport<T> {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        let result = self.inner.read_holding_registers(address, quantity);
+        let outcome = match &result {
+            Ok(values) => Outcome::Read(values.clone()),
+            Err(e) => outcome_of_error(e),
+        };
+        self.record(Transaction {
+            kind: Kind::Read,
+            register: address,
+            arg: quantity,
+            outcome,
+        });
+        result
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        let result = self.inner.write_single_register(address, value);
+        let outcome = match &result {
+            Ok(_) => Outcome::Written,
+            Err(e) => outcome_of_error(e),
+        };
+        self.record(Transaction {
+            kind: Kind::Write,
+            register: address,
+            arg: value,
+            outcome,
+        });
+        result
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        self.inner.set_unit_id(unit_id);
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        let result = self.inner.write_read_multiple_registers(
+            write_address,
+            values,
+            read_address,
+            read_quantity,
+        );
+        let outcome = match &result {
+            Ok(read) => Outcome::WrittenAndRead(read.clone(), values.to_vec(), read_address),
+            Err(e) => outcome_of_error(e),
+        };
+        self.record(Transaction {
+            kind: Kind::WriteRead,
+            register: write_address,
+            arg: read_quantity,
+            outcome,
+        });
+        result
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+// Serves a recording made by RecordingTransport back in order.  Every
+// request must match the next recorded one (same operation, register and
+// quantity/value), otherwise an error is returned, so a replay fails loudly
+// as soon as the code under test diverges from the recorded behaviour.
+pub struct ReplayTransport {
+    transactions: VecDeque<Transaction>,
+    served: usize,
+}
+
+impl ReplayTransport {
+    pub fn from_file(path: &str) -> Result<ReplayTransport, DeviceError> {
+        let unreadable =
+            |e: io::Error| DeviceError::Config(format!("Unable to read recording {}: {}", path, e));
+        let file = File::open(path).map_err(unreadable)?;
+
+        let mut transactions = VecDeque::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(unreadable)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Transaction::from_line(line) {
+                Some(t) => transactions.push_back(t),
+                None => {
+                    return Err(DeviceError::Config(format!(
+                        "Bad line {} in recording {}: {}",
+                        n + 1,
+                        path,
+                        line
+                    )))
+                }
+            }
+        }
+
+        Ok(ReplayTransport {
+            transactions,
+            served: 0,
+        })
+    }
+
+    // How many recorded transactions have not been requested yet
+    pub fn remaining(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn next(&mut self, kind: Kind, register: u16, arg: u16) -> modbus::Result<Outcome> {
+        let expected = match self.transactions.pop_front() {
+            Some(t) => t,
+            None => {
+                return Err(replay_error(format!(
+                    "Recording exhausted after {} transactions",
+                    self.served
+                )))
+            }
+        };
+        self.served += 1;
+
+        if expected.kind != kind || expected.register != register || expected.arg != arg {
+            return Err(replay_error(format!(
+                "Transaction {} diverged from recording: expected {}, got {} {} {}",
+                self.served,
+                expected.to_line(),
+                kind.letter(),
+                register,
+                arg
+            )));
+        }
+
+        match expected.outcome {
+            Outcome::Exception(code) => Err(modbus::Error::Exception(exception_code(code))),
+            Outcome::Failed => Err(replay_error(format!(
+                "Transaction {} failed when it was recorded",
+                self.served
+            ))),
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+// Carried inside the IO error a replay fails with, so retry_or_fail can
+// recognise it and fail at once rather than retry or reconnect, which
+// would only consume more of the recording
+#[derive(Debug)]
+struct ReplayError(String);
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+fn replay_error(message: String) -> modbus::Error {
+    modbus::Error::Io(io::Error::other(ReplayError(message)))
+}
+
+// Why a replay failed, if the error came from one
+pub(crate) fn replay_failure(error: &modbus::Error) -> Option<String> {
+    match error {
+        modbus::Error::Io(e) => e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<ReplayError>())
+            .map(|replay| replay.0.clone()),
+        _ => None,
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        match self.next(Kind::Read, address, quantity)? {
+            Outcome::Read(values) => Ok(values),
+            _ => Err(replay_error(String::from("Recorded read has no values"))),
+        }
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        self.next(Kind::Write, address, value).map(|_| ())
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        match self.next(Kind::WriteRead, write_address, read_quantity)? {
+            Outcome::WrittenAndRead(read, written, register)
+                if written == values && register == read_address =>
+            {
+                Ok(read)
+            }
+            Outcome::WrittenAndRead(_, written, register) => Err(replay_error(format!(
+                "Transaction {} diverged from recording: expected {:?} written and a read from {}",
+                self.served, written, register
+            ))),
+            _ => Err(replay_error(String::from(
+                "Recorded transaction has no values",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_outcome_round_trips_through_a_line() {
+        let transactions = vec![
+            Transaction {
+                kind: Kind::Read,
+                register: 5,
+                arg: 2,
+                outcome: Outcome::Read(vec![1, 65535]),
+            },
+            Transaction {
+                kind: Kind::Write,
+                register: 124,
+                arg: 103,
+                outcome: Outcome::Written,
+            },
+            Transaction {
+                kind: Kind::WriteRead,
+                register: 27,
+                arg: 1,
+                outcome: Outcome::WrittenAndRead(vec![16], vec![1, 2], 1),
+            },
+            Transaction {
+                kind: Kind::Read,
+                register: 300,
+                arg: 1,
+                outcome: Outcome::Exception(2),
+            },
+            Transaction {
+                kind: Kind::Write,
+                register: 124,
+                arg: 159,
+                outcome: Outcome::Failed,
+            },
+        ];
+        for transaction in transactions {
+            let line = transaction.to_line();
+            assert_eq!(Transaction::from_line(&line), Some(transaction), "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert_eq!(Transaction::from_line("R 5"), None);
+        assert_eq!(Transaction::from_line("W 5 x"), None);
+        assert_eq!(Transaction::from_line("X Q 5 1 2"), None);
+        assert_eq!(Transaction::from_line("Z 1 2"), None);
+    }
+
+    #[test]
+    fn replay_failures_are_recognised() {
+        let error = replay_error(String::from("diverged"));
+        assert_eq!(replay_failure(&error), Some(String::from("diverged")));
+        assert_eq!(replay_failure(&modbus::Error::InvalidResponse), None);
+    }
+}
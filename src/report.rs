@@ -0,0 +1,80 @@
+use crate::{AppliedDevice, Operation, OperationOutcome};
+use std::fmt::Write;
+
+impl AppliedDevice {
+    // A multi-line summary of the servo, for a support email or a terminal
+    // dashboard.  The drive is read afresh; if it can't be reached the
+    // report says so and shows the last state that was read instead.
+    pub fn report(&mut self) -> String {
+        let health = self.refresh_snapshot();
+        let snapshot = self.last_snapshot();
+        let mut out = String::new();
+
+        let _ = writeln!(out, "Servo:      {}", self.servo_name);
+        let _ = writeln!(out, "Address:    {}", self.servo_address);
+        if self.servo_addresses.len() > 1 {
+            let _ = writeln!(out, "Fallbacks:  {}", self.servo_addresses.join(", "));
+        }
+        let _ = match &health {
+            Ok(()) => writeln!(out, "Connection: ok"),
+            Err(e) => writeln!(
+                out,
+                "Connection: unreachable ({}), showing last read state",
+                e
+            ),
+        };
+        let _ = writeln!(out, "Status:     {}", list_or_none(&snapshot.status));
+        let _ = writeln!(out, "Alarms:     {}", list_or_none(&snapshot.alarms));
+        let _ = match self.counts_to_units(snapshot.encoder_count) {
+            Some(units) => writeln!(
+                out,
+                "Position:   {} counts ({:.3} units)",
+                snapshot.encoder_count, units
+            ),
+            None => writeln!(out, "Position:   {} counts", snapshot.encoder_count),
+        };
+        let _ = writeln!(out, "Cycles:     {}", self.servo_cycle_count);
+
+        let stats = self.stats();
+        let _ = writeln!(
+            out,
+            "Moves:      {} ({} incomplete, {} failed), mean {:.1} ms",
+            stats.moves, stats.incomplete_moves, stats.failed_moves, stats.duration_ms.mean
+        );
+        let operations = self.recent_operations(usize::MAX);
+        let last_move = operations
+            .iter()
+            .rev()
+            .find(|r| matches!(r.operation, Operation::Move { .. }));
+        let _ = match last_move {
+            Some(record) => {
+                let target = match record.operation {
+                    Operation::Move { target, .. } => target,
+                    _ => 0,
+                };
+                let outcome = match &record.outcome {
+                    OperationOutcome::Completed => String::from("completed"),
+                    OperationOutcome::Incomplete => String::from("incomplete"),
+                    OperationOutcome::Failed(e) => format!("failed: {}", e),
+                };
+                writeln!(
+                    out,
+                    "Last move:  to {} in {} ms, {}",
+                    target,
+                    record.duration.as_millis(),
+                    outcome
+                )
+            }
+            None => writeln!(out, "Last move:  none"),
+        };
+        out
+    }
+}
+
+fn list_or_none(names: &[String]) -> String {
+    if names.is_empty() {
+        String::from("none")
+    } else {
+        names.join(", ")
+    }
+}
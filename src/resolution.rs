@@ -0,0 +1,129 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use tracing::{error, info, warn};
+use yaml_rust::Yaml;
+
+static MIN_STEPS_PER_REV: u32 = 200;
+static MAX_STEPS_PER_REV: u32 = 51200;
+static MAX_ENCODER_COUNTS_PER_REV: i64 = u16::MAX as i64; // The drive's register is 16 bits wide
+
+impl AppliedDevice {
+    // Sets the drive's microstep resolution.  Distances given in steps,
+    // including those from revs_to_steps, scale with it.
+    pub fn set_steps_per_rev(&mut self, steps: u32) -> Result<(), DeviceError> {
+        if !(MIN_STEPS_PER_REV..=MAX_STEPS_PER_REV).contains(&steps) {
+            return Err(DeviceError::Config(format!(
+                "Steps per rev of {} must be from {} to {}, got {}",
+                self.servo_name, MIN_STEPS_PER_REV, MAX_STEPS_PER_REV, steps
+            )));
+        }
+        info!("Setting steps per rev of {} to {}", self.servo_name, steps);
+        self.write_register(self.mapped(MappedRegister::StepsPerRev)?, steps as u64)?;
+        self.steps_per_rev = Some(steps);
+        self.volatile.steps_per_rev = Some(steps);
+        Ok(())
+    }
+
+    // Reads the microstep resolution from the drive, warning if it isn't
+    // what this device expected.  Unit conversions use the drive's value
+    // from then on.
+    pub fn get_steps_per_rev(&mut self) -> Result<u32, DeviceError> {
+        let steps = self.get_register_value(self.mapped(MappedRegister::StepsPerRev)?)? as u32;
+        if let Some(expected) = self.steps_per_rev {
+            if expected != steps {
+                warn!(
+                    "{} is set to {} steps per rev, not the {} expected",
+                    self.servo_name, steps, expected
+                );
+            }
+        }
+        self.steps_per_rev = Some(steps);
+        Ok(steps)
+    }
+
+    // The resolution unit conversions are based on: as configured, last set
+    // or last read from the drive
+    pub fn known_steps_per_rev(&self) -> Option<u32> {
+        self.steps_per_rev
+    }
+
+    // Converts motor revolutions to steps, or None if the resolution isn't known
+    pub fn revs_to_steps(&self, revs: f64) -> Option<i64> {
+        self.steps_per_rev
+            .map(|steps| (revs * steps as f64).round() as i64)
+    }
+
+    pub fn steps_to_revs(&self, steps: i64) -> Option<f64> {
+        self.steps_per_rev
+            .map(|per_rev| steps as f64 / per_rev as f64)
+    }
+
+    // Reads the encoder resolution from the drive.  If a resolution was
+    // expected and the drive reports another, the motor has most likely been
+    // swapped for one with a different encoder and every position would be
+    // scaled wrongly, so this fails rather than carrying on.  Otherwise the
+    // drive's value is used for unit conversions.
+    pub fn get_encoder_counts_per_rev(&mut self) -> Result<u32, DeviceError> {
+        let counts =
+            self.get_register_value(self.mapped(MappedRegister::EncoderResolution)?)? as u32;
+        if let Some(expected) = self.encoder_counts_per_rev {
+            if expected != counts {
+                error!(
+                    "Encoder of {} has {} counts per rev, not the {} expected.  Has the motor been changed?",
+                    self.servo_name, counts, expected
+                );
+                return Err(DeviceError::Config(format!(
+                    "Encoder of {} has {} counts per rev, expected {}",
+                    self.servo_name, counts, expected
+                )));
+            }
+        }
+        self.encoder_counts_per_rev = Some(counts);
+        Ok(counts)
+    }
+
+    pub fn known_encoder_counts_per_rev(&self) -> Option<u32> {
+        self.encoder_counts_per_rev
+    }
+
+    // Converts motor revolutions to encoder counts, or None if the encoder
+    // resolution isn't known
+    pub fn revs_to_counts(&self, revs: f64) -> Option<i64> {
+        self.encoder_counts_per_rev
+            .map(|counts| (revs * counts as f64).round() as i64)
+    }
+
+    pub fn counts_to_revs(&self, counts: i64) -> Option<f64> {
+        self.encoder_counts_per_rev
+            .map(|per_rev| counts as f64 / per_rev as f64)
+    }
+}
+
+// Reads the optional `steps_per_rev:` setting of a device config, the
+// resolution the application's units assume
+pub(crate) fn steps_per_rev_from_yaml(device_conf: &Yaml) -> Result<Option<u32>, DeviceError> {
+    match &device_conf["steps_per_rev"] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(n) if (MIN_STEPS_PER_REV as i64..=MAX_STEPS_PER_REV as i64).contains(n) => {
+            Ok(Some(*n as u32))
+        }
+        other => Err(DeviceError::Config(format!(
+            "steps_per_rev must be from {} to {}, got {:?}",
+            MIN_STEPS_PER_REV, MAX_STEPS_PER_REV, other
+        ))),
+    }
+}
+
+// Reads the optional `encoder_counts_per_rev:` setting of a device config,
+// the encoder resolution the application's units assume
+pub(crate) fn encoder_counts_per_rev_from_yaml(
+    device_conf: &Yaml,
+) -> Result<Option<u32>, DeviceError> {
+    match &device_conf["encoder_counts_per_rev"] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(n) if *n > 0 && *n <= MAX_ENCODER_COUNTS_PER_REV => Ok(Some(*n as u32)),
+        other => Err(DeviceError::Config(format!(
+            "encoder_counts_per_rev must be from 1 to {}, got {:?}",
+            MAX_ENCODER_COUNTS_PER_REV, other
+        ))),
+    }
+}
@@ -0,0 +1,67 @@
+use crate::ModbusException;
+use std::io;
+use std::time::Duration;
+
+// How register reads and writes are retried when a transaction fails in a
+// way that is likely to go away by itself (a timeout, a dropped packet, a
+// busy drive).  Errors that will never succeed on retry, such as an illegal
+// register address, are always returned straight away.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,         // Total attempts, including the first one
+    pub initial_backoff: Duration, // Wait before the first retry
+    pub max_backoff: Duration,     // The wait doubles after every retry up to this
+    pub retry_timeouts: bool,
+    pub retry_io_errors: bool,     // Connection resets, broken pipes, etc.
+    pub retry_bad_responses: bool, // Garbled or unexpected replies
+    pub retry_busy: bool,          // Drive/gateway busy or acknowledge exceptions
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(1000),
+            retry_timeouts: true,
+            retry_io_errors: true,
+            retry_bad_responses: true,
+            retry_busy: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Every failure is returned on the first attempt
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_retryable(&self, error: &modbus::Error) -> bool {
+        match error {
+            modbus::Error::Io(e) => match e.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => self.retry_timeouts,
+                _ => self.retry_io_errors,
+            },
+            modbus::Error::Exception(code) if ModbusException::from_code(code).is_transient() => {
+                self.retry_busy
+            }
+            modbus::Error::InvalidResponse | modbus::Error::InvalidData(_) => {
+                self.retry_bad_responses
+            }
+            _ => false,
+        }
+    }
+
+    // How long to wait after the given (1 based) failed attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::{AppliedDevice, CancelToken, DeviceError, MoveRequest};
+use tracing::info;
+use yaml_rust::Yaml;
+
+// Distance between two encoder positions, allowing for the 32 bit count
+// wrapping around between them
+pub(crate) fn travel_between(start: u64, end: u64) -> u64 {
+    ((end as u32).wrapping_sub(start as u32) as i32).unsigned_abs() as u64
+}
+
+// A cycle must repeat within the drive's 32 bit encoder count.  This also
+// keeps shortest_path's sums well inside a u64.
+static MAX_ROLLOVER: u64 = u32::MAX as u64;
+
+// Shortest signed distance from one position in a cycle to another
+fn shortest_path(from: u64, to: u64, cycle: u64) -> i64 {
+    let forward = (to + cycle - from % cycle) % cycle;
+    if forward > cycle / 2 {
+        forward as i64 - cycle as i64
+    } else {
+        forward as i64
+    }
+}
+
+impl AppliedDevice {
+    // For axes that turn indefinitely, such as a rotary table.  Positions
+    // repeat every `counts_per_cycle` counts, in_range accepts the target in
+    // any cycle and move_rotary takes the shortest way round.  None turns
+    // rollover off.
+    pub fn set_rollover(&mut self, counts_per_cycle: Option<u64>) -> Result<(), DeviceError> {
+        if counts_per_cycle == Some(0) {
+            return Err(DeviceError::Config(String::from(
+                "Rollover needs a non-zero number of counts per cycle",
+            )));
+        }
+        if let Some(cycle) = counts_per_cycle.filter(|c| *c > MAX_ROLLOVER) {
+            return Err(DeviceError::Config(format!(
+                "Rollover of {} counts is longer than the encoder count, which repeats every {}",
+                cycle,
+                MAX_ROLLOVER + 1
+            )));
+        }
+        self.rollover = counts_per_cycle;
+        Ok(())
+    }
+
+    pub fn get_rollover(&self) -> Option<u64> {
+        self.rollover
+    }
+
+    // Where the axis is within its cycle, from 0 to counts_per_cycle - 1.
+    // The plain encoder count if rollover is off.
+    pub fn get_modulo_position(&mut self) -> Result<u64, DeviceError> {
+        let count = self.get_encoder_count()?;
+        Ok(self.modulo_position(count))
+    }
+
+    pub(crate) fn modulo_position(&self, count: u64) -> u64 {
+        match self.rollover {
+            Some(cycle) => (count as u32 as i32 as i64).rem_euclid(cycle as i64) as u64,
+            None => count,
+        }
+    }
+
+    // Whether two positions are within the given distance of each other,
+    // comparing them within the cycle if rollover is on
+    pub(crate) fn positions_within(&self, a: u64, b: u64, range: u64) -> bool {
+        match self.rollover {
+            Some(cycle) => {
+                let distance =
+                    shortest_path(self.modulo_position(a), self.modulo_position(b), cycle);
+                distance.unsigned_abs() <= range
+            }
+            None => a.max(b) - a.min(b) <= range,
+        }
+    }
+
+    pub fn move_rotary(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        position_in_cycle: u64,
+    ) -> Result<(), DeviceError> {
+        self.move_rotary_with_cancel(
+            accel,
+            decel,
+            velocity,
+            position_in_cycle,
+            &CancelToken::new(),
+        )
+    }
+
+    // Moves a rollover axis to a position within its cycle, going whichever
+    // way round is shorter
+    pub fn move_rotary_with_cancel(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        position_in_cycle: u64,
+        cancel: &CancelToken,
+    ) -> Result<(), DeviceError> {
+        let cycle = match self.rollover {
+            Some(cycle) => cycle,
+            None => {
+                return Err(DeviceError::Config(format!(
+                    "{} has no rollover set, so it can't make rotary moves",
+                    self.servo_name
+                )))
+            }
+        };
+        if position_in_cycle >= cycle {
+            return Err(DeviceError::Config(format!(
+                "Position {} is outside the cycle of {} counts",
+                position_in_cycle, cycle
+            )));
+        }
+
+        let count = self.get_encoder_count()?;
+        let distance = shortest_path(self.modulo_position(count), position_in_cycle, cycle);
+        // The target may be past where the 32 bit count wraps around
+        let target = (count as u32).wrapping_add(distance as i32 as u32) as u64;
+        info!(
+            "Moving {} by {} to {} within its cycle",
+            self.servo_name, distance, position_in_cycle
+        );
+        let request = MoveRequest::new(accel, decel, velocity, target);
+        let arrived = self.perform_move(&request, cancel)?;
+        self.require_arrived(arrived, target)
+    }
+}
+
+// Reads the optional `rollover:` setting of a device config, the counts
+// per cycle of a continuous rotation axis
+pub(crate) fn rollover_from_yaml(device_conf: &Yaml) -> Result<Option<u64>, DeviceError> {
+    match &device_conf["rollover"] {
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        Yaml::Integer(n) if *n > 0 && *n as u64 <= MAX_ROLLOVER => Ok(Some(*n as u64)),
+        other => Err(DeviceError::Config(format!(
+            "rollover must be from 1 to {} counts, got {:?}",
+            MAX_ROLLOVER, other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimulatedDrive;
+
+    #[test]
+    fn shortest_path_goes_either_way_round_the_longest_cycle() {
+        assert_eq!(shortest_path(MAX_ROLLOVER - 1, 0, MAX_ROLLOVER), 1);
+        assert_eq!(shortest_path(0, MAX_ROLLOVER - 1, MAX_ROLLOVER), -1);
+        assert_eq!(shortest_path(10, 5, 360), -5);
+    }
+
+    #[test]
+    fn cycles_longer_than_the_encoder_count_are_refused() {
+        let mut device = AppliedDevice::with_transport(
+            String::from("rotary"),
+            String::new(),
+            SimulatedDrive::new(),
+        );
+        assert!(device.set_rollover(Some(MAX_ROLLOVER)).is_ok());
+        assert!(matches!(
+            device.set_rollover(Some(MAX_ROLLOVER + 1)),
+            Err(DeviceError::Config(_))
+        ));
+        assert!(matches!(
+            device.set_rollover(Some(u64::MAX)),
+            Err(DeviceError::Config(_))
+        ));
+        assert_eq!(device.get_rollover(), Some(MAX_ROLLOVER));
+    }
+}
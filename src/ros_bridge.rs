@@ -0,0 +1,240 @@
+use crate::{AppliedDeviceHandle, CancelToken, DeviceError, MonitorHandle, MoveOptions};
+use futures::stream::LocalBoxStream;
+use futures::{FutureExt, StreamExt};
+use r2r::sensor_msgs::msg::JointState;
+use r2r::std_msgs::msg::{Float64, Header};
+use r2r::std_srvs::srv::Trigger;
+use r2r::{QosProfile, ServiceRequest};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+static SPIN_INTERVAL: u64 = 10; // How long each spin of the node waits for work, in ms
+static DEFAULT_PUBLISH_PERIOD: u64 = 50; // Between joint state messages, in ms
+
+// One servo as seen from ROS
+struct RosAxis {
+    handle: AppliedDeviceHandle,
+    monitor: MonitorHandle,
+    joint_name: String,
+    counts_per_unit: f64,
+    move_to: LocalBoxStream<'static, Float64>,
+    home: LocalBoxStream<'static, ServiceRequest<Trigger::Service>>,
+    stop: LocalBoxStream<'static, ServiceRequest<Trigger::Service>>,
+    alarms: r2r::Publisher<r2r::std_msgs::msg::String>,
+    last_alarms: Option<Vec<String>>,
+}
+
+// A service call running on its own thread, so the node keeps spinning
+struct PendingCall {
+    request: ServiceRequest<Trigger::Service>,
+    result: Receiver<Result<(), DeviceError>>,
+}
+
+// Exposes servos to a ROS 2 graph through one node.  Every servo gets
+//
+//   <node>/joint_states             sensor_msgs/JointState, all servos together
+//   <node>/<joint>/move_to          std_msgs/Float64 subscription, target in joint units
+//   <node>/<joint>/home             std_srvs/Trigger service
+//   <node>/<joint>/stop             std_srvs/Trigger service
+//   <node>/<joint>/alarms           std_msgs/String, published when the alarms change
+//
+// Joint units are counts divided by the counts_per_unit given for the
+// servo, e.g. counts per radian for a rotary joint.
+pub struct RosBridge {
+    node: r2r::Node,
+    clock: r2r::Clock,
+    joint_states: r2r::Publisher<JointState>,
+    axes: Vec<RosAxis>,
+    pending: Vec<PendingCall>,
+    publish_period: Duration,
+}
+
+impl RosBridge {
+    pub fn new(node_name: &str, namespace: &str) -> Result<RosBridge, DeviceError> {
+        let context = r2r::Context::create().map_err(ros_error)?;
+        let mut node = r2r::Node::create(context, node_name, namespace).map_err(ros_error)?;
+        let joint_states = node
+            .create_publisher::<JointState>("~/joint_states", QosProfile::default())
+            .map_err(ros_error)?;
+        let clock = r2r::Clock::create(r2r::ClockType::RosTime).map_err(ros_error)?;
+        info!("Created ROS 2 node {} in {:?}", node_name, namespace);
+        Ok(RosBridge {
+            node,
+            clock,
+            joint_states,
+            axes: Vec::new(),
+            pending: Vec::new(),
+            publish_period: Duration::from_millis(DEFAULT_PUBLISH_PERIOD),
+        })
+    }
+
+    // How often joint states are published (every 50 ms by default)
+    pub fn set_publish_period(&mut self, period: Duration) {
+        self.publish_period = period;
+    }
+
+    pub fn add_axis(
+        &mut self,
+        handle: AppliedDeviceHandle,
+        joint_name: &str,
+        counts_per_unit: f64,
+    ) -> Result<(), DeviceError> {
+        if counts_per_unit <= 0.0 {
+            return Err(DeviceError::Config(format!(
+                "counts_per_unit of joint {} must be positive, got {}",
+                joint_name, counts_per_unit
+            )));
+        }
+        let topic = |name: &str| format!("~/{}/{}", joint_name, name);
+        let qos = QosProfile::default;
+        let move_to = self
+            .node
+            .subscribe::<Float64>(&topic("move_to"), qos())
+            .map_err(ros_error)?;
+        let home = self
+            .node
+            .create_service::<Trigger::Service>(&topic("home"), qos())
+            .map_err(ros_error)?;
+        let stop = self
+            .node
+            .create_service::<Trigger::Service>(&topic("stop"), qos())
+            .map_err(ros_error)?;
+        let alarms = self
+            .node
+            .create_publisher(&topic("alarms"), qos())
+            .map_err(ros_error)?;
+        info!("Exposing {} as joint {}", handle.get_name(), joint_name);
+        self.axes.push(RosAxis {
+            monitor: handle.monitor(),
+            handle,
+            joint_name: joint_name.to_string(),
+            counts_per_unit,
+            move_to: move_to.boxed_local(),
+            home: home.boxed_local(),
+            stop: stop.boxed_local(),
+            alarms,
+            last_alarms: None,
+        });
+        Ok(())
+    }
+
+    // Spins the node until the token is cancelled
+    pub fn spin(&mut self, cancel: &CancelToken) -> Result<(), DeviceError> {
+        let mut last_publish: Option<Instant> = None;
+        while !cancel.is_cancelled() {
+            self.node.spin_once(Duration::from_millis(SPIN_INTERVAL));
+            self.take_requests();
+            self.answer_finished_calls();
+            if last_publish.is_none_or(|at| at.elapsed() >= self.publish_period) {
+                self.publish()?;
+                last_publish = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    fn take_requests(&mut self) {
+        for axis in &mut self.axes {
+            while let Some(Some(target)) = axis.move_to.next().now_or_never() {
+                let counts = (target.data * axis.counts_per_unit).round() as i64 as u32 as u64;
+                let handle = axis.handle.clone();
+                // Moves are fire and forget; failures show up as alarms
+                thread::spawn(move || {
+                    if let Err(e) = handle.move_with(MoveOptions::to(counts)) {
+                        warn!(
+                            "ROS move of {} to {} failed: {}",
+                            handle.get_name(),
+                            counts,
+                            e
+                        );
+                    }
+                });
+            }
+            while let Some(Some(request)) = axis.home.next().now_or_never() {
+                let handle = axis.handle.clone();
+                self.pending
+                    .push(run_in_background(request, move || handle.home_servo()));
+            }
+            while let Some(Some(request)) = axis.stop.next().now_or_never() {
+                // Abort the running operation first, it would hold up the stop
+                axis.handle.abort();
+                let handle = axis.handle.clone();
+                self.pending
+                    .push(run_in_background(request, move || handle.stop_motion()));
+            }
+        }
+    }
+
+    fn answer_finished_calls(&mut self) {
+        let mut still_running = Vec::new();
+        for call in self.pending.drain(..) {
+            let result = match call.result.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => {
+                    still_running.push(call);
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => Err(DeviceError::WorkerStopped(String::from(
+                    "The call ended without a result",
+                ))),
+            };
+            let response = match result {
+                Ok(()) => Trigger::Response {
+                    success: true,
+                    message: String::new(),
+                },
+                Err(e) => Trigger::Response {
+                    success: false,
+                    message: e.to_string(),
+                },
+            };
+            if let Err(e) = call.request.respond(response) {
+                warn!("Unable to answer a ROS service call: {}", e);
+            }
+        }
+        self.pending = still_running;
+    }
+
+    fn publish(&mut self) -> Result<(), DeviceError> {
+        let now = self.clock.get_now().map_err(ros_error)?;
+        let mut state = JointState {
+            header: Header {
+                stamp: r2r::Clock::to_builtin_time(&now),
+                frame_id: String::new(),
+            },
+            ..JointState::default()
+        };
+        for axis in &mut self.axes {
+            let snapshot = axis.monitor.snapshot();
+            let counts = crate::feed::signed_count(snapshot.encoder_count);
+            state.name.push(axis.joint_name.clone());
+            state.position.push(counts as f64 / axis.counts_per_unit);
+
+            if axis.last_alarms.as_ref() != Some(&snapshot.alarms) {
+                let data = snapshot.alarms.join(", ");
+                axis.alarms
+                    .publish(&r2r::std_msgs::msg::String { data })
+                    .map_err(ros_error)?;
+                axis.last_alarms = Some(snapshot.alarms);
+            }
+        }
+        self.joint_states.publish(&state).map_err(ros_error)
+    }
+}
+
+fn run_in_background<F>(request: ServiceRequest<Trigger::Service>, f: F) -> PendingCall
+where
+    F: FnOnce() -> Result<(), DeviceError> + Send + 'static,
+{
+    let (sender, result) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    PendingCall { request, result }
+}
+
+fn ros_error(e: r2r::Error) -> DeviceError {
+    DeviceError::Connection(format!("ROS 2: {}", e))
+}
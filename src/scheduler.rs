@@ -0,0 +1,374 @@
+use crate::{AppliedDevice, AppliedDeviceHandle, CancelToken, Clock, DeviceError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+static SCHEDULER_TICK: u64 = 250; // How often due motions are checked for, in ms
+static SECONDS_PER_DAY: u64 = 86400;
+
+type Action =
+    Arc<dyn Fn(&mut AppliedDevice, &CancelToken) -> Result<(), DeviceError> + Send + Sync>;
+
+// When a scheduled motion comes due
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    Every(Duration),                  // Counted from when the motion was added
+    DailyAt { hour: u8, minute: u8 }, // UTC, e.g. a shift change
+}
+
+impl Trigger {
+    // When the trigger next fires, timed by the device's clock
+    fn next_run(&self, clock: &dyn Clock) -> Instant {
+        clock.now() + self.next_wait(clock.wall_time())
+    }
+
+    // The first time after `after` the trigger fires, as a wait from now
+    fn next_wait(&self, after: SystemTime) -> Duration {
+        match *self {
+            Trigger::Every(period) => period,
+            Trigger::DailyAt { hour, minute } => {
+                let now = after
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let at = (hour as u64 % 24) * 3600 + (minute as u64 % 60) * 60;
+                let today = now % SECONDS_PER_DAY;
+                let wait = if at > today {
+                    at - today
+                } else {
+                    at + SECONDS_PER_DAY - today
+                };
+                Duration::from_secs(wait)
+            }
+        }
+    }
+}
+
+// What to do when a motion comes due while the servo is busy with another
+// command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    Skip, // Drop this run and wait for the next one
+    Wait, // Run as soon as the servo is free
+}
+
+// A motion run by a MotionScheduler, such as exercising the axis every hour
+// or parking it at the end of a shift
+#[derive(Clone)]
+pub struct ScheduledMotion {
+    name: String,
+    trigger: Trigger,
+    idle_for: Option<Duration>,
+    conflict: ConflictPolicy,
+    action: Action,
+}
+
+impl ScheduledMotion {
+    // The action is run on the servo's worker, like any other call, and
+    // should give up when the token is cancelled
+    pub fn new<F>(name: &str, trigger: Trigger, action: F) -> ScheduledMotion
+    where
+        F: Fn(&mut AppliedDevice, &CancelToken) -> Result<(), DeviceError> + Send + Sync + 'static,
+    {
+        ScheduledMotion {
+            name: name.to_string(),
+            trigger,
+            idle_for: None,
+            conflict: ConflictPolicy::Skip,
+            action: Arc::new(action),
+        }
+    }
+
+    // Only runs once nothing else has used the servo for this long.  Until
+    // then the motion is treated as conflicting.
+    pub fn only_when_idle_for(mut self, idle_for: Duration) -> ScheduledMotion {
+        self.idle_for = Some(idle_for);
+        self
+    }
+
+    pub fn on_conflict(mut self, conflict: ConflictPolicy) -> ScheduledMotion {
+        self.conflict = conflict;
+        self
+    }
+}
+
+struct Entry {
+    motion: ScheduledMotion,
+    enabled: bool,
+    next_run: Instant,
+    last_result: Option<Result<(), String>>,
+}
+
+// Runs scheduled motions on a servo from a background thread, timed by the
+// servo's clock.  They go through the servo's handle, so a manual command
+// sent while one runs waits for it, and the handle's abort() cancels it.
+// Stops when dropped, cancelling any scheduled motion in progress.
+pub struct MotionScheduler {
+    entries: Arc<Mutex<Vec<Entry>>>,
+    stop: CancelToken,
+    running: Arc<Mutex<bool>>, // Whether a scheduled motion is on the worker
+    handle: AppliedDeviceHandle,
+    clock: Arc<dyn Clock>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MotionScheduler {
+    pub fn new(handle: AppliedDeviceHandle) -> Result<MotionScheduler, DeviceError> {
+        let entries: Arc<Mutex<Vec<Entry>>> = Arc::new(Mutex::new(Vec::new()));
+        let stop = CancelToken::new();
+        let running = Arc::new(Mutex::new(false));
+        let thread = {
+            let scheduler = Scheduler {
+                handle: handle.clone(),
+                entries: entries.clone(),
+                stop: stop.clone(),
+                running: running.clone(),
+            };
+            thread::Builder::new()
+                .name(format!("scheduler-{}", handle.get_name()))
+                .spawn(move || scheduler.run())
+                .map_err(|e| {
+                    DeviceError::WorkerStopped(format!(
+                        "Unable to start the scheduler for {}: {}",
+                        handle.get_name(),
+                        e
+                    ))
+                })?
+        };
+        Ok(MotionScheduler {
+            entries,
+            stop,
+            running,
+            clock: handle.clock(),
+            handle,
+            thread: Some(thread),
+        })
+    }
+
+    // Adds a motion, enabled.  Names must be unique.
+    pub fn add(&self, motion: ScheduledMotion) -> Result<(), DeviceError> {
+        let mut entries = self.entries();
+        if entries.iter().any(|e| e.motion.name == motion.name) {
+            return Err(DeviceError::Config(format!(
+                "A motion named {} is already scheduled",
+                motion.name
+            )));
+        }
+        info!("Scheduling {} ({:?})", motion.name, motion.trigger);
+        let next_run = motion.trigger.next_run(self.clock.as_ref());
+        entries.push(Entry {
+            motion,
+            enabled: true,
+            next_run,
+            last_result: None,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        let mut entries = self.entries();
+        let before = entries.len();
+        entries.retain(|e| e.motion.name != name);
+        entries.len() != before
+    }
+
+    // Returns false if no motion has the name.  A re-enabled motion next
+    // runs at its trigger's next time, not straight away.
+    pub fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+        match self.entries().iter_mut().find(|e| e.motion.name == name) {
+            Some(entry) => {
+                if enabled && !entry.enabled {
+                    entry.next_run = entry.motion.trigger.next_run(self.clock.as_ref());
+                }
+                entry.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> Option<bool> {
+        self.entries()
+            .iter()
+            .find(|e| e.motion.name == name)
+            .map(|e| e.enabled)
+    }
+
+    pub fn get_names(&self) -> Vec<String> {
+        self.entries()
+            .iter()
+            .map(|e| e.motion.name.clone())
+            .collect()
+    }
+
+    // How long until the motion is next due, zero if it is waiting on the servo
+    pub fn time_until(&self, name: &str) -> Option<Duration> {
+        self.entries()
+            .iter()
+            .find(|e| e.motion.name == name)
+            .map(|e| e.next_run.saturating_duration_since(self.clock.now()))
+    }
+
+    // The outcome of the motion's last run, None if it hasn't run yet
+    pub fn last_result(&self, name: &str) -> Option<Result<(), String>> {
+        self.entries()
+            .iter()
+            .find(|e| e.motion.name == name)
+            .and_then(|e| e.last_result.clone())
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Vec<Entry>> {
+        lock(&self.entries)
+    }
+}
+
+impl Drop for MotionScheduler {
+    fn drop(&mut self) {
+        // Checked under the same lock the worker takes to start a motion, so
+        // a motion either sees the stop before it starts or is aborted
+        {
+            let running = lock(&self.running);
+            self.stop.cancel();
+            if *running {
+                self.handle.abort();
+            }
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(m) => m,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// The scheduler's side of a MotionScheduler, run on its own thread
+struct Scheduler {
+    handle: AppliedDeviceHandle,
+    entries: Arc<Mutex<Vec<Entry>>>,
+    stop: CancelToken,
+    running: Arc<Mutex<bool>>,
+}
+
+impl Scheduler {
+    fn run(self) {
+        let clock = self.handle.clock();
+        let tick = Duration::from_millis(SCHEDULER_TICK);
+        while !self.stop.is_cancelled() {
+            match self.take_due(clock.as_ref()) {
+                Some(motion) => self.run_motion(motion, clock.as_ref()),
+                None => clock.sleep(tick),
+            }
+        }
+    }
+
+    // One motion per tick, so the list isn't locked while it runs
+    fn take_due(&self, clock: &dyn Clock) -> Option<ScheduledMotion> {
+        let mut entries = lock(&self.entries);
+        let now = clock.now();
+        for entry in entries
+            .iter_mut()
+            .filter(|e| e.enabled && e.next_run <= now)
+        {
+            let idle_enough = entry
+                .motion
+                .idle_for
+                .is_none_or(|idle_for| self.handle.idle_time() >= idle_for);
+            if !self.handle.is_busy() && idle_enough {
+                return Some(entry.motion.clone());
+            }
+            if entry.motion.conflict == ConflictPolicy::Skip {
+                info!(
+                    "Skipping scheduled {} on {}, the servo is in use",
+                    entry.motion.name,
+                    self.handle.get_name()
+                );
+                entry.next_run = entry.motion.trigger.next_run(clock);
+            }
+        }
+        None
+    }
+
+    fn run_motion(&self, motion: ScheduledMotion, clock: &dyn Clock) {
+        info!(
+            "Running scheduled {} on {}",
+            motion.name,
+            self.handle.get_name()
+        );
+        let action = motion.action.clone();
+        let running = self.running.clone();
+        let stop = self.stop.clone();
+        let result = self.handle.call(move |device, abort| {
+            {
+                let mut running = lock(&running);
+                if stop.is_cancelled() {
+                    return None;
+                }
+                *running = true;
+            }
+            let result = action(device, abort);
+            *lock(&running) = false;
+            Some(result)
+        });
+        let result = match result {
+            Ok(Some(inner)) => inner,
+            // The scheduler was dropped while the motion waited on the worker
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            warn!(
+                "Scheduled {} on {} failed: {}",
+                motion.name,
+                self.handle.get_name(),
+                e
+            );
+        }
+        let mut entries = lock(&self.entries);
+        if let Some(entry) = entries.iter_mut().find(|e| e.motion.name == motion.name) {
+            entry.next_run = motion.trigger.next_run(clock);
+            entry.last_result = Some(result.map_err(|e| e.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u64, minute: u64) -> SystemTime {
+        // Some day well after the epoch, at the given time of day
+        UNIX_EPOCH + Duration::from_secs(19000 * SECONDS_PER_DAY + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn every_waits_its_period() {
+        let trigger = Trigger::Every(Duration::from_secs(90));
+        assert_eq!(trigger.next_wait(at(10, 0)), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn daily_at_later_today() {
+        let trigger = Trigger::DailyAt {
+            hour: 10,
+            minute: 30,
+        };
+        assert_eq!(trigger.next_wait(at(10, 0)), Duration::from_secs(30 * 60));
+    }
+
+    #[test]
+    fn daily_at_already_passed_waits_for_tomorrow() {
+        let trigger = Trigger::DailyAt { hour: 9, minute: 0 };
+        assert_eq!(trigger.next_wait(at(10, 0)), Duration::from_secs(23 * 3600));
+        // Exactly now is tomorrow's too, so a motion doesn't run twice
+        assert_eq!(
+            trigger.next_wait(at(9, 0)),
+            Duration::from_secs(SECONDS_PER_DAY)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use crate::feed::signed_count;
+use crate::{AppliedDevice, CancelToken, DeviceError, TraceSample, ACTUAL_CURRENT};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+static CURRENT_UNITS: f64 = 100.0; // The drive reports current in 0.01 A
+static MAX_SCOPE_SAMPLES: usize = 100_000; // Keeps a long capture at a short period in check
+
+// When an armed capture starts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeTrigger {
+    Now,
+    MoveStart, // As the next move is commanded
+}
+
+// A burst of fast sampling for tuning, emulated by polling the drive as
+// quickly as the period asks.  How close to the period the samples come
+// depends on the round trip time to the drive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScopeSettings {
+    pub period: Duration,
+    pub duration: Duration,
+    pub trigger: ScopeTrigger,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScopeSample {
+    pub offset: Duration, // From the trigger
+    pub position: i64,    // Encoder counts
+    pub velocity: f64,    // Counts/s
+    pub current: f64,     // Amps
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeTrace {
+    pub servo_name: String,
+    pub triggered: Instant,
+    pub period: Duration, // As asked for, see the samples' offsets for when they were taken
+    pub samples: Vec<ScopeSample>,
+    pub complete: bool, // False if cut short, by a cancel, a failed read or reaching MAX_SCOPE_SAMPLES
+}
+
+pub(crate) struct Scope {
+    settings: ScopeSettings,
+    trace: Option<ScopeTrace>, // Once triggered
+    last: Option<Instant>,
+    finished: bool,
+}
+
+impl AppliedDevice {
+    // The motor current right now, in amps
+    pub fn get_motor_current(&mut self) -> Result<f64, DeviceError> {
+        let raw = self.get_register_value(ACTUAL_CURRENT)? as u16 as i16;
+        Ok(raw as f64 / CURRENT_UNITS)
+    }
+
+    // Arms a capture, replacing any armed or finished one.  It is sampled
+    // along with the motion trace and streams while homing and moving, so
+    // with a MoveStart trigger it records the next move.
+    pub fn arm_scope(&mut self, settings: ScopeSettings) {
+        let period = settings.period.max(Duration::from_millis(1));
+        self.scope = Some(Scope {
+            settings: ScopeSettings { period, ..settings },
+            trace: None,
+            last: None,
+            finished: false,
+        });
+        if settings.trigger == ScopeTrigger::Now {
+            self.trigger_scope();
+        }
+    }
+
+    // Takes the capture once it is done, or as far as it got if it isn't,
+    // disarming the scope.  None if nothing has been captured.
+    pub fn take_scope_trace(&mut self) -> Option<ScopeTrace> {
+        self.scope.take().and_then(|scope| scope.trace)
+    }
+
+    // Captures for the given duration straight away, waiting here for it
+    // to finish
+    pub fn capture_scope(
+        &mut self,
+        period: Duration,
+        duration: Duration,
+        cancel: &CancelToken,
+    ) -> Result<ScopeTrace, DeviceError> {
+        self.arm_scope(ScopeSettings {
+            period,
+            duration,
+            trigger: ScopeTrigger::Now,
+        });
+        let finished = self.sleep_and_sample(duration, cancel)?;
+        // The last sample may land just short of the duration
+        if finished && self.scope_period().is_some() {
+            self.take_samples()?;
+        }
+        let trace = self.take_scope_trace().unwrap_or_else(|| ScopeTrace {
+            servo_name: self.servo_name.clone(),
+            triggered: self.clock.now(),
+            period,
+            samples: Vec::new(),
+            complete: false,
+        });
+        info!(
+            "Captured {} scope samples from {}",
+            trace.samples.len(),
+            self.servo_name
+        );
+        Ok(trace)
+    }
+
+    pub(crate) fn trigger_scope(&mut self) {
+        let now = self.clock.now();
+        let servo_name = self.servo_name.clone();
+        if let Some(scope) = &mut self.scope {
+            if scope.trace.is_none() {
+                scope.trace = Some(ScopeTrace {
+                    servo_name,
+                    triggered: now,
+                    period: scope.settings.period,
+                    samples: Vec::new(),
+                    complete: false,
+                });
+            }
+        }
+    }
+
+    // How often the scope wants samples, while a capture is running
+    pub(crate) fn scope_period(&self) -> Option<Duration> {
+        match &self.scope {
+            Some(scope) if scope.trace.is_some() && !scope.finished => Some(scope.settings.period),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn scope_due(&self, at: Instant) -> bool {
+        match &self.scope {
+            Some(scope) => {
+                self.scope_period().is_some()
+                    && AppliedDevice::sample_due(scope.last, scope.settings.period, at)
+            }
+            None => false,
+        }
+    }
+
+    // Ends the capture, cut short, if the current can't be read, leaving
+    // the motion being captured to carry on
+    pub(crate) fn record_scope_sample(&mut self, sample: &TraceSample) {
+        let velocity = match sample.velocity {
+            Some(velocity) if self.scope_due(sample.at) => velocity,
+            _ => return,
+        };
+        let current = self.get_motor_current();
+        let servo_name = self.servo_name.clone();
+        let scope = match &mut self.scope {
+            Some(scope) => scope,
+            None => return,
+        };
+        let trace = match &mut scope.trace {
+            Some(trace) => trace,
+            None => return,
+        };
+        let current = match current {
+            Ok(current) => current,
+            Err(e) => {
+                warn!(
+                    "Scope capture of {} stopped, unable to read the motor current: {}",
+                    servo_name, e
+                );
+                scope.finished = true;
+                return;
+            }
+        };
+        let offset = sample.at.saturating_duration_since(trace.triggered);
+        if offset > scope.settings.duration {
+            scope.finished = true;
+            trace.complete = true;
+            return;
+        }
+        scope.last = Some(sample.at);
+        trace.samples.push(ScopeSample {
+            offset,
+            position: signed_count(sample.encoder_count),
+            velocity,
+            current,
+        });
+        if offset + scope.settings.period > scope.settings.duration {
+            scope.finished = true;
+            trace.complete = true;
+        } else if trace.samples.len() >= MAX_SCOPE_SAMPLES {
+            warn!(
+                "Scope capture of {} stopped at {} samples",
+                servo_name, MAX_SCOPE_SAMPLES
+            );
+            scope.finished = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    fn scope_device() -> AppliedDevice {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), drive);
+        device.set_clock(clock);
+        device.encoder_counts_per_rev = Some(20000);
+        device
+    }
+
+    #[test]
+    fn captures_run_for_their_duration_at_their_period() {
+        let mut device = scope_device();
+        device.enable_motor().expect("Unable to enable the motor");
+        let trace = device
+            .capture_scope(
+                Duration::from_millis(10),
+                Duration::from_millis(100),
+                &CancelToken::new(),
+            )
+            .expect("Capture failed");
+
+        assert!(trace.complete);
+        assert_eq!(trace.samples.len(), 11);
+        assert_eq!(trace.samples[0].offset, Duration::ZERO);
+        assert!(trace
+            .samples
+            .windows(2)
+            .all(|pair| pair[1].offset >= pair[0].offset + Duration::from_millis(9)));
+        assert!(trace.samples.last().unwrap().offset <= Duration::from_millis(100));
+        assert!(trace.samples.iter().all(|s| s.current == 0.3));
+    }
+
+    #[test]
+    fn a_cancelled_capture_is_incomplete() {
+        let mut device = scope_device();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let trace = device
+            .capture_scope(Duration::from_millis(10), Duration::from_secs(1), &cancel)
+            .expect("Capture failed");
+        assert!(!trace.complete);
+        assert!(trace.samples.len() < 100);
+        assert!(device.take_scope_trace().is_none());
+    }
+
+    #[test]
+    fn move_start_captures_wait_for_the_next_move() {
+        let mut device = scope_device();
+        device.enable_motor().expect("Unable to enable the motor");
+        device.arm_scope(ScopeSettings {
+            period: Duration::from_millis(5),
+            duration: Duration::from_millis(200),
+            trigger: ScopeTrigger::MoveStart,
+        });
+        device
+            .sleep_and_sample(Duration::from_millis(100), &CancelToken::new())
+            .expect("Sleep failed");
+        assert_eq!(device.scope_period(), None);
+
+        device.move_servo(60, 60, 240, 20_000).expect("Move failed");
+        let trace = device.take_scope_trace().expect("Nothing captured");
+        assert!(trace.complete);
+        assert!(trace.samples.len() > 30);
+        assert!(trace
+            .samples
+            .windows(2)
+            .all(|pair| pair[1].position >= pair[0].position));
+        // Speeding up draws more than holding
+        assert!(trace.samples.iter().any(|s| s.current == 2.0));
+        assert!(trace.samples.iter().any(|s| s.velocity > 0.0));
+    }
+
+    #[test]
+    fn nothing_is_taken_before_a_trigger() {
+        let mut device = scope_device();
+        assert!(device.take_scope_trace().is_none());
+        device.arm_scope(ScopeSettings {
+            period: Duration::from_millis(5),
+            duration: Duration::from_millis(200),
+            trigger: ScopeTrigger::MoveStart,
+        });
+        assert!(device.take_scope_trace().is_none());
+    }
+}
@@ -0,0 +1,58 @@
+use crate::{AppliedDevice, DeviceError};
+use std::io;
+use tracing::{info, warn};
+
+// The drives accept one Modbus master at a time.  When another client, such
+// as the vendor's configuration tool, connects, the drive drops our session
+// and every transaction on it fails with the socket reset or closed.  A
+// network glitch looks the same, so these are retried over a new
+// connection first and only treated as a seizure if that fails.
+pub(crate) fn is_seizure(error: &modbus::Error) -> bool {
+    match error {
+        modbus::Error::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+impl AppliedDevice {
+    // Whether to reconnect and carry on when another client takes the
+    // drive over, rather than failing with ConnectionSeized.  Off by
+    // default, since taking the drive back interrupts whoever took it.
+    pub fn set_reacquire_on_seizure(&mut self, reacquire: bool) {
+        self.reacquire_on_seizure = reacquire;
+    }
+
+    // Reports a lost session that retrying didn't recover.  Returns Ok once
+    // the connection has been re-acquired and the transaction should be
+    // tried again.
+    pub(crate) fn handle_seizure(
+        &mut self,
+        error: modbus::Error,
+        attempt: u32,
+    ) -> Result<(), DeviceError> {
+        warn!(
+            "Connection to {} at {} was closed by the drive, most likely another client took it over: {}",
+            self.servo_name, self.servo_address, error
+        );
+        self.emit(|t| t.on_connection_seized(&self.servo_name, &self.servo_address));
+
+        // Not past the transaction's last attempt, so a drive that keeps
+        // dropping us fails
+        if self.reacquire_on_seizure && attempt <= self.retry_policy.max_attempts {
+            info!("Re-acquiring the connection to {}", self.servo_name);
+            self.reconnect()?;
+            return Ok(());
+        }
+        Err(DeviceError::ConnectionSeized(format!(
+            "{} at {}: {}",
+            self.servo_name, self.servo_address, error
+        )))
+    }
+}
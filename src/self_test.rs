@@ -0,0 +1,140 @@
+use crate::feed::signed_count;
+use crate::{AppliedDevice, DeviceError, LengthFeed, MOTOR_ENABLED};
+use tracing::{info, warn};
+
+static SELF_TEST_VELOCITY: u64 = 120; // Half a rev per second
+static SELF_TEST_ACCEL: u64 = 60;
+static SELF_TEST_TOLERANCE: u64 = 10; // Allowed error in the distance moved, in percent
+
+// The outcome of one check of a self test
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestStep {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    pub servo_name: String,
+    pub steps: Vec<SelfTestStep>, // In the order they ran, up to the first failure
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.passed)
+    }
+
+    pub fn failures(&self) -> Vec<&SelfTestStep> {
+        self.steps.iter().filter(|step| !step.passed).collect()
+    }
+
+    fn record(&mut self, name: &str, passed: bool, detail: String) -> bool {
+        if passed {
+            info!(
+                "Self test of {}: {} passed, {}",
+                self.servo_name, name, detail
+            );
+        } else {
+            warn!(
+                "Self test of {}: {} failed, {}",
+                self.servo_name, name, detail
+            );
+        }
+        self.steps.push(SelfTestStep {
+            name: name.to_string(),
+            passed,
+            detail,
+        });
+        passed
+    }
+}
+
+fn describe<T>(result: &Result<T, DeviceError>) -> String {
+    match result {
+        Ok(_) => String::from("ok"),
+        Err(e) => e.to_string(),
+    }
+}
+
+impl AppliedDevice {
+    // Commissioning check for a newly built axis: talks to the drive, checks
+    // no limit switch is tripped, enables it, then moves `distance` counts
+    // forward and back again checking the encoder follows in the right
+    // direction by about the right amount.  The axis needs room to move
+    // `distance` forward.  Stops at the first failed check.
+    pub fn self_test(&mut self, distance: u64) -> SelfTestReport {
+        let mut report = SelfTestReport {
+            servo_name: self.servo_name.clone(),
+            steps: Vec::new(),
+        };
+        info!("Starting self test of {}", self.servo_name);
+
+        let status = self.get_servo_status().cloned();
+        if !report.record("communication", status.is_ok(), describe(&status)) {
+            return report;
+        }
+
+        // Before the enable, which resets any limit alarm
+        let (clear, detail) = self.self_test_limits();
+        if !report.record("limits", clear, detail) {
+            return report;
+        }
+
+        let enabled = self
+            .reset_alarm_or_fault()
+            .and_then(|_| self.enable_motor())
+            .and_then(|_| self.has_status(MOTOR_ENABLED));
+        let detail = match &enabled {
+            Ok(false) => String::from("drive did not report Motor Enabled"),
+            other => describe(other),
+        };
+        if !report.record("enable", matches!(enabled, Ok(true)), detail) {
+            return report;
+        }
+
+        for (name, direction) in [("forward move", 1), ("reverse move", -1)] {
+            let moved = self.self_test_move(distance as i64 * direction);
+            let (passed, detail) = match moved {
+                Ok(delta) => {
+                    let error = (delta - distance as i64 * direction).unsigned_abs();
+                    let passed = delta.signum() == direction
+                        && error * 100 <= distance * SELF_TEST_TOLERANCE;
+                    (
+                        passed,
+                        format!("moved {} counts for {}", delta, distance as i64 * direction),
+                    )
+                }
+                Err(e) => (false, e.to_string()),
+            };
+            if !report.record(name, passed, detail) {
+                return report;
+            }
+        }
+
+        let (clear, detail) = self.self_test_limits();
+        report.record("limits after motion", clear, detail);
+        report
+    }
+
+    fn self_test_limits(&mut self) -> (bool, String) {
+        match self.limit_switches() {
+            Ok(limits) => (!limits.any_active(), format!("{:?}", limits)),
+            Err(e) => (false, e.to_string()),
+        }
+    }
+
+    // Feeds the given distance, returning how far the encoder actually moved
+    fn self_test_move(&mut self, distance: i64) -> Result<i64, DeviceError> {
+        let start = self.get_encoder_count()?;
+        let feed = LengthFeed {
+            accel: SELF_TEST_ACCEL,
+            decel: SELF_TEST_ACCEL,
+            velocity: SELF_TEST_VELOCITY,
+            distance,
+        };
+        self.feed_to_length(&feed)?;
+        let end = self.get_encoder_count()?;
+        Ok(signed_count(end).wrapping_sub(signed_count(start)))
+    }
+}
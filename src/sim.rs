@@ -0,0 +1,818 @@
+use crate::clock::{Clock, SystemClock};
+use crate::transport::Transport;
+use crate::{
+    DriveCommand, MappedRegister, OpcodeTable, RegisterMap, ACCELERATION, ACTUAL_CURRENT,
+    ACTUAL_VELOCITY, ALARM_REG, DECELERATION, DISTANCE_1, DISTANCE_2, DISTANCE_CHANGE_1,
+    DISTANCE_CHANGE_2, ENCODER_POS_1_REG, ENCODER_POS_2_REG, EXECUTE_COMMAND, FOLLOWING_ERROR_1,
+    FOLLOWING_ERROR_2, INPUTS_REG, PARAMETER_1, PARAMETER_2, STATUS_REG, VELOCITY,
+};
+use modbus::ExceptionCode;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+static REGISTER_COUNT: usize = 256; // Size of the simulated register map
+static DEFAULT_COUNTS_PER_REV: f64 = 20000.0;
+static DEFAULT_HOMING_TIME: u64 = 2000; // How long simulated homing takes, in ms
+static DEFAULT_TUNING_TIME: u64 = 5000; // How long simulated auto-tuning takes, in ms
+static TUNED_GAINS: &[u16] = &[4000, 100, 1500, 2000, 300]; // Written once tuning finishes
+static DEFAULT_SETTLE_TIME: u64 = 20; // Delay between motion ending and In Position, in ms
+static DEFAULT_STEPS_PER_REV: u16 = 20000;
+static DEFAULT_FIRMWARE: u16 = 0x0107; // 1.07
+static STEP: f64 = 0.001; // Integration step, in seconds
+
+// Where the simulated drive keeps the registers outside the documented map,
+// given to devices by SimulatedDrive::register_map
+static GEAR_NUMERATOR: u16 = 44;
+static GEAR_DENOMINATOR: u16 = 45;
+static JERK_FILTER: u16 = 46;
+static CAPTURE_INPUT: u16 = 47;
+static CAPTURE_EDGE: u16 = 48;
+static CAPTURE_POS_1: u16 = 49;
+static CAPTURE_POS_2: u16 = 50;
+static CAPTURE_FLAG: u16 = 51;
+static LIMIT_MODE: u16 = 52;
+static LIMIT_CW_INPUT: u16 = 53;
+static LIMIT_CCW_INPUT: u16 = 54;
+static IN_POSITION_COUNTS: u16 = 55;
+static IN_POSITION_TIME: u16 = 56;
+static FOLLOWING_ERROR_LIMIT: u16 = 58;
+static STALL_MODE: u16 = 59;
+static STALL_SENSITIVITY: u16 = 60;
+static IDLE_CURRENT_PERCENT: u16 = 61;
+static IDLE_CURRENT_DELAY: u16 = 62;
+static STEPS_PER_REV: u16 = 63;
+static ENCODER_RESOLUTION: u16 = 64;
+static ABSOLUTE_POS_1: u16 = 65;
+static GAIN_REGISTERS: &[u16] = &[68, 69, 70, 71, 72]; // KP, KI, KD, KV and KK
+static ANTI_RESONANCE: u16 = 73;
+static ANTI_RESONANCE_DAMPING: u16 = 74;
+static COMMAND_SMOOTHING: u16 = 75;
+static FIRMWARE_REVISION: u16 = 76;
+static MODEL_CODE: u16 = 77;
+static SERIAL_NUMBER_1: u16 = 78;
+static JOG_ACCEL: u16 = 80;
+static JOG_DECEL: u16 = 81;
+static JOG_VELOCITY: u16 = 82;
+
+// Drive register units
+static VELOCITY_UNITS: f64 = 240.0; // VE is in 1/240 rev/s
+static ACCEL_UNITS: f64 = 6.0; // AC and DE are in 1/6 rev/s/s
+static CURRENT_UNITS: f64 = 100.0; // IC is in 0.01 A
+
+// Motor current drawn, in A
+static HOLDING_CURRENT: f64 = 0.3;
+static RUNNING_CURRENT: f64 = 0.8;
+static ACCEL_CURRENT: f64 = 2.0; // While speeding up or slowing down
+
+// Status register bits, matching the order of STATUS_CODE_NAMES
+static STATUS_MOTOR_ENABLED: u16 = 1 << 0;
+static STATUS_TUNING: u16 = 1 << 1;
+static STATUS_FAULT: u16 = 1 << 2;
+static STATUS_IN_POSITION: u16 = 1 << 3;
+static STATUS_MOVING: u16 = 1 << 4;
+static STATUS_ALARM: u16 = 1 << 9;
+static STATUS_HOMING: u16 = 1 << 10;
+
+static ALARM_POSITION_LIMIT: u16 = 1 << 0; // Following error over its limit
+static ALARM_CCW_LIMIT: u16 = 1 << 1;
+static ALARM_CW_LIMIT: u16 = 1 << 2;
+
+// A feed to sensor move waiting on its input
+struct SensorWatch {
+    input: u16,
+    condition: u8, // The SCL letter, L, H, R or F
+    last_level: bool,
+    offset: f64,               // How far to carry on once the input triggers
+    masked_until: Option<f64>, // The input is ignored until the axis passes here
+}
+
+struct SimState {
+    registers: Vec<u16>,
+    position: f64, // In encoder counts
+    speed: f64,    // Current speed along the direction of travel, in counts/s
+    target: f64,
+    max_speed: f64, // Commanded velocity, in counts/s
+    accel: f64,     // In counts/s/s
+    decel: f64,     // In counts/s/s
+    counts_per_rev: f64,
+    clock: Arc<dyn Clock>,
+    last_update: Instant,
+    homing_until: Option<Instant>,
+    tuning_until: Option<Instant>,
+    settled_at: Option<Instant>, // When In Position should be raised after a move
+    homing_time: Duration,
+    tuning_time: Duration,
+    settle_time: Duration,
+    sensor: Option<SensorWatch>,
+    input_trips: Vec<(u16, f64)>, // Inputs to raise once the position passes a point
+    master_speed: f64,            // Of the simulated master encoder, in counts/s
+    following: Option<f64>,       // Gear ratio while following the master encoder
+    jogging: bool,
+    opcodes: OpcodeTable, // How the simulated drive numbers its commands
+}
+
+impl SimState {
+    // Read with the table for the simulated drive's own model code
+    fn command(&self, opcode: u16) -> DriveCommand {
+        match self.opcodes.has_models() {
+            true => self
+                .opcodes
+                .for_model(self.registers[MODEL_CODE as usize])
+                .command(opcode),
+            false => self.opcodes.command(opcode),
+        }
+    }
+
+    fn status(&self) -> u16 {
+        self.registers[STATUS_REG as usize]
+    }
+
+    fn set_status(&mut self, bits: u16, on: bool) {
+        let status = &mut self.registers[STATUS_REG as usize];
+        if on {
+            *status |= bits;
+        } else {
+            *status &= !bits;
+        }
+    }
+
+    fn set_position(&mut self, position: f64) {
+        self.position = position;
+        let raw = position.round() as i64 as u32;
+        self.registers[ENCODER_POS_1_REG as usize] = (raw >> 16) as u16;
+        self.registers[ENCODER_POS_2_REG as usize] = (raw & 0xffff) as u16;
+        // The absolute encoder registers hold the same position, 48 bits wide
+        let absolute = position.round() as i64 as u64;
+        for word in 0..3 {
+            self.registers[ABSOLUTE_POS_1 as usize + word] = (absolute >> (32 - 16 * word)) as u16;
+        }
+    }
+
+    // Fills in the actual velocity register from the speed and direction of
+    // travel, in the drive's velocity units
+    fn report_velocity(&mut self) {
+        let velocity = match self.following {
+            Some(ratio) => self.master_speed * ratio,
+            None if self.status() & STATUS_MOVING != 0 => {
+                self.speed * (self.target - self.position).signum()
+            }
+            None => 0.0,
+        };
+        let raw = (velocity / self.counts_per_rev * VELOCITY_UNITS).round();
+        self.registers[ACTUAL_VELOCITY as usize] =
+            raw.clamp(i16::MIN as f64, i16::MAX as f64) as i16 as u16;
+    }
+
+    // Fills in the actual current register, drawing most while the speed
+    // is changing
+    fn report_current(&mut self) {
+        let current = if self.status() & STATUS_MOTOR_ENABLED == 0 {
+            0.0
+        } else if self.following.is_some() {
+            RUNNING_CURRENT
+        } else if self.status() & STATUS_MOVING == 0 {
+            HOLDING_CURRENT
+        } else if self.speed < self.max_speed {
+            ACCEL_CURRENT
+        } else {
+            RUNNING_CURRENT
+        };
+        self.registers[ACTUAL_CURRENT as usize] = (current * CURRENT_UNITS).round() as i16 as u16;
+    }
+
+    fn following_error_over_limit(&self) -> bool {
+        let limit = self.registers[FOLLOWING_ERROR_LIMIT as usize] as u32;
+        let error = self.distance_register(FOLLOWING_ERROR_1, FOLLOWING_ERROR_2);
+        limit != 0 && error.unsigned_abs() > limit
+    }
+
+    fn input_level(&self, input: u16) -> bool {
+        (1..=16).contains(&input) && self.registers[INPUTS_REG as usize] & (1 << (input - 1)) != 0
+    }
+
+    fn set_input(&mut self, input: u16, level: bool) {
+        self.check_capture(input, level);
+        if (1..=16).contains(&input) {
+            let inputs = &mut self.registers[INPUTS_REG as usize];
+            if level {
+                *inputs |= 1 << (input - 1);
+            } else {
+                *inputs &= !(1 << (input - 1));
+            }
+        }
+    }
+
+    // Latches the position if the change of input is the edge capture is armed for
+    fn check_capture(&mut self, input: u16, level: bool) {
+        if input == 0 || self.registers[CAPTURE_INPUT as usize] != input {
+            return;
+        }
+        let previous = self.input_level(input);
+        let edge = match self.registers[CAPTURE_EDGE as usize] as u8 {
+            b'R' => !previous && level,
+            b'F' => previous && !level,
+            _ => false,
+        };
+        if edge && self.registers[CAPTURE_FLAG as usize] == 0 {
+            let raw = self.position.round() as i64 as u32;
+            self.registers[CAPTURE_POS_1 as usize] = (raw >> 16) as u16;
+            self.registers[CAPTURE_POS_2 as usize] = (raw & 0xffff) as u16;
+            self.registers[CAPTURE_FLAG as usize] = 1;
+        }
+    }
+
+    fn distance_register(&self, high: u16, low: u16) -> i32 {
+        let high = self.registers[high as usize] as u32;
+        let low = self.registers[low as usize] as u32;
+        ((high << 16) | low) as i32
+    }
+
+    fn start_feed(&mut self, target: f64) {
+        self.target = target;
+        self.max_speed = self.register_rate(VELOCITY, VELOCITY_UNITS);
+        self.accel = self.register_rate(ACCELERATION, ACCEL_UNITS);
+        self.decel = self.register_rate(DECELERATION, ACCEL_UNITS);
+        self.settled_at = None;
+        self.set_status(STATUS_IN_POSITION, false);
+        self.set_status(STATUS_MOVING, true);
+    }
+
+    // Raises any inputs tripped by the last step and checks whether a feed
+    // to sensor has seen its input
+    fn check_inputs(&mut self, previous: f64) {
+        let position = self.position;
+        let mut tripped = Vec::new();
+        self.input_trips.retain(|(input, at)| {
+            let crossed =
+                (previous < *at && position >= *at) || (previous > *at && position <= *at);
+            if crossed {
+                tripped.push(*input);
+            }
+            !crossed
+        });
+        for input in tripped {
+            self.set_input(input, true);
+        }
+
+        if let Some(watch) = &mut self.sensor {
+            if let Some(until) = watch.masked_until {
+                if (watch.offset > 0.0 && position < until)
+                    || (watch.offset < 0.0 && position > until)
+                {
+                    return;
+                }
+                watch.masked_until = None;
+            }
+        }
+        if let Some(watch) = &self.sensor {
+            let level = self.input_level(watch.input);
+            let triggered = match watch.condition {
+                b'L' => !level,
+                b'H' => level,
+                b'R' => !watch.last_level && level,
+                b'F' => watch.last_level && !level,
+                _ => false,
+            };
+            if triggered {
+                self.target = position + watch.offset;
+                self.sensor = None;
+            } else if let Some(watch) = &mut self.sensor {
+                watch.last_level = level;
+            }
+        }
+    }
+
+    fn register_rate(&self, register: u16, units: f64) -> f64 {
+        // Treat a zero rate as the slowest one the drive accepts so motion always ends
+        (self.registers[register as usize].max(1) as f64 / units) * self.counts_per_rev
+    }
+
+    // Brings the simulation up to the present.  Moves follow a trapezoidal
+    // profile built from the commanded accel, decel and velocity, and the
+    // status bits change the way the real drive's do: Moving clears when the
+    // profile ends and In Position is raised once the settle time passes.
+    fn update(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        if self.status() & STATUS_ALARM != 0 {
+            self.speed = 0.0;
+            return;
+        }
+
+        if let Some(done) = self.homing_until {
+            if now >= done {
+                self.homing_until = None;
+                self.set_position(0.0);
+                self.set_status(STATUS_HOMING, false);
+                self.set_status(STATUS_IN_POSITION, true);
+            }
+        }
+
+        if let Some(done) = self.tuning_until {
+            if now >= done {
+                self.tuning_until = None;
+                for (register, gain) in GAIN_REGISTERS.iter().zip(TUNED_GAINS) {
+                    self.registers[*register as usize] = *gain;
+                }
+                self.set_status(STATUS_TUNING, false);
+            }
+        }
+
+        if let Some(ratio) = self.following {
+            let position = self.position + self.master_speed * ratio * elapsed;
+            self.set_position(position);
+            self.speed = (self.master_speed * ratio).abs();
+            return;
+        }
+
+        if self.status() & STATUS_MOVING != 0 && self.following_error_over_limit() {
+            self.speed = 0.0;
+            self.set_status(STATUS_MOVING, false);
+            self.registers[ALARM_REG as usize] |= ALARM_POSITION_LIMIT;
+            self.set_status(STATUS_ALARM | STATUS_FAULT, true);
+            return;
+        }
+
+        if self.status() & STATUS_MOVING != 0 {
+            let mut remaining_time = elapsed;
+            while remaining_time > 0.0 && self.status() & STATUS_MOVING != 0 {
+                let dt = remaining_time.min(STEP);
+                self.step(dt);
+                remaining_time -= dt;
+            }
+            if self.status() & STATUS_MOVING == 0 {
+                self.settled_at = Some(now + self.settle_time);
+            }
+        }
+
+        if let Some(settled) = self.settled_at {
+            if now >= settled {
+                self.settled_at = None;
+                self.set_status(STATUS_IN_POSITION, true);
+            }
+        }
+    }
+
+    fn step(&mut self, dt: f64) {
+        let remaining = self.target - self.position;
+        let direction = remaining.signum();
+        let stopping_distance = self.speed * self.speed / (2.0 * self.decel);
+
+        if remaining.abs() <= stopping_distance {
+            // Never slow to a crawl short of the target
+            self.speed = (self.speed - self.decel * dt).max(self.decel * dt);
+        } else {
+            self.speed = (self.speed + self.accel * dt).min(self.max_speed);
+        }
+
+        let previous = self.position;
+        let travel = self.speed * dt;
+        if travel >= remaining.abs() {
+            self.set_position(self.target);
+            self.speed = 0.0;
+            self.sensor = None;
+            self.set_status(STATUS_MOVING, false);
+        } else {
+            self.set_position(self.position + direction * travel);
+        }
+        self.check_inputs(previous);
+        self.check_limits(direction);
+    }
+
+    // Stops dead at an end of travel switch tripped in the direction of
+    // travel, raising the limit alarm, if the limits are enabled
+    fn check_limits(&mut self, direction: f64) {
+        // As for "DL", 1 trips on a closed (high) input and 2 on an open one
+        let tripped_level = match self.registers[LIMIT_MODE as usize] {
+            1 => true,
+            2 => false,
+            _ => return,
+        };
+        let (input, alarm) = match direction > 0.0 {
+            true => (LIMIT_CW_INPUT, ALARM_CW_LIMIT),
+            false => (LIMIT_CCW_INPUT, ALARM_CCW_LIMIT),
+        };
+        let input = self.registers[input as usize];
+        if input == 0 || self.input_level(input) != tripped_level {
+            return;
+        }
+        self.speed = 0.0;
+        self.target = self.position;
+        self.sensor = None;
+        self.jogging = false;
+        self.set_status(STATUS_MOVING, false);
+        self.registers[ALARM_REG as usize] |= alarm;
+        self.set_status(STATUS_ALARM, true);
+    }
+
+    // Heads a long way off in the direction of the jog velocity.  A change
+    // of direction reverses from a standstill rather than ramping through it.
+    fn jog(&mut self) {
+        let velocity = self.registers[JOG_VELOCITY as usize] as i16 as f64;
+        let direction = if velocity < 0.0 { -1.0 } else { 1.0 };
+        if (self.target - self.position).signum() != direction {
+            self.speed = 0.0;
+        }
+        self.target = self.position + direction * f64::from(i32::MAX);
+        self.max_speed = (velocity.abs() / VELOCITY_UNITS) * self.counts_per_rev;
+        self.accel = self.register_rate(JOG_ACCEL, ACCEL_UNITS);
+        self.decel = self.register_rate(JOG_DECEL, ACCEL_UNITS);
+        self.settled_at = None;
+        self.set_status(STATUS_IN_POSITION, false);
+        self.set_status(STATUS_MOVING, true);
+    }
+
+    fn execute(&mut self, command: DriveCommand) {
+        let enabled = self.status() & STATUS_MOTOR_ENABLED != 0;
+        match command {
+            // Feed to position
+            DriveCommand::StartMove if enabled => {
+                let target = self.distance_register(DISTANCE_1, DISTANCE_2) as f64;
+                self.sensor = None;
+                self.start_feed(target);
+            }
+            // Feed to length
+            DriveCommand::FeedToLength if enabled => {
+                let distance = self.distance_register(DISTANCE_1, DISTANCE_2) as f64;
+                self.sensor = None;
+                self.start_feed(self.position + distance);
+            }
+            // Feed to sensor, with a mask or safety distance.  The sign of
+            // the distance register sets the direction.
+            DriveCommand::FeedToSensorMasked | DriveCommand::FeedToSensorSafe if enabled => {
+                let offset = self.distance_register(DISTANCE_1, DISTANCE_2) as f64;
+                let change = self.distance_register(DISTANCE_CHANGE_1, DISTANCE_CHANGE_2) as f64;
+                let input = self.registers[PARAMETER_1 as usize];
+                let masked_until = match command {
+                    DriveCommand::FeedToSensorMasked => {
+                        Some(self.position + offset.signum() * change.abs())
+                    }
+                    _ => None,
+                };
+                self.sensor = Some(SensorWatch {
+                    input,
+                    condition: self.registers[PARAMETER_2 as usize] as u8,
+                    last_level: self.input_level(input),
+                    offset,
+                    masked_until,
+                });
+                // A masked feed has no limit, so just head a long way off
+                let limit = match command {
+                    DriveCommand::FeedToSensorMasked => f64::from(i32::MAX),
+                    _ => change.abs(),
+                };
+                self.start_feed(self.position + offset.signum() * limit);
+            }
+            // Run a Q segment, segment 1 being the homing routine
+            DriveCommand::StartHoming if enabled && self.registers[PARAMETER_1 as usize] == 1 => {
+                self.homing_until = Some(self.clock.now() + self.homing_time);
+                self.set_status(STATUS_IN_POSITION, false);
+                self.set_status(STATUS_HOMING, true);
+            }
+            // Auto-tuning
+            DriveCommand::Autotune if enabled => {
+                self.tuning_until = Some(self.clock.now() + self.tuning_time);
+                self.set_status(STATUS_TUNING, true);
+            }
+            // Follow the master encoder at the gearing ratio
+            DriveCommand::FollowEncoder if enabled => {
+                let numerator = self.registers[GEAR_NUMERATOR as usize] as i16 as f64;
+                let denominator = self.registers[GEAR_DENOMINATOR as usize].max(1) as f64;
+                self.following = Some(numerator / denominator);
+                self.settled_at = None;
+                self.set_status(STATUS_IN_POSITION, false);
+                self.set_status(STATUS_MOVING, true);
+            }
+            // Jog at the jog velocity until stopped, or change its speed
+            DriveCommand::CommenceJog if enabled => {
+                self.sensor = None;
+                self.jogging = true;
+                self.jog();
+            }
+            DriveCommand::ChangeJogSpeed if self.jogging => self.jog(),
+            // Decelerate a jog to a stop
+            DriveCommand::StopJog if self.jogging => {
+                let direction = (self.target - self.position).signum();
+                let stopping_distance = self.speed * self.speed / (2.0 * self.decel.max(1.0));
+                self.target = self.position + direction * stopping_distance;
+                self.jogging = false;
+            }
+            // Motor disable / enable
+            DriveCommand::DisableMotor => {
+                self.speed = 0.0;
+                self.homing_until = None;
+                self.tuning_until = None;
+                self.following = None;
+                self.jogging = false;
+                self.set_status(
+                    STATUS_MOTOR_ENABLED | STATUS_MOVING | STATUS_HOMING | STATUS_TUNING,
+                    false,
+                );
+            }
+            DriveCommand::EnableMotor => self.set_status(STATUS_MOTOR_ENABLED, true),
+            // Alarm reset
+            DriveCommand::ResetAlarm => {
+                self.registers[ALARM_REG as usize] = 0;
+                self.set_status(STATUS_ALARM | STATUS_FAULT, false);
+            }
+            // Stop decelerates at the commanded rate rather than stopping dead
+            DriveCommand::Stop => {
+                let direction = (self.target - self.position).signum();
+                let stopping_distance = self.speed * self.speed / (2.0 * self.decel.max(1.0));
+                self.target = self.position + direction * stopping_distance;
+                self.homing_until = None;
+                self.tuning_until = None;
+                self.sensor = None;
+                self.jogging = false;
+                self.set_status(STATUS_HOMING | STATUS_TUNING, false);
+                // A following axis is simply stopped dead in the simulation
+                if self.following.take().is_some() {
+                    self.speed = 0.0;
+                    self.set_status(STATUS_MOVING, false);
+                    self.settled_at = Some(self.clock.now() + self.settle_time);
+                }
+            }
+            // Anything else (disconnect, identify, commands issued while
+            // disabled) is accepted and ignored
+            _ => {}
+        }
+    }
+}
+
+// An in-memory stand in for a drive.  It keeps a register map and reacts to
+// the opcodes this crate issues (enable, disable, alarm reset, homing, feed
+// to length or position, feed to sensor, following, jogging, auto-tuning and
+// stop) by setting status bits and moving the encoder position over time
+// according to the commanded profile, so whole homing and move sequences
+// can run without hardware.
+//
+// Clones share the same simulated drive, so a test can keep one to inspect
+// registers or inject alarms while a device owns the other.
+#[derive(Clone)]
+pub struct SimulatedDrive {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl Default for SimulatedDrive {
+    fn default() -> SimulatedDrive {
+        SimulatedDrive::new()
+    }
+}
+
+impl SimulatedDrive {
+    pub fn new() -> SimulatedDrive {
+        SimulatedDrive::with_clock(Arc::new(SystemClock))
+    }
+
+    // A simulated drive whose motion follows the given clock.  Share a
+    // ManualClock with the device under test to run moves without waiting.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> SimulatedDrive {
+        let mut registers = vec![0; REGISTER_COUNT];
+        registers[STEPS_PER_REV as usize] = DEFAULT_STEPS_PER_REV;
+        registers[ENCODER_RESOLUTION as usize] = DEFAULT_COUNTS_PER_REV as u16;
+        registers[FIRMWARE_REVISION as usize] = DEFAULT_FIRMWARE;
+        let state = SimState {
+            registers,
+            position: 0.0,
+            speed: 0.0,
+            target: 0.0,
+            max_speed: 0.0,
+            accel: 1.0,
+            decel: 1.0,
+            counts_per_rev: DEFAULT_COUNTS_PER_REV,
+            last_update: clock.now(),
+            clock,
+            homing_until: None,
+            tuning_until: None,
+            settled_at: None,
+            homing_time: Duration::from_millis(DEFAULT_HOMING_TIME),
+            tuning_time: Duration::from_millis(DEFAULT_TUNING_TIME),
+            settle_time: Duration::from_millis(DEFAULT_SETTLE_TIME),
+            sensor: None,
+            input_trips: Vec::new(),
+            master_speed: 0.0,
+            following: None,
+            jogging: false,
+            opcodes: OpcodeTable::new(),
+        };
+
+        SimulatedDrive {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    // Where the simulated drive keeps the registers outside the documented
+    // map, for AppliedDevice::set_register_map
+    pub fn register_map() -> RegisterMap {
+        let mut map = RegisterMap::new();
+        let registers = [
+            (MappedRegister::GearNumerator, GEAR_NUMERATOR),
+            (MappedRegister::GearDenominator, GEAR_DENOMINATOR),
+            (MappedRegister::JerkFilter, JERK_FILTER),
+            (MappedRegister::CaptureInput, CAPTURE_INPUT),
+            (MappedRegister::CaptureEdge, CAPTURE_EDGE),
+            (MappedRegister::CapturePosition, CAPTURE_POS_1),
+            (MappedRegister::CaptureFlag, CAPTURE_FLAG),
+            (MappedRegister::LimitMode, LIMIT_MODE),
+            (MappedRegister::LimitCwInput, LIMIT_CW_INPUT),
+            (MappedRegister::LimitCcwInput, LIMIT_CCW_INPUT),
+            (MappedRegister::InPositionCounts, IN_POSITION_COUNTS),
+            (MappedRegister::InPositionTime, IN_POSITION_TIME),
+            (MappedRegister::FollowingErrorLimit, FOLLOWING_ERROR_LIMIT),
+            (MappedRegister::StallMode, STALL_MODE),
+            (MappedRegister::StallSensitivity, STALL_SENSITIVITY),
+            (MappedRegister::IdleCurrentPercent, IDLE_CURRENT_PERCENT),
+            (MappedRegister::IdleCurrentDelay, IDLE_CURRENT_DELAY),
+            (MappedRegister::StepsPerRev, STEPS_PER_REV),
+            (MappedRegister::EncoderResolution, ENCODER_RESOLUTION),
+            (MappedRegister::AbsolutePosition, ABSOLUTE_POS_1),
+            (MappedRegister::PositionGain, GAIN_REGISTERS[0]),
+            (MappedRegister::IntegralGain, GAIN_REGISTERS[1]),
+            (MappedRegister::DerivativeGain, GAIN_REGISTERS[2]),
+            (MappedRegister::VelocityFeedforward, GAIN_REGISTERS[3]),
+            (MappedRegister::AccelerationFeedforward, GAIN_REGISTERS[4]),
+            (MappedRegister::AntiResonance, ANTI_RESONANCE),
+            (MappedRegister::AntiResonanceDamping, ANTI_RESONANCE_DAMPING),
+            (MappedRegister::CommandSmoothing, COMMAND_SMOOTHING),
+            (MappedRegister::FirmwareRevision, FIRMWARE_REVISION),
+            (MappedRegister::ModelCode, MODEL_CODE),
+            (MappedRegister::SerialNumber, SERIAL_NUMBER_1),
+            (MappedRegister::JogAccel, JOG_ACCEL),
+            (MappedRegister::JogDecel, JOG_DECEL),
+            (MappedRegister::JogVelocity, JOG_VELOCITY),
+        ];
+        for (register, address) in registers {
+            map.set(register, address);
+        }
+        map
+    }
+
+    fn state(&self) -> MutexGuard<'_, SimState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Encoder counts per motor revolution, used to turn the drive's rev/s
+    // based velocity and acceleration registers into counts
+    pub fn set_counts_per_rev(&self, counts: u32) {
+        let mut state = self.state();
+        state.counts_per_rev = counts.max(1) as f64;
+        state.registers[ENCODER_RESOLUTION as usize] = counts.clamp(1, u16::MAX as u32) as u16;
+    }
+
+    pub fn set_homing_time(&self, time: Duration) {
+        self.state().homing_time = time;
+    }
+
+    pub fn set_tuning_time(&self, time: Duration) {
+        self.state().tuning_time = time;
+    }
+
+    pub fn set_firmware_version(&self, major: u8, minor: u8) {
+        self.state().registers[FIRMWARE_REVISION as usize] = (major as u16) << 8 | minor as u16;
+    }
+
+    pub fn set_settle_time(&self, time: Duration) {
+        self.state().settle_time = time;
+    }
+
+    pub fn position(&self) -> i64 {
+        let mut state = self.state();
+        state.update();
+        state.position.round() as i64
+    }
+
+    // Current speed in counts/s, always positive
+    pub fn speed(&self) -> f64 {
+        let mut state = self.state();
+        state.update();
+        state.speed
+    }
+
+    pub fn set_position(&self, position: i64) {
+        self.state().set_position(position as f64);
+    }
+
+    // None past the end of the simulated register map
+    pub fn register(&self, register: u16) -> Option<u16> {
+        self.state().registers.get(register as usize).copied()
+    }
+
+    // Returns false, leaving the map alone, past the end of it
+    pub fn set_register(&self, register: u16, value: u16) -> bool {
+        match self.state().registers.get_mut(register as usize) {
+            Some(r) => {
+                *r = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Has the drive take opcodes as numbered by the table, as a drive whose
+    // model or firmware numbers its commands differently would.  Opcodes for
+    // particular models follow the model code register.
+    pub fn set_opcode_table(&self, table: &OpcodeTable) {
+        self.state().opcodes = table.clone();
+    }
+
+    // Sets the level of a drive input, 1 being X1
+    pub fn set_input(&self, input: u16, level: bool) {
+        self.state().set_input(input, level);
+    }
+
+    // Raises the input once the simulated position passes the given point,
+    // like a registration mark coming under a sensor
+    pub fn trip_input_at(&self, input: u16, position: i64) {
+        self.state().input_trips.push((input, position as f64));
+    }
+
+    // Speed of the simulated master encoder an axis follows, in counts/s
+    pub fn set_master_speed(&self, counts_per_second: f64) {
+        let mut state = self.state();
+        state.update();
+        state.master_speed = counts_per_second;
+    }
+
+    // Sets the following error the drive reports, as a binding axis would.
+    // A moving axis faults if this is over the drive's limit.
+    pub fn set_following_error(&self, counts: i32) {
+        let mut state = self.state();
+        state.update();
+        state.registers[FOLLOWING_ERROR_1 as usize] = (counts as u32 >> 16) as u16;
+        state.registers[FOLLOWING_ERROR_2 as usize] = (counts as u32 & 0xffff) as u16;
+    }
+
+    // Raises the given alarm code bits, which also sets the alarm status bit
+    // and freezes any motion until the alarm is reset.
+    pub fn inject_alarm(&self, alarm_bits: u16) {
+        let mut state = self.state();
+        state.registers[ALARM_REG as usize] |= alarm_bits;
+        state.set_status(STATUS_ALARM, true);
+    }
+}
+
+fn check_range(address: u16, quantity: u16) -> modbus::Result<()> {
+    if address as usize + quantity as usize > REGISTER_COUNT {
+        return Err(modbus::Error::Exception(ExceptionCode::IllegalDataAddress));
+    }
+    Ok(())
+}
+
+impl Transport for SimulatedDrive {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        check_range(address, quantity)?;
+        let mut state = self.state();
+        state.update();
+        state.report_velocity();
+        state.report_current();
+
+        let start = address as usize;
+        Ok(state.registers[start..start + quantity as usize].to_vec())
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        check_range(address, 1)?;
+        let mut state = self.state();
+        state.update();
+        state.registers[address as usize] = value;
+        if address == EXECUTE_COMMAND {
+            let command = state.command(value);
+            state.execute(command);
+        }
+        // The position is redefined once its low word is written
+        if address == ENCODER_POS_2_REG {
+            let position = state.distance_register(ENCODER_POS_1_REG, ENCODER_POS_2_REG);
+            state.target += position as f64 - state.position;
+            state.set_position(position as f64);
+        }
+        Ok(())
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        check_range(write_address, values.len() as u16)?;
+        check_range(read_address, read_quantity)?;
+        let mut state = self.state();
+        state.update();
+        for (offset, value) in values.iter().enumerate() {
+            let address = write_address + offset as u16;
+            state.registers[address as usize] = *value;
+            if address == EXECUTE_COMMAND {
+                let command = state.command(*value);
+                state.execute(command);
+            }
+        }
+        let start = read_address as usize;
+        Ok(state.registers[start..start + read_quantity as usize].to_vec())
+    }
+}
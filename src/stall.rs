@@ -0,0 +1,57 @@
+use crate::{AppliedDevice, DeviceError, MappedRegister};
+use tracing::info;
+
+// Reported in place of "Position Limit Error" while stall detection is on,
+// since a stepper drive uses that alarm for a stall
+pub static STALL_DETECTED: &str = "Stall Detected";
+
+// What a stepper drive does when its encoder shows the motor losing steps
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StallMode {
+    #[default]
+    Off,
+    Detect,  // Stop and raise an alarm
+    Prevent, // Slow down to keep the motor from stalling, then alarm if that fails
+}
+
+impl StallMode {
+    fn code(&self) -> u64 {
+        match self {
+            StallMode::Off => 0,
+            StallMode::Detect => 1,
+            StallMode::Prevent => 2,
+        }
+    }
+}
+
+impl AppliedDevice {
+    // Sensitivity is the position error, in encoder counts, treated as a stall
+    pub fn set_stall_detection(
+        &mut self,
+        mode: StallMode,
+        sensitivity: u16,
+    ) -> Result<(), DeviceError> {
+        if mode != StallMode::Off && sensitivity == 0 {
+            return Err(DeviceError::Config(String::from(
+                "Stall detection needs a non-zero sensitivity",
+            )));
+        }
+
+        info!(
+            "Setting stall detection of {} to {:?}, sensitivity {}",
+            self.servo_name, mode, sensitivity
+        );
+        self.write_register(
+            self.mapped(MappedRegister::StallSensitivity)?,
+            sensitivity as u64,
+        )?;
+        self.write_register(self.mapped(MappedRegister::StallMode)?, mode.code())?;
+        self.stall_mode = mode;
+        self.volatile.stall_detection = Some((mode, sensitivity));
+        Ok(())
+    }
+
+    pub fn get_stall_mode(&self) -> StallMode {
+        self.stall_mode
+    }
+}
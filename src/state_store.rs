@@ -0,0 +1,330 @@
+use crate::{AppliedDevice, Clock, DeviceError, ServiceRecord};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use yaml_rust::{Yaml, YamlLoader};
+
+static DEFAULT_FLUSH_INTERVAL: u64 = 60; // In seconds
+
+// Counters that survive restarts
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PersistentState {
+    pub cycle_count: i64,
+    pub total_travel: u64,  // Encoder counts moved, summed over every move
+    pub runtime_hours: f64, // Time spent homing and moving
+    pub last_position: Option<u64>, // Encoder position as last read
+    pub serviced: Vec<ServiceRecord>, // When each maintenance task was last done
+    pub raised: Vec<String>, // Maintenance tasks already reported as due
+}
+
+// Quotes a string for JSON output
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl PersistentState {
+    fn to_json(&self) -> String {
+        let last_position = match self.last_position {
+            Some(p) => p.to_string(),
+            None => String::from("null"),
+        };
+        let serviced: Vec<String> = self
+            .serviced
+            .iter()
+            .map(|r| {
+                format!(
+                    "    {{\"task\": {}, \"cycle_count\": {}, \"total_travel\": {}, \"runtime_hours\": {:.6}}}",
+                    json_string(&r.task),
+                    r.cycle_count,
+                    r.total_travel,
+                    r.runtime_hours
+                )
+            })
+            .collect();
+        let raised: Vec<String> = self.raised.iter().map(|t| json_string(t)).collect();
+        format!(
+            "{{\n  \"cycle_count\": {},\n  \"total_travel\": {},\n  \"runtime_hours\": {:.6},\n  \"last_position\": {},\n  \"serviced\": [\n{}\n  ],\n  \"raised\": [{}]\n}}\n",
+            self.cycle_count,
+            self.total_travel,
+            self.runtime_hours,
+            last_position,
+            serviced.join(",\n"),
+            raised.join(", ")
+        )
+    }
+
+    // JSON is a subset of YAML, so the YAML parser we already use reads it back
+    fn from_json(contents: &str) -> Result<PersistentState, String> {
+        let docs = YamlLoader::load_from_str(contents).map_err(|e| e.to_string())?;
+        let doc = docs
+            .first()
+            .ok_or_else(|| String::from("empty state file"))?;
+        let number = |doc: &Yaml, key: &str| match &doc[key] {
+            Yaml::Integer(i) => Some(*i as f64),
+            Yaml::Real(_) => doc[key].as_f64(),
+            _ => None,
+        };
+        // Counts and positions are parsed as integers, going through f64
+        // would round anything past 2^53.  Values past i64::MAX come back
+        // from the YAML parser as a Real holding the original digits.
+        let count = |doc: &Yaml, key: &str| match &doc[key] {
+            Yaml::Integer(i) => u64::try_from(*i).ok(),
+            Yaml::Real(digits) => digits.parse::<u64>().ok(),
+            _ => None,
+        };
+
+        let mut serviced = Vec::new();
+        if let Some(records) = doc["serviced"].as_vec() {
+            for r in records {
+                serviced.push(ServiceRecord {
+                    task: r["task"].as_str().unwrap_or_default().to_string(),
+                    cycle_count: r["cycle_count"].as_i64().unwrap_or(0),
+                    total_travel: count(r, "total_travel").unwrap_or(0),
+                    runtime_hours: number(r, "runtime_hours").unwrap_or(0.0),
+                });
+            }
+        }
+
+        let raised = match doc["raised"].as_vec() {
+            Some(tasks) => tasks
+                .iter()
+                .filter_map(|t| t.as_str().map(String::from))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        Ok(PersistentState {
+            cycle_count: doc["cycle_count"].as_i64().unwrap_or(0),
+            total_travel: count(doc, "total_travel").unwrap_or(0),
+            runtime_hours: number(doc, "runtime_hours").unwrap_or(0.0),
+            last_position: count(doc, "last_position"),
+            serviced,
+            raised,
+        })
+    }
+}
+
+// A small JSON file holding a device's PersistentState.  Writes go to a
+// temporary file that is then renamed over the old one, so a crash mid
+// write never leaves a truncated state file behind.
+pub(crate) struct StateStore {
+    path: PathBuf,
+    flush_interval: Duration,
+    last_flush: Option<Instant>,
+}
+
+impl StateStore {
+    pub(crate) fn new(path: &Path) -> StateStore {
+        StateStore {
+            path: path.to_path_buf(),
+            flush_interval: Duration::from_secs(DEFAULT_FLUSH_INTERVAL),
+            last_flush: None,
+        }
+    }
+
+    fn load(&self) -> Result<PersistentState, DeviceError> {
+        if !self.path.exists() {
+            info!("No saved state at {}, starting fresh", self.path.display());
+            return Ok(PersistentState::default());
+        }
+
+        let contents = fs::read_to_string(&self.path).map_err(|e| {
+            DeviceError::Config(format!("Unable to read {}: {}", self.path.display(), e))
+        })?;
+        PersistentState::from_json(&contents).map_err(|e| {
+            DeviceError::Config(format!("Unable to parse {}: {}", self.path.display(), e))
+        })
+    }
+
+    fn save(&mut self, state: &PersistentState, clock: &dyn Clock) -> Result<(), DeviceError> {
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, state.to_json())
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|e| {
+                DeviceError::Config(format!("Unable to write {}: {}", self.path.display(), e))
+            })?;
+        self.last_flush = Some(clock.now());
+        Ok(())
+    }
+
+    fn due(&self, clock: &dyn Clock) -> bool {
+        match self.last_flush {
+            Some(last) => clock.elapsed(last) >= self.flush_interval,
+            None => true,
+        }
+    }
+}
+
+impl AppliedDevice {
+    // Loads saved counters from the given file (if it exists) and keeps
+    // saving them there from now on
+    pub fn set_state_file(&mut self, path: &Path) -> Result<(), DeviceError> {
+        let store = StateStore::new(path);
+        self.persistent = store.load()?;
+        self.servo_cycle_count = self.persistent.cycle_count;
+        let cycle_count = self.servo_cycle_count;
+        self.update_snapshot(|s| s.cycle_count = cycle_count);
+        self.state_store = Some(store);
+        Ok(())
+    }
+
+    // How often counters are written out after operations (60s by default)
+    pub fn set_state_flush_interval(&mut self, interval: Duration) {
+        if let Some(store) = &mut self.state_store {
+            store.flush_interval = interval;
+        }
+    }
+
+    pub fn persistent_state(&self) -> &PersistentState {
+        &self.persistent
+    }
+
+    // Writes the counters out now, regardless of the flush interval
+    pub fn flush_state(&mut self) -> Result<(), DeviceError> {
+        self.persistent.cycle_count = self.servo_cycle_count;
+        match &mut self.state_store {
+            Some(store) => store.save(&self.persistent, self.clock.as_ref()),
+            None => Ok(()),
+        }
+    }
+
+    // Adds the effects of a finished operation to the counters, flushing
+    // them if the flush interval has passed
+    pub(crate) fn accumulate_state(&mut self, travel: u64, runtime: Duration) {
+        self.persistent.cycle_count = self.servo_cycle_count;
+        self.persistent.total_travel += travel;
+        self.persistent.runtime_hours += runtime.as_secs_f64() / 3600.0;
+        self.check_maintenance();
+
+        let due = match &self.state_store {
+            Some(store) => store.due(self.clock.as_ref()),
+            None => false,
+        };
+        if due {
+            if let Err(e) = self.flush_state() {
+                warn!("Unable to save state of {}: {}", self.servo_name, e);
+            }
+        }
+    }
+}
+
+impl Drop for AppliedDevice {
+    fn drop(&mut self) {
+        if self.state_store.is_some() {
+            if let Err(e) = self.flush_state() {
+                warn!("Unable to save state of {}: {}", self.servo_name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    fn device_with_state_file(path: &Path) -> AppliedDevice {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        drive.set_position(500);
+        let mut device = AppliedDevice::with_transport(String::from("state"), String::new(), drive);
+        device.set_clock(clock);
+        device.set_state_file(path).expect("Unable to load state");
+        device
+    }
+
+    #[test]
+    fn state_round_trips_through_json() {
+        let state = PersistentState {
+            cycle_count: 42,
+            total_travel: u64::MAX - 1,
+            runtime_hours: 1.5,
+            last_position: Some(1 << 60),
+            serviced: vec![ServiceRecord {
+                task: String::from("grease \"rails\""),
+                cycle_count: 40,
+                total_travel: 1000,
+                runtime_hours: 1.25,
+            }],
+            raised: vec![String::from("regrease"), String::from("inspect_belt")],
+        };
+        let read = PersistentState::from_json(&state.to_json()).expect("Unable to parse");
+        assert_eq!(read, state);
+    }
+
+    #[test]
+    fn saves_fall_due_by_the_given_clock() {
+        let clock = ManualClock::new();
+        let path = std::env::temp_dir().join(format!("state_due_{}.json", std::process::id()));
+        let mut store = StateStore::new(&path);
+        assert!(store.due(&clock));
+        store
+            .save(&PersistentState::default(), &clock)
+            .expect("Unable to save");
+        assert!(!store.due(&clock));
+        clock.advance(Duration::from_secs(DEFAULT_FLUSH_INTERVAL));
+        assert!(store.due(&clock));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn saved_position_is_kept_until_the_encoder_is_read() {
+        let path = std::env::temp_dir().join(format!("state_position_{}.json", std::process::id()));
+        let saved = PersistentState {
+            last_position: Some(1234),
+            ..Default::default()
+        };
+        fs::write(&path, saved.to_json()).expect("Unable to write state");
+
+        drop(device_with_state_file(&path));
+        let read = StateStore::new(&path).load().expect("Unable to load state");
+        assert_eq!(read.last_position, Some(1234));
+
+        let mut device = device_with_state_file(&path);
+        device.get_encoder_count().expect("Read failed");
+        drop(device);
+        let read = StateStore::new(&path).load().expect("Unable to load state");
+        assert_eq!(read.last_position, Some(500));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn raised_maintenance_is_not_raised_again_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("state_raised_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut task = crate::MaintenanceTask::new("regrease");
+        task.every_cycles = Some(10);
+
+        let mut device = device_with_state_file(&path);
+        device.add_maintenance_task(task.clone());
+        device.servo_cycle_count = 10;
+        device.check_maintenance();
+        assert_eq!(device.persistent_state().raised, vec!["regrease"]);
+        drop(device);
+
+        let mut device = device_with_state_file(&path);
+        device.add_maintenance_task(task);
+        assert_eq!(device.persistent_state().raised, vec!["regrease"]);
+        device.check_maintenance();
+        assert_eq!(device.persistent_state().raised, vec!["regrease"]);
+        device
+            .mark_serviced("regrease")
+            .expect("Unable to mark serviced");
+        assert!(device.persistent_state().raised.is_empty());
+        drop(device);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::AppliedDevice;
+use std::time::{Duration, Instant};
+
+// Min, mean, max and spread of a series of samples, updated one sample at
+// a time so nothing needs to be kept around
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunningStats {
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    sum_squares: f64, // Of differences from the mean, for the standard deviation
+}
+
+impl RunningStats {
+    pub fn add(&mut self, sample: f64) {
+        if self.count == 0 {
+            self.min = sample;
+            self.max = sample;
+        } else {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+        }
+        self.count += 1;
+        let delta = sample - self.mean;
+        self.mean += delta / self.count as f64;
+        self.sum_squares += delta * (sample - self.mean);
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        (self.sum_squares / (self.count - 1) as f64).sqrt()
+    }
+}
+
+// Aggregated behaviour of the axis' moves since the device was created (or
+// the stats were last reset).  A slowly growing mean duration, settle time
+// or position error spread is an early sign of a wearing axis.
+#[derive(Clone, Debug, Default)]
+pub struct MoveStats {
+    pub moves: u64,
+    pub incomplete_moves: u64, // Timed out, cancelled or missed the target
+    pub failed_moves: u64,     // Ended with an error
+    pub duration_ms: RunningStats,
+    pub settle_ms: RunningStats,      // From motion ending to In Position
+    pub position_error: RunningStats, // Final position minus target, in encoder counts
+    pub moves_per_hour: f64,
+}
+
+pub(crate) struct StatsCollector {
+    stats: MoveStats,
+    since: Instant,
+}
+
+impl StatsCollector {
+    pub(crate) fn new(since: Instant) -> StatsCollector {
+        StatsCollector {
+            stats: MoveStats::default(),
+            since,
+        }
+    }
+}
+
+impl AppliedDevice {
+    pub fn stats(&self) -> MoveStats {
+        let mut stats = self.stats.stats.clone();
+        let hours = self.clock.elapsed(self.stats.since).as_secs_f64() / 3600.0;
+        if hours > 0.0 {
+            stats.moves_per_hour = stats.moves as f64 / hours;
+        }
+        stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = StatsCollector::new(self.clock.now());
+    }
+
+    // Adds a finished move to the stats.  Moves that ended in an error only
+    // count towards failed_moves.
+    pub(crate) fn record_move_stats(
+        &mut self,
+        completed: Option<bool>,
+        duration: Duration,
+        target: u64,
+        actual: u64,
+    ) {
+        let settle_time = self.last_settle_time.take();
+        let stats = &mut self.stats.stats;
+        stats.moves += 1;
+        match completed {
+            None => {
+                stats.failed_moves += 1;
+                return;
+            }
+            Some(false) => stats.incomplete_moves += 1,
+            Some(true) => {}
+        }
+
+        stats.duration_ms.add(duration.as_secs_f64() * 1000.0);
+        stats.position_error.add(actual as f64 - target as f64);
+        if let Some(settle) = settle_time {
+            stats.settle_ms.add(settle.as_secs_f64() * 1000.0);
+        }
+    }
+}
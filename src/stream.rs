@@ -0,0 +1,86 @@
+use crate::{AppliedDevice, TraceSample};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+pub(crate) struct PositionStream {
+    sender: Sender<(Instant, i64)>,
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl PositionStream {
+    fn due(&self, at: Instant) -> bool {
+        AppliedDevice::sample_due(self.last, self.period, at)
+    }
+}
+
+pub(crate) struct VelocityStream {
+    sender: Sender<(Instant, f64)>,
+    period: Duration,
+    last: Option<Instant>,
+}
+
+impl VelocityStream {
+    fn due(&self, at: Instant) -> bool {
+        AppliedDevice::sample_due(self.last, self.period, at)
+    }
+}
+
+impl AppliedDevice {
+    // Delivers encoder positions at (roughly) the given period, taken from
+    // the same reads the device already makes while homing, moving and, once
+    // spawned, idling.  The stream ends when the receiver is dropped.
+    pub fn stream_position(&mut self, period: Duration) -> Receiver<(Instant, i64)> {
+        let (sender, receiver) = mpsc::channel();
+        self.position_streams.push(PositionStream {
+            sender,
+            period: period.max(Duration::from_millis(1)),
+            last: None,
+        });
+        receiver
+    }
+
+    // As stream_position, but delivering the drive's actual velocity in
+    // counts/s.  Each sample costs a further read while any are open.
+    pub fn stream_velocity(&mut self, period: Duration) -> Receiver<(Instant, f64)> {
+        let (sender, receiver) = mpsc::channel();
+        self.velocity_streams.push(VelocityStream {
+            sender,
+            period: period.max(Duration::from_millis(1)),
+            last: None,
+        });
+        receiver
+    }
+
+    pub(crate) fn stream_period(&self) -> Option<Duration> {
+        let positions = self.position_streams.iter().map(|s| s.period);
+        let velocities = self.velocity_streams.iter().map(|s| s.period);
+        positions.chain(velocities).min()
+    }
+
+    pub(crate) fn stream_due(&self, at: Instant) -> bool {
+        self.position_streams.iter().any(|s| s.due(at))
+            || self.velocity_streams.iter().any(|s| s.due(at))
+    }
+
+    pub(crate) fn record_stream_sample(&mut self, sample: &TraceSample) {
+        // The drive holds position as a signed 32 bit count
+        let position = sample.encoder_count as u32 as i32 as i64;
+        self.position_streams.retain_mut(|stream| {
+            if !stream.due(sample.at) {
+                return true;
+            }
+            stream.last = Some(sample.at);
+            stream.sender.send((sample.at, position)).is_ok()
+        });
+        if let Some(velocity) = sample.velocity {
+            self.velocity_streams.retain_mut(|stream| {
+                if !stream.due(sample.at) {
+                    return true;
+                }
+                stream.last = Some(sample.at);
+                stream.sender.send((sample.at, velocity)).is_ok()
+            });
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{
+    AppliedDevice, FirmwareMismatch, MaintenanceDue, MoveVelocitySample, PositionCrossing,
+    RecipeProgress, TrajectoryProgress,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Details of a move as it is handed to the drive
+#[derive(Clone, Debug)]
+pub struct MoveStart {
+    pub servo_name: String,
+    pub accel: u64,
+    pub decel: u64,
+    pub velocity: u64,
+    pub target: u64,
+}
+
+// The outcome of a move once the device has stopped waiting on it
+#[derive(Clone, Debug)]
+pub struct MoveEnd {
+    pub servo_name: String,
+    pub target: u64,
+    pub actual: u64,     // Encoder position as last read
+    pub completed: bool, // False if the move timed out, was cancelled or missed the target
+    pub duration: Duration,
+}
+
+// Implemented by the application to ship device telemetry to its own
+// systems, independent of whatever logging backend this crate uses.  Every
+// method has an empty default so sinks only need to handle what they care
+// about.  Callbacks run on the thread driving the device, so they should be
+// quick.
+pub trait TelemetrySink: Send + Sync {
+    fn on_move_start(&self, _event: &MoveStart) {}
+
+    fn on_move_end(&self, _event: &MoveEnd) {}
+
+    // Called for each sample taken while the move velocity monitor is on
+    fn on_move_velocity(&self, _event: &MoveVelocitySample) {}
+
+    // Called when the drive starts reporting a new set of (non empty) alarms
+    fn on_alarm(&self, _servo_name: &str, _alarms: &[String]) {}
+
+    // Called when the drive reports a stall, along with the last position
+    // read.  on_alarm is called as well.
+    fn on_stall(&self, _servo_name: &str, _position: u64) {}
+
+    // Called as each waypoint of a trajectory is reached
+    fn on_trajectory_progress(&self, _event: &TrajectoryProgress) {}
+
+    // Called as each step of a recipe starts
+    fn on_recipe_progress(&self, _event: &RecipeProgress) {}
+
+    // Called when a drive's firmware is outside the configured range but
+    // the device is allowed to carry on
+    fn on_firmware_mismatch(&self, _event: &FirmwareMismatch) {}
+
+    fn on_reconnect(&self, _servo_name: &str, _address: &str) {}
+
+    // Another client took over the drive's Modbus session
+    fn on_connection_seized(&self, _servo_name: &str, _address: &str) {}
+
+    // Nothing has been read from the drive for longer than the watchdog
+    // allows.  age is how old the last read was.
+    fn on_comms_stale(&self, _servo_name: &str, _age: Duration) {}
+
+    // Reads succeed again after on_comms_stale; outage is how long the data
+    // was stale for
+    fn on_comms_recovered(&self, _servo_name: &str, _outage: Duration) {}
+
+    // Called once when a maintenance task becomes due, and again only after
+    // it has been marked as serviced and comes due once more
+    fn on_maintenance_due(&self, _event: &MaintenanceDue) {}
+
+    // Called as the position crosses a threshold being watched with
+    // watch_position
+    fn on_position_crossing(&self, _event: &PositionCrossing) {}
+}
+
+impl AppliedDevice {
+    pub fn add_telemetry_sink(&mut self, sink: Arc<dyn TelemetrySink>) {
+        self.telemetry.push(sink);
+    }
+
+    pub(crate) fn emit<F: Fn(&dyn TelemetrySink)>(&self, f: F) {
+        for sink in &self.telemetry {
+            f(sink.as_ref());
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{AppliedDevice, ALARM};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// One sample of the axis' position, status and, if wanted, velocity
+#[derive(Clone, Debug)]
+pub struct TraceSample {
+    pub at: Instant,
+    pub encoder_count: u64,
+    pub velocity: Option<f64>, // In counts/s, only read while a velocity stream is open
+    pub status: Vec<String>,
+}
+
+// A fixed size ring buffer of samples taken during motion.  Recording stops
+// at the first sample showing an alarm, so the trace keeps the lead up to
+// the alarm until it is cleared.
+pub(crate) struct MotionTrace {
+    samples: VecDeque<TraceSample>,
+    capacity: usize,
+    period: Duration,
+    frozen: bool,
+}
+
+impl AppliedDevice {
+    // Starts sampling position and status every `period` while waiting on
+    // homing or moves, keeping the last `capacity` samples
+    pub fn enable_motion_trace(&mut self, capacity: usize, period: Duration) {
+        self.trace = Some(MotionTrace {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            period: period.max(Duration::from_millis(1)),
+            frozen: false,
+        });
+    }
+
+    pub fn disable_motion_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // The recorded samples, oldest first
+    pub fn motion_trace(&self) -> Vec<TraceSample> {
+        match &self.trace {
+            Some(trace) => trace.samples.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Empties the trace and resumes recording if an alarm had stopped it
+    pub fn clear_motion_trace(&mut self) {
+        if let Some(trace) = &mut self.trace {
+            trace.samples.clear();
+            trace.frozen = false;
+        }
+    }
+
+    // How often samples are wanted, if a trace is recording
+    pub(crate) fn trace_period(&self) -> Option<Duration> {
+        match &self.trace {
+            Some(trace) if !trace.frozen => Some(trace.period),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn record_trace_sample(&mut self, sample: &TraceSample) {
+        let trace = match &mut self.trace {
+            Some(trace) if !trace.frozen => trace,
+            _ => return,
+        };
+        while trace.samples.len() >= trace.capacity {
+            trace.samples.pop_front();
+        }
+        let alarm = sample.status.iter().any(|s| s == ALARM);
+        trace.samples.push_back(sample.clone());
+        if alarm {
+            warn!(
+                "Alarm on {}, motion trace stopped at {} samples",
+                self.servo_name,
+                trace.samples.len()
+            );
+            trace.frozen = true;
+        }
+    }
+}
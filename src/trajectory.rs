@@ -0,0 +1,293 @@
+use crate::feed::signed_count;
+use crate::history::outcome_of;
+use crate::rollover::travel_between;
+use crate::{
+    AppliedDevice, CancelToken, DeviceError, DriveCommand, InterlockAction, MotionProfile, MoveEnd,
+    MoveRequest, MoveStart, Operation, ACCELERATION, DECELERATION, MOVING, VELOCITY,
+};
+use std::time::Duration;
+use tracing::{info, info_span, warn};
+
+static BLEND_POLL_INTERVAL: u64 = 10; // How often a blended segment checks its position, in ms
+
+// One point of a trajectory: the move there and how long to stay once
+// arrived
+#[derive(Clone, Debug, PartialEq)]
+pub struct Waypoint {
+    pub position: u64,
+    pub velocity: u64,
+    pub accel: u64,
+    pub decel: u64,
+    pub dwell: Duration,
+    pub profile: Option<MotionProfile>, // None uses the device's default profile
+    // Start towards the next waypoint this many counts before reaching this
+    // one, rather than stopping here.  Ignored for the last waypoint, with a
+    // dwell, or when the drive can't take a new target while moving.
+    pub blend: Option<u64>,
+}
+
+impl Waypoint {
+    pub fn new(position: u64, velocity: u64, accel: u64, decel: u64) -> Waypoint {
+        Waypoint {
+            position,
+            velocity,
+            accel,
+            decel,
+            dwell: Duration::from_millis(0),
+            profile: None,
+            blend: None,
+        }
+    }
+
+    pub fn dwell(mut self, dwell: Duration) -> Waypoint {
+        self.dwell = dwell;
+        self
+    }
+
+    pub fn profile(mut self, profile: MotionProfile) -> Waypoint {
+        self.profile = Some(profile);
+        self
+    }
+
+    pub fn blend(mut self, distance: u64) -> Waypoint {
+        self.blend = Some(distance);
+        self
+    }
+
+    fn request(&self) -> MoveRequest {
+        MoveRequest {
+            accel: self.accel,
+            decel: self.decel,
+            velocity: self.velocity,
+            target: self.position,
+            profile: self.profile.clone(),
+        }
+    }
+}
+
+// Reported to telemetry sinks as each waypoint is reached
+#[derive(Clone, Debug)]
+pub struct TrajectoryProgress {
+    pub servo_name: String,
+    pub segment: usize, // Index of the waypoint just reached
+    pub segments: usize,
+    pub position: u64,
+}
+
+impl AppliedDevice {
+    // Whether the drive accepts a new feed to position while one is under
+    // way, which blended trajectory segments rely on.  Off by default, so
+    // trajectories stop at every waypoint.
+    pub fn set_blending_supported(&mut self, supported: bool) {
+        self.blending_supported = supported;
+    }
+
+    pub fn execute_trajectory(&mut self, waypoints: Vec<Waypoint>) -> Result<usize, DeviceError> {
+        self.execute_trajectory_with_cancel(waypoints, &CancelToken::new())
+    }
+
+    // Moves through each waypoint in turn, dwelling at each as asked.  Stops
+    // at the first segment that doesn't reach its waypoint, or when the
+    // token is cancelled.  Returns how many waypoints were reached.
+    pub fn execute_trajectory_with_cancel(
+        &mut self,
+        waypoints: Vec<Waypoint>,
+        cancel: &CancelToken,
+    ) -> Result<usize, DeviceError> {
+        let span = info_span!(
+            "trajectory",
+            servo = %self.servo_name,
+            segments = waypoints.len()
+        );
+        let _enter = span.enter();
+        let segments = waypoints.len();
+
+        for (segment, waypoint) in waypoints.iter().enumerate() {
+            self.check_interlocks(InterlockAction::Move {
+                target: waypoint.position,
+            })?;
+            let blended = self.blending_supported
+                && waypoint.dwell == Duration::from_millis(0)
+                && segment + 1 < segments;
+            if let (true, Some(distance)) = (blended, waypoint.blend) {
+                info!(
+                    "Trajectory segment {} of {}: to {} at {}, blending {} early",
+                    segment + 1,
+                    segments,
+                    waypoint.position,
+                    waypoint.velocity,
+                    distance
+                );
+                if !self.run_blended_segment(waypoint, distance, cancel)? {
+                    warn!(
+                        "Trajectory of {} stopped at segment {} of {}",
+                        self.servo_name,
+                        segment + 1,
+                        segments
+                    );
+                    return Ok(segment);
+                }
+                let progress = TrajectoryProgress {
+                    servo_name: self.servo_name.clone(),
+                    segment,
+                    segments,
+                    position: self.last_snapshot().encoder_count,
+                };
+                self.emit(|t| t.on_trajectory_progress(&progress));
+                continue;
+            }
+
+            info!(
+                "Trajectory segment {} of {}: to {} at {}",
+                segment + 1,
+                segments,
+                waypoint.position,
+                waypoint.velocity
+            );
+            if !self.perform_move(&waypoint.request(), cancel)? {
+                warn!(
+                    "Trajectory of {} stopped at segment {} of {}",
+                    self.servo_name,
+                    segment + 1,
+                    segments
+                );
+                return Ok(segment);
+            }
+
+            let progress = TrajectoryProgress {
+                servo_name: self.servo_name.clone(),
+                segment,
+                segments,
+                position: self.last_snapshot().encoder_count,
+            };
+            self.emit(|t| t.on_trajectory_progress(&progress));
+
+            if waypoint.dwell > Duration::from_millis(0)
+                && !self.sleep_and_sample(waypoint.dwell, cancel)?
+            {
+                warn!("Trajectory of {} was cancelled", self.servo_name);
+                return Ok(segment + 1);
+            }
+        }
+
+        Ok(segments)
+    }
+
+    // Starts the move to the waypoint (or just updates it, if the axis is
+    // already moving) and returns once the axis is within the blend distance
+    // of it, leaving the next segment to take over without a stop.  Returns
+    // false if the axis stopped short, timed out or was cancelled.  Kept
+    // in the stats, history and telemetry like any other move.
+    fn run_blended_segment(
+        &mut self,
+        waypoint: &Waypoint,
+        distance: u64,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let started = self.clock.now();
+        let start_position = self.last_snapshot().encoder_count;
+        let io_before = self.io;
+        self.emit(|t| {
+            t.on_move_start(&MoveStart {
+                servo_name: self.servo_name.clone(),
+                accel: waypoint.accel,
+                decel: waypoint.decel,
+                velocity: waypoint.velocity,
+                target: waypoint.position,
+            })
+        });
+
+        let result = self.approach_blend_point(waypoint, distance, cancel);
+        self.set_feeding(false);
+        self.move_target = None;
+        let operation = Operation::Move {
+            accel: waypoint.accel,
+            decel: waypoint.decel,
+            velocity: waypoint.velocity,
+            target: waypoint.position,
+        };
+        self.record_operation(operation, started, outcome_of(&result));
+        let completed = *result.as_ref().unwrap_or(&false);
+        if completed {
+            self.servo_cycle_count += 1;
+            let cycle_count = self.servo_cycle_count;
+            self.update_snapshot(|s| s.cycle_count = cycle_count);
+        }
+        let end_position = self.last_snapshot().encoder_count;
+        let duration = self.clock.elapsed(started);
+        self.accumulate_state(travel_between(start_position, end_position), duration);
+        let ended = result.as_ref().ok().copied();
+        self.record_move_stats(ended, duration, waypoint.position, end_position);
+        self.record_move_io(io_before);
+
+        self.emit(|t| {
+            t.on_move_end(&MoveEnd {
+                servo_name: self.servo_name.clone(),
+                target: waypoint.position,
+                actual: end_position,
+                completed,
+                duration,
+            })
+        });
+        result
+    }
+
+    fn approach_blend_point(
+        &mut self,
+        waypoint: &Waypoint,
+        distance: u64,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        if !self.has_status(MOVING)? {
+            self.reset_alarm_or_fault()?;
+        }
+        self.apply_profile(waypoint.profile.as_ref())?;
+        self.move_velocity = waypoint.velocity;
+        self.write_register(ACCELERATION, waypoint.accel)?;
+        self.write_register(DECELERATION, waypoint.decel)?;
+        self.write_register(VELOCITY, self.override_velocity(waypoint.velocity))?;
+        self.write_distance(waypoint.position)?;
+        self.execute(DriveCommand::StartMove)?;
+        self.move_target = Some(waypoint.position);
+        // Handed on to the next segment part way, so like a feed it can't be
+        // retargeted, held or sped up through the motion control
+        self.set_feeding(true);
+
+        let started = self.clock.now();
+        loop {
+            if cancel.is_cancelled() {
+                warn!("Move of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(false);
+            }
+            let position = self.get_encoder_count()?;
+            let remaining =
+                (signed_count(waypoint.position) - signed_count(position)).unsigned_abs();
+            if remaining <= distance {
+                return Ok(true);
+            }
+            if self.clock.elapsed(started) > self.motion_defaults.move_timeout {
+                warn!(
+                    "Segment of {} did not reach its blend point",
+                    self.servo_name
+                );
+                self.stop_motion()?;
+                return Ok(false);
+            }
+            let poll = Duration::from_millis(BLEND_POLL_INTERVAL);
+            if !self.sleep_and_sample(poll, cancel)? {
+                warn!("Move of servo {} was cancelled", self.servo_name);
+                self.stop_motion()?;
+                return Ok(false);
+            }
+            // Checked after the wait, so the drive has had time to start
+            if !self.has_status(MOVING)? {
+                warn!(
+                    "Servo {} stopped {} counts short of its blend point",
+                    self.servo_name, remaining
+                );
+                return Ok(false);
+            }
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use crate::exception::exception_code;
+use modbus::tcp;
+use modbus::{Client, ExceptionCode};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+static DEFAULT_CONNECT_TIMEOUT: u64 = 1000; // In ms
+static DEFAULT_IO_TIMEOUT: u64 = 1000; // In ms
+static DEFAULT_MODBUS_PORT: u16 = 502;
+static MBAP_HEADER_SIZE: usize = 7;
+static MAX_PDU_SIZE: usize = 253;
+
+// How long Modbus transactions may take.  connect and write are applied to
+// the TCP socket when it is opened.  read is how long a transaction waits
+// for its reply, except for status/position reads and register writes,
+// which wait for status_poll and command instead where they are set.  Those
+// are set on the socket before each transaction, so one that runs past its
+// limit fails as timed out (and is retried per the RetryPolicy).  A reply
+// that arrives within the limit is used even if the transaction as a whole
+// (retries aside) took longer, with a warning.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModbusTimeouts {
+    pub connect: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    pub status_poll: Option<Duration>,
+    pub command: Option<Duration>,
+}
+
+impl Default for ModbusTimeouts {
+    fn default() -> ModbusTimeouts {
+        ModbusTimeouts {
+            connect: Some(Duration::from_millis(DEFAULT_CONNECT_TIMEOUT)),
+            read: Some(Duration::from_millis(DEFAULT_IO_TIMEOUT)),
+            write: Some(Duration::from_millis(DEFAULT_IO_TIMEOUT)),
+            status_poll: None,
+            command: None,
+        }
+    }
+}
+
+// The register level operations a device needs from whatever it is talking
+// to.  Implemented for the real Modbus TCP transport, and by wrappers and
+// stand-ins such as the record/replay transports.
+pub trait Transport: Send {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>>;
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()>;
+
+    // Addresses the drive with this unit ID on the transactions that follow,
+    // for connections shared by several drives behind a gateway
+    fn set_unit_id(&mut self, _unit_id: u8) {}
+
+    // Writes a block of registers then reads another in one transaction
+    // (FC23).  Transports that can't answer as a drive without FC23 would.
+    fn write_read_multiple_registers(
+        &mut self,
+        _write_address: u16,
+        _values: &[u16],
+        _read_address: u16,
+        _read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        Err(modbus::Error::Exception(ExceptionCode::IllegalFunction))
+    }
+
+    // How long the transactions that follow wait for their reply, None for
+    // as long as it takes.  Transports without a socket have nothing to set.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> modbus::Result<()> {
+        Ok(())
+    }
+}
+
+// A Modbus TCP connection to a drive.  Unlike modbus::tcp::Transport, whose
+// socket timeouts are fixed when it connects, it owns its socket so the read
+// timeout can be set for each transaction.
+pub(crate) struct TcpTransport {
+    stream: TcpStream,
+    transaction_id: u16,
+    unit_id: u8,
+    read_timeout: Option<Duration>,
+}
+
+impl TcpTransport {
+    // Connects to the first of the host's addresses that answers, on the
+    // Modbus port unless another is given
+    pub(crate) fn connect(
+        host: &str,
+        port: Option<u16>,
+        timeouts: &ModbusTimeouts,
+    ) -> io::Result<TcpTransport> {
+        let mut failure = None;
+        for address in (host, port.unwrap_or(DEFAULT_MODBUS_PORT)).to_socket_addrs()? {
+            let stream = match timeouts.connect {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(timeouts.read)?;
+                    stream.set_write_timeout(timeouts.write)?;
+                    stream.set_nodelay(true)?;
+                    return Ok(TcpTransport {
+                        stream,
+                        transaction_id: 0,
+                        unit_id: 1,
+                        read_timeout: timeouts.read,
+                    });
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host))
+        }))
+    }
+
+    // Sends a request and returns the data of the reply, after its function
+    // code.  A reply to an earlier request that timed out is passed over.
+    fn transact(&mut self, request: &[u8]) -> modbus::Result<Vec<u8>> {
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let mut frame = Vec::with_capacity(MBAP_HEADER_SIZE + request.len());
+        frame.extend_from_slice(&self.transaction_id.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]); // Protocol ID, always 0 for Modbus
+        frame.extend_from_slice(&(request.len() as u16 + 1).to_be_bytes());
+        frame.push(self.unit_id);
+        frame.extend_from_slice(request);
+        self.stream.write_all(&frame)?;
+
+        loop {
+            let mut header = [0; MBAP_HEADER_SIZE];
+            self.stream.read_exact(&mut header)?;
+            let transaction_id = u16::from_be_bytes([header[0], header[1]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if header[2..4] != [0, 0] || length < 2 || length > MAX_PDU_SIZE + 1 {
+                return Err(modbus::Error::InvalidResponse);
+            }
+            let mut reply = vec![0; length - 1];
+            self.stream.read_exact(&mut reply)?;
+            if transaction_id != self.transaction_id {
+                continue;
+            }
+
+            let function = request[0];
+            return match reply[0] {
+                code if code == function => Ok(reply.split_off(1)),
+                code if code == function | 0x80 && reply.len() == 2 => {
+                    Err(modbus::Error::Exception(exception_code(reply[1])))
+                }
+                _ => Err(modbus::Error::InvalidResponse),
+            };
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        let mut request = vec![0x03];
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&quantity.to_be_bytes());
+        registers(&self.transact(&request)?, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        let mut request = vec![0x06];
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&value.to_be_bytes());
+        // The drive echoes the address and value it wrote
+        if self.transact(&request)? != request[1..] {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        self.unit_id = unit_id;
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        let mut request = vec![0x17];
+        request.extend_from_slice(&read_address.to_be_bytes());
+        request.extend_from_slice(&read_quantity.to_be_bytes());
+        request.extend_from_slice(&write_address.to_be_bytes());
+        request.extend_from_slice(&(values.len() as u16).to_be_bytes());
+        request.push((values.len() * 2) as u8);
+        for value in values {
+            request.extend_from_slice(&value.to_be_bytes());
+        }
+        if request.len() > MAX_PDU_SIZE {
+            return Err(modbus::Error::InvalidData(modbus::Reason::SendBufferTooBig));
+        }
+        registers(&self.transact(&request)?, read_quantity)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+        if timeout != self.read_timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.read_timeout = timeout;
+        }
+        Ok(())
+    }
+}
+
+// The registers in a read reply, which starts with its byte count
+fn registers(reply: &[u8], quantity: u16) -> modbus::Result<Vec<u16>> {
+    let expected = quantity as usize * 2;
+    if reply.len() != expected + 1 || reply[0] as usize != expected {
+        return Err(modbus::Error::InvalidData(
+            modbus::Reason::UnexpectedReplySize,
+        ));
+    }
+    Ok(reply[1..]
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect())
+}
+
+impl Transport for tcp::Transport {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        Client::read_holding_registers(self, address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        Client::write_single_register(self, address, value)
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        self.set_uid(unit_id);
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        Client::write_read_multiple_registers(
+            self,
+            write_address,
+            values.len() as u16,
+            values,
+            read_address,
+            read_quantity,
+        )
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        (**self).read_holding_registers(address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        (**self).write_single_register(address, value)
+    }
+
+    fn set_unit_id(&mut self, unit_id: u8) {
+        (**self).set_unit_id(unit_id)
+    }
+
+    fn write_read_multiple_registers(
+        &mut self,
+        write_address: u16,
+        values: &[u16],
+        read_address: u16,
+        read_quantity: u16,
+    ) -> modbus::Result<Vec<u16>> {
+        (**self).write_read_multiple_registers(write_address, values, read_address, read_quantity)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppliedDevice, Clock, ManualClock, SimulatedDrive};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use yaml_rust::YamlLoader;
+
+    type Transactions = Arc<Mutex<Vec<(u16, Option<Duration>)>>>; // Register and read timeout
+
+    // Passes everything to the drive, noting the read timeout each read or
+    // write was made with, and taking `delay` on the clock to answer status
+    // reads
+    struct SlowStatus {
+        drive: SimulatedDrive,
+        clock: ManualClock,
+        delay: Duration,
+        timeout: Option<Duration>,
+        transactions: Transactions,
+    }
+
+    impl Transport for SlowStatus {
+        fn read_holding_registers(
+            &mut self,
+            address: u16,
+            quantity: u16,
+        ) -> modbus::Result<Vec<u16>> {
+            self.transactions
+                .lock()
+                .unwrap()
+                .push((address, self.timeout));
+            if address == 1 {
+                self.clock.advance(self.delay);
+            }
+            self.drive.read_holding_registers(address, quantity)
+        }
+
+        fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+            self.transactions
+                .lock()
+                .unwrap()
+                .push((address, self.timeout));
+            self.drive.write_single_register(address, value)
+        }
+
+        fn set_read_timeout(&mut self, timeout: Option<Duration>) -> modbus::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+    }
+
+    fn slow_status_device(delay: Duration) -> (AppliedDevice, Transactions) {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let transactions = Arc::new(Mutex::new(Vec::new()));
+        let transport = SlowStatus {
+            drive: SimulatedDrive::with_clock(shared.clone()),
+            clock,
+            delay,
+            timeout: None,
+            transactions: transactions.clone(),
+        };
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), transport);
+        device.set_clock(shared);
+        device.timeouts = ModbusTimeouts {
+            status_poll: Some(Duration::from_millis(50)),
+            command: Some(Duration::from_millis(500)),
+            ..ModbusTimeouts::default()
+        };
+        (device, transactions)
+    }
+
+    #[test]
+    fn status_reads_wait_for_the_status_poll_limit() {
+        let (mut device, transactions) = slow_status_device(Duration::ZERO);
+        device.get_register_value(1).unwrap();
+        device.get_register_value(29).unwrap();
+        assert_eq!(
+            *transactions.lock().unwrap(),
+            vec![
+                (1, Some(Duration::from_millis(50))),
+                (29, Some(Duration::from_millis(DEFAULT_IO_TIMEOUT)))
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_wait_for_the_command_limit() {
+        let (mut device, transactions) = slow_status_device(Duration::ZERO);
+        device.write_register(29, 240).unwrap();
+        device.get_register_value(29).unwrap();
+        assert_eq!(
+            *transactions.lock().unwrap(),
+            vec![
+                (29, Some(Duration::from_millis(500))),
+                (29, Some(Duration::from_millis(DEFAULT_IO_TIMEOUT)))
+            ]
+        );
+    }
+
+    #[test]
+    fn timeouts_not_configured_keep_their_defaults() {
+        let conf =
+            &YamlLoader::load_from_str("timeouts:\n  status_poll_ms: 250\n  read_ms: 2000\n")
+                .unwrap()[0];
+        let timeouts = ModbusTimeouts::from_yaml(conf).unwrap();
+        assert_eq!(timeouts.status_poll, Some(Duration::from_millis(250)));
+        assert_eq!(timeouts.read, Some(Duration::from_millis(2000)));
+        assert_eq!(timeouts.write, ModbusTimeouts::default().write);
+        assert_eq!(timeouts.command, None);
+    }
+
+    #[test]
+    fn late_reply_is_used_rather_than_read_again() {
+        let (mut device, transactions) = slow_status_device(Duration::from_millis(200));
+        device.get_register_value(1).unwrap();
+        assert_eq!(transactions.lock().unwrap().len(), 1);
+    }
+
+    // A server that answers each read of `quantity` registers with the
+    // transaction ID as every value, after the given delay
+    fn answering_after(delays: Vec<Duration>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for delay in delays {
+                let mut request = [0; 12];
+                stream.read_exact(&mut request).unwrap();
+                thread::sleep(delay);
+                let quantity = u16::from_be_bytes([request[10], request[11]]);
+                let mut reply = request[..4].to_vec();
+                reply.extend_from_slice(&(3 + quantity * 2).to_be_bytes());
+                reply.extend_from_slice(&[request[6], 0x03, (quantity * 2) as u8]);
+                for _ in 0..quantity {
+                    reply.extend_from_slice(&request[..2]);
+                }
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn read_timeout_fails_a_slow_reply_which_is_then_passed_over() {
+        let port = answering_after(vec![Duration::from_millis(300), Duration::ZERO]);
+        let mut transport =
+            TcpTransport::connect("127.0.0.1", Some(port), &ModbusTimeouts::default()).unwrap();
+        transport
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(matches!(
+            transport.read_holding_registers(1, 1),
+            Err(modbus::Error::Io(_))
+        ));
+
+        transport
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(transport.read_holding_registers(1, 2).unwrap(), vec![2, 2]);
+    }
+}
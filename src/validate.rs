@@ -0,0 +1,678 @@
+use crate::absolute::absolute_encoder_from_yaml;
+use crate::config::{load_device_yaml, min_request_gap, servo_addresses, verify_writes};
+use crate::device_lock::lock_dir_from_yaml;
+use crate::input_filter::input_filters_from_yaml;
+use crate::resolution::{encoder_counts_per_rev_from_yaml, steps_per_rev_from_yaml};
+use crate::rollover::rollover_from_yaml;
+use crate::{
+    CommsWatchdog, DeviceError, FilterSettings, FirmwareRange, IdleCurrent, InitialState,
+    LimitSwitchConfig, MaintenanceTask, ModbusTimeouts, MotionDefaults, OpcodeTable, RegisterMap,
+};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tracing::{error, warn};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+// Every top level key a device config may have
+static KNOWN_KEYS: &[&str] = &[
+    "base",
+    "device",
+    "timeouts",
+    "rate_limit",
+    "verify_writes",
+    "state_dir",
+    "maintenance",
+    "input_filters",
+    "limit_switches",
+    "idle_current",
+    "steps_per_rev",
+    "encoder_counts_per_rev",
+    "absolute_encoder",
+    "rollover",
+    "filters",
+    "firmware",
+    "motion",
+    "homing",
+    "registers",
+    "exclusive_lock",
+    "watchdog",
+    "initial_state",
+    "opcodes",
+    "register_map",
+];
+
+// The keys of each section made up of fixed fields.  Sections naming things
+// of their own, such as registers or maintenance tasks, aren't listed.
+static SECTION_KEYS: &[(&str, &[&str])] = &[
+    (
+        "timeouts",
+        &[
+            "connect_ms",
+            "read_ms",
+            "write_ms",
+            "status_poll_ms",
+            "command_ms",
+        ],
+    ),
+    ("rate_limit", &["max_per_second"]),
+    (
+        "limit_switches",
+        &["enabled", "normally_closed", "cw_input", "ccw_input"],
+    ),
+    ("idle_current", &["percent", "delay_ms"]),
+    ("absolute_encoder", &["skip_homing"]),
+    (
+        "filters",
+        &[
+            "anti_resonance",
+            "anti_resonance_damping",
+            "command_smoothing",
+        ],
+    ),
+    ("firmware", &["min", "max", "on_mismatch"]),
+    (
+        "motion",
+        &[
+            "accel",
+            "decel",
+            "velocity",
+            "tolerance",
+            "move_timeout_ms",
+            "counts_per_unit",
+        ],
+    ),
+    (
+        "homing",
+        &[
+            "mode",
+            "timeout_ms",
+            "on_connect",
+            "retries",
+            "retry_delay_ms",
+            "reset_alarms",
+            "offset",
+            "zero_at_offset",
+        ],
+    ),
+    ("watchdog", &["stale_after_ms", "stop_on_recovery"]),
+    ("initial_state", &["motor", "brake", "outputs"]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,   // The config can't be used
+    Warning, // Probably a mistake, such as a misspelt key, but ignored
+}
+
+// One thing wrong with a device config
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    pub field: String,       // e.g. "device.axis1", empty for the file as a whole
+    pub line: Option<usize>, // Of the field in the file checked, where it can be found
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match (self.line, self.field.is_empty()) {
+            (Some(line), _) => write!(
+                f,
+                "{} at line {} ({}): {}",
+                severity, line, self.field, self.message
+            ),
+            (None, false) => write!(f, "{} in {}: {}", severity, self.field, self.message),
+            (None, true) => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigReport {
+    pub source: String,
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl ConfigReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors().is_empty()
+    }
+
+    pub fn errors(&self) -> Vec<&ConfigProblem> {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == Severity::Error)
+            .collect()
+    }
+
+    pub fn warnings(&self) -> Vec<&ConfigProblem> {
+        self.problems
+            .iter()
+            .filter(|p| p.severity == Severity::Warning)
+            .collect()
+    }
+
+    fn add(&mut self, severity: Severity, field: &str, line: Option<usize>, message: String) {
+        self.problems.push(ConfigProblem {
+            severity,
+            field: field.to_string(),
+            line,
+            message,
+        });
+    }
+
+    // Logs every problem, failing with all the errors together if there are any
+    pub(crate) fn into_result(self) -> Result<(), DeviceError> {
+        for problem in &self.problems {
+            match problem.severity {
+                Severity::Error => error!("Device config {}: {}", self.source, problem),
+                Severity::Warning => warn!("Device config {}: {}", self.source, problem),
+            }
+        }
+        let errors = self.errors();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(DeviceError::Config(format!(
+            "{} has {} problem(s): {}",
+            self.source,
+            errors.len(),
+            errors
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<String>>()
+                .join("; ")
+        )))
+    }
+}
+
+// Checks a device config file, and any bases it names, reporting every
+// problem found rather than stopping at the first
+pub fn validate_config(path: &str) -> ConfigReport {
+    let mut report = ConfigReport {
+        source: path.to_string(),
+        problems: Vec::new(),
+    };
+    match load_device_yaml(path, None) {
+        Ok(conf) => {
+            let text = std::fs::read_to_string(path).unwrap_or_default();
+            check_device_yaml(&conf, &text, &mut report);
+        }
+        Err(e) => report.add(Severity::Error, "", None, message_of(e)),
+    }
+    report
+}
+
+// Checks an already parsed config.  text is the config's own source, used
+// to find the lines fields are on.
+pub(crate) fn check_device_yaml(conf: &Yaml, text: &str, report: &mut ConfigReport) {
+    let keys = match conf {
+        Yaml::Hash(h) => h.keys(),
+        other => {
+            report.add(
+                Severity::Error,
+                "",
+                None,
+                format!("A device config must be a map, got {:?}", other),
+            );
+            return;
+        }
+    };
+
+    for key in keys {
+        match key.as_str() {
+            Some(k) if KNOWN_KEYS.contains(&k) => {}
+            Some(k) => report.add(
+                Severity::Warning,
+                k,
+                line_of(text, k, false),
+                String::from("unknown field, ignored"),
+            ),
+            None => report.add(
+                Severity::Warning,
+                "",
+                None,
+                format!("unknown field {:?}, ignored", key),
+            ),
+        }
+    }
+
+    check_devices(conf, text, report);
+
+    if !matches!(
+        conf["state_dir"],
+        Yaml::BadValue | Yaml::Null | Yaml::String(_)
+    ) {
+        report.add(
+            Severity::Error,
+            "state_dir",
+            line_of(text, "state_dir", false),
+            format!("must be a directory name, got {:?}", conf["state_dir"]),
+        );
+    }
+
+    for (section, keys) in SECTION_KEYS {
+        if let Yaml::Hash(fields) = &conf[*section] {
+            for key in fields.keys() {
+                let name = key_name(key);
+                if !keys.contains(&name.as_str()) {
+                    report.add(
+                        Severity::Warning,
+                        &format!("{}.{}", section, name),
+                        line_in_section(text, section, &name),
+                        String::from("unknown field, ignored"),
+                    );
+                }
+            }
+        }
+    }
+
+    let sections: Vec<(&str, SectionCheck)> = vec![
+        ("timeouts", |c| ModbusTimeouts::from_yaml(c).map(|_| ())),
+        ("rate_limit", |c| min_request_gap(c).map(|_| ())),
+        ("verify_writes", |c| verify_writes(c).map(|_| ())),
+        ("maintenance", |c| MaintenanceTask::from_yaml(c).map(|_| ())),
+        ("input_filters", |c| input_filters_from_yaml(c).map(|_| ())),
+        ("limit_switches", |c| {
+            LimitSwitchConfig::from_yaml(c).map(|_| ())
+        }),
+        ("idle_current", |c| IdleCurrent::from_yaml(c).map(|_| ())),
+        ("steps_per_rev", |c| steps_per_rev_from_yaml(c).map(|_| ())),
+        ("encoder_counts_per_rev", |c| {
+            encoder_counts_per_rev_from_yaml(c).map(|_| ())
+        }),
+        ("absolute_encoder", |c| {
+            absolute_encoder_from_yaml(c).map(|_| ())
+        }),
+        ("rollover", |c| rollover_from_yaml(c).map(|_| ())),
+        ("filters", |c| FilterSettings::from_yaml(c).map(|_| ())),
+        ("firmware", |c| FirmwareRange::from_yaml(c).map(|_| ())),
+        // The motion defaults are read from all three
+        ("motion", |c| MotionDefaults::from_yaml(c).map(|_| ())),
+        ("homing", |c| MotionDefaults::from_yaml(c).map(|_| ())),
+        ("registers", |c| MotionDefaults::from_yaml(c).map(|_| ())),
+        ("exclusive_lock", |c| lock_dir_from_yaml(c).map(|_| ())),
+        ("watchdog", |c| CommsWatchdog::from_yaml(c).map(|_| ())),
+        ("initial_state", |c| InitialState::from_yaml(c).map(|_| ())),
+        ("opcodes", |c| OpcodeTable::from_yaml(c).map(|_| ())),
+        ("register_map", |c| RegisterMap::from_yaml(c).map(|_| ())),
+    ];
+    for (section, check) in sections {
+        check_section(conf, text, section, check, report);
+    }
+}
+
+// Reads a section, given a config holding only that section
+type SectionCheck = fn(&Yaml) -> Result<(), DeviceError>;
+
+// Reports every field of a section its reader rejects.  A reader stops at
+// its first error, so each field is read again from a copy of the section
+// holding only it, plus any other field it can't be read without.  A field
+// needing one that is wrong itself can't be checked until that is fixed.
+fn check_section(
+    conf: &Yaml,
+    text: &str,
+    section: &str,
+    check: SectionCheck,
+    report: &mut ConfigReport,
+) {
+    let only = |fields: Yaml| {
+        let mut conf = Hash::new();
+        conf.insert(Yaml::String(section.to_string()), fields);
+        Yaml::Hash(conf)
+    };
+    let error = match check(&only(conf[section].clone())) {
+        Ok(()) => return,
+        Err(e) => message_of(e),
+    };
+    let section_line = line_of(text, section, false);
+    let fields = match &conf[section] {
+        Yaml::Hash(fields) if !fields.is_empty() => fields,
+        _ => {
+            report.add(Severity::Error, section, section_line, error);
+            return;
+        }
+    };
+
+    let mut problems: Vec<(String, Option<usize>, String)> = Vec::new();
+    for (key, value) in fields {
+        let mut copy = Hash::new();
+        copy.insert(key.clone(), value.clone());
+        // Each pass either settles this field or adds one it needs
+        let problem = loop {
+            let message = match check(&only(Yaml::Hash(copy.clone()))) {
+                Ok(()) => break None,
+                Err(e) => message_of(e),
+            };
+            match fields.keys().find(|k| names_field(&message, section, k)) {
+                Some(needed) if needed == key => {}
+                // A needed field that is wrong itself is reported on its own
+                Some(needed) if copy.contains_key(needed) => break None,
+                Some(needed) => {
+                    copy.insert(needed.clone(), fields[needed].clone());
+                    continue;
+                }
+                // A field that is missing altogether
+                None if message.starts_with(&format!("{}.", section)) => {
+                    break Some((section.to_string(), section_line, message))
+                }
+                // Worded without a field name, such as a bad register number
+                None => {}
+            }
+            let name = key_name(key);
+            let line = line_in_section(text, section, &name).or(section_line);
+            break Some((format!("{}.{}", section, name), line, message));
+        };
+        if let Some(problem) = problem {
+            if !problems.iter().any(|p| p.2 == problem.2) {
+                problems.push(problem);
+            }
+        }
+    }
+    // Such as two fields that can't be given together
+    if problems.is_empty() {
+        problems.push((section.to_string(), section_line, error));
+    }
+    for (field, line, message) in problems {
+        report.add(Severity::Error, &field, line, message);
+    }
+}
+
+// Whether an error message is about a field of a section, going by how the
+// readers word them, e.g. "homing.retries must be ..." or "read_ms must be ..."
+fn names_field(message: &str, section: &str, key: &Yaml) -> bool {
+    let name = key_name(key);
+    let rest = message
+        .strip_prefix(section)
+        .and_then(|m| m.strip_prefix('.'))
+        .unwrap_or(message);
+    match rest.strip_prefix(name.as_str()) {
+        Some(after) => after.starts_with(' ') || after.starts_with('.'),
+        None => false,
+    }
+}
+
+fn key_name(key: &Yaml) -> String {
+    match key {
+        Yaml::String(s) => s.clone(),
+        Yaml::Integer(n) => n.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn message_of(e: DeviceError) -> String {
+    match e {
+        DeviceError::Config(msg) => msg,
+        other => other.to_string(),
+    }
+}
+
+fn check_devices(conf: &Yaml, text: &str, report: &mut ConfigReport) {
+    let servos = match &conf["device"] {
+        Yaml::Hash(h) => h,
+        Yaml::BadValue | Yaml::Null => {
+            report.add(
+                Severity::Error,
+                "device",
+                line_of(text, "device", false),
+                String::from("missing, it must map each servo name to its address"),
+            );
+            return;
+        }
+        other => {
+            report.add(
+                Severity::Error,
+                "device",
+                line_of(text, "device", false),
+                format!("must map each servo name to its address, got {:?}", other),
+            );
+            return;
+        }
+    };
+    if servos.is_empty() {
+        report.add(
+            Severity::Warning,
+            "device",
+            line_of(text, "device", false),
+            String::from("no servos are listed"),
+        );
+    }
+
+    for (name, address) in servos {
+        let name = match name.as_str() {
+            Some(n) => n,
+            None => {
+                report.add(
+                    Severity::Error,
+                    "device",
+                    None,
+                    format!("servo names must be strings, got {:?}", name),
+                );
+                continue;
+            }
+        };
+        let field = format!("device.{}", name);
+        let line = line_of(text, name, true);
+        let addresses = match servo_addresses(address) {
+            Some(addresses) if !addresses.is_empty() => addresses,
+            _ => {
+                report.add(
+                    Severity::Error,
+                    &field,
+                    line,
+                    format!(
+                        "the address must be a string or a list of them, got {:?}",
+                        address
+                    ),
+                );
+                continue;
+            }
+        };
+        for a in addresses {
+            if !valid_address(&a) {
+                report.add(
+                    Severity::Error,
+                    &field,
+                    line,
+                    format!(
+                        "{:?} is not an IP address or host name, with an optional port",
+                        a
+                    ),
+                );
+            }
+        }
+    }
+}
+
+// Whether a servo address is an IP address or a well formed host name,
+// with an optional port
+pub(crate) fn valid_address(address: &str) -> bool {
+    parse_address(address).is_some()
+}
+
+// Splits a servo address into the host to connect to and the port, if one
+// is given.  IPv6 addresses need brackets when a port is given.
+//
+//   10.0.0.11   10.0.0.11:5020   axis1.local:5020   fd00::11   [fd00::11]:5020
+pub(crate) fn parse_address(address: &str) -> Option<(String, Option<u16>)> {
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, after) = rest.split_once(']')?;
+        host.parse::<Ipv6Addr>().ok()?;
+        let port = match after {
+            "" => None,
+            _ => Some(after.strip_prefix(':')?.parse::<u16>().ok()?),
+        };
+        return Some((host.to_string(), port));
+    }
+    if address.parse::<IpAddr>().is_ok() {
+        return Some((address.to_string(), None));
+    }
+    let (host, port) = match address.split_once(':') {
+        Some((host, port)) => (host, Some(port.parse::<u16>().ok()?)),
+        None => (address, None),
+    };
+    if host.parse::<Ipv4Addr>().is_ok() || valid_host_name(host) {
+        Some((host.to_string(), port))
+    } else {
+        None
+    }
+}
+
+fn valid_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// The line a field of a top level section is given on, None if it isn't
+// written out on a line of its own
+fn line_in_section(text: &str, section: &str, key: &str) -> Option<usize> {
+    let start = line_of(text, section, false)?;
+    let wanted = format!("{}:", key);
+    text.lines()
+        .enumerate()
+        .skip(start)
+        .take_while(|(_, line)| line.trim().is_empty() || line.starts_with([' ', '#']))
+        .find(|(_, line)| line.trim_start().starts_with(&wanted))
+        .map(|(index, _)| index + 1)
+}
+
+// The 1-based line a key is given on, found by its text.  Top level keys
+// must start the line, nested ones may be indented.
+fn line_of(text: &str, key: &str, nested: bool) -> Option<usize> {
+    let wanted = format!("{}:", key);
+    text.lines()
+        .position(|line| {
+            let line = if nested { line.trim_start() } else { line };
+            line.starts_with(&wanted)
+        })
+        .map(|index| index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn check(text: &str) -> Vec<ConfigProblem> {
+        let docs = YamlLoader::load_from_str(text).expect("Bad test YAML");
+        let mut report = ConfigReport {
+            source: String::from("test"),
+            problems: Vec::new(),
+        };
+        check_device_yaml(&docs[0], text, &mut report);
+        report.problems
+    }
+
+    fn found(problems: &[ConfigProblem], field: &str) -> Option<ConfigProblem> {
+        problems.iter().find(|p| p.field == field).cloned()
+    }
+
+    #[test]
+    fn unknown_keys_inside_sections_are_warned_of() {
+        let problems =
+            check("device:\n  axis1: 10.0.0.11\nhoming:\n  retries: 2\n  retry_dealy_ms: 500\n");
+        let problem = found(&problems, "homing.retry_dealy_ms").expect("Not warned of");
+        assert_eq!(problem.severity, Severity::Warning);
+        assert_eq!(problem.line, Some(5));
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+    }
+
+    #[test]
+    fn every_bad_field_of_a_section_is_reported() {
+        let problems = check(
+            "device:\n  axis1: 10.0.0.11\ntimeouts:\n  read_ms: -1\n  write_ms: 100\n  command_ms: soon\n",
+        );
+        let read = found(&problems, "timeouts.read_ms").expect("read_ms not reported");
+        assert_eq!(read.severity, Severity::Error);
+        assert_eq!(read.line, Some(4));
+        let command = found(&problems, "timeouts.command_ms").expect("command_ms not reported");
+        assert_eq!(command.line, Some(6));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+    }
+
+    #[test]
+    fn fields_are_checked_alongside_the_ones_they_need() {
+        // delay_ms is only read once percent has been
+        let problems =
+            check("device:\n  axis1: 10.0.0.11\nidle_current:\n  percent: 50\n  delay_ms: -5\n");
+        assert_eq!(
+            found(&problems, "idle_current.delay_ms").unwrap().line,
+            Some(5)
+        );
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+
+        // A missing field is put on the section
+        let problems = check("device:\n  axis1: 10.0.0.11\nidle_current:\n  delay_ms: 5\n");
+        let missing = found(&problems, "idle_current").expect("Missing field not reported");
+        assert_eq!(missing.line, Some(3));
+        assert!(missing.message.contains("percent"), "{}", missing.message);
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+    }
+
+    #[test]
+    fn sections_read_together_are_reported_apart() {
+        let problems = check(
+            "device:\n  axis1: 10.0.0.11\nmotion:\n  accel: 0\nhoming:\n  on_connect: yes please\nregisters:\n  40: 70000\n",
+        );
+        assert_eq!(found(&problems, "motion.accel").unwrap().line, Some(4));
+        assert_eq!(found(&problems, "homing.on_connect").unwrap().line, Some(6));
+        assert_eq!(found(&problems, "registers.40").unwrap().line, Some(8));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+    }
+
+    #[test]
+    fn encoder_resolutions_past_the_drives_register_are_refused() {
+        let problems = check("device:\n  axis1: 10.0.0.11\nencoder_counts_per_rev: 65535\n");
+        assert!(problems.is_empty(), "{:?}", problems);
+        let problems = check("device:\n  axis1: 10.0.0.11\nencoder_counts_per_rev: 65536\n");
+        let problem = found(&problems, "encoder_counts_per_rev").expect("Not reported");
+        assert_eq!(problem.severity, Severity::Error);
+        assert_eq!(problem.line, Some(3));
+    }
+
+    #[test]
+    fn conflicting_fields_are_put_on_the_section() {
+        let problems =
+            check("device:\n  axis1: 10.0.0.11\nhoming:\n  retries: 2\n  zero_at_offset: true\n");
+        let problem = found(&problems, "homing.zero_at_offset").expect("Not reported");
+        assert_eq!(problem.line, Some(5));
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+    }
+
+    #[test]
+    fn addresses_are_split_into_host_and_port() {
+        let split = |host: &str, port: Option<u16>| Some((host.to_string(), port));
+        assert_eq!(parse_address("[::1]:502"), split("::1", Some(502)));
+        assert_eq!(parse_address("[::1]"), split("::1", None));
+        assert_eq!(parse_address("::1"), split("::1", None));
+        assert_eq!(parse_address("host:502"), split("host", Some(502)));
+        assert_eq!(
+            parse_address("10.0.0.11:5020"),
+            split("10.0.0.11", Some(5020))
+        );
+        assert_eq!(parse_address("axis1.local"), split("axis1.local", None));
+    }
+
+    #[test]
+    fn bad_addresses_are_refused() {
+        assert_eq!(parse_address("host:70000"), None);
+        assert_eq!(parse_address("host:modbus"), None);
+        assert_eq!(parse_address("[::1]:"), None);
+        assert_eq!(parse_address("[::1]502"), None);
+        assert_eq!(parse_address("[::1"), None);
+        assert_eq!(parse_address("[::1:502"), None);
+        assert_eq!(parse_address("[axis1]:502"), None);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::feed::signed_count;
+use crate::virtual_master::VELOCITY_UNITS;
+use crate::{AppliedDevice, DeviceError, TraceSample, ACTUAL_VELOCITY};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+static ACCEL_UNITS: f64 = 6.0; // The drive's accelerations are in 1/6 rev/s/s
+static DEFAULT_MONITOR_PERIOD: u64 = 100; // In ms
+static DEFAULT_SLOW_FRACTION: f64 = 0.8;
+
+// How fast the axis is actually turning, as measured by the drive.  Negative
+// in the direction of decreasing encoder counts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AxisVelocity {
+    pub counts_per_sec: f64,
+    pub units_per_sec: Option<f64>, // None without a configured counts_per_unit
+}
+
+// Sampling of commanded against actual velocity while a move is under way,
+// to catch a loaded-down axis before the move times out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VelocityMonitor {
+    pub period: Duration,
+    pub slow_fraction: f64, // Running below this fraction of the commanded velocity counts as slow
+}
+
+impl Default for VelocityMonitor {
+    fn default() -> VelocityMonitor {
+        VelocityMonitor {
+            period: Duration::from_millis(DEFAULT_MONITOR_PERIOD),
+            slow_fraction: DEFAULT_SLOW_FRACTION,
+        }
+    }
+}
+
+// One sample of a move's velocity, all in counts/s and signed the way the
+// axis is travelling
+#[derive(Clone, Debug)]
+pub struct MoveVelocitySample {
+    pub servo_name: String,
+    pub at: Instant,
+    pub commanded: f64, // What the move's profile calls for at this time and position
+    pub actual: f64,
+    pub acceleration: Option<f64>, // In counts/s/s, since the previous sample of the move
+    pub slow: bool,
+}
+
+// The monitor's state for the move under way
+struct MonitoredMove {
+    accel: u64, // In the drive's units
+    decel: u64,
+    started: Instant, // Of the move, or of its latest resume
+    last: Option<(Instant, f64)>,
+    slow_reported: bool,
+}
+
+pub(crate) struct MoveVelocityMonitor {
+    settings: VelocityMonitor,
+    current: Option<MonitoredMove>,
+    streams: Vec<Sender<MoveVelocitySample>>,
+}
+
+impl AppliedDevice {
+    // Reads the drive's actual velocity, e.g. to check the axis reaches the
+    // commanded speed during a qualification run.  The encoder resolution is
+    // read from the drive the first time if it isn't already known.
+    pub fn current_velocity(&mut self) -> Result<AxisVelocity, DeviceError> {
+        let counts_per_sec = self.read_velocity()?;
+        Ok(AxisVelocity {
+            counts_per_sec,
+            units_per_sec: self
+                .motion_defaults
+                .counts_per_unit
+                .map(|per_unit| counts_per_sec / per_unit),
+        })
+    }
+
+    // The actual velocity in counts/s, also kept in the status snapshot
+    pub(crate) fn read_velocity(&mut self) -> Result<f64, DeviceError> {
+        let raw = self.get_register_value(ACTUAL_VELOCITY)? as u16 as i16;
+        let velocity = raw as f64 / VELOCITY_UNITS * self.velocity_scale()?;
+        self.update_snapshot(|s| s.velocity = velocity);
+        Ok(velocity)
+    }
+
+    // Encoder counts per rev, to turn the drive's rev/s into counts/s
+    fn velocity_scale(&mut self) -> Result<f64, DeviceError> {
+        let counts = match self.known_encoder_counts_per_rev() {
+            Some(counts) => counts,
+            None => self.get_encoder_counts_per_rev()?,
+        };
+        Ok(counts as f64)
+    }
+
+    // Samples commanded and actual velocity during every move from now on,
+    // passing each sample to the telemetry sinks' on_move_velocity and any
+    // streams from stream_move_velocity.  None turns the monitor off.
+    pub fn monitor_move_velocity(&mut self, monitor: Option<VelocityMonitor>) {
+        self.velocity_monitor = monitor.map(|settings| MoveVelocityMonitor {
+            settings,
+            current: None,
+            streams: Vec::new(),
+        });
+    }
+
+    pub fn get_move_velocity_monitor(&self) -> Option<VelocityMonitor> {
+        self.velocity_monitor.as_ref().map(|m| m.settings)
+    }
+
+    // Delivers the monitor's samples until the receiver is dropped or the
+    // monitor is turned off.  Turns the monitor on with its defaults if it
+    // isn't already.
+    pub fn stream_move_velocity(&mut self) -> Receiver<MoveVelocitySample> {
+        if self.velocity_monitor.is_none() {
+            self.monitor_move_velocity(Some(VelocityMonitor::default()));
+        }
+        let (sender, receiver) = mpsc::channel();
+        if let Some(monitor) = &mut self.velocity_monitor {
+            monitor.streams.push(sender);
+        }
+        receiver
+    }
+
+    // Called as a move is started, and again when a held move resumes
+    pub(crate) fn begin_velocity_monitor(&mut self, accel: u64, decel: u64) {
+        let now = self.clock.now();
+        if let Some(monitor) = &mut self.velocity_monitor {
+            monitor.current = Some(MonitoredMove {
+                accel,
+                decel,
+                started: now,
+                last: None,
+                slow_reported: false,
+            });
+        }
+    }
+
+    pub(crate) fn restart_velocity_monitor(&mut self) {
+        let now = self.clock.now();
+        if let Some(current) = self
+            .velocity_monitor
+            .as_mut()
+            .and_then(|m| m.current.as_mut())
+        {
+            current.started = now;
+            current.last = None;
+        }
+    }
+
+    pub(crate) fn end_velocity_monitor(&mut self) {
+        if let Some(monitor) = &mut self.velocity_monitor {
+            monitor.current = None;
+        }
+    }
+
+    // How often the monitor wants samples, while a move is under way
+    pub(crate) fn velocity_monitor_period(&self) -> Option<Duration> {
+        match &self.velocity_monitor {
+            Some(monitor) if monitor.current.is_some() => Some(monitor.settings.period),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn velocity_monitor_due(&self, at: Instant) -> bool {
+        match &self.velocity_monitor {
+            Some(monitor) => match &monitor.current {
+                Some(current) => AppliedDevice::sample_due(
+                    current.last.map(|(at, _)| at),
+                    monitor.settings.period,
+                    at,
+                ),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    // A sample that can't be scaled is skipped, the move carries on
+    pub(crate) fn record_move_velocity(&mut self, sample: &TraceSample) {
+        let actual = match sample.velocity {
+            Some(actual) if self.velocity_monitor_due(sample.at) => actual,
+            _ => return,
+        };
+        let counts_per_rev = match self.velocity_scale() {
+            Ok(counts_per_rev) => counts_per_rev,
+            Err(e) => {
+                warn!(
+                    "Unable to sample the move velocity of {}: {}",
+                    self.servo_name, e
+                );
+                return;
+            }
+        };
+        let cruise = self.override_velocity(self.move_velocity) as f64 / VELOCITY_UNITS;
+        let held = self.motion_control.is_held();
+        let remaining = self.move_target.map_or(0, |target| {
+            signed_count(target) - signed_count(sample.encoder_count)
+        });
+        let monitor = match &mut self.velocity_monitor {
+            Some(monitor) => monitor,
+            None => return,
+        };
+        let current = match &mut monitor.current {
+            Some(current) => current,
+            None => return,
+        };
+
+        // A trapezoidal profile: ramping up from the start at the accel, at
+        // most the (overridden) velocity, and slowing at the decel to stop
+        // at the target
+        let accel = current.accel.max(1) as f64 / ACCEL_UNITS * counts_per_rev;
+        let decel = current.decel.max(1) as f64 / ACCEL_UNITS * counts_per_rev;
+        let ramp = accel
+            * sample
+                .at
+                .saturating_duration_since(current.started)
+                .as_secs_f64();
+        let stopping = (2.0 * decel * remaining.unsigned_abs() as f64).sqrt();
+        let commanded = match held {
+            true => 0.0,
+            false => (cruise * counts_per_rev).min(ramp).min(stopping) * remaining.signum() as f64,
+        };
+
+        let acceleration = current.last.and_then(|(at, velocity)| {
+            let elapsed = sample.at.saturating_duration_since(at).as_secs_f64();
+            (elapsed > 0.0).then(|| (actual - velocity) / elapsed)
+        });
+        let slow = actual.abs() < commanded.abs() * monitor.settings.slow_fraction;
+        current.last = Some((sample.at, actual));
+        let first_slow = slow && !current.slow_reported;
+        current.slow_reported |= slow;
+
+        let event = MoveVelocitySample {
+            servo_name: self.servo_name.clone(),
+            at: sample.at,
+            commanded,
+            actual,
+            acceleration,
+            slow,
+        };
+        monitor
+            .streams
+            .retain(|stream| stream.send(event.clone()).is_ok());
+        if first_slow {
+            warn!(
+                "{} is running slow, at {:.0} counts/s of the {:.0} commanded",
+                self.servo_name, actual, commanded
+            );
+        }
+        self.emit(|t| t.on_move_velocity(&event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    // At 24000 counts/rev a velocity of 240 is 24000 counts/s, and an accel
+    // or decel of 6 is 24000 counts/s/s
+    static COUNTS_PER_REV: u32 = 24000;
+
+    fn monitored_device() -> (AppliedDevice, ManualClock, Receiver<MoveVelocitySample>) {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let drive = SimulatedDrive::with_clock(shared.clone());
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), drive);
+        device.set_clock(shared);
+        device.encoder_counts_per_rev = Some(COUNTS_PER_REV);
+        let samples = device.stream_move_velocity();
+        (device, clock, samples)
+    }
+
+    // Starts the monitor on a move from where the axis is to `target`
+    fn begin_move(device: &mut AppliedDevice, target: u64) {
+        device.move_velocity = 240;
+        device.move_target = Some(target);
+        device.begin_velocity_monitor(6, 6);
+    }
+
+    fn sample(device: &mut AppliedDevice, encoder_count: u64, velocity: f64) {
+        let sample = TraceSample {
+            at: device.clock.now(),
+            encoder_count,
+            velocity: Some(velocity),
+            status: Vec::new(),
+        };
+        device.record_move_velocity(&sample);
+    }
+
+    #[test]
+    fn commanded_velocity_follows_the_trapezoid() {
+        let (mut device, clock, samples) = monitored_device();
+        begin_move(&mut device, 100_000);
+
+        // Ramping up at the accel
+        clock.advance(Duration::from_millis(250));
+        sample(&mut device, 750, 6000.0);
+        let ramping = samples.try_recv().expect("No sample");
+        assert_eq!(ramping.commanded, 6000.0);
+        assert_eq!(ramping.acceleration, None);
+        assert!(!ramping.slow);
+
+        // Capped at the move's velocity
+        clock.advance(Duration::from_secs(2));
+        sample(&mut device, 50_000, 24000.0);
+        let cruising = samples.try_recv().expect("No sample");
+        assert_eq!(cruising.commanded, 24000.0);
+        assert_eq!(cruising.acceleration, Some(9000.0));
+
+        // Slowing at the decel to stop at the target
+        clock.advance(Duration::from_secs(1));
+        sample(&mut device, 97_000, 12000.0);
+        let stopping = samples.try_recv().expect("No sample");
+        assert_eq!(stopping.commanded, 12000.0);
+        assert!(!stopping.slow);
+    }
+
+    #[test]
+    fn commanded_velocity_is_signed_by_the_direction_of_travel() {
+        let (mut device, clock, samples) = monitored_device();
+        begin_move(&mut device, 0);
+        clock.advance(Duration::from_secs(2));
+        sample(&mut device, 50_000, -24000.0);
+        let sample = samples.try_recv().expect("No sample");
+        assert_eq!(sample.commanded, -24000.0);
+        assert!(!sample.slow);
+    }
+
+    #[test]
+    fn running_below_the_slow_fraction_is_slow() {
+        let (mut device, clock, samples) = monitored_device();
+        begin_move(&mut device, 100_000);
+        clock.advance(Duration::from_secs(2));
+        sample(&mut device, 50_000, 19000.0);
+        assert!(samples.try_recv().expect("No sample").slow);
+        clock.advance(Duration::from_millis(100));
+        sample(&mut device, 52_000, 20000.0);
+        assert!(!samples.try_recv().expect("No sample").slow);
+    }
+
+    #[test]
+    fn samples_come_no_faster_than_the_period() {
+        let (mut device, clock, samples) = monitored_device();
+        begin_move(&mut device, 100_000);
+        clock.advance(Duration::from_secs(2));
+        sample(&mut device, 50_000, 24000.0);
+        clock.advance(Duration::from_millis(50));
+        sample(&mut device, 51_200, 24000.0);
+        assert_eq!(samples.try_iter().count(), 1);
+
+        // Nor once the move is over
+        device.end_velocity_monitor();
+        clock.advance(Duration::from_secs(1));
+        sample(&mut device, 60_000, 24000.0);
+        assert_eq!(samples.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn moves_are_sampled_within_their_velocity() {
+        let (mut device, _clock, samples) = monitored_device();
+        device.enable_motor().expect("Unable to enable the motor");
+        device.move_servo(6, 6, 120, 30_000).expect("Move failed");
+
+        let samples: Vec<MoveVelocitySample> = samples.try_iter().collect();
+        assert!(samples.len() > 10);
+        assert!(samples
+            .iter()
+            .all(|s| s.commanded >= 0.0 && s.commanded <= 12000.0));
+        assert!(samples.iter().any(|s| s.actual > 11000.0));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{
+    AppliedDevice, CancelToken, Clock, DeviceError, DriveCommand, InterlockAction, ACCELERATION,
+    ALARM, DECELERATION, FAULT, VELOCITY,
+};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+static DEFAULT_MASTER_PERIOD: u64 = 20; // How often followers get a new target, in ms
+static FOLLOWER_VELOCITY_HEADROOM: f64 = 1.25; // Lets a follower catch up after a late update
+pub(crate) static VELOCITY_UNITS: f64 = 240.0; // VE is in 1/240 rev/s
+
+// A real axis following the virtual master: its target is
+// offset + ratio * master position, in its own encoder counts
+struct Follower {
+    device: AppliedDevice,
+    ratio: f64,
+    offset: i64,
+}
+
+impl Follower {
+    fn target(&self, master_position: f64) -> u64 {
+        let target = self.offset + (self.ratio * master_position).round() as i64;
+        // The drive takes a 32 bit count, which may wrap on a long run
+        target as i32 as u32 as u64
+    }
+}
+
+// A software master axis.  Its position advances at a fixed velocity while
+// running, and each follower is streamed a target derived from it, so
+// several axes can move in a fixed relationship without an external motion
+// controller.  Followers need a known encoder resolution, so their speed
+// can be set to keep up, and must take a new feed to position while one is
+// under way (see AppliedDevice::set_blending_supported).
+pub struct VirtualMaster {
+    velocity: f64, // Master counts per second, may be negative
+    position: f64,
+    accel: u64,
+    decel: u64,
+    period: Duration,
+    followers: Vec<Follower>,
+}
+
+impl VirtualMaster {
+    // accel and decel are in the drive's units and used by every follower
+    // while it chases its targets
+    pub fn new(velocity: f64, accel: u64, decel: u64) -> VirtualMaster {
+        VirtualMaster {
+            velocity,
+            position: 0.0,
+            accel,
+            decel,
+            period: Duration::from_millis(DEFAULT_MASTER_PERIOD),
+            followers: Vec::new(),
+        }
+    }
+
+    pub fn set_period(&mut self, period: Duration) {
+        self.period = period.max(Duration::from_millis(1));
+    }
+
+    pub fn set_velocity(&mut self, velocity: f64) {
+        self.velocity = velocity;
+    }
+
+    pub fn get_velocity(&self) -> f64 {
+        self.velocity
+    }
+
+    pub fn set_position(&mut self, position: f64) {
+        self.position = position;
+    }
+
+    pub fn get_position(&self) -> f64 {
+        self.position
+    }
+
+    pub fn add_follower(
+        &mut self,
+        device: AppliedDevice,
+        ratio: f64,
+        offset: i64,
+    ) -> Result<(), DeviceError> {
+        if device.known_encoder_counts_per_rev().is_none() {
+            return Err(DeviceError::Config(format!(
+                "{} needs a known encoder resolution to follow a virtual master",
+                device.servo_name
+            )));
+        }
+        // Targets are sent every period, long before the last one is reached
+        if !device.blending_supported {
+            return Err(DeviceError::Config(format!(
+                "{} must accept a new target while moving to follow a virtual master",
+                device.servo_name
+            )));
+        }
+        if self
+            .followers
+            .iter()
+            .any(|f| f.device.servo_name == device.servo_name)
+        {
+            return Err(DeviceError::Config(format!(
+                "{} already follows this master",
+                device.servo_name
+            )));
+        }
+        self.followers.push(Follower {
+            device,
+            ratio,
+            offset,
+        });
+        Ok(())
+    }
+
+    // Where the named follower is currently being sent
+    pub fn get_follower_target(&self, servo_name: &str) -> Option<u64> {
+        self.followers
+            .iter()
+            .find(|f| f.device.servo_name == servo_name)
+            .map(|f| f.target(self.position))
+    }
+
+    pub fn follower(&mut self, servo_name: &str) -> Option<&mut AppliedDevice> {
+        self.followers
+            .iter_mut()
+            .find(|f| f.device.servo_name == servo_name)
+            .map(|f| &mut f.device)
+    }
+
+    pub fn into_devices(self) -> Vec<AppliedDevice> {
+        self.followers.into_iter().map(|f| f.device).collect()
+    }
+
+    pub fn run_for(&mut self, duration: Duration) -> Result<bool, DeviceError> {
+        self.run_for_with_cancel(duration, &CancelToken::new())
+    }
+
+    // Advances the master for the given time, streaming each follower its
+    // target every period.  Returns true once the time is up, with each
+    // follower heading for its final target, or false if the run was
+    // cancelled or a follower alarmed, in which case every follower is
+    // stopped.
+    pub fn run_for_with_cancel(
+        &mut self,
+        duration: Duration,
+        cancel: &CancelToken,
+    ) -> Result<bool, DeviceError> {
+        let clock = match self.followers.first() {
+            Some(follower) => follower.device.clock.clone(),
+            None => return Ok(true),
+        };
+        info!(
+            "Running virtual master at {} counts/s for {:?} with {} followers",
+            self.velocity,
+            duration,
+            self.followers.len()
+        );
+
+        for follower in &mut self.followers {
+            let device = &mut follower.device;
+            device.reset_alarm_or_fault()?;
+            device.write_register(ACCELERATION, self.accel)?;
+            device.write_register(DECELERATION, self.decel)?;
+            let velocity = follower_velocity(device, self.velocity * follower.ratio);
+            device.write_register(VELOCITY, velocity)?;
+        }
+
+        let started = clock.now();
+        let start_position = self.position;
+        loop {
+            let elapsed = clock.elapsed(started).min(duration);
+            self.position = start_position + self.velocity * elapsed.as_secs_f64();
+            if let Err(e) = self.send_targets() {
+                self.stop_followers();
+                return Err(e);
+            }
+            match self.alarmed_follower() {
+                Ok(Some(name)) => {
+                    error!("{} alarmed while following, stopping all followers", name);
+                    self.stop_followers();
+                    return Ok(false);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    self.stop_followers();
+                    return Err(e);
+                }
+            }
+            if elapsed >= duration {
+                return Ok(true);
+            }
+            if cancel.is_cancelled() {
+                warn!("Virtual master run was cancelled");
+                self.stop_followers();
+                return Ok(false);
+            }
+            sleep_until_next(&*clock, started, self.period);
+        }
+    }
+
+    // Each target is checked against the follower's interlocks like any
+    // other move, so a denial stops the run
+    fn send_targets(&mut self) -> Result<(), DeviceError> {
+        let position = self.position;
+        for follower in &mut self.followers {
+            let target = follower.target(position);
+            follower
+                .device
+                .check_interlocks(InterlockAction::Move { target })?;
+            follower.device.write_distance(target)?;
+            follower.device.execute(DriveCommand::StartMove)?;
+        }
+        Ok(())
+    }
+
+    fn alarmed_follower(&mut self) -> Result<Option<String>, DeviceError> {
+        for follower in &mut self.followers {
+            let status = follower.device.get_servo_status()?;
+            if status.iter().any(|s| s == ALARM || s == FAULT) {
+                return Ok(Some(follower.device.servo_name.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    // Stops every follower, carrying on past any that fail to stop
+    fn stop_followers(&mut self) {
+        for follower in &mut self.followers {
+            if let Err(e) = follower.device.stop_motion() {
+                error!("Could not stop {}: {}", follower.device.servo_name, e);
+            }
+        }
+    }
+}
+
+// The drive velocity a follower needs to keep up with the given speed in
+// counts per second, with some headroom
+fn follower_velocity(device: &AppliedDevice, counts_per_second: f64) -> u64 {
+    let counts_per_rev = device.known_encoder_counts_per_rev().unwrap_or(1) as f64;
+    let velocity = counts_per_second.abs() / counts_per_rev * VELOCITY_UNITS;
+    let velocity = (velocity * FOLLOWER_VELOCITY_HEADROOM).ceil() as u64;
+    device.override_velocity(velocity.max(1))
+}
+
+// Sleeps to the next whole period since the run started, so late updates
+// don't push every later one back
+fn sleep_until_next(clock: &dyn Clock, started: Instant, period: Duration) {
+    let elapsed = clock.elapsed(started).as_nanos();
+    let period = period.as_nanos();
+    // Always more than nothing and no more than a period
+    let wait = (elapsed / period + 1) * period - elapsed;
+    clock.sleep(Duration::from_nanos(wait.min(u64::MAX as u128) as u64));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SimulatedDrive};
+    use std::sync::Arc;
+
+    fn follower(clock: &Arc<dyn Clock>) -> (AppliedDevice, SimulatedDrive) {
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let mut device =
+            AppliedDevice::with_transport(String::from("follower"), String::new(), drive.clone());
+        device.set_clock(clock.clone());
+        device.encoder_counts_per_rev = Some(20000);
+        (device, drive)
+    }
+
+    #[test]
+    fn followers_must_take_new_targets_while_moving() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let (device, _) = follower(&clock);
+        let mut master = VirtualMaster::new(1000.0, 10, 10);
+        assert!(matches!(
+            master.add_follower(device, 1.0, 0),
+            Err(DeviceError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn followers_are_streamed_their_targets() {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let (mut device, drive) = follower(&clock);
+        device.set_blending_supported(true);
+        device.enable_motor().expect("Unable to enable the motor");
+        let mut master = VirtualMaster::new(1000.0, 100, 100);
+        master.add_follower(device, 2.0, 500).unwrap();
+
+        assert!(master.run_for(Duration::from_secs(1)).unwrap());
+        assert_eq!(master.get_follower_target("follower"), Some(2500));
+        clock.sleep(Duration::from_secs(1));
+        assert_eq!(drive.position(), 2500);
+    }
+
+    #[test]
+    fn periods_are_kept_to_however_long_the_run() {
+        let manual = ManualClock::new();
+        let clock: Arc<dyn Clock> = Arc::new(manual.clone());
+        let started = clock.now();
+        // Billions of periods in, past what fits in a u32
+        manual.advance(Duration::from_secs(100_000_000) + Duration::from_millis(5));
+        sleep_until_next(&*clock, started, Duration::from_millis(20));
+        assert_eq!(
+            clock.elapsed(started),
+            Duration::from_secs(100_000_000) + Duration::from_millis(20)
+        );
+    }
+}
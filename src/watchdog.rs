@@ -0,0 +1,211 @@
+use crate::config::millis;
+use crate::{AppliedDevice, DeviceError};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+use yaml_rust::Yaml;
+
+// Marks a device unhealthy when nothing has been read from the drive for
+// too long
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommsWatchdog {
+    pub stale_after: Duration,
+    pub stop_on_recovery: bool, // Stop the axis as soon as the drive answers again
+}
+
+pub(crate) struct WatchdogState {
+    config: CommsWatchdog,
+    stale_since: Option<Instant>,
+}
+
+impl CommsWatchdog {
+    // Reads the optional `watchdog:` section of a device config
+    //
+    //   watchdog:
+    //     stale_after_ms: 2000
+    //     stop_on_recovery: true
+    pub(crate) fn from_yaml(device_conf: &Yaml) -> Result<Option<CommsWatchdog>, DeviceError> {
+        let section = &device_conf["watchdog"];
+        if section.is_badvalue() || section.is_null() {
+            return Ok(None);
+        }
+        let stale_after = match millis(section, "stale_after_ms")? {
+            Some(stale_after) if stale_after > Duration::ZERO => stale_after,
+            _ => {
+                return Err(DeviceError::Config(format!(
+                    "watchdog.stale_after_ms must be a positive number of milliseconds, got {:?}",
+                    section["stale_after_ms"]
+                )))
+            }
+        };
+        let stop_on_recovery = match section["stop_on_recovery"] {
+            Yaml::BadValue | Yaml::Null => false,
+            Yaml::Boolean(stop) => stop,
+            ref other => {
+                return Err(DeviceError::Config(format!(
+                    "watchdog.stop_on_recovery must be true or false, got {:?}",
+                    other
+                )))
+            }
+        };
+        Ok(Some(CommsWatchdog {
+            stale_after,
+            stop_on_recovery,
+        }))
+    }
+}
+
+impl AppliedDevice {
+    // A spawned device checks the watchdog each time its worker polls the
+    // drive.  Otherwise check_comms_watchdog must be called periodically.
+    pub fn set_comms_watchdog(&mut self, watchdog: Option<CommsWatchdog>) {
+        self.watchdog = watchdog.map(|config| WatchdogState {
+            config,
+            stale_since: None,
+        });
+        self.annotate_snapshot(|s| s.comms_stale = false);
+    }
+
+    // Compares the age of the last successful read with the watchdog's limit,
+    // raising on_comms_stale when it goes over and on_comms_recovered when
+    // reads succeed again
+    pub fn check_comms_watchdog(&mut self) {
+        let (config, stale_since) = match &self.watchdog {
+            Some(state) => (state.config, state.stale_since),
+            None => return,
+        };
+        // Nothing read at all yet is left to the connection to report
+        let age = match self.last_snapshot().updated {
+            Some(at) => self.clock.elapsed(at),
+            None => return,
+        };
+        let stale = age > config.stale_after;
+
+        match (stale, stale_since) {
+            (true, None) => {
+                error!(
+                    "No data from {} for {}ms, marking it unhealthy",
+                    self.servo_name,
+                    age.as_millis()
+                );
+                self.set_stale_since(Some(self.clock.now() - age));
+                self.annotate_snapshot(|s| s.comms_stale = true);
+                self.emit(|t| t.on_comms_stale(&self.servo_name, age));
+            }
+            (false, Some(since)) => {
+                let outage = self.clock.elapsed(since);
+                info!(
+                    "Communication with {} recovered after {}ms",
+                    self.servo_name,
+                    outage.as_millis()
+                );
+                self.set_stale_since(None);
+                self.annotate_snapshot(|s| s.comms_stale = false);
+                self.emit(|t| t.on_comms_recovered(&self.servo_name, outage));
+                if config.stop_on_recovery {
+                    if let Err(e) = self.stop_motion() {
+                        error!("Unable to stop {} after recovery: {}", self.servo_name, e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_stale_since(&mut self, stale_since: Option<Instant>) {
+        if let Some(state) = &mut self.watchdog {
+            state.stale_since = stale_since;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Clock, DriveCommand, ManualClock, SimulatedDrive, TelemetrySink, ACCELERATION,
+        DECELERATION, MOVING, VELOCITY,
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl TelemetrySink for Events {
+        fn on_comms_stale(&self, servo_name: &str, age: Duration) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} stale for {:?}", servo_name, age));
+        }
+
+        fn on_comms_recovered(&self, servo_name: &str, outage: Duration) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} recovered after {:?}", servo_name, outage));
+        }
+    }
+
+    fn watched_device(stop_on_recovery: bool) -> (AppliedDevice, ManualClock, Arc<Events>) {
+        let clock = ManualClock::new();
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        let drive = SimulatedDrive::with_clock(shared.clone());
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), drive);
+        device.set_clock(shared);
+        let events = Arc::new(Events::default());
+        device.add_telemetry_sink(events.clone());
+        device.set_comms_watchdog(Some(CommsWatchdog {
+            stale_after: Duration::from_millis(500),
+            stop_on_recovery,
+        }));
+        (device, clock, events)
+    }
+
+    #[test]
+    fn old_data_is_marked_stale_once_until_reads_succeed_again() {
+        let (mut device, clock, events) = watched_device(false);
+        device.check_comms_watchdog();
+        device.refresh_snapshot().unwrap();
+        clock.advance(Duration::from_millis(400));
+        device.check_comms_watchdog();
+        assert!(!device.last_snapshot().comms_stale);
+
+        clock.advance(Duration::from_millis(200));
+        device.check_comms_watchdog();
+        device.check_comms_watchdog();
+        assert!(device.last_snapshot().comms_stale);
+
+        // The outage runs from the last data read before it
+        clock.advance(Duration::from_millis(100));
+        device.refresh_snapshot().unwrap();
+        device.check_comms_watchdog();
+        assert!(!device.last_snapshot().comms_stale);
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            vec![
+                String::from("x stale for 600ms"),
+                String::from("x recovered after 700ms")
+            ]
+        );
+    }
+
+    #[test]
+    fn the_axis_can_be_stopped_once_the_drive_answers_again() {
+        let (mut device, clock, _) = watched_device(true);
+        device.enable_motor().unwrap();
+        device.write_register(ACCELERATION, 10).unwrap();
+        device.write_register(DECELERATION, 100).unwrap();
+        device.write_register(VELOCITY, 100).unwrap();
+        device.write_distance(500_000).unwrap();
+        device.execute(DriveCommand::StartMove).unwrap();
+        device.refresh_snapshot().unwrap();
+
+        clock.advance(Duration::from_secs(1));
+        device.check_comms_watchdog();
+        device.refresh_snapshot().unwrap();
+        device.check_comms_watchdog();
+        clock.advance(Duration::from_secs(2));
+        assert!(!device.has_status(MOVING).unwrap());
+        assert!(device.get_encoder_count().unwrap() < 500_000);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::exception::exception_code_value;
+use crate::AppliedDevice;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// Log target used for every wire level record, so it can be filtered on its own
+pub static WIRE_LOG_TARGET: &str = "applied_device::wire";
+
+// The Modbus functions this crate issues
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModbusFunction {
+    ReadHoldingRegisters,       // FC03
+    WriteSingleRegister,        // FC06
+    WriteMultipleRegisters,     // FC16
+    WriteReadMultipleRegisters, // FC23
+}
+
+impl ModbusFunction {
+    pub fn code(self) -> u8 {
+        match self {
+            ModbusFunction::ReadHoldingRegisters => 0x03,
+            ModbusFunction::WriteSingleRegister => 0x06,
+            ModbusFunction::WriteMultipleRegisters => 0x10,
+            ModbusFunction::WriteReadMultipleRegisters => 0x17,
+        }
+    }
+}
+
+// Opt-in record of every Modbus request/response made by a device.  Records
+// always go to the WIRE_LOG_TARGET log target at debug level and, if a file
+// was given, are also appended to it one line per transaction.
+pub(crate) struct WireLog {
+    file: Option<File>,
+}
+
+impl WireLog {
+    pub(crate) fn new() -> WireLog {
+        WireLog { file: None }
+    }
+
+    pub(crate) fn with_file(path: &str) -> Result<WireLog, String> {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(f) => Ok(WireLog { file: Some(f) }),
+            Err(e) => Err(format!("Unable to open wire log {}: {}", path, e)),
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        servo_name: &str,
+        function: ModbusFunction,
+        register: u16,
+        values: &[u16],
+        latency: Duration,
+        error: Option<&modbus::Error>,
+    ) {
+        let outcome = match error {
+            None => String::from("ok"),
+            Some(modbus::Error::Exception(code)) => {
+                format!("exception {:#04x}", exception_code_value(code))
+            }
+            Some(e) => format!("error {}", e),
+        };
+        let line = format!(
+            "{} FC{:02} reg={} values={:?} latency_us={} {}",
+            servo_name,
+            function.code(),
+            register,
+            values,
+            latency.as_micros(),
+            outcome
+        );
+
+        debug!(target: "applied_device::wire", "{}", line); // Must match WIRE_LOG_TARGET
+        if let Some(file) = &mut self.file {
+            if let Err(e) = writeln!(file, "{}", line) {
+                warn!("Unable to write to wire log, disabling file output: {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl AppliedDevice {
+    // Logs every Modbus transaction to the applied_device::wire target
+    pub fn enable_wire_logging(&mut self) {
+        self.wire_log = Some(WireLog::new());
+    }
+
+    // Logs every Modbus transaction and also appends it to the given file
+    pub fn enable_wire_logging_to_file(&mut self, path: &str) -> Result<(), String> {
+        self.wire_log = Some(WireLog::with_file(path)?);
+        Ok(())
+    }
+
+    pub fn disable_wire_logging(&mut self) {
+        self.wire_log = None;
+    }
+}
@@ -0,0 +1,250 @@
+use crate::exception::transaction_error;
+use crate::registers::split_u32;
+use crate::{
+    AppliedDevice, DeviceError, ModbusException, ModbusFunction, WordOrder, ACCELERATION,
+    ALARM_REG, DISTANCE_2, STATUS_REG,
+};
+use tracing::{info, warn};
+
+impl AppliedDevice {
+    // Loads a move's acceleration, deceleration, velocity and distance and
+    // reads status back in a single Read/Write Multiple Registers (FC23)
+    // transaction, cutting the time between setting up a move and starting
+    // it.  A drive or gateway that turns FC23 down switches this back off.
+    pub fn set_combined_move_writes(&mut self, combined: bool) {
+        self.combined_move_writes = combined;
+    }
+
+    pub fn get_combined_move_writes(&self) -> bool {
+        self.combined_move_writes
+    }
+
+    // Writes the move parameter block in one transaction, returning false if
+    // that isn't possible and the registers must be written one by one
+    pub(crate) fn write_move_block(
+        &mut self,
+        accel: u64,
+        decel: u64,
+        velocity: u64,
+        encoder_position: u64,
+    ) -> Result<bool, DeviceError> {
+        if !self.combined_move_writes {
+            return Ok(false);
+        }
+        // ACCELERATION through DISTANCE_2 are contiguous
+        let [distance_1, distance_2] = split_u32(encoder_position as u32, WordOrder::HighFirst);
+        let block = [
+            accel as u16,
+            decel as u16,
+            velocity as u16,
+            distance_1,
+            distance_2,
+        ];
+        // Status, and the block itself when verifying writes
+        let read_count = match self.verify_writes {
+            true => DISTANCE_2 + 1 - ALARM_REG,
+            false => STATUS_REG + 1 - ALARM_REG,
+        };
+        let read = match self.write_read_registers(ACCELERATION, &block, ALARM_REG, read_count) {
+            Ok(read) => read,
+            Err(DeviceError::Exception {
+                exception: ModbusException::IllegalFunction,
+                ..
+            }) => {
+                warn!(
+                    "{} does not support FC23, writing move parameters one at a time",
+                    self.servo_name
+                );
+                self.combined_move_writes = false;
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        info!("Move block written: {:?}", block);
+        // Only once written, since the fallback audits each register it writes
+        for (offset, value) in block.iter().enumerate() {
+            self.audit_write(ACCELERATION + offset as u16, *value)?;
+        }
+
+        if let Some(status) = read.get(STATUS_REG as usize) {
+            self.set_servo_status(*status as usize);
+        }
+        if self.verify_writes {
+            let start = (ACCELERATION - ALARM_REG) as usize;
+            for (offset, written) in block.iter().enumerate() {
+                let register = ACCELERATION + offset as u16;
+                let read = *read.get(start + offset).unwrap_or(&0);
+                if read != *written {
+                    return Err(DeviceError::WriteMismatch {
+                        register,
+                        written: *written,
+                        read,
+                    });
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // Every combined write and read goes through here
+    fn write_read_registers(
+        &mut self,
+        write_register: u16,
+        values: &[u16],
+        read_register: u16,
+        read_count: u16,
+    ) -> Result<Vec<u16>, DeviceError> {
+        let function = ModbusFunction::WriteReadMultipleRegisters;
+        let mut attempt = 1;
+        let first_started = self.clock.now();
+        loop {
+            let started = self.clock.now();
+            let result = self
+                .client
+                .set_read_timeout(self.timeouts.command.or(self.timeouts.read))
+                .and_then(|_| {
+                    self.client.write_read_multiple_registers(
+                        write_register,
+                        values,
+                        read_register,
+                        read_count,
+                    )
+                });
+            self.io
+                .count_write_read(values.len(), result.as_ref().ok().map(|v| v.len()));
+            let took = self.clock.elapsed(started);
+            if let Some(log) = &mut self.wire_log {
+                log.record(
+                    &self.servo_name,
+                    function,
+                    write_register,
+                    values,
+                    took,
+                    result.as_ref().err(),
+                );
+            }
+            // As for write_register, an acknowledged write is never failed
+            if result.is_ok() {
+                self.warn_if_late(self.timeouts.command, started, write_register);
+            }
+            match result {
+                Ok(values) => return Ok(values),
+                // No further attempts once the command's time has run out
+                Err(e)
+                    if self
+                        .check_deadline(self.timeouts.command, first_started)
+                        .is_err() =>
+                {
+                    return Err(transaction_error(e, function, write_register))
+                }
+                // Not worth retrying, the caller falls back to single writes
+                Err(modbus::Error::Exception(code))
+                    if code == modbus::ExceptionCode::IllegalFunction =>
+                {
+                    return Err(transaction_error(
+                        modbus::Error::Exception(code),
+                        function,
+                        write_register,
+                    ))
+                }
+                Err(e) => self.retry_or_fail(e, function, write_register, attempt)?,
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Clock, ManualClock, SimulatedDrive, Transport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Counts each kind of transaction, turning FC23 down unless `combined`
+    struct Counting {
+        drive: SimulatedDrive,
+        combined: bool,
+        single_writes: Arc<AtomicUsize>,
+        write_reads: Arc<AtomicUsize>,
+    }
+
+    impl Transport for Counting {
+        fn read_holding_registers(
+            &mut self,
+            address: u16,
+            quantity: u16,
+        ) -> modbus::Result<Vec<u16>> {
+            self.drive.read_holding_registers(address, quantity)
+        }
+
+        fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+            self.single_writes.fetch_add(1, Ordering::SeqCst);
+            self.drive.write_single_register(address, value)
+        }
+
+        fn write_read_multiple_registers(
+            &mut self,
+            write_address: u16,
+            values: &[u16],
+            read_address: u16,
+            read_quantity: u16,
+        ) -> modbus::Result<Vec<u16>> {
+            self.write_reads.fetch_add(1, Ordering::SeqCst);
+            if !self.combined {
+                return Err(modbus::Error::Exception(
+                    modbus::ExceptionCode::IllegalFunction,
+                ));
+            }
+            self.drive.write_read_multiple_registers(
+                write_address,
+                values,
+                read_address,
+                read_quantity,
+            )
+        }
+    }
+
+    type Counts = (Arc<AtomicUsize>, Arc<AtomicUsize>); // Single writes and FC23s
+
+    fn combined_device(combined: bool) -> (AppliedDevice, SimulatedDrive, Counts) {
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let drive = SimulatedDrive::with_clock(clock.clone());
+        let counts: Counts = Default::default();
+        let transport = Counting {
+            drive: drive.clone(),
+            combined,
+            single_writes: counts.0.clone(),
+            write_reads: counts.1.clone(),
+        };
+        let mut device = AppliedDevice::with_transport(String::from("x"), String::new(), transport);
+        device.set_clock(clock);
+        device.enable_motor().unwrap();
+        device.set_combined_move_writes(true);
+        (device, drive, counts)
+    }
+
+    #[test]
+    fn move_parameters_go_in_one_transaction() {
+        let (mut device, drive, (single_writes, write_reads)) = combined_device(true);
+        let writes_before = single_writes.load(Ordering::SeqCst);
+        device.move_servo(10, 10, 100, 4000).unwrap();
+        assert_eq!(drive.position(), 4000);
+        assert_eq!(write_reads.load(Ordering::SeqCst), 1);
+        // Only the command that starts the move is written on its own
+        assert_eq!(single_writes.load(Ordering::SeqCst) - writes_before, 1);
+        assert!(device.get_combined_move_writes());
+    }
+
+    #[test]
+    fn a_drive_without_fc23_gets_single_writes_from_then_on() {
+        let (mut device, drive, (_, write_reads)) = combined_device(false);
+        device.move_servo(10, 10, 100, 4000).unwrap();
+        assert_eq!(drive.position(), 4000);
+        assert!(!device.get_combined_move_writes());
+
+        device.move_servo(10, 10, 100, 0).unwrap();
+        assert_eq!(drive.position(), 0);
+        assert_eq!(write_reads.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,52 @@
+// What the audit log gets told about writes and the commands they set up
+use applied_device::{
+    AppliedDevice, AuditRecord, Clock, DriveCommand, ManualClock, SimulatedDrive,
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+static VELOCITY: u16 = 29;
+
+fn audited_device(clock: &ManualClock) -> (AppliedDevice, Arc<Mutex<Vec<AuditRecord>>>) {
+    let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+    let drive = SimulatedDrive::with_clock(shared.clone());
+    let mut device = AppliedDevice::with_transport(String::from("audit"), String::new(), drive);
+    device.set_clock(shared);
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let sink = records.clone();
+    device.set_audit_sink(Box::new(move |record: &AuditRecord| {
+        sink.lock().unwrap().push(record.clone());
+        Ok(())
+    }));
+    (device, records)
+}
+
+#[test]
+fn writes_no_command_follows_are_recorded() {
+    let clock = ManualClock::starting_at(UNIX_EPOCH + Duration::from_secs(1000));
+    let (mut device, records) = audited_device(&clock);
+    device.write_register(VELOCITY, 240).expect("Write failed");
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].opcode, None);
+    assert_eq!(records[0].parameters, vec![(VELOCITY, 240)]);
+    assert_eq!(records[0].timestamp, clock.wall_time());
+}
+
+#[test]
+fn commands_carry_the_writes_made_since_the_last_one() {
+    let clock = ManualClock::new();
+    let (mut device, records) = audited_device(&clock);
+    device.write_register(VELOCITY, 240).expect("Write failed");
+    device
+        .execute(DriveCommand::EnableMotor)
+        .expect("Enable failed");
+    device.execute(DriveCommand::Stop).expect("Stop failed");
+
+    let records = records.lock().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1].opcode, Some(DriveCommand::EnableMotor.opcode()));
+    assert_eq!(records[1].parameters, vec![(VELOCITY, 240)]);
+    assert_eq!(records[2].parameters, vec![]);
+}
@@ -0,0 +1,107 @@
+// Calls queued on a handle's worker when the servo is aborted or emergency
+// stopped
+use applied_device::{
+    AppliedDevice, AppliedDeviceHandle, Clock, DeviceError, DriveCommand, ManualClock,
+    RecoveryPolicy, SimulatedDrive, MOTOR_ENABLED,
+};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+fn spawned_device() -> (AppliedDeviceHandle, SimulatedDrive) {
+    let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+    let drive = SimulatedDrive::with_clock(clock.clone());
+    let mut device =
+        AppliedDevice::with_transport(String::from("handle"), String::new(), drive.clone());
+    device.set_clock(clock);
+    (device.spawn().expect("Unable to spawn worker"), drive)
+}
+
+// Runs the queued call from its own thread while the worker is kept busy,
+// emergency stops the servo, then lets the worker go on to the queued call
+fn queued_behind_an_emergency_stop<R, F>(handle: &AppliedDeviceHandle, queued: F) -> R
+where
+    R: Send + 'static,
+    F: FnOnce(AppliedDeviceHandle) -> R + Send + 'static,
+{
+    let (release, released) = mpsc::channel::<()>();
+    let busy = handle.clone();
+    let blocking = thread::spawn(move || busy.call(move |_, _| released.recv().is_ok()));
+    while !handle.is_busy() {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let waiting = handle.clone();
+    let queued = thread::spawn(move || queued(waiting));
+    // Give the call time to reach the worker's queue
+    thread::sleep(Duration::from_millis(100));
+
+    let stopping = handle.clone();
+    let stop = thread::spawn(move || stopping.emergency_stop());
+    thread::sleep(Duration::from_millis(100));
+    release.send(()).expect("Worker stopped");
+    assert!(blocking.join().unwrap().expect("Blocking call failed"));
+    stop.join().unwrap().expect("Emergency stop failed");
+    queued.join().unwrap()
+}
+
+#[test]
+fn emergency_stop_drops_a_queued_self_test() {
+    let (handle, drive) = spawned_device();
+    let result = queued_behind_an_emergency_stop(&handle, |h| h.self_test(4000));
+    assert!(matches!(result, Err(DeviceError::EmergencyStopped(_))));
+    assert!(!handle
+        .get_servo_status()
+        .unwrap()
+        .iter()
+        .any(|s| s == MOTOR_ENABLED));
+    assert_eq!(drive.position(), 0);
+}
+
+#[test]
+fn emergency_stop_drops_a_queued_recovery() {
+    let (handle, drive) = spawned_device();
+    drive.set_position(1234);
+    let result = queued_behind_an_emergency_stop(&handle, |h| {
+        h.recover(RecoveryPolicy::Rehome, Duration::from_secs(60))
+    });
+    assert!(matches!(result, Err(DeviceError::EmergencyStopped(_))));
+    assert!(!handle
+        .get_servo_status()
+        .unwrap()
+        .iter()
+        .any(|s| s == MOTOR_ENABLED));
+    assert_eq!(drive.position(), 1234);
+}
+
+#[test]
+fn emergency_stop_drops_a_queued_enable() {
+    let (handle, _drive) = spawned_device();
+    let result = queued_behind_an_emergency_stop(&handle, |h| h.enable_motor());
+    assert!(matches!(result, Err(DeviceError::EmergencyStopped(_))));
+    assert!(!handle
+        .get_servo_status()
+        .unwrap()
+        .iter()
+        .any(|s| s == MOTOR_ENABLED));
+}
+
+#[test]
+fn emergency_stop_drops_a_queued_command() {
+    let (handle, _drive) = spawned_device();
+    let result = queued_behind_an_emergency_stop(&handle, |h| h.execute(DriveCommand::EnableMotor));
+    assert!(matches!(result, Err(DeviceError::EmergencyStopped(_))));
+    assert!(!handle
+        .get_servo_status()
+        .unwrap()
+        .iter()
+        .any(|s| s == MOTOR_ENABLED));
+}
+
+#[test]
+fn an_abort_while_idle_leaves_the_next_call_alone() {
+    let (handle, drive) = spawned_device();
+    handle.enable_motor().expect("Unable to enable the motor");
+    handle.abort();
+    handle.move_servo(10, 10, 100, 4000).expect("Move failed");
+    assert_eq!(drive.position(), 4000);
+}
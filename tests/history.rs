@@ -0,0 +1,19 @@
+// The operation history's times, on a simulated clock
+use applied_device::{AppliedDevice, Clock, ManualClock, Operation, SimulatedDrive};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn operations_start_at_the_device_clocks_time_of_day() {
+    let started = UNIX_EPOCH + Duration::from_secs(1000);
+    let clock: Arc<dyn Clock> = Arc::new(ManualClock::starting_at(started));
+    let drive = SimulatedDrive::with_clock(clock.clone());
+    let mut device = AppliedDevice::with_transport(String::from("history"), String::new(), drive);
+    device.set_clock(clock);
+    device.enable_motor().expect("Unable to enable the motor");
+
+    let record = device.recent_operations(1).pop().expect("Not recorded");
+    assert_eq!(record.operation, Operation::EnableMotor);
+    assert_eq!(record.started, started);
+    assert_eq!(record.duration, Duration::from_secs(1));
+}
@@ -0,0 +1,231 @@
+// Whole homing and move sequences against the simulated drive, both on a
+// ManualClock so waits and timeouts pass instantly
+use applied_device::{
+    AppliedDevice, CancelToken, Clock, DeviceError, DriveCommand, HomingAttemptOutcome, LengthFeed,
+    LimitSwitchConfig, ManualClock, ModbusException, OperationOutcome, SimulatedDrive, Transport,
+    MOTOR_ENABLED, MOVING,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+static EXECUTE_COMMAND: u16 = 124;
+static STATUS_REG: u16 = 1;
+static HOMING_ALARM: u16 = 0x0004;
+
+// The drive is returned too, for inspecting it while the device owns its clone
+fn device_on<T, F>(wrap: F) -> (AppliedDevice, SimulatedDrive, ManualClock)
+where
+    T: Transport + 'static,
+    F: FnOnce(SimulatedDrive) -> T,
+{
+    let clock = ManualClock::new();
+    let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+    let drive = SimulatedDrive::with_clock(shared.clone());
+    let mut device =
+        AppliedDevice::with_transport(String::from("sim"), String::new(), wrap(drive.clone()));
+    device.set_clock(shared);
+    (device, drive, clock)
+}
+
+fn enabled_device() -> (AppliedDevice, SimulatedDrive, ManualClock) {
+    let (mut device, drive, clock) = device_on(|drive| drive);
+    device.enable_motor().expect("Unable to enable the motor");
+    (device, drive, clock)
+}
+
+fn last_outcome(device: &AppliedDevice) -> Option<OperationOutcome> {
+    device.recent_operations(1).pop().map(|r| r.outcome)
+}
+
+// Raises an alarm on the first homing, a few status polls after it started
+struct AlarmPartWayThroughHoming {
+    drive: SimulatedDrive,
+    polls_left: Option<u32>,
+    interrupted: bool,
+}
+
+impl Transport for AlarmPartWayThroughHoming {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        if address == STATUS_REG {
+            match self.polls_left {
+                Some(0) => {
+                    self.drive.inject_alarm(HOMING_ALARM);
+                    self.polls_left = None;
+                    self.interrupted = true;
+                }
+                Some(n) => self.polls_left = Some(n - 1),
+                None => {}
+            }
+        }
+        self.drive.read_holding_registers(address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        let homing = address == EXECUTE_COMMAND && value == DriveCommand::StartHoming.opcode();
+        if homing && !self.interrupted {
+            self.polls_left = Some(3);
+        }
+        self.drive.write_single_register(address, value)
+    }
+}
+
+// Runs every relative move it is sent but answers it with an Acknowledge
+// exception, as a drive still busy with the command would
+struct AcknowledgesFeeds {
+    drive: SimulatedDrive,
+}
+
+impl Transport for AcknowledgesFeeds {
+    fn read_holding_registers(&mut self, address: u16, quantity: u16) -> modbus::Result<Vec<u16>> {
+        self.drive.read_holding_registers(address, quantity)
+    }
+
+    fn write_single_register(&mut self, address: u16, value: u16) -> modbus::Result<()> {
+        self.drive.write_single_register(address, value)?;
+        if address == EXECUTE_COMMAND && value == DriveCommand::FeedToLength.opcode() {
+            return Err(modbus::Error::Exception(modbus::ExceptionCode::Acknowledge));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn enabling_turns_the_motor_on() {
+    let (mut device, _drive, _clock) = device_on(|drive| drive);
+    assert!(!device.has_status(MOTOR_ENABLED).unwrap());
+    device.enable_motor().expect("Unable to enable the motor");
+    assert!(device.has_status(MOTOR_ENABLED).unwrap());
+    assert_eq!(last_outcome(&device), Some(OperationOutcome::Completed));
+}
+
+#[test]
+fn homing_zeroes_the_position() {
+    let (mut device, drive, _clock) = enabled_device();
+    drive.set_position(1234);
+    let report = device
+        .home_servo_with_report(&CancelToken::new())
+        .expect("Homing failed");
+    assert!(report.completed);
+    assert_eq!(report.attempts.len(), 1);
+    assert_eq!(device.get_encoder_count().unwrap(), 0);
+    assert_eq!(drive.position(), 0);
+}
+
+#[test]
+fn homing_is_retried_after_an_alarm_part_way_through() {
+    let (mut device, drive, _clock) = device_on(|drive| AlarmPartWayThroughHoming {
+        drive,
+        polls_left: None,
+        interrupted: false,
+    });
+    device.enable_motor().expect("Unable to enable the motor");
+    drive.set_position(1234);
+
+    let report = device
+        .home_servo_with_report(&CancelToken::new())
+        .expect("Homing failed");
+    assert!(report.completed, "{:?}", report);
+    assert_eq!(report.attempts.len(), 2);
+    assert!(matches!(
+        report.attempts[0].outcome,
+        HomingAttemptOutcome::Alarm(_)
+    ));
+    assert_eq!(report.attempts[1].outcome, HomingAttemptOutcome::Completed);
+    assert_eq!(drive.position(), 0);
+}
+
+#[test]
+fn move_reaches_its_target() {
+    let (mut device, drive, _clock) = enabled_device();
+    device.move_servo(10, 10, 100, 50000).expect("Move failed");
+    assert_eq!(drive.position(), 50000);
+    assert_eq!(device.get_encoder_count().unwrap(), 50000);
+    assert!(!device.has_status(MOVING).unwrap());
+    assert_eq!(last_outcome(&device), Some(OperationOutcome::Completed));
+    assert_eq!(device.get_servo_cycle_count(), 1);
+}
+
+#[test]
+fn move_that_runs_out_of_time_is_stopped() {
+    let (mut device, drive, clock) = enabled_device();
+    let mut defaults = device.get_motion_defaults().clone();
+    defaults.move_timeout = Duration::from_secs(2);
+    device.set_motion_defaults(defaults);
+
+    // At 1/240 rev/s this would take the best part of an hour
+    let result = device.move_servo(10, 10, 1, 1_000_000);
+    assert!(matches!(result, Err(DeviceError::MoveIncomplete(_))));
+    assert_eq!(last_outcome(&device), Some(OperationOutcome::Incomplete));
+    clock.advance(Duration::from_secs(1));
+    assert!(!device.has_status(MOVING).unwrap());
+    assert!(drive.position() < 1_000_000);
+    assert_eq!(device.get_servo_cycle_count(), 0);
+}
+
+#[test]
+fn cancelled_move_is_stopped() {
+    let (mut device, drive, clock) = enabled_device();
+    let cancel = CancelToken::new();
+    cancel.cancel();
+
+    let result = device.move_servo_with_cancel(10, 10, 100, 500_000, &cancel);
+    assert!(matches!(result, Err(DeviceError::MoveIncomplete(_))));
+    assert_eq!(last_outcome(&device), Some(OperationOutcome::Incomplete));
+    clock.advance(Duration::from_secs(5));
+    assert!(!device.has_status(MOVING).unwrap());
+    assert!(drive.position() < 500_000);
+}
+
+#[test]
+fn tripped_limit_stops_the_move() {
+    let (mut device, drive, _clock) = enabled_device();
+    device.set_register_map(&SimulatedDrive::register_map());
+    device
+        .set_limit_switch_config(&LimitSwitchConfig {
+            enabled: true,
+            normally_closed: false,
+            cw_input: 6,
+            ccw_input: 7,
+        })
+        .expect("Unable to set up the limits");
+    drive.trip_input_at(6, 20000);
+
+    let result = device.move_servo(10, 10, 100, 50000);
+    assert!(matches!(result, Err(DeviceError::MoveIncomplete(_))));
+    assert_eq!(last_outcome(&device), Some(OperationOutcome::Incomplete));
+    assert!(
+        (20000..20100).contains(&drive.position()),
+        "{}",
+        drive.position()
+    );
+    let limits = device.limit_switches().unwrap();
+    assert!(limits.cw_active);
+    assert!(!limits.ccw_active);
+}
+
+#[test]
+fn acknowledged_command_is_not_sent_again() {
+    let (mut device, drive, clock) = device_on(|drive| AcknowledgesFeeds { drive });
+    device.enable_motor().expect("Unable to enable the motor");
+    let feed = LengthFeed {
+        accel: 10,
+        decel: 10,
+        velocity: 100,
+        distance: 4000,
+    };
+
+    let result = device.feed_to_length(&feed);
+    assert!(
+        matches!(
+            result,
+            Err(DeviceError::Exception {
+                exception: ModbusException::Acknowledge,
+                ..
+            })
+        ),
+        "{:?}",
+        result
+    );
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(drive.position(), 4000);
+}